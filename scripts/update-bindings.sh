@@ -0,0 +1,21 @@
+#!/bin/sh
+# Regenerates the Rust bindings in src/bindings from the interfaces in candid/, using the
+# template, methods and type options of each canister in canister.toml.
+#
+# With --fetch, first replaces candid/*.did with the interfaces the live canisters publish, so
+# that the diff of the update shows exactly how the registry's interface changed.
+#
+# Needs didc from https://github.com/dfinity/candid, and dfx for --fetch.
+set -eu
+cd "$(dirname "$0")/.."
+DIDC=${DIDC:-didc}
+
+for name in mops storage; do
+  if [ "${1:-}" = "--fetch" ]; then
+    id=$(sed -n "/^\[imports\.$name\]/,/^\[/s/^canister_id = \"\(.*\)\"/\1/p" canister.toml)
+    dfx canister --ic metadata "$id" candid:service > "candid/$name.did"
+  fi
+  "$DIDC" bind "candid/$name.did" --target rs-agent --config canister.toml --import "$name" \
+    > "src/bindings/$name.rs"
+  rustfmt --edition 2021 "src/bindings/$name.rs"
+done
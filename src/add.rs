@@ -0,0 +1,176 @@
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::mops;
+use crate::toml::{
+    download_packages_from_lock, parse_mops_lock, update_mops_lock, PackageType, ResolveOptions,
+};
+use crate::utils::{confirm, ensure_online, get_cache_dir, Pending};
+use crate::workspace::lock_path;
+use anyhow::{anyhow, Result};
+use console::style;
+use futures::future::join_all;
+use ic_agent::Agent;
+use indicatif::HumanBytes;
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use toml_edit::{value, DocumentMut};
+
+/// Adds a registry package to mops.toml, then updates mops.lock and installs it.
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn add(agent: &Agent, config: &Config, args: crate::AddArg) -> Result<()> {
+    let (name, version) = match args.package.split_once('@') {
+        Some((name, version)) => (name.to_string(), Some(version.to_string())),
+        None => (args.package.clone(), None),
+    };
+    ensure_online(format_args!("look up {name} in the registry"))?;
+    let service = mops::Service(crate::utils::registry_id(), agent);
+    let version = match version {
+        Some(version) => version,
+        None => crate::metadata::highest_version(&service, &name)
+            .await?
+            .map_err(|_| anyhow!("{name} is not in the registry"))?,
+    };
+    let table = if args.dev {
+        "dev-dependencies"
+    } else {
+        "dependencies"
+    };
+    let path = Path::new("mops.toml");
+    let mut doc = match fs::read_to_string(path) {
+        Ok(str) => str.parse::<DocumentMut>()?,
+        Err(_) => DocumentMut::new(),
+    };
+    status!(
+        "{:>12} {name}@{version} to [{table}]",
+        style("Adding").green().bold()
+    );
+    // The lookups of the preview also fill the metadata cache, so the lock update that follows
+    // mostly reads from it.
+    let impact = impact(&service, &name, &version).await;
+    status!(
+        "{:>12} {} new package(s), {}{}",
+        style("Impact").cyan().bold(),
+        impact.packages,
+        HumanBytes(impact.bytes),
+        if impact.unknown > 0 {
+            format!(" and {} of unknown size", impact.unknown)
+        } else {
+            String::new()
+        }
+    );
+    let heavy = args.heavy_threshold.filter(|t| impact.bytes > *t);
+    if let Some(threshold) = heavy {
+        status!(
+            "{:>12} {name}@{version} is over --heavy-threshold {}",
+            style("Heavy").yellow().bold(),
+            HumanBytes(threshold)
+        );
+    }
+    let result = |added: bool| {
+        json!({
+            "name": name,
+            "version": version,
+            "table": table,
+            "added": added,
+            "new_packages": impact.packages,
+            "bytes": impact.bytes,
+            "unknown_size": impact.unknown,
+        })
+    };
+    if heavy.is_some() && !args.yes && !confirm("add the dependency")? {
+        if crate::utils::json() {
+            crate::utils::print_json(&result(false))?;
+        }
+        status!(
+            "{:>12} nothing was changed",
+            style("Aborted").yellow().bold()
+        );
+        return Ok(());
+    }
+    if doc.get(table).is_none() {
+        doc[table] = toml_edit::table();
+    }
+    doc[table][&name] = value(&version);
+    fs::write(path, doc.to_string())?;
+    let pending = Pending::default();
+    update_mops_lock(agent, &pending, &ResolveOptions::from_config(config)?).await?;
+    download_packages_from_lock(
+        agent,
+        &get_cache_dir(config)?,
+        &pending,
+        HashAlgorithm::from_config(config)?,
+        false,
+    )
+    .await?;
+    status!("{:>12} {name}@{version}", style("Added").green().bold());
+    if crate::utils::json() {
+        crate::utils::print_json(&result(true))?;
+    }
+    Ok(())
+}
+
+/// What adding a package brings into the project.
+#[derive(Default)]
+struct Impact {
+    /// Registry packages that are not locked yet, the added one included
+    packages: usize,
+    /// Their source size, as published in the registry
+    bytes: u64,
+    /// Packages whose metadata could not be fetched, not counted in `bytes`
+    unknown: usize,
+}
+
+/// Walks `name@version` and its transitive registry dependencies, one level of the tree at a
+/// time, stopping at versions mops.lock already has.
+async fn impact(service: &mops::Service<'_>, name: &str, version: &str) -> Impact {
+    let mut seen: BTreeSet<_> = parse_mops_lock(&lock_path())
+        .map(|lock| {
+            lock.package
+                .iter()
+                .filter_map(|pkg| match pkg.get_type() {
+                    PackageType::Mops { ver, .. } => {
+                        Some((pkg.registry_name().to_string(), ver.to_string()))
+                    }
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut res = Impact::default();
+    let mut level = vec![(name.to_string(), version.to_string())];
+    while !level.is_empty() {
+        level.retain(|pkg| seen.insert(pkg.clone()));
+        let details = join_all(
+            level
+                .iter()
+                .map(|(name, version)| crate::metadata::package_details(service, name, version)),
+        )
+        .await;
+        res.packages += level.len();
+        level = Vec::new();
+        for details in details {
+            let Ok(details) = details else {
+                res.unknown += 1;
+                continue;
+            };
+            res.bytes += details
+                .file_stats
+                .source_size
+                .0
+                .to_string()
+                .parse::<u64>()
+                .unwrap_or(0);
+            level.extend(
+                details
+                    .config
+                    .dependencies
+                    .into_iter()
+                    .filter(|dep| !dep.version.is_empty())
+                    .map(|dep| (dep.name, dep.version)),
+            );
+        }
+    }
+    res
+}
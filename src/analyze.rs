@@ -0,0 +1,324 @@
+//! Experimental: attributes the code of a compiled canister to the packages it comes from.
+use crate::bench::instr_seqs;
+use crate::build::get_targets;
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::toml::{download_packages_from_lock, generate_moc_args, get_package_sources};
+use crate::utils::{create_spinner_bar, download_moc, get_cache_dir, get_moc, Pending};
+use crate::workspace::lock_path;
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use flate2::{write::GzEncoder, Compression};
+use ic_agent::Agent;
+use indicatif::HumanBytes;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use walrus::ir::Instr;
+use walrus::{ExportItem, FunctionId, FunctionKind, Module};
+
+const OUT_DIR: &str = ".mops/analyze";
+/// Code the source map doesn't place in any file: the runtime system and compiler-generated glue.
+const RUNTIME: &str = "(runtime)";
+/// Files of the project itself.
+const PROJECT: &str = "(project)";
+/// Largest Wasm module that can be installed in a single ingress message.
+const INSTALL_LIMIT: u64 = 2 * 1024 * 1024;
+/// Largest Wasm module the IC accepts, installed in chunks.
+const MODULE_LIMIT: u64 = 100 * 1024 * 1024;
+
+#[derive(Default)]
+struct Usage {
+    functions: usize,
+    bytes: u64,
+    instructions: u64,
+    /// Canister methods that can reach code of this package.
+    methods: usize,
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn analyze(agent: &Agent, config: &Config, args: crate::AnalyzeArg) -> Result<()> {
+    let cache_dir = get_cache_dir(config)?;
+    download_moc(&cache_dir).await?;
+    if lock_path().exists() {
+        let algorithm = HashAlgorithm::from_config(config)?;
+        download_packages_from_lock(agent, &cache_dir, &Pending::default(), algorithm, false)
+            .await?;
+    }
+    let pkgs = generate_moc_args(&cache_dir, false)?;
+    let packages = package_dirs(&cache_dir)?;
+    let targets = get_targets(args.main.as_deref(), None, Path::new(OUT_DIR))?;
+    fs::create_dir_all(OUT_DIR)?;
+    for (main, wasm) in &targets {
+        let bar = create_spinner_bar(format!(
+            "{:>12} {}",
+            style("Compiling").cyan(),
+            main.display()
+        ));
+        let mut moc = get_moc(&cache_dir)?;
+        moc.arg(main)
+            .args(&pkgs)
+            .arg("--release")
+            .arg("--map")
+            .arg("-o")
+            .arg(wasm);
+        let output = moc
+            .output()
+            .with_context(|| format!("Error executing {:#?}", moc))?;
+        bar.finish_and_clear();
+        if !output.status.success() {
+            return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr)));
+        }
+        report(main, wasm, &packages, args.budget)?;
+    }
+    Ok(())
+}
+
+/// Source directories of the locked packages, canonicalized for matching source map paths.
+fn package_dirs(cache_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut dirs: Vec<_> = get_package_sources(cache_dir, false)?
+        .into_iter()
+        .map(|(name, path)| {
+            let path = fs::canonicalize(&path).unwrap_or(path);
+            (name, path)
+        })
+        .collect();
+    // Longest first, so nested packages win over their parent.
+    dirs.sort_by_key(|(_, path)| std::cmp::Reverse(path.as_os_str().len()));
+    Ok(dirs)
+}
+
+fn owner(source: &str, packages: &[(String, PathBuf)]) -> String {
+    let path = Path::new(source);
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    packages
+        .iter()
+        .find(|(_, dir)| path.starts_with(dir))
+        .map(|(name, _)| name.clone())
+        .unwrap_or_else(|| PROJECT.to_string())
+}
+
+fn report(main: &Path, wasm: &Path, packages: &[(String, PathBuf)], budget: bool) -> Result<()> {
+    let bytes = fs::read(wasm)?;
+    let mut gz = GzEncoder::new(Vec::new(), Compression::best());
+    gz.write_all(&bytes)?;
+    let gzipped = gz.finish()?.len() as u64;
+    let module = Module::from_buffer(&bytes)?;
+    let map_path = PathBuf::from(format!("{}.map", wasm.display()));
+    let map: SourceMap = serde_json::from_str(
+        &fs::read_to_string(&map_path)
+            .with_context(|| format!("moc did not write {}", map_path.display()))?,
+    )?;
+
+    // Each function belongs to the package most of its mapped instructions come from.
+    let mut ranges: Vec<(usize, usize, FunctionId)> = module
+        .funcs
+        .iter_local()
+        .filter_map(|(id, f)| f.original_range.map(|r| (r.start, r.end, id)))
+        .collect();
+    ranges.sort();
+    let mut votes: HashMap<FunctionId, BTreeMap<String, usize>> = HashMap::new();
+    for (offset, source) in map.decode()? {
+        let i = ranges.partition_point(|(start, _, _)| *start <= offset);
+        let Some((_, end, id)) = i.checked_sub(1).map(|i| ranges[i]) else {
+            continue;
+        };
+        if offset < end {
+            let pkg = owner(&map.sources[source], packages);
+            *votes.entry(id).or_default().entry(pkg).or_default() += 1;
+        }
+    }
+    let owners: HashMap<FunctionId, String> = ranges
+        .iter()
+        .map(|(_, _, id)| {
+            let pkg = votes
+                .get(id)
+                .and_then(|v| v.iter().max_by_key(|(_, n)| **n))
+                .map(|(pkg, _)| pkg.clone())
+                .unwrap_or_else(|| RUNTIME.to_string());
+            (*id, pkg)
+        })
+        .collect();
+
+    let mut usage: BTreeMap<String, Usage> = BTreeMap::new();
+    let mut calls: HashMap<FunctionId, Vec<FunctionId>> = HashMap::new();
+    for (start, end, id) in &ranges {
+        let FunctionKind::Local(func) = &module.funcs.get(*id).kind else {
+            continue;
+        };
+        let u = usage.entry(owners[id].clone()).or_default();
+        u.functions += 1;
+        u.bytes += (end - start) as u64;
+        for seq in instr_seqs(func) {
+            for (instr, _) in &func.block(seq).instrs {
+                u.instructions += 1;
+                if let Instr::Call(call) = instr {
+                    calls.entry(*id).or_default().push(call.func);
+                }
+            }
+        }
+    }
+
+    // Which packages each canister method can reach, following direct calls.
+    let methods: Vec<_> = module
+        .exports
+        .iter()
+        .filter_map(|e| match e.item {
+            ExportItem::Function(f) if e.name.starts_with("canister_") => Some(f),
+            _ => None,
+        })
+        .collect();
+    for method in &methods {
+        let mut seen = HashSet::new();
+        let mut stack = vec![*method];
+        let mut reached = HashSet::new();
+        while let Some(f) = stack.pop() {
+            if !seen.insert(f) {
+                continue;
+            }
+            if let Some(pkg) = owners.get(&f) {
+                reached.insert(pkg.clone());
+            }
+            stack.extend(calls.get(&f).into_iter().flatten());
+        }
+        for pkg in reached {
+            usage.entry(pkg).or_default().methods += 1;
+        }
+    }
+
+    let total: u64 = usage.values().map(|u| u.bytes).sum::<u64>().max(1);
+    status!(
+        "{:>12} {} to {} ({}, {} gzipped)",
+        style("Analyzed").green().bold(),
+        main.display(),
+        wasm.display(),
+        HumanBytes(bytes.len() as u64),
+        HumanBytes(gzipped)
+    );
+    let mut rows: Vec<_> = usage.into_iter().collect();
+    rows.sort_by_key(|(_, u)| std::cmp::Reverse(u.bytes));
+    if budget {
+        println!(
+            "  {:<24} {:>9} {:>10} {:>6} {:>13} {:>9}",
+            "package", "functions", "code", "share", "instructions", "methods"
+        );
+    } else {
+        println!(
+            "  {:<24} {:>9} {:>10} {:>6}",
+            "package", "functions", "code", "share"
+        );
+    }
+    for (pkg, u) in &rows {
+        let share = format!("{:.1}%", u.bytes as f64 * 100.0 / total as f64);
+        let code = HumanBytes(u.bytes).to_string();
+        if budget {
+            println!(
+                "  {pkg:<24} {:>9} {code:>10} {share:>6} {:>13} {:>4}/{:<4}",
+                u.functions,
+                u.instructions,
+                u.methods,
+                methods.len()
+            );
+        } else {
+            println!("  {pkg:<24} {:>9} {code:>10} {share:>6}", u.functions);
+        }
+    }
+    if budget {
+        check_limit(
+            "gzipped module",
+            gzipped,
+            INSTALL_LIMIT,
+            "a single install message",
+        );
+        check_limit(
+            "module",
+            bytes.len() as u64,
+            MODULE_LIMIT,
+            "chunked install",
+        );
+        println!(
+            "  Instructions are static counts of the code each package contributes. Methods is how many canister methods can reach that code through direct calls."
+        );
+    }
+    Ok(())
+}
+
+fn check_limit(what: &str, size: u64, limit: u64, via: &str) {
+    let percent = size as f64 * 100.0 / limit as f64;
+    let msg = format!(
+        "{what} is {} of the {} limit for {via} ({percent:.0}%)",
+        HumanBytes(size),
+        HumanBytes(limit)
+    );
+    if size > limit {
+        status!("{:>12} {msg}", style("Over").red().bold());
+    } else if percent > 80.0 {
+        status!("{:>12} {msg}", style("Near").yellow().bold());
+    } else {
+        status!("{:>12} {msg}", style("Within").green().bold());
+    }
+}
+
+/// The parts of a version 3 source map we need. For Wasm, the generated column is the byte
+/// offset in the module and there is a single generated line.
+#[derive(Deserialize)]
+struct SourceMap {
+    sources: Vec<String>,
+    mappings: String,
+}
+impl SourceMap {
+    /// `(byte offset, source index)` of every mapping segment.
+    fn decode(&self) -> Result<Vec<(usize, usize)>> {
+        let mut res = Vec::new();
+        let mut source = 0i64;
+        let mut column;
+        for line in self.mappings.split(';') {
+            column = 0;
+            for segment in line.split(',').filter(|s| !s.is_empty()) {
+                let fields = decode_vlq(segment)?;
+                column += fields[0];
+                if fields.len() >= 4 {
+                    source += fields[1];
+                    let index = usize::try_from(source)?;
+                    if index < self.sources.len() {
+                        res.push((usize::try_from(column)?, index));
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Decodes the base64 VLQ fields of one source map segment.
+fn decode_vlq(segment: &str) -> Result<Vec<i64>> {
+    let mut res = Vec::new();
+    let (mut value, mut shift) = (0i64, 0);
+    for c in segment.bytes() {
+        let digit = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return Err(anyhow!("Invalid source map segment {segment}")),
+        } as i64;
+        value += (digit & 31) << shift;
+        if digit & 32 != 0 {
+            shift += 5;
+            continue;
+        }
+        res.push(if value & 1 == 1 {
+            -(value >> 1)
+        } else {
+            value >> 1
+        });
+        (value, shift) = (0, 0);
+    }
+    if res.is_empty() {
+        return Err(anyhow!("Invalid source map segment {segment}"));
+    }
+    Ok(res)
+}
@@ -0,0 +1,389 @@
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::toml::{download_packages_from_lock, generate_moc_args};
+use crate::utils::{create_spinner_bar, download_moc, get_cache_dir, get_moc, Pending};
+use crate::workspace::lock_path;
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use ic_agent::Agent;
+use inferno::flamegraph;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use walrus::ir::{BinaryOp, Instr, InstrSeqId, MemArg, StoreKind, UnaryOp, Value};
+use walrus::{
+    ExportItem, FunctionBuilder, FunctionId, ImportKind, InitExpr, LocalFunction, Module, ValType,
+};
+
+const BENCH_DIR: &str = "bench";
+const OUT_DIR: &str = ".mops/bench";
+const EXPORT: &str = "__mops_bench";
+/// "MOPSPROF", marks the start of the counters in the benchmark's stdout.
+const MAGIC: u64 = u64::from_le_bytes(*b"MOPSPROF");
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Stats {
+    instructions: u64,
+    heap: u64,
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn bench(agent: &Agent, config: &Config, args: crate::BenchArg) -> Result<()> {
+    let cache_dir = get_cache_dir(config)?;
+    download_moc(&cache_dir).await?;
+    if lock_path().exists() {
+        let algorithm = HashAlgorithm::from_config(config)?;
+        download_packages_from_lock(agent, &cache_dir, &Pending::default(), algorithm, false)
+            .await?;
+    }
+    let pkgs = generate_moc_args(&cache_dir, true)?;
+    let files = find_bench_files(args.filter.as_deref())?;
+    if files.is_empty() {
+        status!("No {BENCH_DIR}/*.mo files found");
+        return Ok(());
+    }
+    let baseline = match &args.compare {
+        Some(name) => Some(load_baseline(name)?),
+        None => None,
+    };
+    let mut results = BTreeMap::new();
+    for file in &files {
+        let name = file.file_stem().unwrap().to_string_lossy().to_string();
+        let bar = create_spinner_bar(format!(
+            "{:>12} {}",
+            style("Running").cyan(),
+            file.display()
+        ));
+        let (stats, profile) = run_bench(&cache_dir, file, &pkgs)
+            .with_context(|| format!("Benchmark {} failed", file.display()))?;
+        bar.finish_and_clear();
+        let previous = baseline.as_ref().and_then(|b| b.get(&name));
+        status!(
+            "{:>12} {name:<30} {:>15} instructions{} {:>12} heap{}",
+            style("Bench").green().bold(),
+            stats.instructions,
+            delta(stats.instructions, previous.map(|p| p.instructions)),
+            format_bytes(stats.heap),
+            delta(stats.heap, previous.map(|p| p.heap)),
+        );
+        if args.flamegraph {
+            let svg = write_flamegraph(&name, &profile)?;
+            status!(
+                "{:>12} {}",
+                style("Flamegraph").green().bold(),
+                svg.display()
+            );
+        }
+        results.insert(name, stats);
+    }
+    if let Some(name) = &args.save {
+        let path = baseline_path(name);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, serde_json::to_string_pretty(&results)?)?;
+        status!(
+            "{:>12} baseline {name} to {}",
+            style("Saved").green().bold(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn find_bench_files(filter: Option<&str>) -> Result<Vec<PathBuf>> {
+    let dir = Path::new(BENCH_DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "mo") {
+            continue;
+        }
+        if filter.is_some_and(|f| !path.to_string_lossy().contains(f)) {
+            continue;
+        }
+        files.push(path);
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Instruction counts of each function of a benchmark, in the order of the counters.
+struct Profile {
+    names: Vec<String>,
+    counts: Vec<u64>,
+}
+
+/// Compiles `file` to a WASI module, instruments it and runs it with wasmtime.
+fn run_bench(cache_dir: &Path, file: &Path, pkgs: &[String]) -> Result<(Stats, Profile)> {
+    let wasm = Path::new(OUT_DIR).join(file.with_extension("wasm").file_name().unwrap());
+    fs::create_dir_all(OUT_DIR)?;
+    let mut moc = get_moc(cache_dir)?;
+    moc.args(pkgs)
+        .arg("-wasi-system-api")
+        .arg("-o")
+        .arg(&wasm)
+        .arg(file);
+    let output = moc
+        .output()
+        .with_context(|| format!("Error executing {:#?}", moc))?;
+    if !output.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr)));
+    }
+    let names = instrument(&wasm)?;
+    let output = Command::new("wasmtime")
+        .arg("run")
+        .arg("--invoke")
+        .arg(EXPORT)
+        .arg(&wasm)
+        .output()
+        .context("Error executing wasmtime. Is it installed and in PATH?")?;
+    if !output.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr)));
+    }
+    // stdout holds the benchmark's own output, then the counters block, then the heap size
+    // printed by wasmtime as the result of the invoked export.
+    let stdout = &output.stdout;
+    let start = stdout
+        .windows(8)
+        .rposition(|w| w == MAGIC.to_le_bytes())
+        .ok_or_else(|| anyhow!("No profile in wasmtime output"))?;
+    let block = &stdout[start + 8..];
+    let read = |i: usize| -> Result<u64> {
+        let bytes = block
+            .get(i * 8..i * 8 + 8)
+            .ok_or_else(|| anyhow!("Truncated profile in wasmtime output"))?;
+        Ok(u64::from_le_bytes(bytes.try_into()?))
+    };
+    let n = read(0)? as usize;
+    if n != names.len() {
+        return Err(anyhow!("Expected {} counters, got {n}", names.len()));
+    }
+    let counts = (1..=n).map(read).collect::<Result<Vec<_>>>()?;
+    let rest = String::from_utf8_lossy(&block[(n + 1) * 8..]);
+    let heap = rest
+        .trim()
+        .parse()
+        .with_context(|| format!("Unexpected wasmtime output {rest:?}"))?;
+    let stats = Stats {
+        instructions: counts.iter().sum(),
+        heap,
+    };
+    Ok((stats, Profile { names, counts }))
+}
+
+/// Adds an instruction counter to every function, and exports a function that runs `_start`,
+/// writes the counters to stdout after [`MAGIC`] and returns the final size of linear memory
+/// in bytes. Returns the function names in counter order.
+fn instrument(wasm: &Path) -> Result<Vec<String>> {
+    let mut module = Module::from_file(wasm)?;
+    let ids: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let mut counters = HashMap::new();
+    let mut names = Vec::new();
+    for (i, id) in ids.iter().enumerate() {
+        let global = module
+            .globals
+            .add_local(ValType::I64, true, InitExpr::Value(Value::I64(0)));
+        counters.insert(*id, global);
+        let name = module.funcs.get(*id).name.clone();
+        names.push(name.unwrap_or_else(|| format!("func{i}")));
+    }
+    for (id, func) in module.funcs.iter_local_mut() {
+        let counter = counters[&id];
+        for seq in instr_seqs(func) {
+            let block = func.block_mut(seq);
+            let count = block.instrs.len() as i64;
+            let loc = Default::default();
+            let prelude = [
+                Instr::GlobalGet(walrus::ir::GlobalGet { global: counter }),
+                Instr::Const(walrus::ir::Const {
+                    value: Value::I64(count),
+                }),
+                Instr::Binop(walrus::ir::Binop {
+                    op: BinaryOp::I64Add,
+                }),
+                Instr::GlobalSet(walrus::ir::GlobalSet { global: counter }),
+            ];
+            block
+                .instrs
+                .splice(0..0, prelude.into_iter().map(|instr| (instr, loc)));
+        }
+    }
+    let start = module
+        .exports
+        .iter()
+        .find_map(|e| match e.item {
+            ExportItem::Function(id) if e.name == "_start" => Some(id),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("{} has no _start export", wasm.display()))?;
+    let memory = module
+        .memories
+        .iter()
+        .next()
+        .map(|m| m.id())
+        .ok_or_else(|| anyhow!("{} has no memory", wasm.display()))?;
+    let fd_write = match module.imports.find("wasi_snapshot_preview1", "fd_write") {
+        Some(import) => match module.imports.get(import).kind {
+            ImportKind::Function(id) => id,
+            _ => return Err(anyhow!("fd_write is not a function")),
+        },
+        None => {
+            let ty = module.types.add(&[ValType::I32; 4], &[ValType::I32]);
+            module
+                .add_import_func("wasi_snapshot_preview1", "fd_write", ty)
+                .0
+        }
+    };
+
+    // Layout of the scratch pages: iovec (8 bytes), nwritten (8 bytes), then the data:
+    // MAGIC, the number of counters and the counters, all as little-endian u64.
+    let data_len = 8 * (ids.len() + 2);
+    let pages = (16 + data_len).div_ceil(65536) as i32;
+    let heap = module.locals.add(ValType::I64);
+    let base = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I64]);
+    let mut body = builder.func_body();
+    let i64_store = |offset: u32| {
+        (
+            StoreKind::I64 { atomic: false },
+            MemArg { align: 8, offset },
+        )
+    };
+    body.call(start)
+        .memory_size(memory)
+        .unop(UnaryOp::I64ExtendUI32)
+        .i64_const(65536)
+        .binop(BinaryOp::I64Mul)
+        .local_set(heap)
+        .i32_const(pages)
+        .memory_grow(memory)
+        .i32_const(65536)
+        .binop(BinaryOp::I32Mul)
+        .local_set(base);
+    let (kind, arg) = i64_store(16);
+    body.local_get(base)
+        .i64_const(MAGIC as i64)
+        .store(memory, kind, arg);
+    let (kind, arg) = i64_store(24);
+    body.local_get(base)
+        .i64_const(ids.len() as i64)
+        .store(memory, kind, arg);
+    for (i, id) in ids.iter().enumerate() {
+        let (kind, arg) = i64_store(32 + 8 * i as u32);
+        body.local_get(base)
+            .global_get(counters[id])
+            .store(memory, kind, arg);
+    }
+    let i32_store = |offset: u32| {
+        (
+            StoreKind::I32 { atomic: false },
+            MemArg { align: 4, offset },
+        )
+    };
+    let (kind, arg) = i32_store(0);
+    body.local_get(base)
+        .local_get(base)
+        .i32_const(16)
+        .binop(BinaryOp::I32Add)
+        .store(memory, kind, arg);
+    let (kind, arg) = i32_store(4);
+    body.local_get(base)
+        .i32_const(data_len as i32)
+        .store(memory, kind, arg);
+    body.i32_const(1)
+        .local_get(base)
+        .i32_const(1)
+        .local_get(base)
+        .i32_const(8)
+        .binop(BinaryOp::I32Add)
+        .call(fd_write)
+        .drop()
+        .local_get(heap);
+    let func = builder.finish(vec![], &mut module.funcs);
+    module.exports.add(EXPORT, func);
+    module.emit_wasm_file(wasm)?;
+    Ok(names)
+}
+
+/// Writes a flamegraph of the profile. Function names are split on `.` so that functions of the
+/// same Motoko module, and so of the same dependency, are grouped together.
+fn write_flamegraph(name: &str, profile: &Profile) -> Result<PathBuf> {
+    let lines: Vec<String> = profile
+        .names
+        .iter()
+        .zip(&profile.counts)
+        .filter(|(_, count)| **count > 0)
+        .map(|(func, count)| {
+            let frames = func.trim_start_matches('$').replace(['.', ';', ' '], ";");
+            format!("{name};{frames} {count}")
+        })
+        .collect();
+    let path = Path::new(OUT_DIR).join(format!("{name}.svg"));
+    let mut options = flamegraph::Options::default();
+    options.title = format!("{name} (instructions)");
+    options.count_name = "instructions".to_string();
+    flamegraph::from_lines(
+        &mut options,
+        lines.iter().map(|l| l.as_str()),
+        fs::File::create(&path)?,
+    )?;
+    Ok(path)
+}
+
+/// All instruction sequences of a function: the body and every nested block.
+pub(crate) fn instr_seqs(func: &LocalFunction) -> Vec<InstrSeqId> {
+    let mut res = Vec::new();
+    let mut stack = vec![func.entry_block()];
+    while let Some(id) = stack.pop() {
+        res.push(id);
+        for (instr, _) in &func.block(id).instrs {
+            match instr {
+                Instr::Block(b) => stack.push(b.seq),
+                Instr::Loop(l) => stack.push(l.seq),
+                Instr::IfElse(i) => {
+                    stack.push(i.consequent);
+                    stack.push(i.alternative);
+                }
+                _ => {}
+            }
+        }
+    }
+    res
+}
+
+fn baseline_path(name: &str) -> PathBuf {
+    Path::new(OUT_DIR).join(format!("{name}.json"))
+}
+
+fn load_baseline(name: &str) -> Result<BTreeMap<String, Stats>> {
+    let path = baseline_path(name);
+    let str = fs::read_to_string(&path)
+        .with_context(|| format!("Baseline {name} not found. Save one with --save {name}"))?;
+    Ok(serde_json::from_str(&str)?)
+}
+
+fn delta(current: u64, previous: Option<u64>) -> String {
+    let Some(previous) = previous else {
+        return String::new();
+    };
+    if previous == 0 {
+        return String::new();
+    }
+    let pct = (current as f64 - previous as f64) / previous as f64 * 100.0;
+    let text = format!(" ({pct:+.2}%)");
+    if pct > 0.0 {
+        style(text).red().to_string()
+    } else if pct < 0.0 {
+        style(text).green().to_string()
+    } else {
+        style(text).dim().to_string()
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    indicatif::HumanBytes(bytes).to_string()
+}
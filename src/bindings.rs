@@ -0,0 +1,20 @@
+//! Typed bindings for the canisters of the mops registry, for Rust tools that talk to the
+//! registry directly.
+//!
+//! The bindings are generated from the Candid interfaces committed in `candid/`, so changes to
+//! the registry's interface show up as a diff of those files. Run `scripts/update-bindings.sh
+//! --fetch` to pick up a new interface.
+//!
+//! ```no_run
+//! # async fn run(agent: &ic_agent::Agent) -> anyhow::Result<()> {
+//! use mops_cli::bindings::mops;
+//! let registry = mops::Service(mops::CANISTER_ID, agent);
+//! let version = registry.get_highest_version(&"base".to_string()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+/// The package registry: versions, metadata and publishing.
+pub mod mops;
+/// Where the files of published packages are stored, in chunks.
+pub mod storage;
@@ -1,5 +1,6 @@
-// This is an experimental feature to generate Rust binding from Candid.
-// You may want to manually adjust some of the types.
+//! Bindings for the `mops` canister, generated from `candid/mops.did` by
+//! `scripts/update-bindings.sh`. Don't edit by hand: change the template `agent.hbs` or the
+//! options in `canister.toml` instead.
 #![allow(dead_code, unused_imports)]
 use candid::{self, CandidType, Decode, Deserialize, Encode, Principal};
 type Result<T> = std::result::Result<T, ic_agent::AgentError>;
@@ -230,9 +231,24 @@ pub struct PackageDetails {
     pub publication: PackagePublication,
 }
 pub type Result4 = candid::MotokoResult<PackageDetails, Err>;
+pub type Result6 = candid::MotokoResult<(), Err>;
+pub type PageCount = candid::Nat;
+pub type PublishingId = String;
+pub type Result8 = candid::MotokoResult<PublishingId, Err>;
+pub type Result9 = candid::MotokoResult<FileId, Err>;
 
 pub struct Service<'a>(pub Principal, pub &'a ic_agent::Agent);
 impl<'a> Service<'a> {
+    pub async fn finish_publish(&self, arg0: &PublishingId) -> Result<Result6> {
+        let args = Encode!(&arg0)?;
+        let bytes = self
+            .1
+            .update(&self.0, "finishPublish")
+            .with_arg(args)
+            .call_and_wait()
+            .await?;
+        Ok(Decode!(&bytes, Result6)?)
+    }
     pub async fn get_file_ids(&self, arg0: &PackageName, arg1: &PackageVersion) -> Result<Result7> {
         let args = Encode!(&arg0, &arg1)?;
         let bytes = self
@@ -267,5 +283,74 @@ impl<'a> Service<'a> {
             .await?;
         Ok(Decode!(&bytes, Result4)?)
     }
+    pub async fn get_package_owners(&self, arg0: &PackageName) -> Result<Vec<Principal>> {
+        let args = Encode!(&arg0)?;
+        let bytes = self
+            .1
+            .query(&self.0, "getPackageOwners")
+            .with_arg(args)
+            .call()
+            .await?;
+        Ok(Decode!(&bytes, Vec<Principal>)?)
+    }
+    pub async fn search(
+        &self,
+        arg0: &String,
+        arg1: &Option<candid::Nat>,
+        arg2: &Option<candid::Nat>,
+    ) -> Result<(Vec<PackageSummary1>, PageCount)> {
+        let args = Encode!(&arg0, &arg1, &arg2)?;
+        let bytes = self
+            .1
+            .query(&self.0, "search")
+            .with_arg(args)
+            .call()
+            .await?;
+        Ok(Decode!(&bytes, Vec<PackageSummary1>, PageCount)?)
+    }
+    pub async fn start_file_upload(
+        &self,
+        arg0: &PublishingId,
+        arg1: &String,
+        arg2: &candid::Nat,
+        arg3: &serde_bytes::ByteBuf,
+    ) -> Result<Result9> {
+        let args = Encode!(&arg0, &arg1, &arg2, &arg3)?;
+        let bytes = self
+            .1
+            .update(&self.0, "startFileUpload")
+            .with_arg(args)
+            .call_and_wait()
+            .await?;
+        Ok(Decode!(&bytes, Result9)?)
+    }
+    pub async fn start_publish(&self, arg0: &PackageConfigV3) -> Result<Result8> {
+        let args = Encode!(&arg0)?;
+        let bytes = self
+            .1
+            .update(&self.0, "startPublish")
+            .with_arg(args)
+            .call_and_wait()
+            .await?;
+        Ok(Decode!(&bytes, Result8)?)
+    }
+    pub async fn upload_file_chunk(
+        &self,
+        arg0: &PublishingId,
+        arg1: &FileId,
+        arg2: &candid::Nat,
+        arg3: &serde_bytes::ByteBuf,
+    ) -> Result<Result6> {
+        let args = Encode!(&arg0, &arg1, &arg2, &arg3)?;
+        let bytes = self
+            .1
+            .update(&self.0, "uploadFileChunk")
+            .with_arg(args)
+            .call_and_wait()
+            .await?;
+        Ok(Decode!(&bytes, Result6)?)
+    }
 }
 pub const CANISTER_ID: Principal = Principal::from_slice(&[0, 0, 0, 0, 1, 144, 1, 124, 1, 1]); // oknww-riaaa-aaaam-qaf6a-cai
+/// The interface these bindings were generated from.
+pub const CANDID: &str = include_str!("../../candid/mops.did");
@@ -0,0 +1,81 @@
+//! Bitbucket Cloud repositories as dependencies, e.g.
+//! `https://bitbucket.org/<workspace>/<repo>#<tag>`.
+use crate::git_host::{download_archive, encode, encode_path, get_file, get_json, GitHost};
+use crate::github::RepoInfo;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+use std::path::Path;
+
+pub(crate) struct Bitbucket;
+
+fn repo_url(repo: &str) -> String {
+    format!("https://api.bitbucket.org/2.0/repositories/{repo}")
+}
+
+fn auth() -> Option<(&'static str, String)> {
+    crate::github::token("bitbucket_token")
+        .map(|token| ("Authorization", format!("Bearer {token}")))
+}
+
+impl GitHost for Bitbucket {
+    fn domain(&self) -> &str {
+        "bitbucket.org"
+    }
+
+    fn default_branch<'a>(&'a self, repo: &'a str) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Repository {
+            mainbranch: Branch,
+        }
+        #[derive(Deserialize)]
+        struct Branch {
+            name: String,
+        }
+        async move {
+            let repository: Repository = get_json(&repo_url(repo), auth()).await?;
+            Ok(repository.mainbranch.name)
+        }
+        .boxed()
+    }
+
+    fn latest_commit<'a>(&'a self, repo: &'a str, tag: &'a str) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Commit {
+            hash: String,
+        }
+        async move {
+            let url = format!("{}/commit/{}", repo_url(repo), encode(tag));
+            let commit: Commit = get_json(&url, auth()).await?;
+            Ok(commit.hash)
+        }
+        .boxed()
+    }
+
+    fn fetch_file<'a>(
+        &'a self,
+        repo: &'a RepoInfo,
+        file: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        let url = format!(
+            "{}/src/{}/{}",
+            repo_url(&repo.repo),
+            repo.commit,
+            encode_path(file)
+        );
+        async move { get_file(&url, auth()).await }.boxed()
+    }
+
+    fn download<'a>(
+        &'a self,
+        base_path: &'a Path,
+        repo: &'a RepoInfo,
+    ) -> BoxFuture<'a, Result<()>> {
+        let url = format!(
+            "https://bitbucket.org/{}/get/{}.tar.gz",
+            repo.repo, repo.commit
+        );
+        async move { download_archive(&url, auth(), base_path, repo).await }.boxed()
+    }
+}
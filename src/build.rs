@@ -1,63 +1,156 @@
-use crate::toml::{download_packages_from_lock, generate_moc_args, update_mops_toml};
-use crate::utils::{create_spinner_bar, download_moc, exec, get_cache_dir, get_moc};
+use crate::config::Config;
+use crate::dfx::motoko_canisters;
+use crate::integrity::HashAlgorithm;
+use crate::toml::{
+    download_packages_from_lock, ensure_cached, generate_moc_args, get_package_base_dir,
+    update_mops_toml, ResolveOptions,
+};
+use crate::utils::{create_spinner_bar, download_moc, exec, get_cache_dir, get_moc, Pending};
 use anyhow::{anyhow, Context, Result};
 use candid::Principal;
 use console::style;
 use ic_agent::Agent;
 use indicatif::HumanDuration;
 use std::collections::BTreeSet;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 use std::time::Instant;
 
+/// Marks a target directory created by `mops build`, so `mops clean` only deletes its own outputs.
+pub(crate) const TARGET_MARKER: &str = ".mops-target";
+
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
-pub async fn build(agent: &Agent, args: crate::BuildArg) -> Result<()> {
-    let main_file = args.main.unwrap_or_else(|| PathBuf::from("main.mo"));
-    let cache_dir = get_cache_dir(&args.cache_dir)?;
+pub async fn build(agent: &Agent, config: &Config, args: crate::BuildArg) -> Result<()> {
+    let targets = get_targets(
+        args.main.as_deref(),
+        args.output.as_deref(),
+        &args.target_dir,
+    )?;
+    let uses_target_dir = targets.iter().any(|(_, o)| o.starts_with(&args.target_dir));
+    if uses_target_dir && !args.target_dir.exists() {
+        std::fs::create_dir_all(&args.target_dir)?;
+        std::fs::write(args.target_dir.join(TARGET_MARKER), "")?;
+    }
+    for (_, output) in &targets {
+        if let Some(dir) = output.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    let cache_dir = get_cache_dir(config)?;
     download_moc(&cache_dir).await?;
     let start = Instant::now();
     if !args.lock {
-        let imports = get_imports(&main_file, &cache_dir)?;
-        update_mops_toml(agent, imports).await?;
-        download_packages_from_lock(agent, &cache_dir).await?;
+        let mut imports = BTreeSet::new();
+        for (main_file, _) in &targets {
+            imports.append(&mut get_imports(main_file, &cache_dir)?);
+        }
+        let pending = Pending::default();
+        let algorithm = HashAlgorithm::from_config(config)?;
+        let options = ResolveOptions {
+            locked: args.locked || args.frozen,
+            ..ResolveOptions::from_config(config)?
+        };
+        update_mops_toml(agent, imports, &pending, &options).await?;
+        if args.frozen {
+            ensure_cached(&cache_dir)?;
+        }
+        download_packages_from_lock(agent, &cache_dir, &pending, algorithm, false).await?;
     }
     let lock_time = start.elapsed();
-    let pkgs = generate_moc_args(&cache_dir)?;
-    let msg = format!("{:>12} {}", style("Compiling").cyan(), main_file.display());
-    let bar = create_spinner_bar(msg);
-    let mut moc = get_moc(&cache_dir)?;
-    moc.arg(&main_file).args(pkgs);
-    if let Some(out) = &args.output {
-        moc.arg("-o").arg(out);
-    }
-    if !args.extra_args.is_empty() {
-        for arg in args.extra_args {
-            moc.arg(arg);
+    let pkgs = generate_moc_args(&cache_dir, false)?;
+    let default_args = args.extra_args.is_empty();
+    for (main_file, output) in &targets {
+        let start = Instant::now();
+        let msg = format!("{:>12} {}", style("Compiling").cyan(), main_file.display());
+        let bar = create_spinner_bar(msg);
+        let mut moc = get_moc(&cache_dir)?;
+        moc.arg(main_file).args(&pkgs).arg("-o").arg(output);
+        if !default_args {
+            moc.args(&args.extra_args);
+        } else {
+            moc.arg("--release")
+                .arg("--idl")
+                .arg("--stable-types")
+                .arg("--public-metadata")
+                .arg("candid:service");
         }
-    } else {
-        moc.arg("--release")
-            .arg("--idl")
-            .arg("--stable-types")
-            .arg("--public-metadata")
-            .arg("candid:service");
+        exec(moc, Some(&bar))?;
+        bar.finish_and_clear();
+        let mut msg = format!(
+            "{:>12} {} to {}",
+            style("Compiled").green().bold(),
+            main_file.display(),
+            output.display()
+        );
+        if default_args {
+            msg.push_str(&format!(" and {}", output.with_extension("did").display()));
+        }
+        msg.push_str(&format!(" in {}", HumanDuration(start.elapsed())));
+        status!("{msg}");
     }
-    exec(moc, Some(&bar))?;
-    bar.finish_and_clear();
-    let mut msg = format!(
-        "{:>12} {} in {}",
-        style("Compiled").green().bold(),
-        main_file.display(),
-        HumanDuration(start.elapsed())
-    );
     if !args.lock {
-        msg.push_str(&format!(
-            " ({} to analyze dependencies)",
+        status!(
+            "{:>12} dependencies in {}",
+            style("Analyzed").green().bold(),
             HumanDuration(lock_time)
-        ));
+        );
     }
-    println!("{msg}");
     Ok(())
 }
 
+/// The files to compile and where to write them. Without an explicit main file, every Motoko
+/// canister of dfx.json is built to `<target-dir>/<canister>.wasm`.
+pub(crate) fn get_targets(
+    main: Option<&Path>,
+    output: Option<&str>,
+    target_dir: &Path,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let default_output = |name: &OsStr| target_dir.join(name).with_extension("wasm");
+    if let Some(main) = main {
+        let output = match output {
+            Some(out) => PathBuf::from(out),
+            None => default_output(main.file_stem().unwrap_or_default()),
+        };
+        return Ok(vec![(main.to_path_buf(), output)]);
+    }
+    let canisters = motoko_canisters()?;
+    if canisters.is_empty() {
+        let main = find_entry_file()?;
+        let output = match output {
+            Some(out) => PathBuf::from(out),
+            None => default_output(main.file_stem().unwrap_or_default()),
+        };
+        return Ok(vec![(main, output)]);
+    }
+    match (output, canisters.as_slice()) {
+        (Some(out), [(_, main)]) => Ok(vec![(main.clone(), PathBuf::from(out))]),
+        (Some(_), _) => Err(anyhow!(
+            "dfx.json has {} Motoko canisters. Pass the main file to use --output.",
+            canisters.len()
+        )),
+        (None, _) => Ok(canisters
+            .into_iter()
+            .map(|(name, main)| {
+                let output = default_output(OsStr::new(&name));
+                (main, output)
+            })
+            .collect()),
+    }
+}
+
+/// Looks for `main.mo` at the project root, then in `src` and the package's `baseDir`.
+fn find_entry_file() -> Result<PathBuf> {
+    let mut candidates = vec![PathBuf::from("main.mo"), PathBuf::from("src/main.mo")];
+    let base_dir = PathBuf::from(get_package_base_dir()?).join("main.mo");
+    if !candidates.contains(&base_dir) {
+        candidates.push(base_dir);
+    }
+    candidates
+        .into_iter()
+        .find(|path| path.is_file())
+        .ok_or_else(|| anyhow!("Cannot find main.mo. Pass the entry file as an argument."))
+}
+
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
 pub enum MotokoImport {
     Canister(String),
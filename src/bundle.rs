@@ -0,0 +1,141 @@
+//! One archive with a project, its locked packages and toolchain pins, for rebuilding it exactly
+//! and offline years later.
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::toml::{
+    download_packages_from_lock, get_toolchain_moc, parse_mops_lock, render_mops_lock, PackageType,
+};
+use crate::utils::{get_cache_dir, Pending};
+use crate::workspace::{lock_dir, lock_path};
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use flate2::{write::GzEncoder, Compression};
+use ic_agent::Agent;
+use ignore::WalkBuilder;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Where the locked packages go in the bundle, recorded as its `vendor` directory.
+const PACKAGES_DIR: &str = "packages";
+
+const REBUILD: &str = r#"#!/bin/sh
+# Rebuilds this project from the bundle. The packages are vendored, so the network is only
+# needed to download the pinned moc when it is not installed yet.
+set -e
+cd "$(dirname "$0")"
+mops install --frozen
+mops build --frozen
+"#;
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn bundle(agent: &Agent, config: &Config, args: crate::BundleArg) -> Result<()> {
+    let lock = lock_path();
+    if !lock.exists() {
+        return Err(anyhow!("mops.lock not found. Run `mops install` first."));
+    }
+    let root = lock_dir();
+    let cache_dir = get_cache_dir(config)?;
+    let algorithm = HashAlgorithm::from_config(config)?;
+    download_packages_from_lock(agent, &cache_dir, &Pending::default(), algorithm, false).await?;
+    let mut pkgs = parse_mops_lock(&lock)?;
+    let from = pkgs.package_root(&cache_dir);
+
+    // Everything goes through `files` first, so the archive is sorted and the manifest can list
+    // every file with its hash.
+    let mut files: BTreeMap<PathBuf, Vec<u8>> = BTreeMap::new();
+    let output = std::path::absolute(&args.output)?;
+    let mut skip = vec![lock.clone(), root.join(".mops"), output.clone()];
+    if let Some(vendor) = &pkgs.vendor {
+        skip.push(root.join(vendor));
+    }
+    for entry in WalkBuilder::new(&root)
+        .require_git(false)
+        .filter_entry(move |entry| !skip.iter().any(|s| entry.path() == s))
+        .build()
+    {
+        let entry = entry?;
+        if entry.file_type().is_some_and(|t| t.is_file()) {
+            let path = entry.path().strip_prefix(&root)?.to_path_buf();
+            files.insert(path, fs::read(entry.path())?);
+        }
+    }
+    for pkg in &pkgs.package {
+        match pkg.get_type() {
+            PackageType::Local(_) => {
+                let path = fs::canonicalize(pkg.get_path())?;
+                if !path.starts_with(fs::canonicalize(&root)?) {
+                    return Err(anyhow!(
+                        "{} is outside the project at {}, so it cannot be bundled",
+                        pkg.name,
+                        path.display()
+                    ));
+                }
+            }
+            _ => {
+                let dir = from.join(pkg.get_path());
+                for entry in WalkDir::new(&dir).follow_links(true) {
+                    let entry = entry?;
+                    if entry.file_type().is_file() {
+                        let path = Path::new(PACKAGES_DIR)
+                            .join(pkg.get_path())
+                            .join(entry.path().strip_prefix(&dir)?);
+                        files.insert(path, fs::read(entry.path())?);
+                    }
+                }
+            }
+        }
+    }
+    pkgs.vendor = Some(PACKAGES_DIR.to_string());
+    files.insert("mops.lock".into(), render_mops_lock(&pkgs)?.into_bytes());
+    files.insert("rebuild.sh".into(), REBUILD.as_bytes().to_vec());
+    let manifest = manifest(&files)?;
+    files.insert("BUNDLE.toml".into(), manifest.into_bytes());
+
+    let gz = GzEncoder::new(File::create(&output)?, Compression::default());
+    let mut tar = tar::Builder::new(gz);
+    for (path, content) in &files {
+        // Fixed metadata, so bundling the same project twice gives the same archive.
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(if path == Path::new("rebuild.sh") {
+            0o755
+        } else {
+            0o644
+        });
+        header.set_mtime(0);
+        tar.append_data(&mut header, path, &content[..])
+            .with_context(|| format!("Failed to bundle {}", path.display()))?;
+    }
+    tar.into_inner()?.finish()?;
+    status!(
+        "{:>12} {} package(s) and {} file(s) into {}",
+        style("Bundled").green().bold(),
+        pkgs.package.len(),
+        files.len(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// BUNDLE.toml: the versions to rebuild with and the SHA-256 of every other file, for audits.
+fn manifest(files: &BTreeMap<PathBuf, Vec<u8>>) -> Result<String> {
+    let mut doc = toml_edit::DocumentMut::new();
+    doc["mops_cli"] = toml_edit::value(env!("CARGO_PKG_VERSION"));
+    if let Some(moc) = get_toolchain_moc()? {
+        doc["toolchain"] = toml_edit::table();
+        doc["toolchain"]["moc"] = toml_edit::value(moc);
+    }
+    let mut hashes = toml_edit::Table::new();
+    for (path, content) in files {
+        let path = path.display().to_string().replace('\\', "/");
+        hashes.insert(
+            &path,
+            toml_edit::value(hex::encode(Sha256::digest(content))),
+        );
+    }
+    doc["files"] = toml_edit::Item::Table(hashes);
+    Ok(doc.to_string())
+}
@@ -0,0 +1,542 @@
+use crate::config::Config;
+use crate::integrity::verify_dir;
+use crate::registry::parse_package_spec;
+use crate::store::Store;
+use crate::toml::{parse_mops_lock, Normalization, PackageType, Packages};
+use crate::utils::get_cache_dir;
+use crate::workspace::lock_path;
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use indicatif::{HumanBytes, HumanDuration};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// Version of the on-disk cache layout, stored in `<cache>/LAYOUT`.
+///
+/// 1. `mops/{name}-{ver}` and `git/{repo}/{commit}` directories with empty `DONE*` markers.
+///    This layout has no `LAYOUT` file.
+/// 2. Same directories, but every `DONE*` marker is a JSON [`Marker`]. The marker's modification
+///    time is when the package was last used.
+pub(crate) const LAYOUT_VERSION: u32 = 2;
+const LAYOUT_FILE: &str = "LAYOUT";
+/// Absolute paths of the mops.lock files that have installed into this cache, one per line.
+/// `gc` keeps everything they reference.
+const PROJECTS_FILE: &str = "projects";
+
+/// Content of a `DONE*` file, written once a package has been fully downloaded.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Marker {
+    pub layout: u32,
+    /// `name@version` for registry packages, `repo@commit` for GitHub ones.
+    pub id: String,
+    /// Unix seconds when the download finished.
+    pub created: u64,
+    /// Set when the directory predates markers and was adopted in place.
+    #[serde(default)]
+    pub adopted: bool,
+}
+
+/// A package directory in the cache.
+#[derive(Serialize)]
+pub(crate) struct Entry {
+    /// `name@version` or `repo@commit`
+    pub id: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Unix seconds of the last build that used the package
+    pub last_used: Option<u64>,
+    /// False if the download never finished
+    pub complete: bool,
+}
+
+pub fn dir(config: &Config) -> Result<()> {
+    println!("{}", get_cache_dir(config)?.display());
+    Ok(())
+}
+
+/// Prints the directory of an installed package. Without a version, the one in mops.lock is used.
+pub fn path(config: &Config, package: &str) -> Result<()> {
+    let root = get_cache_dir(config)?;
+    let dir = if package.contains('@') {
+        let (name, version) = parse_package_spec(package)?;
+        let normalization: Normalization =
+            config.get_parsed("name_normalization")?.unwrap_or_default();
+        root.join(normalization.mops_path(&name, &version))
+    } else {
+        let lock = parse_mops_lock(&lock_path())
+            .context("No mops.lock found. Pass <package>@<version> instead.")?;
+        let pkg = lock
+            .package
+            .iter()
+            .find(|p| p.name == package)
+            .ok_or_else(|| anyhow!("{package} is not in mops.lock"))?;
+        root.join(pkg.get_path())
+    };
+    if !dir.is_dir() {
+        return Err(anyhow!(
+            "{package} is not in the cache at {}",
+            root.display()
+        ));
+    }
+    println!("{}", dir.display());
+    Ok(())
+}
+
+pub fn ls(config: &Config, json: bool) -> Result<()> {
+    let entries = list_entries(&get_cache_dir(config)?)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    let now = now();
+    for e in entries {
+        let last_used = match e.last_used {
+            Some(t) => format!(
+                "{} ago",
+                HumanDuration(Duration::from_secs(now.saturating_sub(t)))
+            ),
+            None => "never".to_string(),
+        };
+        let id = if e.complete {
+            style(e.id)
+        } else {
+            style(format!("{} (incomplete)", e.id)).red()
+        };
+        println!(
+            "{:<40} {:>10} {:>20}  {}",
+            id,
+            HumanBytes(e.size).to_string(),
+            last_used,
+            style(e.path.display()).dim()
+        );
+    }
+    Ok(())
+}
+
+pub fn size(config: &Config) -> Result<()> {
+    let root = get_cache_dir(config)?;
+    let entries = list_entries(&root)?;
+    let total: u64 = entries.iter().map(|e| e.size).sum();
+    let moc = dir_size(&root.join("bin"))? + dir_size(&root.join("moc"))?;
+    status!(
+        "{:>12} {} in {} package(s), {} of compilers, at {}",
+        style("Cache").bold(),
+        HumanBytes(total),
+        entries.len(),
+        HumanBytes(moc),
+        root.display()
+    );
+    if let Some(store) = Store::from_config(config)? {
+        let entries = list_entries(&store.dir)?;
+        let total: u64 = entries.iter().map(|e| e.size).sum();
+        status!(
+            "{:>12} {} in {} package(s), at {}, hard-linked files are counted in both",
+            style("Store").bold(),
+            HumanBytes(total),
+            entries.len(),
+            store.dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Removes cached packages that no known mops.lock references, along with unfinished downloads,
+/// from the cache and then from the global store.
+pub fn gc(config: &Config, dry_run: bool) -> Result<()> {
+    gc_dir(&get_cache_dir(config)?, dry_run)?;
+    if let Some(store) = Store::from_config(config)? {
+        gc_dir(&store.dir, dry_run)?;
+    }
+    Ok(())
+}
+
+/// Garbage collects the cache or store at `root`, against the projects registered there.
+/// Until a project has registered itself by installing, every complete package is kept, since
+/// the locks that use them are unknown.
+fn gc_dir(root: &Path, dry_run: bool) -> Result<()> {
+    if !root.exists() {
+        return Ok(());
+    }
+    let registered =
+        fs::read_to_string(root.join(PROJECTS_FILE)).is_ok_and(|str| !str.trim().is_empty());
+    if !registered {
+        status!(
+            "{:>12} no project has registered with {} yet, so only unfinished downloads are removed. Run `mops install` in each project first.",
+            style("Note").yellow().bold(),
+            root.display()
+        );
+    }
+    let locks = known_locks(root)?;
+    // What an unreadable lock references is unknown, so its packages could be any of them.
+    let unreadable = registered && locks.iter().any(|(_, lock)| lock.is_none());
+    if unreadable {
+        status!(
+            "{:>12} keeping every complete package in {}, since a registered mops.lock cannot be read. Fix it, or delete the project, and run gc again.",
+            style("Note").yellow().bold(),
+            root.display()
+        );
+    }
+    let keep_all = !registered || unreadable;
+    let referenced: BTreeSet<PathBuf> = locks
+        .iter()
+        .flat_map(|(_, lock)| lock.iter().flat_map(|lock| lock.package.iter()))
+        .filter(|pkg| !matches!(pkg.get_type(), PackageType::Local(_)))
+        .map(|pkg| root.join(pkg.get_path()))
+        .collect();
+    let mut total = 0;
+    let mut removed = 0;
+    for entry in list_entries(root)? {
+        if entry.complete && (keep_all || referenced.contains(&entry.path)) {
+            continue;
+        }
+        if !dry_run {
+            fs::remove_dir_all(&entry.path)?;
+            if let Some(parent) = entry.path.parent() {
+                // Drops `git/<repo>` once its last commit is gone.
+                let _ = fs::remove_dir(parent);
+            }
+        }
+        let verb = if dry_run { "Would remove" } else { "Removed" };
+        status!(
+            "{:>12} {} ({})",
+            style(verb).green().bold(),
+            entry.id,
+            HumanBytes(entry.size)
+        );
+        total += entry.size;
+        removed += 1;
+    }
+    if registered && !dry_run {
+        let projects: Vec<String> = locks
+            .iter()
+            .map(|(path, _)| path.display().to_string())
+            .collect();
+        fs::write(root.join(PROJECTS_FILE), projects.join("\n"))?;
+    }
+    status!(
+        "{:>12} {removed} package(s), {}, kept what {} project(s) use, in {}",
+        style(if dry_run { "Would free" } else { "Freed" }).bold(),
+        HumanBytes(total),
+        locks.len(),
+        root.display()
+    );
+    Ok(())
+}
+
+/// Rehashes every cached package that a known mops.lock records an integrity hash for.
+pub fn verify(config: &Config) -> Result<()> {
+    let root = get_cache_dir(config)?;
+    let mut checked = BTreeSet::new();
+    let mut bad = Vec::new();
+    for (_, lock) in known_locks(&root)? {
+        let Some(lock) = lock else {
+            continue;
+        };
+        for pkg in &lock.package {
+            let Some(integrity) = &pkg.integrity else {
+                continue;
+            };
+            let path = root.join(pkg.get_path());
+            if !path.join(pkg.get_done_file()).exists()
+                || !checked.insert((path.join(pkg.hashed_dir()), integrity.clone()))
+            {
+                continue;
+            }
+            if verify_dir(&path, &pkg.hashed_dir(), integrity)? {
+                status!("{:>12} {}", style("OK").green().bold(), path.display());
+            } else {
+                status!("{:>12} {}", style("MODIFIED").red().bold(), path.display());
+                bad.push(path);
+            }
+        }
+    }
+    for entry in list_entries(&root)? {
+        if !entry.complete {
+            status!(
+                "{:>12} {}",
+                style("INCOMPLETE").red().bold(),
+                entry.path.display()
+            );
+            bad.push(entry.path);
+        }
+    }
+    if !bad.is_empty() {
+        return Err(anyhow!(
+            "{} cached package(s) are damaged. Delete them, or run `mops cache gc` for unfinished downloads, then reinstall.",
+            bad.len()
+        ));
+    }
+    status!(
+        "{:>12} {} package(s)",
+        style("Verified").green().bold(),
+        checked.len()
+    );
+    Ok(())
+}
+
+/// Remembers that the project owning `lock` installs into the cache at `root`.
+pub(crate) fn register_project(root: &Path, lock: &Path) -> Result<()> {
+    let lock = std::path::absolute(lock)?.display().to_string();
+    let file = root.join(PROJECTS_FILE);
+    let str = fs::read_to_string(&file).unwrap_or_default();
+    if str.lines().any(|line| line == lock) {
+        return Ok(());
+    }
+    fs::create_dir_all(root)?;
+    let mut str = str.trim_end().to_string();
+    if !str.is_empty() {
+        str.push('\n');
+    }
+    str.push_str(&lock);
+    fs::write(file, str)?;
+    Ok(())
+}
+
+/// The registered lock files that still exist, plus the one in the current directory, with
+/// their packages. A lock that cannot be parsed, e.g. one of a newer mops or with merge
+/// conflicts, is kept with `None` and a warning, so it stays registered.
+fn known_locks(root: &Path) -> Result<Vec<(PathBuf, Option<Packages>)>> {
+    let str = fs::read_to_string(root.join(PROJECTS_FILE)).unwrap_or_default();
+    let mut paths: BTreeSet<PathBuf> = str.lines().map(PathBuf::from).collect();
+    let cwd = lock_path();
+    if cwd.exists() {
+        paths.insert(std::path::absolute(cwd)?);
+    }
+    Ok(paths
+        .into_iter()
+        .filter(|path| path.exists())
+        .map(|path| {
+            let lock = parse_mops_lock(&path)
+                .map_err(|e| {
+                    status!(
+                        "{:>12} cannot read {}: {e:#}",
+                        style("Warning").yellow().bold(),
+                        path.display()
+                    );
+                })
+                .ok();
+            (path, lock)
+        })
+        .collect())
+}
+
+/// Every package directory under `root`, sorted by id.
+pub(crate) fn list_entries(root: &Path) -> Result<Vec<Entry>> {
+    let mut dirs = Vec::new();
+    let mops = root.join("mops");
+    if mops.is_dir() {
+        for entry in fs::read_dir(&mops)? {
+            let dir = entry?.path();
+            let dirname = dir.file_name().unwrap().to_string_lossy().to_string();
+            let id = split_name_version(&dirname)
+                .map(|(name, ver)| format!("{name}@{ver}"))
+                .unwrap_or(dirname);
+            dirs.push((dir, id));
+        }
+    }
+    let git = root.join("git");
+    if git.is_dir() {
+        for repo in fs::read_dir(&git)? {
+            let repo = repo?.path();
+            if !repo.is_dir() {
+                continue;
+            }
+            let repo_name = repo.file_name().unwrap().to_string_lossy().to_string();
+            for commit in fs::read_dir(&repo)? {
+                let dir = commit?.path();
+                let commit = dir.file_name().unwrap().to_string_lossy().to_string();
+                dirs.push((dir, format!("{repo_name}@{commit}")));
+            }
+        }
+    }
+    let mut entries = Vec::new();
+    for (dir, mut id) in dirs {
+        if !dir.is_dir() {
+            continue;
+        }
+        let mut last_used = None;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with("DONE") {
+                continue;
+            }
+            if let Ok(marker) = serde_json::from_str::<Marker>(&fs::read_to_string(entry.path())?) {
+                id = marker.id;
+            }
+            let used = entry
+                .metadata()?
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs());
+            last_used = last_used.max(used);
+        }
+        let complete = last_used.is_some();
+        entries.push(Entry {
+            id,
+            size: dir_size(&dir)?,
+            path: dir,
+            last_used,
+            complete,
+        });
+    }
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(entries)
+}
+
+/// Records that a cached package was used, as the modification time of its marker. Only
+/// touches the file once an hour, so repeated builds don't keep writing.
+pub(crate) fn touch(dir: &Path, file: &str) {
+    let marker = dir.join(file);
+    let Ok(modified) = fs::metadata(&marker).and_then(|m| m.modified()) else {
+        return;
+    };
+    if modified
+        .elapsed()
+        .is_ok_and(|age| age > Duration::from_secs(3600))
+    {
+        if let Ok(f) = fs::File::options().append(true).open(&marker) {
+            let _ = f.set_modified(SystemTime::now());
+        }
+    }
+}
+
+pub(crate) fn dir_size(dir: &Path) -> Result<u64> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut size = 0;
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Brings the cache at `root` to the current layout, adopting legacy directories in place.
+/// Fails if the cache was written by a newer version of the CLI.
+pub(crate) fn ensure_layout(root: &Path) -> Result<()> {
+    let file = root.join(LAYOUT_FILE);
+    let version = if file.exists() {
+        let str = fs::read_to_string(&file)?;
+        str.trim()
+            .parse::<u32>()
+            .with_context(|| format!("Invalid {}", file.display()))?
+    } else {
+        1
+    };
+    if version == LAYOUT_VERSION {
+        return Ok(());
+    }
+    if version > LAYOUT_VERSION {
+        return Err(anyhow!(
+            "The cache at {} uses layout {version}, which is newer than this mops-cli supports ({LAYOUT_VERSION}). Upgrade mops-cli or use another --cache-dir.",
+            root.display()
+        ));
+    }
+    let adopted = migrate_v1(root)?;
+    fs::create_dir_all(root)?;
+    fs::write(&file, LAYOUT_VERSION.to_string())?;
+    if adopted > 0 {
+        status!(
+            "{:>12} {adopted} cached package(s) to cache layout {LAYOUT_VERSION}",
+            style("Migrated").green().bold()
+        );
+    }
+    Ok(())
+}
+
+/// Writes the `DONE*` marker `file` in `dir`.
+pub(crate) fn write_marker(dir: &Path, file: &str, id: String) -> Result<()> {
+    let marker = Marker {
+        layout: LAYOUT_VERSION,
+        id,
+        created: now(),
+        adopted: false,
+    };
+    fs::write(dir.join(file), serde_json::to_string(&marker)?)?;
+    Ok(())
+}
+
+/// Fills the empty markers of layout 1 without touching the downloaded files.
+fn migrate_v1(root: &Path) -> Result<usize> {
+    let mut adopted = 0;
+    let mops = root.join("mops");
+    if mops.is_dir() {
+        for entry in fs::read_dir(&mops)? {
+            let dir = entry?.path();
+            let dirname = dir.file_name().unwrap().to_string_lossy().to_string();
+            let id = split_name_version(&dirname)
+                .map(|(name, ver)| format!("{name}@{ver}"))
+                .unwrap_or(dirname);
+            adopted += adopt_markers(&dir, &id)?;
+        }
+    }
+    let git = root.join("git");
+    if git.is_dir() {
+        for repo in fs::read_dir(&git)? {
+            let repo = repo?.path();
+            if !repo.is_dir() {
+                continue;
+            }
+            let repo_name = repo.file_name().unwrap().to_string_lossy().to_string();
+            for commit in fs::read_dir(&repo)? {
+                let dir = commit?.path();
+                let commit = dir.file_name().unwrap().to_string_lossy().to_string();
+                adopted += adopt_markers(&dir, &format!("{repo_name}@{commit}"))?;
+            }
+        }
+    }
+    Ok(adopted)
+}
+
+fn adopt_markers(dir: &Path, id: &str) -> Result<usize> {
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let mut adopted = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let meta = entry.metadata()?;
+        if !name.starts_with("DONE") || !meta.is_file() || meta.len() > 0 {
+            continue;
+        }
+        let created = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or_else(now, |d| d.as_secs());
+        let marker = Marker {
+            layout: LAYOUT_VERSION,
+            id: id.to_string(),
+            created,
+            adopted: true,
+        };
+        fs::write(entry.path(), serde_json::to_string(&marker)?)?;
+        adopted += 1;
+    }
+    Ok(adopted)
+}
+
+/// Splits a `{name}-{version}` directory name. Names may contain `-`, so we split at the first
+/// `-` after which the rest parses as semver.
+fn split_name_version(dirname: &str) -> Option<(&str, &str)> {
+    dirname
+        .match_indices('-')
+        .map(|(i, _)| (&dirname[..i], &dirname[i + 1..]))
+        .find(|(name, ver)| !name.is_empty() && Version::parse(ver).is_ok())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,88 @@
+use crate::build::TARGET_MARKER;
+use crate::cache::dir_size;
+use crate::config::Config;
+use crate::store::Store;
+use crate::toml::{parse_mops_lock, PackageType};
+use crate::utils::get_cache_dir;
+use crate::workspace::lock_path;
+use anyhow::Result;
+use console::style;
+use indicatif::HumanBytes;
+use std::fs;
+use std::path::PathBuf;
+
+/// Generated files under `.mops`. `.mops/local` overrides and unfinished publishes are left alone.
+const GENERATED: &[&str] = &[
+    ".mops/candid",
+    ".mops/test",
+    ".mops/bench",
+    ".mops/analyze",
+    ".mops/graph.json",
+];
+
+/// Removes the build outputs and generated files of the project, and mops.lock unless kept.
+/// The target directory is only removed when `mops build` created it.
+/// With `global`, the cache and store entries of the locked packages are removed too, so they are
+/// downloaded again on the next install.
+pub fn clean(config: &Config, args: crate::CleanArg) -> Result<()> {
+    let lock_file = lock_path();
+    let mut paths: Vec<PathBuf> = Vec::new();
+    if args.target_dir.join(TARGET_MARKER).exists() {
+        paths.push(args.target_dir.clone());
+    } else if args.target_dir.exists() {
+        status!(
+            "{:>12} {}, it was not created by mops build",
+            style("Skipping").yellow().bold(),
+            args.target_dir.display()
+        );
+    }
+    paths.extend(GENERATED.iter().map(PathBuf::from));
+    if args.global {
+        // The store copies go too, or the next install would just link them back.
+        let mut roots = vec![get_cache_dir(config)?];
+        roots.extend(Store::from_config(config)?.map(|store| store.dir));
+        let lock = parse_mops_lock(&lock_file).unwrap_or_default();
+        for pkg in &lock.package {
+            if !matches!(pkg.get_type(), PackageType::Local(_)) {
+                paths.extend(roots.iter().map(|root| root.join(pkg.get_path())));
+            }
+        }
+    }
+    if !args.keep_lock {
+        paths.push(lock_file);
+    }
+    let mut total = 0;
+    let mut removed = 0;
+    for path in paths {
+        if !path.exists() {
+            continue;
+        }
+        let size = if path.is_dir() {
+            let size = dir_size(&path)?;
+            fs::remove_dir_all(&path)?;
+            size
+        } else {
+            let size = fs::metadata(&path)?.len();
+            fs::remove_file(&path)?;
+            size
+        };
+        status!(
+            "{:>12} {} ({})",
+            style("Removed").green().bold(),
+            path.display(),
+            HumanBytes(size)
+        );
+        total += size;
+        removed += 1;
+    }
+    if removed == 0 {
+        status!("Nothing to clean");
+    } else {
+        status!(
+            "{:>12} {removed} path(s), {} in total",
+            style("Cleaned").green().bold(),
+            HumanBytes(total)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use anyhow::{anyhow, Error, Result};
+use futures::future::{FutureExt, LocalBoxFuture, Shared};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::rc::Rc;
+
+type Lookup<'a, T> = Shared<LocalBoxFuture<'a, Result<Rc<T>, Rc<Error>>>>;
+
+/// Lookups by key, where asking for a key that is already being looked up by a concurrent task
+/// waits for that lookup instead of starting another one. Successful lookups are kept for the
+/// lifetime of the value. A failed one is handed to everyone waiting on it and then forgotten,
+/// so the next ask for its key tries again.
+pub(crate) struct Coalesce<'a, T> {
+    lookups: RefCell<HashMap<String, Lookup<'a, T>>>,
+}
+impl<T> Default for Coalesce<'_, T> {
+    fn default() -> Self {
+        Coalesce {
+            lookups: RefCell::new(HashMap::new()),
+        }
+    }
+}
+impl<'a, T: 'a> Coalesce<'a, T> {
+    /// Returns the result of the lookup for `key`, running `lookup` only if there is none in
+    /// flight or kept.
+    pub(crate) async fn get(
+        &self,
+        key: String,
+        lookup: impl Future<Output = Result<T>> + 'a,
+    ) -> Result<Rc<T>> {
+        let shared = self
+            .lookups
+            .borrow_mut()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                lookup
+                    .map(|res| res.map(Rc::new).map_err(Rc::new))
+                    .boxed_local()
+                    .shared()
+            })
+            .clone();
+        let res = shared.clone().await;
+        if res.is_err() {
+            let mut lookups = self.lookups.borrow_mut();
+            // Unless a retry has already replaced it.
+            if lookups.get(&key).is_some_and(|l| l.ptr_eq(&shared)) {
+                lookups.remove(&key);
+            }
+        }
+        res.map_err(|e| anyhow!("{e:#}"))
+    }
+}
@@ -0,0 +1,103 @@
+//! Codeberg repositories as dependencies, e.g. `https://codeberg.org/<owner>/<repo>#<tag>`.
+//! Codeberg runs Forgejo, whose API is Gitea's.
+use crate::git_host::{download_archive, encode, encode_path, get_file, get_json, GitHost};
+use crate::github::RepoInfo;
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+use std::path::Path;
+
+pub(crate) struct Codeberg;
+
+fn repo_url(repo: &str) -> String {
+    format!("https://codeberg.org/api/v1/repos/{repo}")
+}
+
+fn auth() -> Option<(&'static str, String)> {
+    crate::github::token("codeberg_token").map(|token| ("Authorization", format!("token {token}")))
+}
+
+impl GitHost for Codeberg {
+    fn domain(&self) -> &str {
+        "codeberg.org"
+    }
+
+    fn default_branch<'a>(&'a self, repo: &'a str) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Repository {
+            default_branch: String,
+        }
+        async move {
+            let repository: Repository = get_json(&repo_url(repo), auth()).await?;
+            Ok(repository.default_branch)
+        }
+        .boxed()
+    }
+
+    fn latest_commit<'a>(&'a self, repo: &'a str, tag: &'a str) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Commit {
+            sha: String,
+        }
+        async move {
+            let url = format!(
+                "{}/commits?sha={}&limit=1&stat=false&files=false",
+                repo_url(repo),
+                encode(tag)
+            );
+            let commits: Vec<Commit> = get_json(&url, auth()).await?;
+            let commit = commits
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("No commit found for {tag}"))?;
+            Ok(commit.sha)
+        }
+        .boxed()
+    }
+
+    fn fetch_file<'a>(
+        &'a self,
+        repo: &'a RepoInfo,
+        file: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        let url = format!(
+            "{}/raw/{}?ref={}",
+            repo_url(&repo.repo),
+            encode_path(file),
+            repo.commit
+        );
+        async move { get_file(&url, auth()).await }.boxed()
+    }
+
+    fn download<'a>(
+        &'a self,
+        base_path: &'a Path,
+        repo: &'a RepoInfo,
+    ) -> BoxFuture<'a, Result<()>> {
+        let url = format!("{}/archive/{}.tar.gz", repo_url(&repo.repo), repo.commit);
+        async move { download_archive(&url, auth(), base_path, repo).await }.boxed()
+    }
+
+    fn submodule_commit<'a>(
+        &'a self,
+        repo: &'a RepoInfo,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Content {
+            sha: String,
+        }
+        let url = format!(
+            "{}/contents/{}?ref={}",
+            repo_url(&repo.repo),
+            encode_path(path),
+            repo.commit
+        );
+        async move {
+            let content: Content = get_json(&url, auth()).await?;
+            Ok(content.sha)
+        }
+        .boxed()
+    }
+}
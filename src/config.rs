@@ -0,0 +1,539 @@
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml_edit::ImDocument;
+
+/// Where an effective configuration value came from, in order of precedence.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Flag,
+    Env,
+    Project,
+    User,
+    Default,
+}
+
+struct Key {
+    name: &'static str,
+    env: &'static str,
+    default: fn() -> Option<String>,
+    secret: bool,
+    /// Whether the `[config]` table of mops.toml may set it. Settings that decide whom requests
+    /// carrying tokens trust must not come from a cloned repository.
+    project: bool,
+}
+
+/// Every known configuration key. Project config lives in the `[config]` table of `mops.toml`,
+/// user config in `$HOME/.mops/config.toml`. Both use these names as keys.
+const KEYS: &[Key] = &[
+    Key {
+        name: "cache_dir",
+        env: "MOPS_CACHE_DIR",
+        default: default_cache_dir,
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "store_dir",
+        env: "MOPS_STORE_DIR",
+        default: default_store_dir,
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "link_mode",
+        env: "MOPS_LINK_MODE",
+        default: || Some("hardlink".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "layout",
+        env: "MOPS_LAYOUT",
+        default: || Some("cache".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "name_normalization",
+        env: "MOPS_NAME_NORMALIZATION",
+        default: || Some("safe".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "lock_history",
+        env: "MOPS_LOCK_HISTORY",
+        default: || Some("20".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "offline",
+        env: "MOPS_OFFLINE",
+        default: || Some("false".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "no_cache",
+        env: "MOPS_NO_CACHE",
+        default: || Some("false".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "metadata_ttl",
+        env: "MOPS_METADATA_TTL",
+        default: || Some("300".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "registry_canister",
+        env: "MOPS_REGISTRY_CANISTER",
+        default: || None,
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "registry_url",
+        env: "MOPS_REGISTRY_URL",
+        default: || None,
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "registry_mirrors",
+        env: "MOPS_REGISTRY_MIRRORS",
+        default: || None,
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "registry_timeout",
+        env: "MOPS_REGISTRY_TIMEOUT",
+        default: || Some("60".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "ingress_expiry",
+        env: "MOPS_INGRESS_EXPIRY",
+        default: || None,
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "max_response_size",
+        env: "MOPS_MAX_RESPONSE_SIZE",
+        default: || None,
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "verify_query_signatures",
+        env: "MOPS_VERIFY_QUERY_SIGNATURES",
+        default: || Some("true".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "github_timeout",
+        env: "MOPS_GITHUB_TIMEOUT",
+        default: || Some("60".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "jobs",
+        env: "MOPS_JOBS",
+        default: || Some("8".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "retry_attempts",
+        env: "MOPS_RETRY_ATTEMPTS",
+        default: || Some("4".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "retry_backoff_ms",
+        env: "MOPS_RETRY_BACKOFF_MS",
+        default: || Some("500".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "retry_jitter",
+        env: "MOPS_RETRY_JITTER",
+        default: || Some("true".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "hash_algorithm",
+        env: "MOPS_HASH_ALGORITHM",
+        default: || Some("sha256".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "user_agent",
+        env: "MOPS_USER_AGENT",
+        default: || Some(crate::utils::USER_AGENT.to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "http_connect_timeout",
+        env: "MOPS_HTTP_CONNECT_TIMEOUT",
+        default: || Some("10".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "http_compression",
+        env: "MOPS_HTTP_COMPRESSION",
+        default: || Some("true".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "http_pool_idle_timeout",
+        env: "MOPS_HTTP_POOL_IDLE_TIMEOUT",
+        default: || Some("90".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "http_pool_max_idle",
+        env: "MOPS_HTTP_POOL_MAX_IDLE",
+        default: || Some("32".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "http_keep_alive",
+        env: "MOPS_HTTP_KEEP_ALIVE",
+        default: || Some("30".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "tls_ca_file",
+        env: "MOPS_TLS_CA_FILE",
+        default: || None,
+        secret: false,
+        project: false,
+    },
+    Key {
+        name: "tls_pins",
+        env: "MOPS_TLS_PINS",
+        default: || None,
+        secret: false,
+        project: false,
+    },
+    Key {
+        name: "proxy",
+        env: "MOPS_PROXY",
+        default: || None,
+        // May hold `user:password@`
+        secret: true,
+        project: false,
+    },
+    Key {
+        name: "no_proxy",
+        env: "MOPS_NO_PROXY",
+        default: || None,
+        secret: false,
+        project: false,
+    },
+    Key {
+        name: "network",
+        env: "MOPS_NETWORK",
+        default: || Some("ic".to_string()),
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "identity",
+        env: "MOPS_IDENTITY",
+        default: || None,
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "registry_identities",
+        env: "MOPS_REGISTRY_IDENTITIES",
+        default: || None,
+        secret: false,
+        project: true,
+    },
+    Key {
+        name: "github_token",
+        env: "GITHUB_TOKEN",
+        default: || None,
+        secret: true,
+        project: true,
+    },
+    Key {
+        name: "gitlab_token",
+        env: "GITLAB_TOKEN",
+        default: || None,
+        secret: true,
+        project: true,
+    },
+    Key {
+        name: "bitbucket_token",
+        env: "BITBUCKET_TOKEN",
+        default: || None,
+        secret: true,
+        project: true,
+    },
+    Key {
+        name: "codeberg_token",
+        env: "CODEBERG_TOKEN",
+        default: || None,
+        secret: true,
+        project: true,
+    },
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Setting {
+    pub key: &'static str,
+    pub value: Option<String>,
+    pub source: Source,
+}
+
+/// A GitHub Enterprise Server, from a `[github_hosts."<host>"]` table of the user config.
+#[derive(Debug, Clone, Default)]
+pub struct GithubHost {
+    /// Defaults to `https://<host>/api/v3`
+    pub api: Option<String>,
+    /// Defaults to `https://<host>/raw`
+    pub raw: Option<String>,
+    pub token: Option<String>,
+}
+
+pub struct Config {
+    settings: BTreeMap<&'static str, Setting>,
+    github_hosts: BTreeMap<String, GithubHost>,
+}
+
+impl Config {
+    /// Resolves every key from (in order) `flags`, the environment, the project's mops.toml,
+    /// the user config file and the built-in default. Fails if mops.toml sets a key that only
+    /// the user may set.
+    pub fn load(flags: &[(&'static str, Option<String>)]) -> Result<Self> {
+        let project = read_config_table(Path::new("mops.toml"), "config")?;
+        let user = match user_config_path() {
+            Some(path) => read_config_table(&path, "")?,
+            None => BTreeMap::new(),
+        };
+        // A host's `api` and `raw` receive its token, so only the user may name them.
+        if !read_github_hosts(Path::new("mops.toml"), &["config"])?.is_empty() {
+            return Err(anyhow!(
+                "github_hosts cannot be set in mops.toml. Set it in $HOME/.mops/config.toml instead."
+            ));
+        }
+        let github_hosts = match user_config_path() {
+            Some(path) => read_github_hosts(&path, &[])?,
+            None => BTreeMap::new(),
+        };
+        let mut settings = BTreeMap::new();
+        for key in KEYS {
+            if !key.project && project.contains_key(key.name) {
+                return Err(anyhow!(
+                    "{} cannot be set in mops.toml. Set it in $HOME/.mops/config.toml or {} instead.",
+                    key.name,
+                    key.env
+                ));
+            }
+            let flag = flags
+                .iter()
+                .find(|(name, _)| *name == key.name)
+                .and_then(|(_, v)| v.clone());
+            let (value, source) = if let Some(v) = flag {
+                (Some(v), Source::Flag)
+            } else if let Ok(v) = std::env::var(key.env) {
+                (Some(v), Source::Env)
+            } else if let Some(v) = project.get(key.name) {
+                (Some(v.clone()), Source::Project)
+            } else if let Some(v) = user.get(key.name) {
+                (Some(v.clone()), Source::User)
+            } else {
+                ((key.default)(), Source::Default)
+            };
+            settings.insert(
+                key.name,
+                Setting {
+                    key: key.name,
+                    value,
+                    source,
+                },
+            );
+        }
+        Ok(Config {
+            settings,
+            github_hosts,
+        })
+    }
+    /// The configured GitHub Enterprise Servers by host name.
+    pub fn github_hosts(&self) -> &BTreeMap<String, GithubHost> {
+        &self.github_hosts
+    }
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.settings.get(key).and_then(|s| s.value.as_deref())
+    }
+    pub fn get_parsed<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        let Some(setting) = self.settings.get(key) else {
+            return Ok(None);
+        };
+        match &setting.value {
+            Some(v) => v.parse::<T>().map(Some).map_err(|e| {
+                anyhow!(
+                    "Invalid value {v:?} for {key} (from {:?}): {e}",
+                    setting.source
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+    /// All settings, with secret values masked.
+    pub fn list(&self) -> Vec<Setting> {
+        KEYS.iter()
+            .map(|key| {
+                let mut setting = self.settings[key.name].clone();
+                if key.secret && setting.value.is_some() {
+                    setting.value = Some("********".to_string());
+                }
+                setting
+            })
+            .collect()
+    }
+}
+
+pub fn list(config: &Config, json: bool) -> Result<()> {
+    let settings = config.list();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&settings)?);
+        return Ok(());
+    }
+    for s in settings {
+        let value = s.value.as_deref().unwrap_or("<unset>");
+        println!(
+            "{:<20} {:<50} {}",
+            style(s.key).cyan(),
+            value,
+            style(format!("({:?})", s.source).to_lowercase()).dim()
+        );
+    }
+    Ok(())
+}
+
+fn default_cache_dir() -> Option<String> {
+    let home = std::env::var("HOME").ok()?;
+    Some(PathBuf::from(home).join(".mops").display().to_string())
+}
+
+/// The platform's per-user cache directory, e.g. `$XDG_CACHE_HOME/mops-cli/store` on Linux.
+fn default_store_dir() -> Option<String> {
+    let base = if cfg!(target_os = "macos") {
+        PathBuf::from(std::env::var("HOME").ok()?).join("Library/Caches")
+    } else if cfg!(windows) {
+        PathBuf::from(std::env::var("LOCALAPPDATA").ok()?)
+    } else if let Ok(dir) = std::env::var("XDG_CACHE_HOME") {
+        PathBuf::from(dir)
+    } else {
+        PathBuf::from(std::env::var("HOME").ok()?).join(".cache")
+    };
+    Some(base.join("mops-cli/store").display().to_string())
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    let home = std::env::var("HOME").ok()?;
+    Some(PathBuf::from(home).join(".mops/config.toml"))
+}
+
+/// Reads string-like values of `table` in the toml file at `path`. An empty `table` means the top level.
+fn read_config_table(path: &Path, table: &str) -> Result<BTreeMap<String, String>> {
+    let mut res = BTreeMap::new();
+    if !path.exists() {
+        return Ok(res);
+    }
+    let str = fs::read_to_string(path)?;
+    let doc = str
+        .parse::<ImDocument<_>>()
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let table = if table.is_empty() {
+        Some(doc.as_table())
+    } else {
+        doc.get(table).and_then(|t| t.as_table())
+    };
+    if let Some(table) = table {
+        for (k, v) in table.iter() {
+            let Some(v) = v.as_value() else {
+                continue;
+            };
+            let v = match v.as_str() {
+                Some(s) => s.to_string(),
+                None => v.to_string().trim().to_string(),
+            };
+            res.insert(k.to_string(), v);
+        }
+    }
+    Ok(res)
+}
+
+/// Reads the `github_hosts` table under `parents` in the toml file at `path`.
+fn read_github_hosts(path: &Path, parents: &[&str]) -> Result<BTreeMap<String, GithubHost>> {
+    let mut res = BTreeMap::new();
+    if !path.exists() {
+        return Ok(res);
+    }
+    let str = fs::read_to_string(path)?;
+    let doc = str
+        .parse::<ImDocument<_>>()
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let mut item = doc.as_item();
+    for parent in parents.iter().chain(&["github_hosts"]) {
+        match item.get(parent) {
+            Some(child) => item = child,
+            None => return Ok(res),
+        }
+    }
+    let Some(hosts) = item.as_table_like() else {
+        return Err(anyhow!(
+            "github_hosts in {} must be a table",
+            path.display()
+        ));
+    };
+    for (name, table) in hosts.iter() {
+        let field = |key: &str| {
+            table
+                .get(key)
+                .and_then(|v| v.as_str())
+                .map(|v| v.trim_end_matches('/').to_string())
+        };
+        let host = GithubHost {
+            api: field("api"),
+            raw: field("raw"),
+            token: field("token"),
+        };
+        res.insert(name.to_ascii_lowercase(), host);
+    }
+    Ok(res)
+}
@@ -0,0 +1,120 @@
+//! Tokens for GitHub hosts that are not configured, found where other tools keep them: the OS
+//! keychain (stored with `mops auth github`), the GitHub CLI and `~/.netrc`.
+use anyhow::{anyhow, Context, Result};
+use console::{style, Term};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+
+/// The keychain service of our entries. The user of an entry is the host, e.g. `github.com`.
+const SERVICE: &str = "mops-cli";
+
+/// Discovered tokens by host, looked up once per process.
+static FOUND: OnceLock<Mutex<HashMap<String, Option<String>>>> = OnceLock::new();
+
+/// The token for `host` from the keychain, `gh auth token` or `~/.netrc`, in this order.
+pub(crate) fn discover(host: &str) -> Option<String> {
+    let found = FOUND.get_or_init(Default::default);
+    if let Some(token) = found.lock().unwrap().get(host) {
+        return token.clone();
+    }
+    // The keychain may block on a runtime of its own, which can't start inside ours.
+    let token = std::thread::scope(|s| {
+        s.spawn(|| {
+            from_keychain(host)
+                .or_else(|| from_gh(host))
+                .or_else(|| from_netrc(host))
+        })
+        .join()
+        .ok()
+        .flatten()
+    });
+    found
+        .lock()
+        .unwrap()
+        .insert(host.to_string(), token.clone());
+    token
+}
+
+fn from_keychain(host: &str) -> Option<String> {
+    keyring::Entry::new(SERVICE, host)
+        .ok()?
+        .get_password()
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+fn from_gh(host: &str) -> Option<String> {
+    let output = Command::new("gh")
+        .args(["auth", "token", "--hostname", host])
+        .output()
+        .ok()?;
+    let token = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (output.status.success() && !token.is_empty()).then_some(token)
+}
+
+/// The password of `host`, or of its API host `api.<host>`, in the netrc file.
+fn from_netrc(host: &str) -> Option<String> {
+    let path = match std::env::var_os("NETRC") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".netrc"),
+    };
+    let content = std::fs::read_to_string(path).ok()?;
+    let api = format!("api.{host}");
+    let mut tokens = content.split_whitespace();
+    let mut machine = None;
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => machine = tokens.next(),
+            "default" => machine = None,
+            "password" => match (machine, tokens.next()) {
+                (Some(m), Some(password)) if m == host || m == api => {
+                    return Some(password.to_string())
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Stores a token for `host` in the OS keychain, read from the terminal or stdin, or removes it
+/// with `logout`.
+pub fn github(host: Option<&str>, logout: bool) -> Result<()> {
+    let host = host.unwrap_or("github.com").to_ascii_lowercase();
+    let entry = keyring::Entry::new(SERVICE, &host)?;
+    if logout {
+        match entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => (),
+            Err(e) => return Err(e.into()),
+        }
+        status!(
+            "{:>12} the token for {host} from the keychain",
+            style("Removed").green().bold()
+        );
+        return Ok(());
+    }
+    let term = Term::stderr();
+    let token = if term.is_term() {
+        eprint!("Token for {host}: ");
+        term.read_secure_line()?
+    } else {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        line
+    };
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(anyhow!("No token given"));
+    }
+    entry
+        .set_password(token)
+        .context("Cannot store the token in the OS keychain")?;
+    status!(
+        "{:>12} the token for {host} in the keychain",
+        style("Stored").green().bold()
+    );
+    Ok(())
+}
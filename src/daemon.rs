@@ -0,0 +1,340 @@
+use crate::status::{dependencies, Verified};
+use crate::toml::{generate_moc_args, get_effective_sources};
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Written by a running daemon as `<port> <token>`. Only clients that can read it may talk to the daemon.
+const PORT_FILE: &str = ".mops/daemon.port";
+/// Files whose modification invalidates everything the daemon has cached. Adding or removing a
+/// `.mops/local` override changes the modification time of the directory.
+const WATCHED: &[&str] = &["mops.toml", "mops.lock", crate::toml::LOCAL_OVERRIDES];
+/// How long either side waits for the other to send its line.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What clients ask. Requests about the project name the cache directory the client would use,
+/// and the daemon answers `null` when it was started with another one.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    /// Package names and their source directories, as `mops sources --json` prints them
+    Sources {
+        cache_dir: PathBuf,
+    },
+    /// The moc arguments `mops sources` prints
+    MocArgs {
+        cache_dir: PathBuf,
+    },
+    /// Resolves a `mo:` import to a file
+    ResolveImport {
+        cache_dir: PathBuf,
+        import: String,
+    },
+    /// What `mops status` reports, except whether newer versions are published
+    Dependencies {
+        cache_dir: PathBuf,
+    },
+    Status,
+    Shutdown,
+}
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    token: String,
+    request: Request,
+}
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Response {
+    Ok(Value),
+    Error(String),
+}
+
+struct State {
+    cache_dir: PathBuf,
+    started: Instant,
+    requests: u64,
+    hits: u64,
+    fingerprint: Vec<Option<SystemTime>>,
+    sources: Option<Vec<(String, PathBuf)>>,
+    moc_args: Option<Vec<String>>,
+    verified: Verified,
+}
+
+/// Starts the daemon in the background and waits until it accepts connections.
+pub fn start(cache_dir: &Path) -> Result<()> {
+    if let Some(pid) = running_pid() {
+        status!("{:>12} daemon (pid {pid})", style("Running").green().bold());
+        return Ok(());
+    }
+    let _ = fs::remove_file(PORT_FILE);
+    let child = Command::new(std::env::current_exe()?)
+        .arg("daemon")
+        .arg("run")
+        .arg("--cache-dir")
+        .arg(cache_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to spawn the daemon")?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if running_pid().is_some() {
+            status!(
+                "{:>12} daemon (pid {})",
+                style("Started").green().bold(),
+                child.id()
+            );
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Err(anyhow!("The daemon did not start within 5s"))
+}
+
+pub fn stop() -> Result<()> {
+    if running_pid().is_none() {
+        status!("No daemon is running");
+        return Ok(());
+    }
+    query(Request::Shutdown)?;
+    status!("{:>12} daemon", style("Stopped").green().bold());
+    Ok(())
+}
+
+/// Sends `request` to the running daemon and prints the answer.
+pub fn query(request: Request) -> Result<()> {
+    let value = send(request)?
+        .ok_or_else(|| anyhow!("No daemon is running. Start one with `mops daemon start`."))?;
+    if !value.is_null() {
+        println!("{}", serde_json::to_string_pretty(&value)?);
+    }
+    Ok(())
+}
+
+/// Prints the file a `mo:` import resolves to, asking the daemon when one is running.
+pub fn print_import(cache_dir: &Path, import: String) -> Result<()> {
+    let request = Request::ResolveImport {
+        cache_dir: cache_dir.to_path_buf(),
+        import: import.clone(),
+    };
+    let file = match ask(request) {
+        Some(file) => file,
+        None => resolve_import(&get_effective_sources(cache_dir, false)?, &import)?,
+    };
+    println!("{}", file.display());
+    Ok(())
+}
+
+/// Asks the daemon of the current project. Returns `None` when no daemon is running or it cannot
+/// answer, e.g. because it was started for another cache directory, so callers do the work
+/// themselves. Its errors are left to the direct path to report.
+pub(crate) fn ask<T: DeserializeOwned>(request: Request) -> Option<T> {
+    match send(request) {
+        Ok(Some(value)) if !value.is_null() => serde_json::from_value(value).ok(),
+        _ => None,
+    }
+}
+
+/// Sends `request` to the daemon of the current project. Returns `None` if no daemon is running,
+/// so callers can fall back to doing the work themselves.
+#[tokio::main(flavor = "current_thread")]
+pub async fn send(request: Request) -> Result<Option<Value>> {
+    let Some((port, token)) = read_port_file() else {
+        return Ok(None);
+    };
+    let Ok(stream) = TcpStream::connect(("127.0.0.1", port)).await else {
+        return Ok(None);
+    };
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_string(&Envelope { token, request })?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+    let mut answer = String::new();
+    tokio::time::timeout(READ_TIMEOUT, BufReader::new(read).read_line(&mut answer))
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "The daemon did not answer within {}s",
+                READ_TIMEOUT.as_secs()
+            )
+        })??;
+    match serde_json::from_str(&answer).context("Invalid answer from the daemon")? {
+        Response::Ok(value) => Ok(Some(value)),
+        Response::Error(e) => Err(anyhow!(e)),
+    }
+}
+
+/// Runs the daemon in the foreground until it receives a shutdown request.
+#[tokio::main(flavor = "current_thread")]
+pub async fn run(cache_dir: PathBuf) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let port = listener.local_addr()?.port();
+    let token = new_token()?;
+    write_port_file(&format!("{port} {token}"))?;
+    let mut state = State {
+        cache_dir,
+        started: Instant::now(),
+        requests: 0,
+        hits: 0,
+        fingerprint: Vec::new(),
+        sources: None,
+        moc_args: None,
+        verified: Verified::default(),
+    };
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (read, mut write) = stream.into_split();
+        let mut reader = BufReader::new(read);
+        let mut line = String::new();
+        let read = tokio::time::timeout(READ_TIMEOUT, reader.read_line(&mut line)).await;
+        if !matches!(read, Ok(Ok(_))) {
+            continue;
+        }
+        let (response, shutdown) = match serde_json::from_str::<Envelope>(&line) {
+            Ok(envelope) if envelope.token == token => {
+                let shutdown = matches!(envelope.request, Request::Shutdown);
+                let response = match state.handle(envelope.request) {
+                    Ok(value) => Response::Ok(value),
+                    Err(e) => Response::Error(format!("{e:#}")),
+                };
+                (response, shutdown)
+            }
+            Ok(_) => (Response::Error("Invalid token".to_string()), false),
+            Err(e) => (Response::Error(format!("Invalid request: {e}")), false),
+        };
+        let mut answer = serde_json::to_string(&response)?;
+        answer.push('\n');
+        let _ = write.write_all(answer.as_bytes()).await;
+        if shutdown {
+            break;
+        }
+    }
+    let _ = fs::remove_file(PORT_FILE);
+    Ok(())
+}
+
+impl State {
+    fn handle(&mut self, request: Request) -> Result<Value> {
+        self.requests += 1;
+        match request {
+            Request::Sources { cache_dir }
+            | Request::MocArgs { cache_dir }
+            | Request::ResolveImport { cache_dir, .. }
+            | Request::Dependencies { cache_dir }
+                if cache_dir != self.cache_dir =>
+            {
+                Ok(Value::Null)
+            }
+            Request::Sources { .. } => Ok(json!(self.sources()?)),
+            Request::MocArgs { .. } => Ok(json!(self.moc_args()?)),
+            Request::ResolveImport { import, .. } => {
+                Ok(json!(resolve_import(self.sources()?, &import)?))
+            }
+            Request::Dependencies { .. } => {
+                self.refresh();
+                Ok(json!(dependencies(&self.cache_dir, &mut self.verified)?))
+            }
+            Request::Status => Ok(json!({
+                "pid": std::process::id(),
+                "uptime_secs": self.started.elapsed().as_secs(),
+                "requests": self.requests,
+                "cache_hits": self.hits,
+            })),
+            Request::Shutdown => Ok(Value::Null),
+        }
+    }
+    /// Forgets what was derived from the watched files if one of them changed.
+    fn refresh(&mut self) {
+        let fingerprint = fingerprint();
+        if self.fingerprint != fingerprint {
+            self.sources = None;
+            self.moc_args = None;
+            self.fingerprint = fingerprint;
+        }
+    }
+    /// Package sources from mops.lock with the `.mops/local` overrides, as `mops sources` has them.
+    fn sources(&mut self) -> Result<&[(String, PathBuf)]> {
+        self.refresh();
+        if self.sources.is_some() {
+            self.hits += 1;
+        } else {
+            self.sources = Some(get_effective_sources(&self.cache_dir, false)?);
+        }
+        Ok(self.sources.as_deref().unwrap())
+    }
+    fn moc_args(&mut self) -> Result<&[String]> {
+        self.refresh();
+        if self.moc_args.is_some() {
+            self.hits += 1;
+        } else {
+            self.moc_args = Some(generate_moc_args(&self.cache_dir, false)?);
+        }
+        Ok(self.moc_args.as_deref().unwrap())
+    }
+}
+
+/// The file of `import`, e.g. `mo:base/Array`, in the package sources.
+fn resolve_import(sources: &[(String, PathBuf)], import: &str) -> Result<PathBuf> {
+    let path = import
+        .strip_prefix("mo:")
+        .ok_or_else(|| anyhow!("Not a package import: {import}"))?;
+    let (name, rest) = path.split_once('/').unwrap_or((path, "lib"));
+    let dir = sources
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, dir)| dir)
+        .ok_or_else(|| anyhow!("Unknown package {name}"))?;
+    Ok(dir.join(rest).with_extension("mo"))
+}
+
+fn fingerprint() -> Vec<Option<SystemTime>> {
+    WATCHED
+        .iter()
+        .map(|f| fs::metadata(f).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+fn read_port_file() -> Option<(u16, String)> {
+    let str = fs::read_to_string(PORT_FILE).ok()?;
+    let mut parts = str.split_whitespace();
+    let port = parts.next()?.parse().ok()?;
+    let token = parts.next()?.to_string();
+    Some((port, token))
+}
+
+fn running_pid() -> Option<u64> {
+    let value = send(Request::Status).ok()??;
+    value.get("pid")?.as_u64()
+}
+
+/// Writes the port file readable by the owner only, since the token grants access to the daemon.
+fn write_port_file(contents: &str) -> Result<()> {
+    use std::io::Write;
+    fs::create_dir_all(Path::new(PORT_FILE).parent().unwrap())?;
+    let _ = fs::remove_file(PORT_FILE);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(PORT_FILE)
+        .with_context(|| format!("Failed to create {PORT_FILE}"))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// A 256-bit token from the OS random number generator.
+fn new_token() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("Failed to generate a token: {e}"))?;
+    Ok(hex::encode(bytes))
+}
@@ -0,0 +1,144 @@
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DFX_JSON: &str = "dfx.json";
+/// What dfx should run to get the moc package flags.
+const PACKTOOL: &str = "mops-cli sources";
+/// The replica `dfx start` listens on unless `networks.local.bind` says otherwise.
+const DEFAULT_LOCAL_BIND: &str = "127.0.0.1:4943";
+const MAINNET_URL: &str = "https://icp0.io";
+
+#[derive(Deserialize, Default)]
+struct DfxJson {
+    #[serde(default)]
+    canisters: BTreeMap<String, Canister>,
+    #[serde(default)]
+    networks: BTreeMap<String, Network>,
+}
+#[derive(Deserialize)]
+struct Canister {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    main: Option<PathBuf>,
+}
+#[derive(Deserialize)]
+struct Network {
+    #[serde(default)]
+    providers: Vec<String>,
+    bind: Option<String>,
+}
+
+fn read_dfx_json(path: &Path) -> Result<Option<DfxJson>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let str = fs::read_to_string(path)?;
+    let dfx = serde_json::from_str(&str).with_context(|| format!("Invalid {}", path.display()))?;
+    Ok(Some(dfx))
+}
+
+/// The Motoko canisters of dfx.json in the current directory, as (canister name, main file).
+pub(crate) fn motoko_canisters() -> Result<Vec<(String, PathBuf)>> {
+    let Some(dfx) = read_dfx_json(Path::new(DFX_JSON))? else {
+        return Ok(Vec::new());
+    };
+    Ok(dfx
+        .canisters
+        .into_iter()
+        .filter_map(|(name, canister)| {
+            let main = canister.main?;
+            // dfx assumes "motoko" when a canister has a main file and no type.
+            let motoko = match canister.kind.as_deref() {
+                Some(kind) => kind == "motoko",
+                None => main.extension().is_some_and(|ext| ext == "mo"),
+            };
+            motoko.then_some((name, main))
+        })
+        .collect())
+}
+
+/// Whether `url` is the mainnet endpoint, whose root key the agent knows.
+pub(crate) fn is_mainnet_url(url: &str) -> bool {
+    let url = url.trim_end_matches('/');
+    url == MAINNET_URL || url == "https://ic0.app" || url == "https://icp-api.io"
+}
+
+/// The replica URL of a dfx network. Networks defined in the project's dfx.json take precedence
+/// over the user's `$HOME/.config/dfx/networks.json`. `ic` and `local` work without either.
+pub(crate) fn network_url(network: &str) -> Result<String> {
+    let mut networks = BTreeMap::new();
+    if let Ok(home) = std::env::var("HOME") {
+        let path = PathBuf::from(home).join(".config/dfx/networks.json");
+        if path.exists() {
+            let str = fs::read_to_string(&path)?;
+            let user: BTreeMap<String, Network> = serde_json::from_str(&str)
+                .with_context(|| format!("Invalid {}", path.display()))?;
+            networks.extend(user);
+        }
+    }
+    if let Some(dfx) = read_dfx_json(Path::new(DFX_JSON))? {
+        networks.extend(dfx.networks);
+    }
+    if let Some(def) = networks.get(network) {
+        if let Some(url) = def.providers.first() {
+            return Ok(url.clone());
+        }
+        if let Some(bind) = &def.bind {
+            return Ok(format!("http://{bind}"));
+        }
+    }
+    match network {
+        "ic" => Ok(MAINNET_URL.to_string()),
+        "local" => Ok(format!("http://{DEFAULT_LOCAL_BIND}")),
+        _ => Err(anyhow!(
+            "Unknown network {network}. Define it in dfx.json or $HOME/.config/dfx/networks.json."
+        )),
+    }
+}
+
+/// Sets `defaults.build.packtool` in dfx.json so that dfx gets its package flags from mops.
+pub(crate) fn configure_packtool() -> Result<()> {
+    let path = Path::new(DFX_JSON);
+    if !path.exists() {
+        return Err(anyhow!("No dfx.json in the current directory"));
+    }
+    let mut dfx: Value = serde_json::from_str(&fs::read_to_string(path)?)
+        .with_context(|| format!("Invalid {}", path.display()))?;
+    let root = dfx
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("dfx.json is not an object"))?;
+    let defaults = root.entry("defaults").or_insert_with(|| json!({}));
+    let build = defaults
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("defaults in dfx.json is not an object"))?
+        .entry("build")
+        .or_insert_with(|| json!({}));
+    let build = build
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("defaults.build in dfx.json is not an object"))?;
+    if build.get("packtool").and_then(Value::as_str) == Some(PACKTOOL) {
+        return Ok(());
+    }
+    if let Some(old) = build.get("packtool").and_then(Value::as_str) {
+        if !old.is_empty() {
+            status!(
+                "{:>12} packtool {old:?} in dfx.json",
+                style("Replacing").yellow().bold()
+            );
+        }
+    }
+    build.insert("packtool".to_string(), json!(PACKTOOL));
+    let mut str = serde_json::to_string_pretty(&dfx)?;
+    str.push('\n');
+    fs::write(path, str)?;
+    status!(
+        "{:>12} dfx.json to use {PACKTOOL:?} as packtool",
+        style("Configured").green().bold()
+    );
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use crate::config::Config;
+use crate::toml::{get_package_base_dir, get_package_sources};
+use crate::utils::{create_spinner_bar, download_moc, exec, get_cache_dir, get_mo_doc};
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use console::style;
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum DocFormat {
+    #[default]
+    Html,
+    Adoc,
+    Plain,
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn docs(config: &Config, args: crate::DocsArg) -> Result<()> {
+    let cache_dir = get_cache_dir(config)?;
+    download_moc(&cache_dir).await?;
+    let sources = get_package_sources(&cache_dir, false)?;
+    let (name, source) = match &args.package {
+        Some(dep) => {
+            let (_, path) = sources
+                .iter()
+                .find(|(name, _)| name == dep)
+                .ok_or_else(|| anyhow!("{dep} is not in mops.lock"))?;
+            if !path.exists() {
+                return Err(anyhow!("{dep} is not installed. Run `mops install` first."));
+            }
+            (dep.clone(), path.clone())
+        }
+        None => (
+            "project".to_string(),
+            PathBuf::from(get_package_base_dir()?),
+        ),
+    };
+    let output = match &args.package {
+        Some(dep) => args.output.join(dep),
+        None => args.output.clone(),
+    };
+    let format = match args.doc_format {
+        DocFormat::Html => "html",
+        DocFormat::Adoc => "adoc",
+        DocFormat::Plain => "plain",
+    };
+    let bar = create_spinner_bar(format!("Generating docs for {name}"));
+    let mut mo_doc = get_mo_doc(&cache_dir)?;
+    mo_doc
+        .arg("--source")
+        .arg(&source)
+        .arg("--output")
+        .arg(&output)
+        .arg("--format")
+        .arg(format);
+    for (name, path) in sources {
+        mo_doc.arg("--package").arg(name).arg(path);
+    }
+    exec(mo_doc, Some(&bar))?;
+    bar.finish_and_clear();
+    status!(
+        "{:>12} docs for {name} in {}",
+        style("Generated").green().bold(),
+        output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use crate::config::Config;
+use crate::toml::generate_moc_args;
+use crate::utils::{get_cache_dir, get_mo_doc, get_moc};
+use anyhow::{anyhow, Context, Result};
+use std::process::Command;
+
+/// Runs a command with the package flags appended and exported as `MOPS_MOC_ARGS`. `moc` and
+/// `mo-doc` run from the project's toolchain. Exits with the command's exit code.
+pub fn exec(config: &Config, args: crate::ExecArg) -> Result<()> {
+    let cache_dir = get_cache_dir(config)?;
+    let (program, rest) = args
+        .command
+        .split_first()
+        .ok_or_else(|| anyhow!("No command given. Usage: mops exec -- <cmd> [args]"))?;
+    let mut cmd = match program.as_str() {
+        "moc" => get_moc(&cache_dir)?,
+        "mo-doc" => get_mo_doc(&cache_dir)?,
+        _ => Command::new(program),
+    };
+    let moc_args = generate_moc_args(&cache_dir, true)?;
+    cmd.args(rest)
+        .env("MOPS_MOC_ARGS", moc_args.join(" "))
+        .env("MOPS_CACHE_DIR", &cache_dir);
+    if !args.no_append {
+        cmd.args(&moc_args);
+    }
+    let status = cmd
+        .status()
+        .with_context(|| format!("Error executing {program}"))?;
+    std::process::exit(status.code().unwrap_or(1));
+}
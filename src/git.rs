@@ -0,0 +1,252 @@
+//! Repositories on any git server, e.g. `git+https://git.example.com/team/pkg.git#v1.0.0` or
+//! `git@github.com:org/pkg.git#v1.0.0`. They are fetched with the `git` binary rather than
+//! gitoxide or libgit2: it brings the user's credential helpers, SSH agent and keys,
+//! `known_hosts` and proxy settings along, which the libraries only partly support, and adds no
+//! native library to the build. Only these dependencies need git installed.
+use crate::config::Config;
+use crate::git_host::GitHost;
+use crate::github::RepoInfo;
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+use walkdir::WalkDir;
+
+pub(crate) struct Git;
+
+/// Checkouts by `url@commit`, made once per process even when several lookups need them.
+type Checkouts = Mutex<HashMap<String, Arc<OnceCell<PathBuf>>>>;
+static CHECKOUTS: OnceLock<Checkouts> = OnceLock::new();
+
+/// Where checkouts are made, `git-checkouts` in the cache directory of the user.
+static CHECKOUT_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// The transports a repository may be fetched with. Others, like `ext::`, run commands, and
+/// `file` would let a manifest clone any repository on the machine; `path:` covers local packages.
+const PROTOCOLS: [&str; 4] = ["https", "http", "ssh", "git"];
+
+/// Reads `cache_dir`. Only the first call takes effect.
+pub(crate) fn configure(config: &Config) -> Result<()> {
+    if let Some(dir) = config.get("cache_dir") {
+        let _ = CHECKOUT_DIR.set(PathBuf::from(dir).join("git-checkouts"));
+    }
+    Ok(())
+}
+
+/// Fails for a URL git would read as an option, e.g. `--upload-pack=<cmd>`.
+fn check_url(url: &str) -> Result<()> {
+    if url.starts_with('-') {
+        return Err(anyhow!("Invalid git URL {url}"));
+    }
+    Ok(())
+}
+
+/// Rewrites SSH remotes, `git@host:owner/repo.git#tag` or `ssh://...`, as `git+ssh://` URLs.
+/// Other URLs are returned as they are.
+pub(crate) fn normalize_url(url: &str) -> Cow<'_, str> {
+    if let Some(rest) = url.strip_prefix("ssh://") {
+        return Cow::Owned(format!("git+ssh://{rest}"));
+    }
+    match url.split_once(':') {
+        Some((user_host, path)) if user_host.contains('@') && !user_host.contains('/') => {
+            Cow::Owned(format!("git+ssh://{user_host}/{path}"))
+        }
+        _ => Cow::Borrowed(url),
+    }
+}
+
+/// Runs git and returns its stdout, or its stderr as the error.
+async fn git(dir: Option<&Path>, args: &[&str]) -> Result<String> {
+    let mut cmd = Command::new("git");
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    cmd.args(["-c", "protocol.allow=never"]);
+    for protocol in PROTOCOLS {
+        cmd.arg("-c")
+            .arg(format!("protocol.{protocol}.allow=always"));
+    }
+    // Fail instead of waiting for a password nobody will type. SSH still uses the agent and
+    // keys without passphrase.
+    cmd.args(args).env("GIT_TERMINAL_PROMPT", "0");
+    if std::env::var_os("GIT_SSH_COMMAND").is_none() {
+        cmd.env("GIT_SSH_COMMAND", "ssh -o BatchMode=yes");
+    }
+    let output = cmd.output().await.context("Failed to run git")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {}: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A checkout of `commit` from `url`, made with a shallow fetch of just that commit. Servers that
+/// refuse to serve a commit by id get a full fetch instead.
+async fn checkout(url: &str, commit: &str) -> Result<PathBuf> {
+    let key = format!("{url}@{commit}");
+    let cell = CHECKOUTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(key.clone())
+        .or_default()
+        .clone();
+    let dir = cell
+        .get_or_try_init(|| async {
+            crate::utils::ensure_online(format_args!("fetch {url}"))?;
+            check_url(url)?;
+            check_url(commit)?;
+            let dir = CHECKOUT_DIR
+                .get()
+                .ok_or_else(|| anyhow!("No cache directory to fetch {url} into"))?
+                .join(&hex::encode(Sha256::digest(&key))[..16]);
+            if dir.join(".git").join("mops-done").exists() {
+                return Ok(dir);
+            }
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir)?;
+            }
+            std::fs::create_dir_all(&dir)?;
+            git(Some(&dir), &["init", "-q"]).await?;
+            if git(
+                Some(&dir),
+                &["fetch", "-q", "--depth", "1", "--", url, commit],
+            )
+            .await
+            .is_err()
+            {
+                git(
+                    Some(&dir),
+                    &[
+                        "fetch",
+                        "-q",
+                        "--",
+                        url,
+                        "+refs/heads/*:refs/heads/*",
+                        "+refs/tags/*:refs/tags/*",
+                    ],
+                )
+                .await?;
+            }
+            git(
+                Some(&dir),
+                &["-c", "advice.detachedHead=false", "checkout", "-q", commit],
+            )
+            .await?;
+            std::fs::write(dir.join(".git").join("mops-done"), "")?;
+            Ok::<_, anyhow::Error>(dir)
+        })
+        .await?;
+    Ok(dir.clone())
+}
+
+impl GitHost for Git {
+    fn domain(&self) -> &str {
+        "git"
+    }
+
+    fn default_branch<'a>(&'a self, repo: &'a str) -> BoxFuture<'a, Result<String>> {
+        async move {
+            crate::utils::ensure_online(format_args!("fetch {repo}"))?;
+            check_url(repo)?;
+            // ref: refs/heads/main	HEAD
+            let out = git(None, &["ls-remote", "--symref", "--", repo, "HEAD"]).await?;
+            out.lines()
+                .find_map(|line| {
+                    line.strip_prefix("ref: refs/heads/")?
+                        .strip_suffix("\tHEAD")
+                })
+                .map(|branch| branch.to_string())
+                .ok_or_else(|| anyhow!("{repo} has no default branch"))
+        }
+        .boxed()
+    }
+
+    fn latest_commit<'a>(&'a self, repo: &'a str, tag: &'a str) -> BoxFuture<'a, Result<String>> {
+        async move {
+            if tag.len() == 40 && tag.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Ok(tag.to_string());
+            }
+            crate::utils::ensure_online(format_args!("fetch {repo}"))?;
+            check_url(repo)?;
+            let peeled = format!("{tag}^{{}}");
+            let out = git(None, &["ls-remote", "--", repo, tag, &peeled]).await?;
+            let refs: HashMap<_, _> = out
+                .lines()
+                .filter_map(|line| line.split_once('\t'))
+                .map(|(commit, name)| (name, commit))
+                .collect();
+            // An annotated tag points to the tag object, and `^{}` to its commit.
+            [
+                format!("refs/tags/{tag}^{{}}"),
+                format!("refs/tags/{tag}"),
+                format!("refs/heads/{tag}"),
+            ]
+            .iter()
+            .find_map(|name| refs.get(name.as_str()))
+            .map(|commit| commit.to_string())
+            .ok_or_else(|| anyhow!("No commit found for {tag} in {repo}"))
+        }
+        .boxed()
+    }
+
+    fn fetch_file<'a>(
+        &'a self,
+        repo: &'a RepoInfo,
+        file: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        async move {
+            let dir = checkout(&repo.repo, &repo.commit).await?;
+            std::fs::read_to_string(dir.join(file)).map_err(|_| anyhow!("file not found"))
+        }
+        .boxed()
+    }
+
+    fn download<'a>(
+        &'a self,
+        base_path: &'a Path,
+        repo: &'a RepoInfo,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let dir = checkout(&repo.repo, &repo.commit).await?;
+            for entry in WalkDir::new(dir.join(repo.source_dir())) {
+                let entry = entry?;
+                let path = entry.path().strip_prefix(&dir)?;
+                if entry.file_type().is_file() && path.extension().is_some_and(|e| e == "mo") {
+                    let dest = base_path.join(path);
+                    std::fs::create_dir_all(dest.parent().unwrap())?;
+                    std::fs::copy(entry.path(), dest)?;
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn submodule_commit<'a>(
+        &'a self,
+        repo: &'a RepoInfo,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        async move {
+            let dir = checkout(&repo.repo, &repo.commit).await?;
+            // 160000 commit <sha>	<path>
+            let out = git(Some(&dir), &["ls-tree", &repo.commit, "--", path]).await?;
+            out.split_whitespace()
+                .nth(2)
+                .filter(|_| out.split_whitespace().nth(1) == Some("commit"))
+                .map(|commit| commit.to_string())
+                .ok_or_else(|| anyhow!("{path} is not a submodule"))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,455 @@
+//! The hosts repository dependencies can come from. Each implements the few lookups resolving
+//! and downloading need, and is picked by the host of the dependency URL.
+use crate::bitbucket::Bitbucket;
+use crate::codeberg::Codeberg;
+use crate::git::Git;
+use crate::github::{enterprise_hosts, RepoInfo, GITHUB};
+use crate::gitlab::GitLab;
+use crate::retry::{is_transient, retry};
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use flate2::read::GzDecoder;
+use futures::future::BoxFuture;
+use futures::{FutureExt, TryStreamExt};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tar::Archive;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+pub(crate) trait GitHost: Sync {
+    /// The host of the dependency URLs, e.g. `gitlab.com`, or `git` for any git server.
+    fn domain(&self) -> &str;
+    fn default_branch<'a>(&'a self, repo: &'a str) -> BoxFuture<'a, Result<String>>;
+    /// The current name of `repo`, which differs when it has been renamed or transferred.
+    fn canonical_name<'a>(&'a self, repo: &'a str) -> BoxFuture<'a, Result<String>> {
+        async move { Ok(repo.to_string()) }.boxed()
+    }
+    /// The commit a branch or tag points to.
+    fn latest_commit<'a>(&'a self, repo: &'a str, tag: &'a str) -> BoxFuture<'a, Result<String>>;
+    /// The content of `file` at the commit of `repo`.
+    fn fetch_file<'a>(&'a self, repo: &'a RepoInfo, file: &'a str)
+        -> BoxFuture<'a, Result<String>>;
+    /// Downloads the `.mo` files under the base directory of `repo` into `base_path`.
+    fn download<'a>(&'a self, base_path: &'a Path, repo: &'a RepoInfo)
+        -> BoxFuture<'a, Result<()>>;
+    /// The commit the submodule at `path` points to, at the commit of `repo`.
+    fn submodule_commit<'a>(
+        &'a self,
+        repo: &'a RepoInfo,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        let _ = (repo, path);
+        async move { Err(anyhow!("Submodules on {} are not supported", self.domain())) }.boxed()
+    }
+}
+
+static HOSTS: [&dyn GitHost; 4] = [&GITHUB, &GitLab, &Bitbucket, &Codeberg];
+
+/// The hosts matched by the domain of URLs: the built-in ones, then the GitHub Enterprise
+/// Servers of the config.
+fn hosts() -> impl Iterator<Item = &'static dyn GitHost> {
+    HOSTS
+        .iter()
+        .copied()
+        .chain(enterprise_hosts().iter().map(|host| host as &dyn GitHost))
+}
+
+/// The host of `url` and the rest of it, e.g. `owner/repo#tag`. A `git+` prefix picks plain git
+/// for any server, and the rest is the clone URL.
+pub(crate) fn split_url(url: &str) -> Option<(&'static dyn GitHost, &str)> {
+    if let Some(url) = url.strip_prefix("git+") {
+        return Some((&Git, url));
+    }
+    let url = url.strip_prefix("https://")?;
+    hosts().find_map(|host| {
+        let rest = url.strip_prefix(host.domain())?.strip_prefix('/')?;
+        Some((host, rest))
+    })
+}
+
+/// The implementation for the host of a [`RepoInfo`], where `None` is GitHub.
+pub(crate) fn by_domain(domain: Option<&str>) -> Result<&'static dyn GitHost> {
+    let domain = domain.unwrap_or(GITHUB.domain());
+    hosts()
+        .chain([&Git as &dyn GitHost])
+        .find(|host| host.domain() == domain)
+        .ok_or_else(|| anyhow!("Unsupported git host {domain}"))
+}
+
+/// The longest wait for a rate limit to reset before giving up.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// Sends a GET request over the shared client, with the `(header, value)` of the host's token.
+/// A rate limited request is sent again once the limit resets, if that is soon.
+pub(crate) async fn request(url: &str, auth: Option<(&str, String)>) -> Result<reqwest::Response> {
+    conditional_request(url, auth, None).await
+}
+
+/// Like [`request`], but only answered with a body when it no longer matches `etag`, and with
+/// 304 Not Modified otherwise.
+pub(crate) async fn conditional_request(
+    url: &str,
+    auth: Option<(&str, String)>,
+    etag: Option<&str>,
+) -> Result<reqwest::Response> {
+    crate::utils::ensure_online(format_args!("fetch {url}"))?;
+    let mut waited = false;
+    loop {
+        let send = || async {
+            let mut request = crate::github::client().get(url);
+            if let Some((header, value)) = &auth {
+                request = request.header(*header, value);
+            }
+            if let Some(etag) = etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            let response = request.send().await?;
+            match response.status() {
+                StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT => response.error_for_status(),
+                _ => Ok(response),
+            }
+        };
+        let response = retry(url, send, |e: &reqwest::Error| {
+            is_transient(e) || e.status().is_some_and(|s| s.is_server_error())
+        })
+        .await?;
+        let Some(wait) = rate_limit_wait(&response) else {
+            return Ok(response);
+        };
+        if waited || wait > MAX_RATE_LIMIT_WAIT {
+            return Err(rate_limit_error(url, wait, auth.is_some()));
+        }
+        crate::utils::println(
+            None,
+            "stderr",
+            &format!(
+                "{:>12} {}s for the rate limit of {} to reset",
+                style("Waiting").yellow().bold(),
+                wait.as_secs(),
+                url_host(url)
+            ),
+        );
+        tokio::time::sleep(wait).await;
+        waited = true;
+    }
+}
+
+/// How long until a rate limited request may be sent again, from `Retry-After` or
+/// `X-RateLimit-Reset`. `None` when the request was not rate limited.
+fn rate_limit_wait(response: &reqwest::Response) -> Option<Duration> {
+    let status = response.status();
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+    if let Some(secs) = header("retry-after") {
+        return Some(Duration::from_secs(secs));
+    }
+    if header("x-ratelimit-remaining") != Some(0) && status != StatusCode::TOO_MANY_REQUESTS {
+        // A plain 403, e.g. a private repo without access
+        return None;
+    }
+    let reset = header("x-ratelimit-reset").map(|reset| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Duration::from_secs(reset.saturating_sub(now.as_secs()) + 1)
+    });
+    Some(reset.unwrap_or(MAX_RATE_LIMIT_WAIT))
+}
+
+fn rate_limit_error(url: &str, wait: Duration, authenticated: bool) -> anyhow::Error {
+    let host = url_host(url);
+    let resets = match wait.as_secs() {
+        secs @ 0..60 => format!("{secs} second(s)"),
+        secs => format!("{} minute(s)", secs.div_ceil(60)),
+    };
+    if authenticated {
+        return anyhow!(
+            "The rate limit of {host} for your token is exhausted. It resets in {resets}."
+        );
+    }
+    let hint = match host {
+        "api.github.com" | "codeload.github.com" | "raw.githubusercontent.com" => {
+            "Set GITHUB_TOKEN, or github_token in the config, to a GitHub token"
+        }
+        "gitlab.com" => "Set GITLAB_TOKEN, or gitlab_token in the config, to a GitLab token",
+        "api.bitbucket.org" | "bitbucket.org" => {
+            "Set BITBUCKET_TOKEN, or bitbucket_token in the config, to a Bitbucket token"
+        }
+        "codeberg.org" => {
+            "Set CODEBERG_TOKEN, or codeberg_token in the config, to a Codeberg token"
+        }
+        _ => "Set a token for the host in github_hosts of the config",
+    };
+    anyhow!(
+        "{host} is rate limiting anonymous requests. It resets in {resets}. {hint} for a much higher limit."
+    )
+}
+
+/// The host part of `url`, for messages.
+fn url_host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.split(['/', '?']).next().unwrap_or(rest)
+}
+
+/// Requests `url` and parses its JSON answer. An error answer becomes the error message.
+pub(crate) async fn get_json<T: for<'de> Deserialize<'de>>(
+    url: &str,
+    auth: Option<(&str, String)>,
+) -> Result<T> {
+    let body = request(url, auth).await?.text().await?;
+    serde_json::from_str(&body).map_err(|_| anyhow!("{body}"))
+}
+
+/// Requests a raw file, telling a missing file apart from other failures.
+pub(crate) async fn get_file(url: &str, auth: Option<(&str, String)>) -> Result<String> {
+    let response = request(url, auth).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Err(anyhow!("file not found"));
+    }
+    Ok(response.error_for_status()?.text().await?)
+}
+
+/// Percent-encodes everything but unreserved characters, for names and refs in URL paths.
+pub(crate) fn encode(str: &str) -> String {
+    str.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Like [`encode`], but keeps the `/` between path components.
+pub(crate) fn encode_path(path: &str) -> String {
+    path.split('/').map(encode).collect::<Vec<_>>().join("/")
+}
+
+/// The host answered that it has no archive of the commit, e.g. a GitHub Enterprise Server with
+/// archive downloads turned off. Nothing was unpacked.
+#[derive(Debug)]
+pub(crate) struct ArchiveUnavailable(StatusCode);
+impl std::fmt::Display for ArchiveUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no archive of the commit ({})", self.0)
+    }
+}
+impl std::error::Error for ArchiveUnavailable {}
+
+/// Downloads the `.tar.gz` archive of a commit at `url` and unpacks the `.mo` files under the
+/// base directory of `repo`, as one request instead of one per file. The archive is unpacked
+/// while it downloads, without holding it in memory. Fails with [`ArchiveUnavailable`] when the
+/// host has none.
+pub(crate) async fn download_archive(
+    url: &str,
+    auth: Option<(&str, String)>,
+    base_path: &Path,
+    repo: &RepoInfo,
+) -> Result<()> {
+    let response = request(url, auth).await?;
+    if matches!(
+        response.status(),
+        StatusCode::NOT_FOUND | StatusCode::NOT_IMPLEMENTED
+    ) {
+        return Err(ArchiveUnavailable(response.status()).into());
+    }
+    let response = response.error_for_status()?;
+    let stream = response.bytes_stream().map_err(std::io::Error::other);
+    let reader = SyncIoBridge::new(StreamReader::new(stream));
+    let base_path = base_path.to_path_buf();
+    let base_dir = repo.source_dir();
+    tokio::task::spawn_blocking(move || unpack(reader, &base_path, &base_dir)).await?
+}
+
+fn unpack(reader: impl Read, base_path: &Path, base_dir: &str) -> Result<()> {
+    let mut archive = Archive::new(GzDecoder::new(reader));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // Hosts put the files under a directory named after the repo and commit.
+        let path: PathBuf = entry.path()?.components().skip(1).collect();
+        if path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            continue;
+        }
+        let name = path.to_string_lossy().replace('\\', "/");
+        if entry.header().entry_type().is_file()
+            && path.starts_with(base_dir)
+            && name.ends_with(".mo")
+        {
+            let dest = base_path.join(&path);
+            std::fs::create_dir_all(dest.parent().unwrap())?;
+            entry.unpack(&dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// An entry of `.gitmodules`.
+struct Submodule {
+    path: String,
+    url: String,
+}
+
+fn parse_gitmodules(str: &str) -> Vec<Submodule> {
+    let mut res = Vec::new();
+    let mut current: Option<(Option<String>, Option<String>)> = None;
+    let mut flush = |current: &mut Option<(Option<String>, Option<String>)>| {
+        if let Some((Some(path), Some(url))) = current.take() {
+            res.push(Submodule { path, url });
+        }
+    };
+    for line in str.lines().map(str::trim) {
+        if line.starts_with('[') {
+            flush(&mut current);
+            if line.starts_with("[submodule") {
+                current = Some((None, None));
+            }
+        } else if let (Some((path, url)), Some((key, value))) = (&mut current, line.split_once('='))
+        {
+            let value = value.trim().trim_matches('"').to_string();
+            match key.trim() {
+                "path" => *path = Some(value),
+                "url" => *url = Some(value),
+                _ => (),
+            }
+        }
+    }
+    flush(&mut current);
+    res
+}
+
+/// Resolves a submodule URL relative to the URL of the superproject, as git does.
+fn submodule_url(repo: &RepoInfo, url: &str) -> String {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return url.to_string();
+    }
+    let mut base = match repo.host.as_deref() {
+        Some("git") => repo.web_path(),
+        _ => format!("https://{}", repo.web_path()),
+    };
+    let mut rest = url;
+    loop {
+        if let Some(r) = rest.strip_prefix("./") {
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("../") {
+            if let Some(i) = base.trim_end_matches('/').rfind('/') {
+                base.truncate(i);
+            }
+            rest = r;
+        } else {
+            break;
+        }
+    }
+    format!("{}/{rest}", base.trim_end_matches('/'))
+}
+
+/// Downloads the submodules holding sources of `repo` into `base_path`, at the commits the repo
+/// pins, and then their own submodules. Archives and file lists of hosts leave them out.
+pub(crate) fn download_submodules<'a>(
+    base_path: &'a Path,
+    repo: &'a RepoInfo,
+) -> BoxFuture<'a, Result<()>> {
+    async move {
+        let host = by_domain(repo.host.as_deref())?;
+        let modules = match host.fetch_file(repo, ".gitmodules").await {
+            Ok(modules) => modules,
+            Err(e) if e.to_string() == "file not found" => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let source_dir = repo.source_dir();
+        let source_dir = source_dir.trim_end_matches('/');
+        for module in parse_gitmodules(&modules) {
+            let path = module.path.trim_end_matches('/');
+            // The part of the sources that is inside the submodule.
+            let base_dir = if source_dir.is_empty()
+                || path == source_dir
+                || path.starts_with(&format!("{source_dir}/"))
+            {
+                ""
+            } else if let Some(rest) = source_dir.strip_prefix(&format!("{path}/")) {
+                rest
+            } else {
+                continue;
+            };
+            let commit = host.submodule_commit(repo, path).await.with_context(|| {
+                format!(
+                    "Cannot find the commit of submodule {path} in {}",
+                    repo.location()
+                )
+            })?;
+            let url = submodule_url(repo, &module.url);
+            let url = if crate::github::is_repo_url(&url) {
+                url
+            } else {
+                format!("git+{url}")
+            };
+            let mut sub = crate::github::parse_github_url(&format!("{url}#{commit}@{commit}"))
+                .await
+                .with_context(|| format!("Cannot fetch submodule {path} from {}", module.url))?;
+            sub.subdir = None;
+            sub.base_dir = base_dir.to_string();
+            let dest = base_path.join(path);
+            by_domain(sub.host.as_deref())?
+                .download(&dest, &sub)
+                .await?;
+            download_submodules(&dest, &sub).await?;
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+
+    fn archive(files: &[&str]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Default::default()));
+        for file in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("org-pkg-907a4e7/{file}"), &[][..])
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn unpack_keeps_only_motoko_files_of_the_base_dir() {
+        let dir = std::env::temp_dir().join(format!("mops-unpack-test-{}", std::process::id()));
+        let bytes = archive(&[
+            "src/A.mo",
+            "src/lib/B.mo",
+            "src/notes.txt",
+            "src-old/C.mo",
+            "D.mo",
+        ]);
+        unpack(&bytes[..], &dir, "src").unwrap();
+        assert!(dir.join("src/A.mo").exists());
+        assert!(dir.join("src/lib/B.mo").exists());
+        assert!(!dir.join("src/notes.txt").exists());
+        assert!(!dir.join("src-old").exists());
+        assert!(!dir.join("D.mo").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,32 +1,368 @@
+use crate::config::{Config, GithubHost};
+use crate::git::Git;
+use crate::git_host::{self, GitHost};
 use crate::utils::println;
 use anyhow::Result;
 use console::style;
-use futures::future::try_join_all;
+use futures::future::{join_all, try_join_all, BoxFuture};
+use futures::FutureExt;
 use indicatif::ProgressBar;
 use semver::Version;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+struct Settings {
+    /// Shared by every git host request, so connections are reused
+    client: reqwest::Client,
+    /// By config key, e.g. `github_token`
+    tokens: BTreeMap<&'static str, String>,
+    enterprise: Vec<GitHub>,
+    /// Where API answers are kept with their ETag, when there is a cache root
+    api_cache: Option<PathBuf>,
+    /// Skip reading the API cache, but still write fresh answers to it.
+    refresh: bool,
+}
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// The config keys of the git host tokens, with the variables read when not configured.
+const TOKENS: [(&str, &str); 4] = [
+    ("github_token", "GITHUB_TOKEN"),
+    ("gitlab_token", "GITLAB_TOKEN"),
+    ("bitbucket_token", "BITBUCKET_TOKEN"),
+    ("codeberg_token", "CODEBERG_TOKEN"),
+];
+
+/// GitHub API answers with their ETag, under the cache root. Lookups are sent again with
+/// `If-None-Match`, and an unchanged answer is a 304 that costs no rate limit.
+const API_CACHE_DIR: &str = "github-api";
+
+#[derive(Serialize, Deserialize)]
+struct CachedResponse {
+    etag: String,
+    body: String,
+}
+
+/// Sets the HTTP client settings, timeout and tokens applied to every git host request. Only the
+/// first call takes effect.
+pub fn configure(config: &Config) -> Result<()> {
+    let mut builder = crate::utils::http_client_builder(config)?;
+    if let Some(timeout) = config.get_parsed::<u64>("github_timeout")? {
+        builder = builder.timeout(Duration::from_secs(timeout));
+    }
+    let tokens = TOKENS
+        .iter()
+        .filter_map(|(key, _)| Some((*key, config.get(key)?.to_string())))
+        .collect();
+    let enterprise = config
+        .github_hosts()
+        .iter()
+        .map(|(domain, host)| GitHub::enterprise(domain, host))
+        .collect();
+    let _ = SETTINGS.set(Settings {
+        client: builder.build()?,
+        tokens,
+        enterprise,
+        api_cache: config
+            .get("cache_dir")
+            .map(|dir| PathBuf::from(dir).join(API_CACHE_DIR)),
+        refresh: config.get_parsed::<bool>("no_cache")?.unwrap_or(false),
+    });
+    Ok(())
+}
+
+/// The configured settings, or defaults taking the tokens from `GITHUB_TOKEN` etc. when embedded
+/// without calling [`configure`].
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(|| Settings {
+        client: reqwest::Client::builder()
+            .user_agent(crate::utils::USER_AGENT)
+            .build()
+            .unwrap_or_default(),
+        tokens: TOKENS
+            .iter()
+            .filter_map(|(key, env)| Some((*key, std::env::var(env).ok()?)))
+            .collect(),
+        enterprise: Vec::new(),
+        api_cache: None,
+        refresh: false,
+    })
+}
+
+/// The HTTP client shared by git hosts and release downloads, so connections are pooled across
+/// them. Created on first use.
+pub(crate) fn client() -> &'static reqwest::Client {
+    &settings().client
+}
+
+/// The token configured under `key`, e.g. `gitlab_token`.
+pub(crate) fn token(key: &str) -> Option<&'static str> {
+    settings().tokens.get(key).map(|token| token.as_str())
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RepoInfo {
+    /// The git host when it is not GitHub, e.g. `gitlab.com`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
     pub repo: String,
     pub tag: String,
     pub commit: String,
+    /// The directory of the package in a monorepo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdir: Option<String>,
+    /// Relative to `subdir`
     pub base_dir: String,
+    /// What `tag` is, when mops.toml says so with `branch`, `tag` or `rev`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<RefKind>,
+    /// The name in mops.toml, when the repo has since been renamed to `repo`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renamed_from: Option<String>,
+}
+
+/// The kind of ref a repository dependency follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RefKind {
+    /// Moves on when the branch does
+    Branch,
+    Tag,
+    /// A fixed commit
+    Rev,
 }
 
+/// How [`resolve_github_urls`] reuses the answers to branch and tag lookups.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Remember lookups for the lifetime of the process, so every caller asking about the same
+    /// repo and ref gets the same commit.
+    #[default]
+    Process,
+    /// Always ask GitHub. Identical lookups within one call are still made only once.
+    Refresh,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResolveErrorKind {
+    /// The URL is neither a `https://<host>/<owner>/<repo>` URL of a supported git host, a
+    /// `git+<clone url>` nor an SSH remote
+    InvalidUrl,
+    /// The host does not know the repo, branch or tag
+    NotFound,
+    /// The request failed or the host refused it, e.g. when rate limited
+    Network,
+}
+
+/// Why one URL of a batch could not be resolved.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResolveError {
+    pub url: String,
+    pub kind: ResolveErrorKind,
+    pub message: String,
+}
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.url, self.message)
+    }
+}
+impl std::error::Error for ResolveError {}
+
+/// The parts of a repository URL, before looking anything up.
+struct ParsedUrl {
+    host: Option<String>,
+    repo: String,
+    renamed_from: Option<String>,
+    tag: Option<String>,
+    commit: Option<String>,
+    subdir: Option<String>,
+    base_dir: String,
+}
+
+/// Default branches by repo location, and commits by `location#ref`, for
+/// [`CachePolicy::Process`].
+static LOOKUPS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
 /// Parse github url as specified in `https://docs.mops.one/mops.toml`
 pub async fn parse_github_url(url: &str) -> Result<RepoInfo> {
+    let mut res = resolve_github_urls(&[url.to_string()], CachePolicy::Process).await;
+    Ok(res.pop().unwrap()?)
+}
+
+/// Resolves many GitHub URLs to exact commits at once. Branch and tag lookups shared by several
+/// URLs (e.g. different base directories of one repo) are made once and concurrently. Results
+/// are in the order of `urls`, each with its own error.
+pub async fn resolve_github_urls(
+    urls: &[String],
+    policy: CachePolicy,
+) -> Vec<Result<RepoInfo, ResolveError>> {
+    let parsed: Vec<_> = urls.iter().map(|url| split_github_url(url)).collect();
+    let error = |url: &str, kind, message: String| ResolveError {
+        url: url.to_string(),
+        kind,
+        message,
+    };
+    // Renamed repos still answer under their old name, but the lock should record the new one.
+    // URLs with a commit need no lookup, and keep working as they are.
+    let names: HashMap<_, _> = join_all(
+        parsed
+            .iter()
+            .flatten()
+            .filter(|p| p.commit.is_none())
+            .map(|p| (p.host.clone(), p.repo.clone()))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|(host, repo)| async move {
+                let key = format!("{}~name", location(host.as_deref(), &repo));
+                let res = cached(policy, key, get_canonical_name(host.as_deref(), &repo)).await;
+                ((host, repo), res)
+            }),
+    )
+    .await
+    .into_iter()
+    .collect();
+    let parsed: Vec<_> = parsed
+        .into_iter()
+        .map(|p| {
+            p.map(|mut p| {
+                // A failed lookup shows up again in the ones below, with a better error.
+                if let Some(Ok(name)) = names.get(&(p.host.clone(), p.repo.clone())) {
+                    if *name != p.repo {
+                        p.renamed_from = Some(std::mem::replace(&mut p.repo, name.clone()));
+                    }
+                }
+                p
+            })
+        })
+        .collect();
+    let repos: BTreeSet<_> = parsed
+        .iter()
+        .flatten()
+        .filter(|p| p.tag.is_none())
+        .map(|p| (p.host.clone(), p.repo.clone()))
+        .collect();
+    let branches: HashMap<_, _> = join_all(repos.into_iter().map(|(host, repo)| async move {
+        let key = location(host.as_deref(), &repo);
+        let res = cached(policy, key, get_default_branch(host.as_deref(), &repo)).await;
+        ((host, repo), res)
+    }))
+    .await
+    .into_iter()
+    .collect();
+    let tag_of = |p: &ParsedUrl| match &p.tag {
+        Some(tag) => Ok(tag.clone()),
+        None => branches[&(p.host.clone(), p.repo.clone())]
+            .as_ref()
+            .map(|t| t.clone())
+            .map_err(|e| format!("{e:#}")),
+    };
+    let refs: BTreeSet<_> = parsed
+        .iter()
+        .flatten()
+        .filter(|p| p.commit.is_none())
+        .filter_map(|p| Some((p.host.clone(), p.repo.clone(), tag_of(p).ok()?)))
+        .collect();
+    let commits: HashMap<_, _> = join_all(refs.into_iter().map(|(host, repo, tag)| async move {
+        let key = format!("{}#{tag}", location(host.as_deref(), &repo));
+        let res = cached(policy, key, get_latest_commit(host.as_deref(), &repo, &tag)).await;
+        ((host, repo, tag), res)
+    }))
+    .await
+    .into_iter()
+    .collect();
+    urls.iter()
+        .zip(parsed)
+        .map(|(url, parsed)| {
+            let p = parsed.map_err(|e| error(url, ResolveErrorKind::InvalidUrl, e))?;
+            let tag = tag_of(&p).map_err(|e| error(url, lookup_error_kind(&e), e))?;
+            let commit = match p.commit {
+                Some(commit) => commit,
+                None => commits[&(p.host.clone(), p.repo.clone(), tag.clone())]
+                    .as_ref()
+                    .map(|c| c.clone())
+                    .map_err(|e| {
+                        let e = format!("{e:#}");
+                        error(url, lookup_error_kind(&e), e)
+                    })?,
+            };
+            Ok(RepoInfo {
+                host: p.host,
+                repo: p.repo,
+                tag,
+                commit,
+                subdir: p.subdir,
+                base_dir: p.base_dir,
+                kind: None,
+                renamed_from: p.renamed_from,
+            })
+        })
+        .collect()
+}
+
+/// Hosts answer unknown repos and refs with a JSON body whose message says it is not found.
+fn lookup_error_kind(message: &str) -> ResolveErrorKind {
+    let message = message.to_lowercase();
+    if ["not found", "couldn't be found", "no commit found"]
+        .iter()
+        .any(|m| message.contains(m))
+    {
+        ResolveErrorKind::NotFound
+    } else {
+        ResolveErrorKind::Network
+    }
+}
+
+/// Runs `lookup` unless the process cache already has an answer for `key`.
+async fn cached(
+    policy: CachePolicy,
+    key: String,
+    lookup: impl std::future::Future<Output = Result<String>>,
+) -> Result<String> {
+    let cache = LOOKUPS.get_or_init(Default::default);
+    if policy == CachePolicy::Process {
+        if let Some(hit) = cache.lock().unwrap().get(&key) {
+            return Ok(hit.clone());
+        }
+    }
+    let value = lookup.await?;
+    cache.lock().unwrap().insert(key, value.clone());
+    Ok(value)
+}
+
+fn split_github_url(url: &str) -> Result<ParsedUrl, String> {
     // https://github.com/icdevsorg/candy_library/base_dir#v0.3.0@907a4e7363aac6c6a4e114ebc73e3d3f21e138af
     // or https://github.com/chenyan2002/motoko-splay.git
-    let url = url
-        .strip_prefix("https://github.com/")
-        .ok_or_else(|| anyhow::anyhow!("invalid url"))?;
+    // or https://gitlab.com/org/pkg#v1.0.0
+    // or git+https://git.example.com/team/pkg.git#v1.0.0
+    // or git@github.com:org/pkg.git#v1.0.0
+    let url = crate::git::normalize_url(url);
+    let (host, url) = git_host::split_url(&url).ok_or_else(|| "invalid url".to_string())?;
+    if host.domain() == Git.domain() {
+        let (repo, fragment) = match url.split_once('#') {
+            Some((repo, fragment)) => (repo, Some(fragment)),
+            None => (url, None),
+        };
+        let (tag, commit) = split_ref(fragment);
+        return Ok(ParsedUrl {
+            host: Some(Git.domain().to_string()),
+            repo: repo.to_string(),
+            renamed_from: None,
+            tag,
+            commit,
+            subdir: None,
+            base_dir: "src".to_string(),
+        });
+    }
+    let host = (host.domain() != GITHUB.domain()).then(|| host.domain().to_string());
     let parts: Vec<&str> = url.splitn(3, '/').collect();
     let base_dir = match parts.len() {
-        0 | 1 => return Err(anyhow::anyhow!("invalid url")),
+        0 | 1 => return Err("invalid url".to_string()),
         2 => "src".to_string(),
         3 => parts[2].to_string(),
         _ => unreachable!(),
@@ -38,48 +374,86 @@ pub async fn parse_github_url(url: &str) -> Result<RepoInfo> {
     }
     let repo_parts: Vec<&str> = repo_part.split('#').collect();
     let repo = format!("{}/{}", owner, repo_parts[0]);
-    let mut tag = None;
-    let mut commit = None;
-    if repo_parts.len() > 1 {
-        let tag_commit_parts: Vec<&str> = repo_parts[1].split('@').collect();
-        tag = Some(tag_commit_parts[0].to_string());
-        if tag_commit_parts.len() > 1 {
-            commit = Some(tag_commit_parts[1].to_string());
-        }
-    }
-    if tag.is_none() {
-        tag = Some(get_default_branch(&repo).await?);
-    }
-    if commit.is_none() {
-        commit = Some(get_latest_commit(&repo, tag.as_ref().unwrap()).await?);
+    let (mut tag, commit) = split_ref(repo_parts.get(1).copied());
+    // The web URL of a monorepo directory: https://github.com/org/monorepo/tree/main/packages/foo
+    // (GitLab adds `-/` before `tree`)
+    let tree = base_dir.strip_prefix("-/").unwrap_or(&base_dir);
+    if let Some(tree) = tree.strip_prefix("tree/") {
+        let (reference, subdir) = tree.split_once('/').unwrap_or((tree, ""));
+        tag = Some(reference.to_string());
+        let subdir = subdir.trim_matches('/');
+        return Ok(ParsedUrl {
+            host,
+            repo,
+            renamed_from: None,
+            tag,
+            commit,
+            subdir: (!subdir.is_empty()).then(|| subdir.to_string()),
+            base_dir: "src".to_string(),
+        });
     }
-    Ok(RepoInfo {
+    Ok(ParsedUrl {
+        host,
         repo,
-        tag: tag.unwrap(),
-        commit: commit.unwrap(),
+        renamed_from: None,
+        tag,
+        commit,
+        subdir: None,
         base_dir,
     })
 }
 
+/// Whether `locked` is what `url` resolved to before, so it can be kept without asking the host.
+/// A URL without a ref matches any branch, which was its default branch when it was locked, and
+/// the old name of a renamed repo matches its new one.
+pub(crate) fn is_locked_as(url: &str, subdir: Option<&str>, locked: &RepoInfo) -> bool {
+    let Ok(p) = split_github_url(url) else {
+        return false;
+    };
+    p.host == locked.host
+        && (p.repo == locked.repo || locked.renamed_from.as_ref() == Some(&p.repo))
+        && p.tag.as_ref().is_none_or(|tag| *tag == locked.tag)
+        && p.commit
+            .as_ref()
+            .is_none_or(|commit| *commit == locked.commit)
+        && subdir.or(p.subdir.as_deref()) == locked.subdir.as_deref()
+        && p.base_dir == locked.base_dir
+}
+
+/// Splits the `tag@commit` after the `#` of a URL.
+fn split_ref(fragment: Option<&str>) -> (Option<String>, Option<String>) {
+    let Some(fragment) = fragment else {
+        return (None, None);
+    };
+    let tag_commit_parts: Vec<&str> = fragment.split('@').collect();
+    let tag = Some(tag_commit_parts[0].to_string());
+    let commit = tag_commit_parts.get(1).map(|c| c.to_string());
+    (tag, commit)
+}
+
 pub async fn download_github_package(
     base_path: PathBuf,
     repo: RepoInfo,
     bar: Rc<ProgressBar>,
 ) -> Result<()> {
-    let files = get_file_list(&repo).await?;
-    let mut futures = Vec::new();
-    for file in files {
-        futures.push(download_file(base_path.clone(), repo.clone(), file));
-    }
-    try_join_all(futures).await?;
-    fs::write(base_path.join(repo.get_done_file()), "")?;
+    git_host::by_domain(repo.host.as_deref())?
+        .download(&base_path, &repo)
+        .await?;
+    git_host::download_submodules(&base_path, &repo).await?;
+    // A subdirectory without sources still gets its marker.
+    std::fs::create_dir_all(&base_path)?;
+    crate::cache::write_marker(
+        &base_path,
+        &repo.get_done_file(),
+        format!("{}@{}", repo.location(), repo.commit),
+    )?;
     println(
         Some(&bar),
         "stdout",
         &format!(
             "{:>12} {}@{}",
             style("Downloaded").green().bold(),
-            repo.repo,
+            repo.location(),
             repo.tag
         ),
     );
@@ -88,7 +462,9 @@ pub async fn download_github_package(
 }
 
 async fn download_file(base_path: PathBuf, repo: RepoInfo, file: String) -> Result<()> {
-    let content = fetch_file(&repo, &file).await?;
+    let content = git_host::by_domain(repo.host.as_deref())?
+        .fetch_file(&repo, &file)
+        .await?;
     let path = base_path.join(file);
     let dir = path.parent().unwrap();
     std::fs::create_dir_all(dir)?;
@@ -96,89 +472,394 @@ async fn download_file(base_path: PathBuf, repo: RepoInfo, file: String) -> Resu
     Ok(())
 }
 
+/// Fetches `file` of the package, i.e. relative to its subdirectory in a monorepo.
 pub async fn fetch_file(repo: &RepoInfo, file: &str) -> Result<String> {
-    let url = format!(
-        "https://raw.githubusercontent.com/{}/{}/{}",
-        repo.repo, repo.commit, file
-    );
-    let body = github_request(&url).await?;
-    if body.starts_with("404: Not Found") {
-        return Err(anyhow::anyhow!("file not found"));
-    }
-    Ok(body)
+    let file = match &repo.subdir {
+        Some(subdir) => format!("{subdir}/{file}"),
+        None => file.to_string(),
+    };
+    git_host::by_domain(repo.host.as_deref())?
+        .fetch_file(repo, &file)
+        .await
 }
-async fn get_default_branch(repo: &str) -> Result<String> {
-    #[derive(Deserialize)]
-    struct Branch {
-        default_branch: String,
+/// The current name of `repo`, warning when it differs. Names differing only in case are the
+/// same repo on every host we support.
+async fn get_canonical_name(host: Option<&str>, repo: &str) -> Result<String> {
+    let name = git_host::by_domain(host)?.canonical_name(repo).await?;
+    if name.eq_ignore_ascii_case(repo) {
+        return Ok(repo.to_string());
     }
-    let url = format!("https://api.github.com/repos/{}", repo);
-    let body = github_request(&url).await?;
-    let response = serde_json::from_str::<Branch>(&body).map_err(|_| anyhow::anyhow!("{body}"))?;
-    Ok(response.default_branch)
+    println(
+        None,
+        "stderr",
+        &format!(
+            "{:>12} {} is now {}, which mops.lock records. Update the URL in mops.toml to silence this",
+            style("Renamed").yellow().bold(),
+            location(host, repo),
+            location(host, &name)
+        ),
+    );
+    Ok(name)
+}
+async fn get_default_branch(host: Option<&str>, repo: &str) -> Result<String> {
+    git_host::by_domain(host)?.default_branch(repo).await
+}
+
+async fn get_latest_commit(host: Option<&str>, repo: &str, tag: &str) -> Result<String> {
+    git_host::by_domain(host)?.latest_commit(repo, tag).await
+}
+
+/// A GitHub installation: github.com, or a GitHub Enterprise Server from `github_hosts`.
+pub(crate) struct GitHub {
+    domain: Cow<'static, str>,
+    api: Cow<'static, str>,
+    raw: Cow<'static, str>,
+    /// Only for Enterprise Servers. github.com uses `github_token`.
+    token: Option<String>,
+}
+
+pub(crate) static GITHUB: GitHub = GitHub {
+    domain: Cow::Borrowed("github.com"),
+    api: Cow::Borrowed("https://api.github.com"),
+    raw: Cow::Borrowed("https://raw.githubusercontent.com"),
+    token: None,
+};
+
+/// The configured GitHub Enterprise Servers.
+pub(crate) fn enterprise_hosts() -> &'static [GitHub] {
+    &settings().enterprise
 }
 
-async fn get_latest_commit(repo: &str, tag: &str) -> Result<String> {
-    #[derive(Deserialize)]
-    struct Commit {
-        sha: String,
+impl GitHub {
+    fn enterprise(domain: &str, host: &GithubHost) -> Self {
+        GitHub {
+            domain: Cow::Owned(domain.to_string()),
+            api: Cow::Owned(
+                host.api
+                    .clone()
+                    .unwrap_or_else(|| format!("https://{domain}/api/v3")),
+            ),
+            raw: Cow::Owned(
+                host.raw
+                    .clone()
+                    .unwrap_or_else(|| format!("https://{domain}/raw")),
+            ),
+            token: host.token.clone(),
+        }
+    }
+
+    fn auth(&self) -> Option<(&'static str, String)> {
+        let token = if self.domain == GITHUB.domain {
+            token("github_token")
+        } else {
+            self.token.as_deref()
+        };
+        let token = token
+            .map(String::from)
+            .or_else(|| crate::credentials::discover(&self.domain));
+        token.map(|token| ("Authorization", format!("Bearer {token}")))
+    }
+
+    /// Requests `url` of the API, answered from the cache when it has not changed. Entries are
+    /// per token, as a token may see more than anonymous requests.
+    async fn request(&self, url: &str) -> Result<String> {
+        let auth = self.auth();
+        let entry = settings().api_cache.as_ref().map(|dir| {
+            let token = auth.as_ref().map_or("", |(_, token)| token.as_str());
+            let key = Sha256::digest(format!("{url}\n{token}"));
+            dir.join(format!("{}.json", hex::encode(key)))
+        });
+        // A corrupt entry is just a miss.
+        let cached = entry
+            .as_ref()
+            .filter(|_| !settings().refresh)
+            .and_then(|entry| std::fs::read(entry).ok())
+            .and_then(|bytes| serde_json::from_slice::<CachedResponse>(&bytes).ok());
+        let etag = cached.as_ref().map(|c| c.etag.as_str());
+        let response = git_host::conditional_request(url, auth.clone(), etag).await?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            if let Some(cached) = cached {
+                return Ok(cached.body);
+            }
+        }
+        let etag = response
+            .status()
+            .is_success()
+            .then(|| response.headers().get(reqwest::header::ETAG))
+            .flatten()
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+        let body = response.text().await?;
+        if let (Some(entry), Some(etag)) = (entry, etag) {
+            // Only a cache: failing to write it doesn't fail the lookup.
+            let cached = CachedResponse {
+                etag,
+                body: body.clone(),
+            };
+            let _ = std::fs::create_dir_all(entry.parent().unwrap())
+                .and_then(|_| std::fs::write(&entry, serde_json::to_vec(&cached)?));
+        }
+        Ok(body)
+    }
+
+    async fn get_file_list(&self, repo: &RepoInfo) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Tree {
+            tree: Vec<TreeItem>,
+        }
+        #[derive(Deserialize)]
+        struct TreeItem {
+            path: String,
+            r#type: String,
+        }
+        let url = format!(
+            "{}/repos/{}/git/trees/{}?recursive=1",
+            self.api, repo.repo, repo.commit
+        );
+        let body = self.request(&url).await?;
+        let tree = serde_json::from_str::<Tree>(&body).map_err(|_| anyhow::anyhow!("{body}"))?;
+        Ok(tree
+            .tree
+            .into_iter()
+            .filter(|item| {
+                item.r#type == "blob"
+                    && item.path.starts_with(&repo.source_dir())
+                    && item.path.ends_with(".mo")
+            })
+            .map(|item| item.path)
+            .collect())
     }
-    let url = format!("https://api.github.com/repos/{}/commits/{}", repo, tag);
-    let body = github_request(&url).await?;
-    let response = serde_json::from_str::<Commit>(&body).map_err(|_| anyhow::anyhow!("{body}"))?;
-    Ok(response.sha)
 }
-pub async fn get_latest_release_tag(repo: &str) -> Result<String> {
-    #[derive(Deserialize)]
-    struct Release {
-        tag_name: String,
+
+impl GitHost for GitHub {
+    fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    fn default_branch<'a>(&'a self, repo: &'a str) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Branch {
+            default_branch: String,
+        }
+        async move {
+            let url = format!("{}/repos/{}", self.api, repo);
+            let body = self.request(&url).await?;
+            let response =
+                serde_json::from_str::<Branch>(&body).map_err(|_| anyhow::anyhow!("{body}"))?;
+            Ok(response.default_branch)
+        }
+        .boxed()
+    }
+
+    fn canonical_name<'a>(&'a self, repo: &'a str) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Repository {
+            full_name: Option<String>,
+            /// Where a renamed repo moved, in the `Moved Permanently` answer to its old name
+            url: Option<String>,
+        }
+        async move {
+            let mut url = format!("{}/repos/{}", self.api, repo);
+            // The client follows the redirect of a renamed repo by itself, except across hosts.
+            for _ in 0..2 {
+                let body = self.request(&url).await?;
+                let response = serde_json::from_str::<Repository>(&body)
+                    .map_err(|_| anyhow::anyhow!("{body}"))?;
+                match response {
+                    Repository {
+                        full_name: Some(name),
+                        ..
+                    } => return Ok(name),
+                    Repository {
+                        url: Some(moved), ..
+                    } => url = moved,
+                    _ => return Err(anyhow::anyhow!("{body}")),
+                }
+            }
+            Err(anyhow::anyhow!("{repo} keeps moving"))
+        }
+        .boxed()
+    }
+
+    fn latest_commit<'a>(&'a self, repo: &'a str, tag: &'a str) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Commit {
+            sha: String,
+        }
+        async move {
+            let url = format!("{}/repos/{}/commits/{}", self.api, repo, tag);
+            let body = self.request(&url).await?;
+            let response =
+                serde_json::from_str::<Commit>(&body).map_err(|_| anyhow::anyhow!("{body}"))?;
+            Ok(response.sha)
+        }
+        .boxed()
+    }
+
+    fn fetch_file<'a>(
+        &'a self,
+        repo: &'a RepoInfo,
+        file: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        async move {
+            let url = format!("{}/{}/{}/{}", self.raw, repo.repo, repo.commit, file);
+            git_host::get_file(&url, self.auth()).await
+        }
+        .boxed()
+    }
+
+    fn download<'a>(
+        &'a self,
+        base_path: &'a Path,
+        repo: &'a RepoInfo,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            // One tarball is much faster than a request per file and spares the rate limit.
+            // Fetching files one by one remains for when the tarball is unavailable.
+            let url = if self.domain == GITHUB.domain {
+                format!(
+                    "https://codeload.github.com/{}/tar.gz/{}",
+                    repo.repo, repo.commit
+                )
+            } else {
+                format!("{}/repos/{}/tarball/{}", self.api, repo.repo, repo.commit)
+            };
+            match git_host::download_archive(&url, self.auth(), base_path, repo).await {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is::<git_host::ArchiveUnavailable>() => println(
+                    None,
+                    "stderr",
+                    &format!(
+                        "{:>12} {}: {e}, downloading its files one by one",
+                        style("Warning").yellow().bold(),
+                        repo.location()
+                    ),
+                ),
+                Err(e) => return Err(e.context(format!("Cannot download {url}"))),
+            }
+            // Other packages of the repo may share `base_path`, so only this one's files go.
+            let dir = base_path.join(repo.source_dir());
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir)?;
+            }
+            let files = self.get_file_list(repo).await?;
+            let mut futures = Vec::new();
+            for file in files {
+                futures.push(download_file(base_path.to_path_buf(), repo.clone(), file));
+            }
+            try_join_all(futures).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn submodule_commit<'a>(
+        &'a self,
+        repo: &'a RepoInfo,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Content {
+            sha: String,
+        }
+        async move {
+            let url = format!(
+                "{}/repos/{}/contents/{}?ref={}",
+                self.api,
+                repo.repo,
+                git_host::encode_path(path),
+                repo.commit
+            );
+            let content: Content = git_host::get_json(&url, self.auth()).await?;
+            Ok(content.sha)
+        }
+        .boxed()
     }
+}
+#[derive(Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    #[serde(default)]
+    pub assets: Vec<ReleaseAsset>,
+}
+#[derive(Deserialize)]
+pub struct ReleaseAsset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+pub async fn get_latest_release(repo: &str) -> Result<Release> {
     let url = format!("https://api.github.com/repos/{}/releases/latest", repo);
     let body = github_request(&url).await?;
     let response = serde_json::from_str::<Release>(&body).map_err(|_| anyhow::anyhow!("{body}"))?;
-    Ok(response.tag_name)
+    Ok(response)
 }
 
-async fn get_file_list(repo: &RepoInfo) -> Result<Vec<String>> {
-    #[derive(Deserialize)]
-    struct Tree {
-        tree: Vec<TreeItem>,
-    }
-    #[derive(Deserialize)]
-    struct TreeItem {
-        path: String,
-        r#type: String,
-    }
-    let url = format!(
-        "https://api.github.com/repos/{}/git/trees/{}?recursive=1",
-        repo.repo, repo.commit
-    );
-    let body = github_request(&url).await?;
-    let tree = serde_json::from_str::<Tree>(&body).map_err(|_| anyhow::anyhow!("{body}"))?;
-    Ok(tree
-        .tree
-        .into_iter()
-        .filter(|item| {
-            item.r#type == "blob"
-                && item.path.starts_with(&repo.base_dir)
-                && item.path.ends_with(".mo")
-        })
-        .map(|item| item.path)
-        .collect())
+pub async fn get_latest_release_tag(repo: &str) -> Result<String> {
+    Ok(get_latest_release(repo).await?.tag_name)
 }
+
 async fn github_request(url: &str) -> Result<String> {
-    let client = reqwest::Client::new();
-    let mut request = client.get(url).header("User-Agent", "mops-cli");
-    if let Ok(token) = std::env::var("GITHUB_TOKEN") {
-        request = request.header("Authorization", format!("Bearer {token}"));
+    GITHUB.request(url).await
+}
+
+/// Whether `url` names a repository dependency rather than a registry version.
+pub(crate) fn is_repo_url(url: &str) -> bool {
+    git_host::split_url(&crate::git::normalize_url(url)).is_some()
+}
+
+/// `repo`, prefixed with its host unless that is GitHub. The clone URLs of plain git repos keep
+/// only their host and path.
+fn location(host: Option<&str>, repo: &str) -> String {
+    match host {
+        Some(host) if host == Git.domain() => {
+            let path = repo.split_once("://").map_or(repo, |(_, path)| path);
+            let path = path.split_once('@').map_or(path, |(_, path)| path);
+            let path: String = path
+                .chars()
+                .map(|c| match c {
+                    'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '_' | '-' | '/' => c,
+                    _ => '_',
+                })
+                .collect();
+            format!("{host}/{path}")
+        }
+        Some(host) => format!("{host}/{repo}"),
+        None => repo.to_string(),
     }
-    let response = request.send().await?;
-    let body = response.text().await?;
-    Ok(body)
 }
+
 impl RepoInfo {
+    /// `owner/repo` for GitHub, `host/owner/repo` otherwise. Unique across hosts.
+    pub fn location(&self) -> String {
+        location(self.host.as_deref(), &self.repo)
+    }
+    /// The repo with its host, e.g. `github.com/owner/repo`, or the clone URL of a plain git
+    /// repo.
+    pub fn web_path(&self) -> String {
+        if self.host.as_deref() == Some(Git.domain()) {
+            return self.repo.clone();
+        }
+        format!(
+            "{}/{}",
+            self.host.as_deref().unwrap_or("github.com"),
+            self.repo
+        )
+    }
+    /// The base directory relative to the root of the repo.
+    pub fn source_dir(&self) -> String {
+        match &self.subdir {
+            Some(subdir) => format!("{subdir}/{}", self.base_dir),
+            None => self.base_dir.clone(),
+        }
+    }
     pub fn get_done_file(&self) -> String {
-        format!("DONE-{}", self.base_dir.replace('/', "-"))
+        format!("DONE-{}", self.source_dir().replace('/', "-"))
+    }
+    /// The first 8 characters of the commit, for paths and messages. A `rev` or a hand-edited
+    /// lock may hold a shorter one, which is used whole.
+    pub fn short_commit(&self) -> &str {
+        self.commit.get(..8).unwrap_or(&self.commit)
     }
     pub fn guess_version(&self) -> Option<String> {
         let idx = self.tag.find(|c: char| c.is_ascii_digit())?;
@@ -190,3 +871,73 @@ impl RepoInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked(repo: &str, renamed_from: Option<&str>) -> RepoInfo {
+        RepoInfo {
+            host: None,
+            repo: repo.to_string(),
+            tag: "v1.0.0".to_string(),
+            commit: "907a4e7363aac6c6a4e114ebc73e3d3f21e138af".to_string(),
+            subdir: None,
+            base_dir: "src".to_string(),
+            kind: None,
+            renamed_from: renamed_from.map(|r| r.to_string()),
+        }
+    }
+
+    #[test]
+    fn is_locked_as_matches_the_old_name_of_a_renamed_repo() {
+        let locked = locked("new-org/pkg", Some("old-org/pkg"));
+        assert!(is_locked_as(
+            "https://github.com/old-org/pkg#v1.0.0",
+            None,
+            &locked
+        ));
+        assert!(is_locked_as(
+            "https://github.com/new-org/pkg#v1.0.0",
+            None,
+            &locked
+        ));
+        assert!(!is_locked_as(
+            "https://github.com/other-org/pkg#v1.0.0",
+            None,
+            &locked
+        ));
+        assert!(!is_locked_as(
+            "https://github.com/old-org/pkg#v2.0.0",
+            None,
+            &locked
+        ));
+    }
+
+    #[test]
+    fn is_locked_as_needs_the_locked_name_without_a_rename() {
+        let locked = locked("new-org/pkg", None);
+        assert!(!is_locked_as(
+            "https://github.com/old-org/pkg#v1.0.0",
+            None,
+            &locked
+        ));
+    }
+
+    #[test]
+    fn short_commit_keeps_short_revs_whole() {
+        let mut repo = locked("org/pkg", None);
+        assert_eq!(repo.short_commit(), "907a4e73");
+        repo.commit = "907a4e".to_string();
+        assert_eq!(repo.short_commit(), "907a4e");
+    }
+
+    #[test]
+    fn renamed_from_round_trips_through_the_lock() {
+        let json = serde_json::to_string(&locked("new-org/pkg", Some("old-org/pkg"))).unwrap();
+        let parsed: RepoInfo = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.renamed_from.as_deref(), Some("old-org/pkg"));
+        let json = serde_json::to_string(&locked("new-org/pkg", None)).unwrap();
+        assert!(!json.contains("renamed_from"));
+    }
+}
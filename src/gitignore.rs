@@ -0,0 +1,79 @@
+use anyhow::Result;
+use console::{style, Term};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Warns when generated directories inside the project would be committed, or when mops.lock
+/// would not be, and offers to fix .gitignore. Does nothing outside a git work tree.
+pub(crate) fn check_gitignore(cache_dir: &Path) -> Result<()> {
+    if !git(&["rev-parse", "--is-inside-work-tree"]) {
+        return Ok(());
+    }
+    let cwd = std::env::current_dir()?;
+    let mut generated = vec![".mops".to_string()];
+    if let Ok(rel) = cwd.join(cache_dir).strip_prefix(&cwd) {
+        let rel = rel.to_string_lossy().replace('\\', "/");
+        if !rel.is_empty() && !generated.iter().any(|g| rel.starts_with(g.as_str())) {
+            generated.push(rel);
+        }
+    }
+    let mut fixes = Vec::new();
+    for dir in &generated {
+        if Path::new(dir).exists() && !is_ignored(dir) {
+            status!(
+                "{:>12} {dir}/ is not in .gitignore, so downloaded and generated files could be committed",
+                style("Warning").yellow().bold()
+            );
+            fixes.push(format!("/{dir}/"));
+        }
+    }
+    if Path::new("mops.lock").exists() && is_ignored("mops.lock") {
+        status!(
+            "{:>12} mops.lock is ignored by git. Commit it so that everyone gets the same versions",
+            style("Warning").yellow().bold()
+        );
+        fixes.push("!/mops.lock".to_string());
+    }
+    if fixes.is_empty() {
+        return Ok(());
+    }
+    let term = Term::stdout();
+    if !term.is_term() || crate::utils::json() {
+        status!("Add to .gitignore:\n  {}", fixes.join("\n  "));
+        return Ok(());
+    }
+    print!("Append {} to .gitignore? [Y/n] ", fixes.join(" "));
+    std::io::stdout().flush()?;
+    let answer = term.read_char()?;
+    status!();
+    if !matches!(answer, 'y' | 'Y' | '\n' | '\r') {
+        return Ok(());
+    }
+    let path = Path::new(".gitignore");
+    let mut content = fs::read_to_string(path).unwrap_or_default();
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    for fix in &fixes {
+        content.push_str(fix);
+        content.push('\n');
+    }
+    fs::write(path, content)?;
+    status!("{:>12} .gitignore", style("Updated").green().bold());
+    Ok(())
+}
+
+fn is_ignored(path: &str) -> bool {
+    git(&["check-ignore", "-q", path])
+}
+
+fn git(args: &[&str]) -> bool {
+    Command::new("git")
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
@@ -0,0 +1,113 @@
+//! GitLab repositories as dependencies, e.g. `https://gitlab.com/<group>/<project>#<tag>`.
+use crate::git_host::{download_archive, encode, get_file, get_json, GitHost};
+use crate::github::RepoInfo;
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+use std::path::Path;
+
+pub(crate) struct GitLab;
+
+fn project_url(repo: &str) -> String {
+    format!("https://gitlab.com/api/v4/projects/{}", encode(repo))
+}
+
+fn auth() -> Option<(&'static str, String)> {
+    crate::github::token("gitlab_token").map(|token| ("PRIVATE-TOKEN", token.to_string()))
+}
+
+impl GitHost for GitLab {
+    fn domain(&self) -> &str {
+        "gitlab.com"
+    }
+
+    fn default_branch<'a>(&'a self, repo: &'a str) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Project {
+            default_branch: String,
+        }
+        async move {
+            let project: Project = get_json(&project_url(repo), auth()).await?;
+            Ok(project.default_branch)
+        }
+        .boxed()
+    }
+
+    fn latest_commit<'a>(&'a self, repo: &'a str, tag: &'a str) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Commit {
+            id: String,
+        }
+        async move {
+            let url = format!("{}/repository/commits/{}", project_url(repo), encode(tag));
+            let commit: Commit = get_json(&url, auth()).await?;
+            Ok(commit.id)
+        }
+        .boxed()
+    }
+
+    fn fetch_file<'a>(
+        &'a self,
+        repo: &'a RepoInfo,
+        file: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        let url = format!(
+            "{}/repository/files/{}/raw?ref={}",
+            project_url(&repo.repo),
+            encode(file),
+            repo.commit
+        );
+        async move { get_file(&url, auth()).await }.boxed()
+    }
+
+    fn download<'a>(
+        &'a self,
+        base_path: &'a Path,
+        repo: &'a RepoInfo,
+    ) -> BoxFuture<'a, Result<()>> {
+        let url = format!(
+            "{}/repository/archive.tar.gz?sha={}",
+            project_url(&repo.repo),
+            repo.commit
+        );
+        async move { download_archive(&url, auth(), base_path, repo).await }.boxed()
+    }
+
+    fn submodule_commit<'a>(
+        &'a self,
+        repo: &'a RepoInfo,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<String>> {
+        #[derive(Deserialize)]
+        struct Entry {
+            id: String,
+            path: String,
+            r#type: String,
+        }
+        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        async move {
+            // Submodules are listed as `commit` entries of the tree of their parent directory.
+            for page in 1.. {
+                let url = format!(
+                    "{}/repository/tree?path={}&ref={}&per_page=100&page={page}",
+                    project_url(&repo.repo),
+                    encode(parent),
+                    repo.commit
+                );
+                let entries: Vec<Entry> = get_json(&url, auth()).await?;
+                if entries.is_empty() {
+                    break;
+                }
+                if let Some(entry) = entries
+                    .into_iter()
+                    .find(|e| e.path == path && e.r#type == "commit")
+                {
+                    return Ok(entry.id);
+                }
+            }
+            Err(anyhow!("{path} is not a submodule"))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,126 @@
+//! The resolved dependency graph of a project, built from mops.lock, for external analysis tools.
+use crate::toml::{get_direct_dependencies, load_mops_lock, parse_mops_lock, Package, PackageType};
+use crate::workspace::lock_path;
+use anyhow::Result;
+use petgraph::graph::{DiGraph, NodeIndex};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SourceKind {
+    /// Published on mops, stored in the given storage canister
+    Mops { storage: String },
+    /// A GitHub repository pinned to a commit
+    Git { repo: String, commit: String },
+    /// A directory on the local file system
+    Local { path: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Node {
+    pub name: String,
+    pub version: Option<String>,
+    pub source: SourceKind,
+    pub base_dir: String,
+    /// `<algorithm>:<hex>` content hash, if the package has been downloaded
+    pub integrity: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Edge {
+    /// What the parent asked for: a version, a repo url or a path
+    pub requirement: String,
+}
+
+pub struct DependencyGraph {
+    /// Edges point from a package to its dependencies
+    pub graph: DiGraph<Node, Edge>,
+    /// Packages required directly by the project's mops.toml, with the requirement declared there
+    pub roots: Vec<(NodeIndex, Edge)>,
+}
+
+impl DependencyGraph {
+    /// Loads the graph of the project in the current directory from `mops.lock`.
+    pub fn load() -> Result<Self> {
+        let roots = get_direct_dependencies()?;
+        // Without dependencies there is no lock, and nothing to show.
+        if roots.is_empty() && !lock_path().exists() {
+            return Ok(Self::from_packages(&[], &roots));
+        }
+        Ok(Self::from_packages(&load_mops_lock()?.package, &roots))
+    }
+    /// Builds the graph from the lock file at `lock`, with `roots` being the `(name, requirement)` of the direct dependencies.
+    pub fn from_lock(lock: &Path, roots: &[(String, String)]) -> Result<Self> {
+        Ok(Self::from_packages(&parse_mops_lock(lock)?.package, roots))
+    }
+    fn from_packages(pkgs: &[Package], roots: &[(String, String)]) -> Self {
+        let mut graph = DiGraph::new();
+        let mut index = BTreeMap::new();
+        for pkg in pkgs {
+            let idx = graph.add_node(Node::from(pkg));
+            index.insert(pkg.name.clone(), idx);
+        }
+        for pkg in pkgs {
+            let from = index[&pkg.name];
+            for dep in &pkg.dependencies {
+                if let Some((name, requirement)) = split_dependency_key(dep, &index) {
+                    graph.add_edge(from, index[name], Edge { requirement });
+                }
+            }
+        }
+        let roots = roots
+            .iter()
+            .filter_map(|(name, requirement)| {
+                let edge = Edge {
+                    requirement: requirement.clone(),
+                };
+                index.get(name).map(|idx| (*idx, edge))
+            })
+            .collect();
+        DependencyGraph { graph, roots }
+    }
+    pub fn find(&self, name: &str) -> Option<NodeIndex> {
+        self.graph
+            .node_indices()
+            .find(|i| self.graph[*i].name == name)
+    }
+}
+
+/// Dependencies are recorded as `{name}-{requirement}` in mops.lock. Since names can contain `-`,
+/// pick the longest known package name that prefixes the key.
+fn split_dependency_key<'a>(
+    key: &str,
+    index: &'a BTreeMap<String, NodeIndex>,
+) -> Option<(&'a String, String)> {
+    index
+        .keys()
+        .filter(|name| key.starts_with(&format!("{name}-")))
+        .max_by_key(|name| name.len())
+        .map(|name| (name, key[name.len() + 1..].to_string()))
+}
+
+impl From<&Package> for Node {
+    fn from(pkg: &Package) -> Self {
+        let source = match pkg.get_type() {
+            PackageType::Mops { id, .. } => SourceKind::Mops {
+                storage: id.to_string(),
+            },
+            PackageType::Repo(repo) => SourceKind::Git {
+                repo: repo.location(),
+                commit: repo.commit.clone(),
+            },
+            PackageType::Local(path) => SourceKind::Local {
+                path: path.to_string(),
+            },
+        };
+        Node {
+            name: pkg.name.clone(),
+            version: pkg.version.clone(),
+            source,
+            base_dir: pkg.base_dir.clone(),
+            integrity: pkg.integrity.clone(),
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::toml::download_packages_from_lock;
+use crate::utils::{get_cache_dir, Pending};
+use crate::workspace::lock_path;
+use anyhow::{anyhow, Result};
+use console::style;
+use ic_agent::Agent;
+use indicatif::HumanDuration;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Previous generations of mops.lock, relative to the directory of the lock.
+const HISTORY_DIR: &str = ".mops/history";
+const COMMAND_PREFIX: &str = "# command: ";
+/// Names the generation that mops.lock currently holds. Without it, the newest one is current.
+const CURRENT_FILE: &str = "CURRENT";
+
+static LIMIT: AtomicUsize = AtomicUsize::new(20);
+
+/// Reads `lock_history`, the number of generations to keep. 0 turns the history off.
+pub(crate) fn configure(config: &Config) -> Result<()> {
+    if let Some(limit) = config.get_parsed::<usize>("lock_history")? {
+        LIMIT.store(limit, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+struct Generation {
+    path: PathBuf,
+    /// Milliseconds since the epoch, from the file name.
+    time: u64,
+    command: String,
+}
+impl Generation {
+    fn lock(&self) -> Result<String> {
+        let str = fs::read_to_string(&self.path)?;
+        Ok(match str.split_once('\n') {
+            Some((first, rest)) if first.starts_with(COMMAND_PREFIX) => rest.to_string(),
+            _ => str,
+        })
+    }
+}
+
+fn history_dir(lock: &Path) -> PathBuf {
+    lock.parent().unwrap_or(Path::new("")).join(HISTORY_DIR)
+}
+
+/// Generations of `lock`, newest first.
+fn generations(lock: &Path) -> Result<Vec<Generation>> {
+    let dir = history_dir(lock);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut res = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let Some(time) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse().ok())
+        else {
+            continue;
+        };
+        let command = fs::read_to_string(&path)?
+            .lines()
+            .next()
+            .and_then(|l| l.strip_prefix(COMMAND_PREFIX))
+            .unwrap_or("?")
+            .to_string();
+        res.push(Generation {
+            path,
+            time,
+            command,
+        });
+    }
+    res.sort_by_key(|g| std::cmp::Reverse(g.time));
+    Ok(res)
+}
+
+/// Index into `generations` of the one mops.lock currently holds.
+fn current(lock: &Path, generations: &[Generation]) -> Option<usize> {
+    if generations.is_empty() {
+        return None;
+    }
+    let Ok(name) = fs::read_to_string(history_dir(lock).join(CURRENT_FILE)) else {
+        return Some(0);
+    };
+    generations
+        .iter()
+        .position(|g| g.path.file_name().is_some_and(|f| f == name.trim()))
+}
+
+fn set_current(dir: &Path, path: &Path) -> Result<()> {
+    let name = path.file_name().unwrap().to_string_lossy();
+    fs::write(dir.join(CURRENT_FILE), name.as_bytes())?;
+    Ok(())
+}
+
+fn save(dir: &Path, command: &str, content: &str) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let mut time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    // Two writes within the same millisecond still get their own generation.
+    while dir.join(format!("{time}.lock")).exists() {
+        time += 1;
+    }
+    let path = dir.join(format!("{time}.lock"));
+    fs::write(&path, format!("{COMMAND_PREFIX}{command}\n{content}"))?;
+    Ok(path)
+}
+
+/// Archives `content` as the newest generation of `lock`, unless it is what `lock` already holds.
+/// The lock from before the first recorded generation is archived too, so it can be restored.
+pub(crate) fn record(lock: &Path, content: &str) -> Result<()> {
+    if LIMIT.load(Ordering::Relaxed) == 0 {
+        return Ok(());
+    }
+    let old = fs::read_to_string(lock).ok();
+    if old.as_deref() == Some(content) {
+        return Ok(());
+    }
+    if let (Some(old), true) = (old, generations(lock)?.is_empty()) {
+        save(&history_dir(lock), "(before history)", &old)?;
+    }
+    let command: Vec<_> = std::env::args().skip(1).collect();
+    archive(lock, &format!("mops {}", command.join(" ")), content)
+}
+
+/// Saves `content` as the newest generation of `lock`, makes it the current one and drops the
+/// oldest beyond `lock_history`.
+fn archive(lock: &Path, command: &str, content: &str) -> Result<()> {
+    let dir = history_dir(lock);
+    let path = save(&dir, command, content)?;
+    set_current(&dir, &path)?;
+    for old in generations(lock)?
+        .iter()
+        .skip(LIMIT.load(Ordering::Relaxed))
+    {
+        fs::remove_file(&old.path)?;
+    }
+    Ok(())
+}
+
+/// Replaces the content of the current generation, for changes that only add information to
+/// the lock, like integrity hashes. Call it before writing `content` to `lock`. When the lock no
+/// longer matches its generation, e.g. after a manual edit, `content` is recorded as a new one.
+pub(crate) fn amend(lock: &Path, content: &str) -> Result<()> {
+    if LIMIT.load(Ordering::Relaxed) == 0 {
+        return Ok(());
+    }
+    let generations = generations(lock)?;
+    let old = fs::read_to_string(lock).ok();
+    let current = current(lock, &generations).map(|i| &generations[i]);
+    match current {
+        Some(g) if old.is_some() && g.lock().ok() == old => {
+            fs::write(&g.path, format!("{COMMAND_PREFIX}{}\n{content}", g.command))?
+        }
+        _ => record(lock, content)?,
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn rollback(agent: &Agent, config: &Config, args: crate::RollbackArg) -> Result<()> {
+    let lock = &lock_path();
+    if args.list {
+        let generations = generations(lock)?;
+        let current = current(lock, &generations).filter(|i| holds(lock, &generations[*i]));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        for (i, g) in generations.iter().enumerate() {
+            let ago = HumanDuration(Duration::from_millis(now.saturating_sub(g.time))).to_string();
+            let mark = if current == Some(i) { " (current)" } else { "" };
+            println!("{i:>4}  {ago:>16} ago  {}{mark}", g.command);
+        }
+        if current.is_none() && lock.exists() && !generations.is_empty() {
+            println!("mops.lock has changes that are in no generation yet");
+        }
+        return Ok(());
+    }
+    let command = restore(lock, args.n)?;
+    status!(
+        "{:>12} mops.lock to the generation from `{command}`",
+        style("Restored").green().bold(),
+    );
+    let cache_dir = get_cache_dir(config)?;
+    let algorithm = HashAlgorithm::from_config(config)?;
+    download_packages_from_lock(agent, &cache_dir, &Pending::default(), algorithm, false).await?;
+    Ok(())
+}
+
+/// Whether `lock` holds the content of `generation`.
+fn holds(lock: &Path, generation: &Generation) -> bool {
+    fs::read_to_string(lock).ok() == generation.lock().ok()
+}
+
+/// Writes the generation `n` steps before the current one to `lock` and makes it current, so
+/// repeated rollbacks keep going back. A lock that no longer matches its generation, e.g. after a
+/// `git pull` or a manual edit, is recorded first, and going back one step undoes that change.
+/// Returns the command that produced the restored generation.
+fn restore(lock: &Path, n: usize) -> Result<String> {
+    let mut generations = generations(lock)?;
+    let mut current = current(lock, &generations);
+    if let Ok(content) = fs::read_to_string(lock) {
+        if !current.is_some_and(|i| holds(lock, &generations[i])) {
+            if generations.is_empty() {
+                return Err(anyhow!(
+                    "There is no earlier generation of mops.lock in {HISTORY_DIR}"
+                ));
+            }
+            let previous = current.map(|i| generations[i].path.clone());
+            archive(lock, "(changed outside mops)", &content)?;
+            generations = self::generations(lock)?;
+            // The recorded change is newest, so the generation before it is one further back.
+            let before = previous.and_then(|p| generations.iter().position(|g| g.path == p));
+            current = Some(before.map_or(0, |i| i - 1));
+        }
+    }
+    let current = current
+        .ok_or_else(|| anyhow!("mops.lock does not match any generation in {HISTORY_DIR}"))?;
+    let Some(target) = generations.get(current + n) else {
+        return Err(anyhow!(
+            "There are only {} earlier generation(s) of mops.lock in {HISTORY_DIR}",
+            generations.len() - current - 1
+        ));
+    };
+    fs::write(lock, target.lock()?)?;
+    set_current(&history_dir(lock), &target.path)?;
+    Ok(target.command.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for the lock of one test.
+    fn lock_in(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("mops-history-test-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("mops.lock")
+    }
+
+    /// Writes `content` the way mops does.
+    fn write(lock: &Path, content: &str) {
+        record(lock, content).unwrap();
+        fs::write(lock, content).unwrap();
+    }
+
+    fn contents(lock: &Path) -> Vec<String> {
+        generations(lock)
+            .unwrap()
+            .iter()
+            .map(|g| g.lock().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn record_skips_what_the_lock_already_holds() {
+        let lock = lock_in("unchanged");
+        write(&lock, "a");
+        write(&lock, "a");
+        assert_eq!(contents(&lock), ["a"]);
+        fs::remove_dir_all(lock.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn record_keeps_the_lock_from_before_the_history() {
+        let lock = lock_in("before");
+        fs::write(&lock, "a").unwrap();
+        write(&lock, "b");
+        assert_eq!(contents(&lock), ["b", "a"]);
+        assert_eq!(generations(&lock).unwrap()[1].command, "(before history)");
+        fs::remove_dir_all(lock.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn record_drops_the_oldest_beyond_the_limit() {
+        let lock = lock_in("limit");
+        let limit = LIMIT.load(Ordering::Relaxed);
+        for i in 0..limit + 2 {
+            write(&lock, &i.to_string());
+        }
+        let contents = contents(&lock);
+        assert_eq!(contents.len(), limit);
+        assert_eq!(contents[0], (limit + 1).to_string());
+        assert_eq!(contents[limit - 1], "2");
+        fs::remove_dir_all(lock.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn amend_replaces_the_current_generation() {
+        let lock = lock_in("amend");
+        write(&lock, "a");
+        write(&lock, "b");
+        let command = generations(&lock).unwrap()[0].command.clone();
+        amend(&lock, "b with hashes").unwrap();
+        fs::write(&lock, "b with hashes").unwrap();
+        assert_eq!(contents(&lock), ["b with hashes", "a"]);
+        assert_eq!(generations(&lock).unwrap()[0].command, command);
+        // After a rollback the current generation is not the newest one.
+        restore(&lock, 1).unwrap();
+        amend(&lock, "a with hashes").unwrap();
+        fs::write(&lock, "a with hashes").unwrap();
+        assert_eq!(contents(&lock), ["b with hashes", "a with hashes"]);
+        fs::remove_dir_all(lock.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn amend_records_a_lock_changed_outside_mops_as_new() {
+        let lock = lock_in("amend-outside");
+        write(&lock, "a");
+        fs::write(&lock, "b").unwrap();
+        amend(&lock, "b with hashes").unwrap();
+        fs::write(&lock, "b with hashes").unwrap();
+        assert_eq!(contents(&lock), ["b with hashes", "a"]);
+        restore(&lock, 1).unwrap();
+        assert_eq!(fs::read_to_string(&lock).unwrap(), "a");
+        fs::remove_dir_all(lock.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn repeated_rollbacks_keep_going_back() {
+        let lock = lock_in("repeated");
+        for content in ["a", "b", "c"] {
+            write(&lock, content);
+        }
+        restore(&lock, 1).unwrap();
+        assert_eq!(fs::read_to_string(&lock).unwrap(), "b");
+        restore(&lock, 1).unwrap();
+        assert_eq!(fs::read_to_string(&lock).unwrap(), "a");
+        assert!(restore(&lock, 1).is_err());
+        assert_eq!(contents(&lock), ["c", "b", "a"]);
+        fs::remove_dir_all(lock.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rollback_records_changes_made_outside_mops() {
+        let lock = lock_in("outside");
+        write(&lock, "a");
+        write(&lock, "b");
+        // e.g. `git pull`
+        fs::write(&lock, "c").unwrap();
+        assert_eq!(
+            restore(&lock, 1).unwrap(),
+            generations(&lock).unwrap()[1].command
+        );
+        assert_eq!(fs::read_to_string(&lock).unwrap(), "b");
+        assert_eq!(contents(&lock), ["c", "b", "a"]);
+        assert_eq!(
+            generations(&lock).unwrap()[0].command,
+            "(changed outside mops)"
+        );
+        restore(&lock, 1).unwrap();
+        assert_eq!(fs::read_to_string(&lock).unwrap(), "a");
+        fs::remove_dir_all(lock.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rollback_after_an_outside_change_undoes_it_from_an_earlier_generation() {
+        let lock = lock_in("outside-after-rollback");
+        for content in ["a", "b", "c"] {
+            write(&lock, content);
+        }
+        restore(&lock, 2).unwrap();
+        assert_eq!(fs::read_to_string(&lock).unwrap(), "a");
+        fs::write(&lock, "d").unwrap();
+        restore(&lock, 1).unwrap();
+        assert_eq!(fs::read_to_string(&lock).unwrap(), "a");
+        assert_eq!(contents(&lock), ["d", "c", "b", "a"]);
+        fs::remove_dir_all(lock.parent().unwrap()).unwrap();
+    }
+}
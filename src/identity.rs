@@ -0,0 +1,125 @@
+use crate::config::Config;
+use crate::toml::get_package_name;
+use anyhow::{anyhow, Context, Error, Result};
+use candid::Principal;
+use console::style;
+use ic_agent::identity::{BasicIdentity, Secp256k1Identity};
+use ic_agent::{Agent, Identity};
+use std::path::PathBuf;
+
+/// Loads the identity configured by the `identity` setting, or else the one `registry_identities`
+/// names for the registry. The value is either a path to a PEM file or the name of a dfx identity
+/// under `$HOME/.config/dfx/identity`. Returns `None` for the anonymous identity.
+pub fn load_identity(config: &Config) -> Result<Option<Box<dyn Identity>>> {
+    match config.get("identity") {
+        Some(name) => load_named(name),
+        None => registry_identity(config, crate::utils::registry_id()),
+    }
+}
+
+/// Loads the identity `registry_identities` names for `registry`. The setting is a
+/// comma-separated list of `<canister>=<identity>`, for registries that only answer known
+/// principals.
+pub(crate) fn registry_identity(
+    config: &Config,
+    registry: Principal,
+) -> Result<Option<Box<dyn Identity>>> {
+    let Some(list) = config.get("registry_identities") else {
+        return Ok(None);
+    };
+    for spec in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (id, name) = spec.split_once('=').ok_or_else(|| {
+            anyhow!("Invalid registry identity {spec}, expected <canister>=<identity>")
+        })?;
+        if Principal::from_text(id.trim()).is_ok_and(|id| id == registry) {
+            return load_named(name.trim());
+        }
+    }
+    Ok(None)
+}
+
+fn load_named(name: &str) -> Result<Option<Box<dyn Identity>>> {
+    if name == "anonymous" {
+        return Ok(None);
+    }
+    let pem = get_pem_path(name)?;
+    if let Ok(id) = Secp256k1Identity::from_pem_file(&pem) {
+        return Ok(Some(Box::new(id)));
+    }
+    let id = BasicIdentity::from_pem_file(&pem)
+        .with_context(|| format!("Cannot load identity from {}", pem.display()))?;
+    Ok(Some(Box::new(id)))
+}
+
+/// Adds to an error of `registry` that says the caller may not read which principal asked, and
+/// how to ask as another one.
+pub(crate) fn explain_unauthorized(registry: &crate::mops::Service<'_>, e: Error) -> Error {
+    let msg = format!("{e:#}").to_lowercase();
+    let refused = [
+        "unauthorized",
+        "not authorized",
+        "permission denied",
+        "access denied",
+    ];
+    if !refused.iter().any(|word| msg.contains(word)) {
+        return e;
+    }
+    let hint = match registry.1.get_principal() {
+        Ok(p) if p != Principal::anonymous() => format!("{p} may not read it"),
+        _ => "It needs an identity with read access: set `identity`, or one for this registry in `registry_identities`".to_string(),
+    };
+    e.context(format!(
+        "The registry {} refused the request. {hint}.",
+        registry.0
+    ))
+}
+
+fn get_pem_path(name: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(name);
+    if path.is_file() {
+        return Ok(path);
+    }
+    let home = std::env::var("HOME").map_err(|_| anyhow!("Cannot find home directory"))?;
+    let path = PathBuf::from(home).join(format!(".config/dfx/identity/{name}/identity.pem"));
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err(anyhow!(
+            "Cannot find identity {name}. Use a path to a PEM file or the name of a dfx identity."
+        ))
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn whoami(agent: &Agent, config: &Config) -> Result<()> {
+    let principal = agent.get_principal().map_err(|e| anyhow!(e))?;
+    let identity = match config.get("identity") {
+        Some(name) => name,
+        None if principal != Principal::anonymous() => "from registry_identities",
+        None => "anonymous",
+    };
+    println!("{principal}");
+    eprintln!("{:>12} {identity}", style("Identity").cyan().bold());
+    let Some(name) = get_package_name()? else {
+        return Ok(());
+    };
+    let service = crate::mops::Service(crate::utils::registry_id(), agent);
+    let owners = service.get_package_owners(&name).await?;
+    if owners.is_empty() {
+        eprintln!(
+            "{:>12} {name} is not published yet",
+            style("Package").cyan().bold()
+        );
+    } else if owners.contains(&principal) {
+        eprintln!(
+            "{:>12} {name} is owned by this principal",
+            style("Package").green().bold()
+        );
+    } else {
+        eprintln!(
+            "{:>12} {name} is not owned by this principal",
+            style("Package").yellow().bold()
+        );
+    }
+    Ok(())
+}
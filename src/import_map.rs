@@ -0,0 +1,45 @@
+use crate::config::Config;
+use crate::toml::get_package_sources;
+use crate::utils::get_cache_dir;
+use anyhow::Result;
+use console::style;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Same shape as a browser import map, keyed by the `mo:` prefix of each package.
+#[derive(Serialize)]
+struct ImportMap {
+    imports: BTreeMap<String, String>,
+}
+
+pub fn import_map(config: &Config, args: crate::ImportMapArg) -> Result<()> {
+    let mut imports = BTreeMap::new();
+    let sources = match &args.base_url {
+        // Paths relative to the cache dir, so they can be served from anywhere
+        Some(_) => get_package_sources(Path::new(""), false)?,
+        None => get_package_sources(&get_cache_dir(config)?, false)?,
+    };
+    for (name, path) in sources {
+        let path = path.to_string_lossy().replace('\\', "/");
+        let target = match &args.base_url {
+            Some(base) => format!("{}/{path}/", base.trim_end_matches('/')),
+            None => format!("{path}/"),
+        };
+        imports.insert(format!("mo:{name}/"), target);
+    }
+    let json = serde_json::to_string_pretty(&ImportMap { imports })?;
+    match &args.output {
+        Some(file) => {
+            fs::write(file, json)?;
+            eprintln!(
+                "{:>12} import map to {}",
+                style("Wrote").green().bold(),
+                file.display()
+            );
+        }
+        None => println!("{json}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use crate::toml::get_package_sources;
+use crate::utils::get_moc;
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use ignore::WalkBuilder;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A `mo:` import that does not resolve to a file.
+struct Broken {
+    file: PathBuf,
+    import: String,
+    reason: String,
+}
+
+/// Checks that every `mo:<pkg>/<path>` import in the project's .mo files names a locked package
+/// and a file under its base directory. The imports are listed by `moc --print-deps`, so moc
+/// must have been downloaded.
+pub(crate) fn check_imports(cache_dir: &Path) -> Result<()> {
+    let sources: BTreeMap<_, _> = get_package_sources(cache_dir, true)?.into_iter().collect();
+    let mut broken = Vec::new();
+    let mut checked = 0;
+    let walker = WalkBuilder::new(".")
+        .filter_entry(|e| e.file_name() != ".mops")
+        .build();
+    for entry in walker {
+        let entry = entry?;
+        let file = entry.path();
+        if file.extension().is_none_or(|ext| ext != "mo") {
+            continue;
+        }
+        for import in package_imports(cache_dir, file)? {
+            checked += 1;
+            if let Err(reason) = resolve(&sources, &import) {
+                broken.push(Broken {
+                    file: file.strip_prefix(".").unwrap_or(file).to_path_buf(),
+                    import,
+                    reason,
+                });
+            }
+        }
+    }
+    if broken.is_empty() {
+        status!(
+            "{:>12} {checked} package import(s)",
+            style("Checked").green().bold()
+        );
+        return Ok(());
+    }
+    for b in &broken {
+        eprintln!(
+            "{}: {}: {}",
+            b.file.display(),
+            style(&b.import).red(),
+            b.reason
+        );
+    }
+    Err(anyhow!(
+        "{} of {checked} package import(s) do not resolve",
+        broken.len()
+    ))
+}
+
+/// The `mo:` imports of `file`, as moc parses them. Each line of `--print-deps` starts with the
+/// import, followed by the resolved path for local files.
+fn package_imports(cache_dir: &Path, file: &Path) -> Result<Vec<String>> {
+    let mut command = get_moc(cache_dir)?;
+    command.arg("--print-deps").arg(file);
+    let output = command
+        .output()
+        .with_context(|| format!("Error executing {command:#?}"))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{}: {}",
+            file.display(),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|import| import.starts_with("mo:"))
+        .map(|import| import.to_string())
+        .collect())
+}
+
+/// Finds the file moc would load for `import`: `<dir>/<path>.mo` or `<dir>/<path>/lib.mo`,
+/// where a bare package name means `lib`.
+fn resolve(sources: &BTreeMap<String, PathBuf>, import: &str) -> Result<PathBuf, String> {
+    let path = import.trim_start_matches("mo:");
+    let (name, rest) = path.split_once('/').unwrap_or((path, "lib"));
+    let dir = sources
+        .get(name)
+        .ok_or_else(|| format!("{name} is not in mops.lock"))?;
+    let file = dir.join(format!("{rest}.mo"));
+    if file.is_file() {
+        return Ok(file);
+    }
+    let lib = dir.join(rest).join("lib.mo");
+    if lib.is_file() {
+        return Ok(lib);
+    }
+    Err(format!("no such file {}", file.display()))
+}
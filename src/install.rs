@@ -0,0 +1,138 @@
+use crate::config::Config;
+use crate::dfx::configure_packtool;
+use crate::gitignore::check_gitignore;
+use crate::imports::check_imports;
+use crate::integrity::HashAlgorithm;
+use crate::toml::{
+    download_packages_from_lock, ensure_cached, plan_downloads, resolve_mops_lock, write_mops_lock,
+    PlanAction, PlannedPackage, ResolveOptions,
+};
+use crate::utils::{confirm, get_cache_dir, Pending};
+use crate::workspace::lock_path;
+use anyhow::{anyhow, Result};
+use console::style;
+use ic_agent::Agent;
+use indicatif::{HumanBytes, HumanDuration};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn install(agent: &Agent, config: &Config, args: crate::InstallArg) -> Result<()> {
+    let cache_dir = get_cache_dir(config)?;
+    if args.configure_dfx {
+        configure_packtool()?;
+    }
+    let start = Instant::now();
+    let pending = Pending::default();
+    let algorithm = HashAlgorithm::from_config(config)?;
+    let options = ResolveOptions {
+        use_author_lock: args.use_author_lock,
+        strict: args.strict,
+        locked: args.locked || args.frozen,
+        ..ResolveOptions::from_config(config)?
+    };
+    let task = async {
+        let lock = resolve_mops_lock(agent, &pending, &options).await?;
+        if args.plan {
+            let plan = plan_downloads(agent, &cache_dir, &lock).await?;
+            print_plan(&plan);
+            if !args.yes && !confirm("apply the plan")? {
+                return Ok(None);
+            }
+        }
+        write_mops_lock(&lock_path(), &lock)?;
+        if args.frozen {
+            ensure_cached(&cache_dir)?;
+        }
+        download_packages_from_lock(agent, &cache_dir, &pending, algorithm, args.keep_going)
+            .await?;
+        Ok::<_, anyhow::Error>(Some(lock))
+    };
+    let installed = if let Some(deadline) = args.deadline {
+        match tokio::time::timeout(Duration::from_secs(deadline), task).await {
+            Ok(res) => res?,
+            Err(_) => {
+                let list = pending.list();
+                return Err(anyhow!(
+                    "Install did not finish within {deadline}s. {} package(s) still pending:\n  {}",
+                    list.len(),
+                    list.join("\n  ")
+                ));
+            }
+        }
+    } else {
+        task.await?
+    };
+    let Some(lock) = installed else {
+        if crate::utils::json() {
+            crate::utils::print_json(&json!({ "installed": false }))?;
+        }
+        status!(
+            "{:>12} nothing was changed",
+            style("Aborted").yellow().bold()
+        );
+        return Ok(());
+    };
+    status!(
+        "{:>12} dependencies in {}",
+        style("Installed").green().bold(),
+        HumanDuration(start.elapsed())
+    );
+    if args.check_imports {
+        crate::utils::download_moc(&cache_dir).await?;
+        check_imports(&cache_dir)?;
+    }
+    check_gitignore(&cache_dir)?;
+    if crate::utils::json() {
+        crate::utils::print_json(&json!({
+            "installed": true,
+            "elapsed_ms": start.elapsed().as_millis() as u64,
+            "packages": lock.package,
+        }))?;
+    }
+    Ok(())
+}
+
+fn print_plan(plan: &[PlannedPackage]) {
+    let mut total = 0;
+    let mut unknown = false;
+    let mut downloads = 0;
+    for pkg in plan {
+        let version = pkg.version.as_deref().unwrap_or("?");
+        match &pkg.action {
+            PlanAction::Cached => status!("{:>12} {}@{version}", style("Cached").dim(), pkg.name),
+            PlanAction::Local => status!(
+                "{:>12} {}@{version} at {}",
+                style("Local").dim(),
+                pkg.name,
+                pkg.path.display()
+            ),
+            PlanAction::Download { from, size } => {
+                downloads += 1;
+                let size = match size {
+                    Some(size) => {
+                        total += size;
+                        HumanBytes(*size).to_string()
+                    }
+                    None => {
+                        unknown = true;
+                        "unknown size".to_string()
+                    }
+                };
+                status!(
+                    "{:>12} {}@{version} from {from} ({size})\n{:>12} {}",
+                    style("Download").cyan().bold(),
+                    pkg.name,
+                    style("Create").cyan(),
+                    pkg.path.display()
+                );
+            }
+        }
+    }
+    let estimate = if unknown { "at least " } else { "" };
+    status!(
+        "\n{:>12} {downloads} package(s) to download, {estimate}{}. Nothing will be removed.",
+        style("Plan").bold(),
+        HumanBytes(total)
+    );
+}
@@ -0,0 +1,111 @@
+use crate::config::Config;
+use anyhow::{anyhow, Result};
+use sha2::Digest;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use walkdir::WalkDir;
+
+/// Hash algorithm used for package integrity. Hashes are recorded as `<algorithm>:<hex>`, so entries
+/// produced with different algorithms can live in the same mops.lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(config
+            .get_parsed::<HashAlgorithm>("hash_algorithm")?
+            .unwrap_or_default())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(anyhow!(
+                "unknown hash algorithm {s}, expected sha256 or blake3"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+            HashAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(h) => hex::encode(h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Checks `dir/sub` against a recorded `<algorithm>:<hex>` hash, using the algorithm it was
+/// recorded with.
+pub fn verify_dir(dir: &Path, sub: &str, expected: &str) -> Result<bool> {
+    let (algorithm, _) = expected
+        .split_once(':')
+        .ok_or_else(|| anyhow!("invalid integrity {expected}"))?;
+    Ok(hash_dir(dir, sub, algorithm.parse()?)? == expected)
+}
+
+/// Hashes every file under `dir/sub` (skipping DONE markers), in path order. Each file contributes
+/// its path relative to `dir` and its contents, so renames and edits both change the result.
+///
+/// The hash is trust-on-first-use: it is taken from the first download, so it detects later
+/// changes to a package, not a package that was already bad when it was first installed.
+pub fn hash_dir(dir: &Path, sub: &str, algorithm: HashAlgorithm) -> Result<String> {
+    let mut hasher = Hasher::new(algorithm);
+    let root = dir.join(sub);
+    // A package without files has no directory of its own.
+    if !root.exists() {
+        return Ok(format!("{algorithm}:{}", hasher.finalize()));
+    }
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() || entry.file_name().to_string_lossy().starts_with("DONE") {
+            continue;
+        }
+        let rel = entry.path().strip_prefix(dir)?;
+        let content = fs::read(entry.path())?;
+        hasher.update(rel.to_string_lossy().replace('\\', "/").as_bytes());
+        hasher.update(&[0]);
+        hasher.update(&(content.len() as u64).to_le_bytes());
+        hasher.update(&content);
+    }
+    Ok(format!("{algorithm}:{}", hasher.finalize()))
+}
@@ -0,0 +1,541 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+/// Prints a status line like `println!`, on stderr when `--format json` keeps stdout for the
+/// result.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::utils::json() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+pub mod add;
+pub mod analyze;
+pub mod bench;
+pub mod bindings;
+mod bitbucket;
+pub mod build;
+pub mod bundle;
+pub mod cache;
+pub mod clean;
+mod coalesce;
+mod codeberg;
+pub mod config;
+pub mod credentials;
+pub mod daemon;
+mod dfx;
+pub mod docs;
+pub mod exec;
+mod git;
+mod git_host;
+pub mod github;
+mod gitignore;
+mod gitlab;
+pub mod graph;
+pub mod history;
+pub mod identity;
+pub mod import_map;
+mod imports;
+pub mod install;
+mod integrity;
+pub mod lint;
+pub mod lock;
+mod metadata;
+pub mod migrate;
+mod mirrors;
+mod npm;
+use bindings::mops;
+pub mod pack;
+pub mod publish;
+pub mod registry;
+mod retry;
+pub mod script;
+pub mod self_update;
+pub mod sources;
+pub mod status;
+pub mod storage;
+mod store;
+pub mod test;
+mod tls;
+mod toml;
+pub mod tree;
+pub mod update;
+pub mod upgrade;
+pub mod utils;
+pub mod vendor;
+mod workspace;
+
+#[derive(Parser)]
+pub struct BuildArg {
+    /// The path to the main Motoko file. Defaults to every Motoko canister in dfx.json, or else main.mo, src/main.mo or <baseDir>/main.mo
+    pub main: Option<PathBuf>,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[arg(short, long)]
+    /// Output Wasm file path. Defaults to <target-dir>/<main>.wasm
+    pub output: Option<String>,
+    #[arg(long, default_value = ".mops/target")]
+    /// Directory for the compiled .wasm and .did files
+    pub target_dir: PathBuf,
+    #[arg(long)]
+    /// Lock the dependencies
+    pub lock: bool,
+    #[arg(long, conflicts_with = "lock")]
+    /// Fail if mops.toml or mops.lock would change
+    pub locked: bool,
+    #[arg(long, conflicts_with = "lock")]
+    /// Like --locked, and also fail if a package needs to be downloaded
+    pub frozen: bool,
+    #[command(flatten)]
+    pub network: NetworkArg,
+    #[clap(last = true)]
+    /// Extra arguments passed to moc. Default args are "--release --idl --stable-types --public-metadata candid:service". When extra arguments are provided, the default args are not included.
+    pub extra_args: Vec<String>,
+}
+
+#[derive(Parser)]
+pub struct AddArg {
+    /// The package to add, as <package> or <package>@<version>
+    pub package: String,
+    #[arg(long)]
+    /// Add it to [dev-dependencies]
+    pub dev: bool,
+    #[arg(short, long)]
+    /// Add it without asking, even when it is over --heavy-threshold
+    pub yes: bool,
+    #[arg(long, value_name = "BYTES")]
+    /// Ask before adding a package whose new dependencies are larger than this
+    pub heavy_threshold: Option<u64>,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct InstallArg {
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[arg(long, value_name = "SECS")]
+    /// Abort the whole install after this many seconds, reporting the packages still pending
+    pub deadline: Option<u64>,
+    #[arg(long)]
+    /// Use the dependency versions a package's author published in its mops.lock, when available
+    pub use_author_lock: bool,
+    #[arg(long)]
+    /// Print what will be downloaded and created, and ask for confirmation before changing anything
+    pub plan: bool,
+    #[arg(short, long, requires = "plan")]
+    /// Apply the plan without asking
+    pub yes: bool,
+    #[arg(long)]
+    /// Fail when a dependency requires a newer moc than the [toolchain] pin, instead of warning
+    pub strict: bool,
+    #[arg(long)]
+    /// Set mops as the packtool in dfx.json
+    pub configure_dfx: bool,
+    #[arg(long)]
+    /// After installing, check that every mo: import of the project resolves to a file
+    pub check_imports: bool,
+    #[arg(long)]
+    /// Fail if mops.lock would change
+    pub locked: bool,
+    #[arg(long)]
+    /// Like --locked, and also fail if a package needs to be downloaded
+    pub frozen: bool,
+    #[arg(long, conflicts_with = "fail_fast")]
+    /// When a download fails, finish the others and report every failure at the end
+    pub keep_going: bool,
+    #[arg(long)]
+    /// Stop at the first failed download (the default)
+    pub fail_fast: bool,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct NetworkArg {
+    #[arg(long, value_name = "SECS")]
+    /// Timeout for each request to the mops registry and storage canisters [default: 60]
+    pub registry_timeout: Option<u64>,
+    #[arg(long, value_name = "SECS")]
+    /// Timeout for each request to GitHub [default: 60]
+    pub github_timeout: Option<u64>,
+    #[arg(long)]
+    /// The dfx network the registry is on, as defined in dfx.json or networks.json [default: ic]
+    pub network: Option<String>,
+    #[arg(long, value_name = "PRINCIPAL")]
+    /// The canister of the mops registry, e.g. a staging or local deployment
+    pub registry_canister: Option<String>,
+    #[arg(long, value_name = "URL")]
+    /// The replica URL to reach the registry at, instead of the one of --network
+    pub registry_url: Option<String>,
+    #[arg(long)]
+    /// Only use mops.lock and the cache. Anything that needs the network fails instead
+    pub offline: bool,
+    #[arg(long)]
+    /// Ask the registry again instead of using cached package metadata, and refresh the cache
+    pub no_cache: bool,
+    #[arg(short, long, value_name = "N")]
+    /// Packages downloaded at the same time [default: 8]
+    pub jobs: Option<usize>,
+}
+impl NetworkArg {
+    pub fn flags(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            (
+                "registry_timeout",
+                self.registry_timeout.map(|t| t.to_string()),
+            ),
+            ("github_timeout", self.github_timeout.map(|t| t.to_string())),
+            ("network", self.network.clone()),
+            ("registry_canister", self.registry_canister.clone()),
+            ("registry_url", self.registry_url.clone()),
+            ("offline", self.offline.then(|| "true".to_string())),
+            ("no_cache", self.no_cache.then(|| "true".to_string())),
+            ("jobs", self.jobs.map(|j| j.to_string())),
+        ]
+    }
+}
+pub fn cache_dir_flag(dir: &Option<PathBuf>) -> (&'static str, Option<String>) {
+    ("cache_dir", dir.as_ref().map(|d| d.display().to_string()))
+}
+#[derive(Parser)]
+pub struct WhoamiArg {
+    #[arg(long)]
+    /// Path to a PEM file, or the name of a dfx identity
+    pub identity: Option<String>,
+}
+#[derive(Parser)]
+pub struct MirrorArg {
+    #[arg(long, value_name = "PRINCIPAL")]
+    /// The canister of the registry to publish to
+    pub to: String,
+    #[arg(long, value_name = "URL")]
+    /// The replica the registry to publish to is on [default: the one of the source registry]
+    pub to_url: Option<String>,
+    #[arg(long)]
+    /// List the packages that would be published, without publishing
+    pub dry_run: bool,
+    #[arg(long)]
+    /// Path to a PEM file, or the name of a dfx identity, allowed to publish to the registry
+    pub identity: Option<String>,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+}
+#[derive(Parser)]
+pub struct SearchArg {
+    /// Text to search for in package names, descriptions and keywords
+    pub query: String,
+    #[arg(long, default_value_t = 10)]
+    /// Maximum number of packages to show
+    pub limit: u64,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct TreeArg {
+    #[arg(long, value_enum, default_value_t)]
+    /// What to show on each dependency edge
+    pub edges: tree::EdgeDisplay,
+}
+#[derive(Parser)]
+pub struct DocsArg {
+    /// Generate docs for an installed dependency instead of the project
+    pub package: Option<String>,
+    #[arg(short, long, default_value = "docs")]
+    /// Output directory
+    pub output: PathBuf,
+    #[arg(long, value_enum, default_value_t)]
+    /// Format of the generated docs
+    pub doc_format: docs::DocFormat,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+}
+#[derive(Parser)]
+pub struct ImportMapArg {
+    #[arg(long)]
+    /// URL the package directory is served from. Without it, the map points to the local cache.
+    pub base_url: Option<String>,
+    #[arg(short, long)]
+    /// Write the import map to this file instead of stdout
+    pub output: Option<PathBuf>,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+}
+#[derive(Parser)]
+pub struct TestArg {
+    /// Only run test files whose path contains this string
+    pub filter: Option<String>,
+    #[arg(long, value_enum, default_value_t)]
+    /// How to run the tests
+    pub mode: test::TestMode,
+    #[arg(long)]
+    /// Run the tests of every workspace member in parallel
+    pub workspace: bool,
+    #[arg(long, requires = "workspace")]
+    /// Stop the other members as soon as one fails
+    pub fail_fast: bool,
+    #[arg(long, hide = true)]
+    /// Don't install the packages, `mops test --workspace` did before starting the members
+    pub no_install: bool,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct UpgradeArg {
+    /// Only upgrade these dependencies
+    pub packages: Vec<String>,
+    #[arg(long, alias = "major")]
+    /// Also upgrade to versions outside the compatible range of the current version
+    pub incompatible: bool,
+    #[arg(long)]
+    /// Only print the upgrades
+    pub dry_run: bool,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct UpdateArg {
+    #[arg(long, required = true)]
+    /// Move repository dependencies declared with `branch` to the newest commit of the branch
+    pub git: bool,
+    #[arg(long)]
+    /// Only print the updates
+    pub dry_run: bool,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct LintArg {
+    #[arg(short = 'A', long, value_name = "RULE")]
+    /// Allow a lint rule
+    pub allow: Vec<String>,
+    #[arg(short = 'W', long, value_name = "RULE")]
+    /// Report a lint rule as a warning
+    pub warn: Vec<String>,
+    #[arg(short = 'D', long, value_name = "RULE")]
+    /// Report a lint rule as an error
+    pub deny: Vec<String>,
+}
+#[derive(Parser)]
+pub struct PackArg {
+    #[arg(short, long)]
+    /// Output tarball path. Defaults to {name}-{version}.tgz
+    pub output: Option<PathBuf>,
+    #[arg(long)]
+    /// Only list the files that would be packed
+    pub list: bool,
+}
+#[derive(Parser)]
+pub struct PublishArg {
+    #[arg(long)]
+    /// Discard the progress of an interrupted publish and start over
+    pub restart: bool,
+    #[arg(long)]
+    /// Publish mops.lock with the package, so consumers can opt into the tested versions with `install --use-author-lock`
+    pub embed_lock: bool,
+    #[arg(long)]
+    /// Path to a PEM file, or the name of a dfx identity
+    pub identity: Option<String>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct AnalyzeArg {
+    /// The path to the main Motoko file. Defaults to every Motoko canister in dfx.json, or else main.mo, src/main.mo or <baseDir>/main.mo
+    pub main: Option<PathBuf>,
+    #[arg(long)]
+    /// Also estimate the instructions each dependency contributes, and check the module against the IC size limits
+    pub budget: bool,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct BenchArg {
+    /// Only run benchmarks whose path contains this string
+    pub filter: Option<String>,
+    #[arg(long, value_name = "NAME")]
+    /// Save the results as a named baseline
+    pub save: Option<String>,
+    #[arg(long, value_name = "NAME")]
+    /// Compare the results against a saved baseline
+    pub compare: Option<String>,
+    #[arg(long)]
+    /// Write an SVG flamegraph of the instructions spent in each function to .mops/bench
+    pub flamegraph: bool,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct CleanArg {
+    #[arg(long)]
+    /// Keep mops.lock
+    pub keep_lock: bool,
+    #[arg(long)]
+    /// Also remove the packages referenced by mops.lock from the global cache
+    pub global: bool,
+    #[arg(long, default_value = ".mops/target")]
+    /// Directory of the build outputs
+    pub target_dir: PathBuf,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+}
+#[derive(Parser)]
+pub struct ExecArg {
+    #[arg(long)]
+    /// Only export the flags as MOPS_MOC_ARGS instead of also appending them
+    pub no_append: bool,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[clap(last = true, required = true)]
+    /// The command to run and its arguments
+    pub command: Vec<String>,
+}
+#[derive(Parser)]
+pub struct ScriptArg {
+    /// The Motoko file to run. Dependencies go in a leading /* mops.toml ... */ comment
+    pub file: PathBuf,
+    #[arg(long, value_enum, default_value_t)]
+    /// How to run the script
+    pub mode: test::TestMode,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct RollbackArg {
+    #[arg(default_value_t = 1)]
+    /// How many generations to go back
+    pub n: usize,
+    #[arg(long)]
+    /// List the recorded generations instead
+    pub list: bool,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct StatusArg {
+    #[arg(long)]
+    /// Print the state of each dependency as JSON, with its line in mops.toml
+    pub json: bool,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct MigrateVesselArg {
+    #[arg(long)]
+    /// Only print the dependencies that would be added to mops.toml
+    pub dry_run: bool,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct VendorArg {
+    #[arg(long, default_value = "vendor")]
+    /// Directory to copy the packages to, recorded in mops.lock
+    pub dir: PathBuf,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct BundleArg {
+    #[arg(short, long, default_value = "bundle.tar.gz")]
+    /// Archive to write
+    pub output: PathBuf,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub network: NetworkArg,
+}
+#[derive(Parser)]
+pub struct SourcesArg {
+    #[arg(long)]
+    /// Print paths relative to the current directory
+    pub relative: bool,
+    #[arg(long)]
+    /// Print the packages and their paths as JSON
+    pub json: bool,
+    #[arg(short, long)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+}
+#[derive(Parser)]
+pub struct CacheArg {
+    #[command(subcommand)]
+    pub command: CacheCommand,
+    #[arg(short, long, global = true)]
+    /// Directory to store external dependencies
+    pub cache_dir: Option<PathBuf>,
+}
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Print the cache directory
+    Dir,
+    /// Print the directory of a cached package, given as <package>@<version> or a name from mops.lock
+    Path { package: String },
+    /// List cached packages with their size and when they were last used
+    Ls {
+        #[arg(long)]
+        /// Print as JSON
+        json: bool,
+    },
+    /// Print the total size of the cache
+    Size,
+    /// Remove cached packages that no known project's mops.lock uses
+    Gc {
+        #[arg(long)]
+        /// Only list what would be removed
+        dry_run: bool,
+    },
+    /// Check cached packages against the integrity hashes in mops.lock files
+    Verify,
+}
@@ -0,0 +1,213 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use console::style;
+use semver::Version;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use toml_edit::ImDocument;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// Every lint rule with its default level.
+const RULES: &[(&str, Level, &str)] = &[
+    (
+        "missing-description",
+        Level::Warn,
+        "package.description is required for publishing",
+    ),
+    (
+        "missing-license",
+        Level::Warn,
+        "package.license is required for publishing",
+    ),
+    (
+        "floating-version",
+        Level::Warn,
+        "dependency versions should be exact",
+    ),
+    (
+        "git-without-tag",
+        Level::Warn,
+        "git dependencies should be pinned to a tag or commit",
+    ),
+    (
+        "local-path",
+        Level::Warn,
+        "local path dependencies don't work on other machines",
+    ),
+    ("duplicate-key", Level::Deny, "keys must be unique"),
+];
+
+struct Finding {
+    rule: &'static str,
+    message: String,
+}
+
+pub fn lint(args: crate::LintArg) -> Result<()> {
+    let path = Path::new("mops.toml");
+    let str = fs::read_to_string(path).context("Cannot find mops.toml")?;
+    // Duplicate keys make the file unparsable, so the other rules only run on a valid file.
+    let findings = match parse_manifest(&str)? {
+        Ok(doc) => check_manifest(&doc),
+        Err(duplicate) => vec![duplicate],
+    };
+    let levels = get_levels(&str, &args)?;
+    let mut denied = 0;
+    let mut warned = 0;
+    let mut reported = Vec::new();
+    for f in &findings {
+        let level = levels[f.rule];
+        let label = match level {
+            Level::Allow => continue,
+            Level::Warn => {
+                warned += 1;
+                style("warning").yellow().bold()
+            }
+            Level::Deny => {
+                denied += 1;
+                style("error").red().bold()
+            }
+        };
+        let note = RULES.iter().find(|(r, _, _)| *r == f.rule).unwrap().2;
+        if crate::utils::json() {
+            reported.push(serde_json::json!({
+                "rule": f.rule,
+                "level": if matches!(level, Level::Deny) { "error" } else { "warning" },
+                "message": f.message,
+                "note": note,
+            }));
+            continue;
+        }
+        println!("{label}[{}]: {}", f.rule, f.message);
+        println!("  {} {note}", style("=").dim());
+    }
+    if crate::utils::json() {
+        crate::utils::print_json(&reported)?;
+    }
+    if denied > 0 {
+        return Err(anyhow!("{denied} error(s), {warned} warning(s)"));
+    }
+    if warned == 0 {
+        status!("{:>12} mops.toml", style("Linted").green().bold());
+    }
+    Ok(())
+}
+
+/// Levels from the defaults, overridden by the `[lint]` table in mops.toml, then by command-line flags.
+fn get_levels(str: &str, args: &crate::LintArg) -> Result<BTreeMap<&'static str, Level>> {
+    let mut levels: BTreeMap<_, _> = RULES.iter().map(|(r, l, _)| (*r, *l)).collect();
+    let find_rule = |name: &str| {
+        RULES
+            .iter()
+            .map(|(r, _, _)| *r)
+            .find(|r| *r == name)
+            .ok_or_else(|| anyhow!("Unknown lint rule {name}"))
+    };
+    if let Ok(doc) = str.parse::<ImDocument<_>>() {
+        if let Some(table) = doc.get("lint").and_then(|t| t.as_table()) {
+            for (rule, level) in table.iter() {
+                let level = level
+                    .as_str()
+                    .ok_or_else(|| anyhow!("lint.{rule} must be a string"))?;
+                let level = Level::from_str(level, true).map_err(|e| anyhow!(e))?;
+                levels.insert(find_rule(rule)?, level);
+            }
+        }
+    }
+    for (rules, level) in [
+        (&args.allow, Level::Allow),
+        (&args.warn, Level::Warn),
+        (&args.deny, Level::Deny),
+    ] {
+        for rule in rules {
+            levels.insert(find_rule(rule)?, level);
+        }
+    }
+    Ok(levels)
+}
+
+fn check_manifest(doc: &ImDocument<String>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    if let Some(pkg) = doc.get("package") {
+        if pkg.get("description").is_none() {
+            findings.push(Finding {
+                rule: "missing-description",
+                message: "package.description is missing".to_string(),
+            });
+        }
+        if pkg.get("license").is_none() {
+            findings.push(Finding {
+                rule: "missing-license",
+                message: "package.license is missing".to_string(),
+            });
+        }
+    }
+    for table in ["dependencies", "dev-dependencies"] {
+        let Some(deps) = doc.get(table).and_then(|t| t.as_table()) else {
+            continue;
+        };
+        for (name, value) in deps.iter() {
+            // `{ path = "..." }` and `{ package = "...", version = "..." }`
+            let spec = value.as_inline_table();
+            let path = spec.and_then(|t| t.get("path")).and_then(|v| v.as_str());
+            let version = spec.and_then(|t| t.get("version")).and_then(|v| v.as_str());
+            let Some(value) = path
+                .map(|p| format!("path:{p}"))
+                .or(version.map(String::from))
+                .or(value.as_str().map(String::from))
+            else {
+                continue;
+            };
+            let value = value.as_str();
+            if value.starts_with("https://") || value.starts_with("git@") {
+                if !value.contains('#') {
+                    findings.push(Finding {
+                        rule: "git-without-tag",
+                        message: format!("{table}.{name} = {value:?} tracks the default branch"),
+                    });
+                }
+            } else if value.starts_with("path:") || Path::new(value).exists() {
+                let path = value.strip_prefix("path:").unwrap_or(value);
+                findings.push(Finding {
+                    rule: "local-path",
+                    message: format!("{table}.{name} points to local path {path:?}"),
+                });
+            } else if value.parse::<Version>().is_err() {
+                findings.push(Finding {
+                    rule: "floating-version",
+                    message: format!("{table}.{name} = {value:?} is not an exact version"),
+                });
+            }
+        }
+    }
+    findings
+}
+
+/// Parses mops.toml. The parser stops at the first duplicate key or table, which is reported
+/// as a finding with its line, while other syntax errors fail the lint.
+fn parse_manifest(str: &str) -> Result<std::result::Result<ImDocument<String>, Finding>> {
+    let err = match str.parse::<ImDocument<_>>() {
+        Ok(doc) => return Ok(Ok(doc)),
+        Err(err) => err,
+    };
+    let Some(message) = err
+        .message()
+        .lines()
+        .find(|line| line.starts_with("duplicate key"))
+    else {
+        return Err(anyhow!("Cannot parse mops.toml: {err}"));
+    };
+    let line = err
+        .span()
+        .map_or(0, |span| str[..span.start].matches('\n').count() + 1);
+    Ok(Err(Finding {
+        rule: "duplicate-key",
+        message: format!("line {line}: {message}"),
+    }))
+}
@@ -0,0 +1,243 @@
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::toml::{
+    download_packages_from_lock, has_conflict_markers, load_mops_lock_or_default, merge_locks,
+    parse_mops_lock, parse_mops_lock_str, resolve_mops_lock, write_mops_lock, Package, PackageType,
+    Packages, ResolveOptions,
+};
+use crate::utils::{get_cache_dir, Pending};
+use crate::workspace::lock_path;
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use ic_agent::Agent;
+use semver::Version;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Change {
+    Added,
+    Removed,
+    Upgraded,
+    Downgraded,
+    /// Same version, different source
+    Changed,
+}
+
+#[derive(Serialize)]
+struct Locked {
+    version: Option<String>,
+    source: String,
+}
+impl From<&Package> for Locked {
+    fn from(pkg: &Package) -> Self {
+        let source = match pkg.get_type() {
+            PackageType::Mops { .. } => match &pkg.package {
+                Some(package) => format!("mops:{package}"),
+                None => "mops".to_string(),
+            },
+            PackageType::Repo(repo) => format!("{}@{}", repo.web_path(), repo.short_commit()),
+            PackageType::Local(path) => path.to_string(),
+        };
+        Locked {
+            version: pkg.version.clone(),
+            source,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    change: Change,
+    old: Option<Locked>,
+    new: Option<Locked>,
+}
+
+/// Compares mops.lock against `against`, a lock file or a git revision (HEAD by default).
+pub fn diff(against: Option<String>) -> Result<()> {
+    let against = against.unwrap_or_else(|| "HEAD".to_string());
+    let old = if Path::new(&against).is_file() {
+        parse_mops_lock(Path::new(&against))?
+    } else {
+        read_from_git(&against)?
+    };
+    let new = load_mops_lock_or_default()?;
+    let entries = compare(&old, &new);
+    if crate::utils::json() {
+        crate::utils::print_json(&entries)?;
+    } else {
+        if entries.is_empty() {
+            println!("No changes to mops.lock since {against}");
+        }
+        for e in &entries {
+            print_entry(e);
+        }
+    }
+    Ok(())
+}
+
+/// mops.lock of the current directory at a git revision. A revision without one is an empty lock.
+fn read_from_git(rev: &str) -> Result<Packages> {
+    let lock = lock_path();
+    let dir = lock.parent().filter(|p| !p.as_os_str().is_empty());
+    let output = Command::new("git")
+        .args(["show", &format!("{rev}:./mops.lock")])
+        .current_dir(dir.unwrap_or(Path::new(".")))
+        .output()
+        .context("Failed to run git")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("exists on disk, but not in") || stderr.contains("does not exist in") {
+            return Ok(Packages::default());
+        }
+        return Err(anyhow!(
+            "{rev} is neither a file nor a git revision with a mops.lock:\n{}",
+            stderr.trim()
+        ));
+    }
+    parse_mops_lock_str(&String::from_utf8(output.stdout)?)
+}
+
+fn compare(old: &Packages, new: &Packages) -> Vec<Entry> {
+    let old: BTreeMap<_, _> = old.package.iter().map(|p| (&p.name, p)).collect();
+    let new: BTreeMap<_, _> = new.package.iter().map(|p| (&p.name, p)).collect();
+    let mut names: Vec<_> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    let mut res = Vec::new();
+    for name in names {
+        let (old, new) = (old.get(name), new.get(name));
+        let change = match (old, new) {
+            (None, Some(_)) => Change::Added,
+            (Some(_), None) => Change::Removed,
+            (Some(old), Some(new)) => {
+                let parse =
+                    |p: &Package| p.version.as_deref().and_then(|v| v.parse::<Version>().ok());
+                match (parse(old), parse(new)) {
+                    (Some(o), Some(n)) if o < n => Change::Upgraded,
+                    (Some(o), Some(n)) if o > n => Change::Downgraded,
+                    _ if old.version != new.version => Change::Changed,
+                    _ if Locked::from(*old).source != Locked::from(*new).source => Change::Changed,
+                    _ => continue,
+                }
+            }
+            (None, None) => unreachable!(),
+        };
+        res.push(Entry {
+            name: name.to_string(),
+            change,
+            old: old.map(|p| Locked::from(*p)),
+            new: new.map(|p| Locked::from(*p)),
+        });
+    }
+    res
+}
+
+fn print_entry(e: &Entry) {
+    let show = |l: &Locked| format!("{} ({})", l.version.as_deref().unwrap_or("?"), l.source);
+    match (&e.old, &e.new) {
+        (None, Some(new)) => status!(
+            "{:>12} {} {}",
+            style("Added").green().bold(),
+            e.name,
+            show(new)
+        ),
+        (Some(old), None) => status!(
+            "{:>12} {} {}",
+            style("Removed").red().bold(),
+            e.name,
+            show(old)
+        ),
+        (Some(old), Some(new)) => {
+            let verb = match e.change {
+                Change::Upgraded => "Upgraded",
+                Change::Downgraded => "Downgraded",
+                _ => "Changed",
+            };
+            let versions = |l: &Locked| l.version.clone().unwrap_or_else(|| "?".to_string());
+            let change = if old.source == new.source {
+                format!("{} -> {} ({})", versions(old), versions(new), new.source)
+            } else {
+                format!("{} -> {}", show(old), show(new))
+            };
+            status!("{:>12} {} {change}", style(verb).yellow().bold(), e.name);
+        }
+        (None, None) => unreachable!(),
+    }
+}
+
+/// Regenerates a mops.lock that has merge conflicts. The versions pinned on either side are kept
+/// as long as mops.toml still allows them; the rest is resolved again.
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn resolve(agent: &Agent, config: &Config) -> Result<()> {
+    let path = &lock_path();
+    let str = fs::read_to_string(path)?;
+    if !has_conflict_markers(&str) {
+        status!("mops.lock has no merge conflicts");
+        return Ok(());
+    }
+    let (ours, theirs) = split_conflict(&str);
+    // A side that doesn't parse on its own just contributes no pins.
+    let ours = parse_mops_lock_str(&ours).unwrap_or_default();
+    let theirs = parse_mops_lock_str(&theirs).unwrap_or_default();
+    let pins = merge_locks(ours, theirs);
+    let kept = pins.package.len();
+    // The conflicted file stays until the resolution succeeded.
+    let options = ResolveOptions {
+        pins: Some(pins),
+        ..ResolveOptions::from_config(config)?
+    };
+    let pending = Pending::default();
+    let lock = resolve_mops_lock(agent, &pending, &options).await?;
+    write_mops_lock(path, &lock)?;
+    let cache_dir = get_cache_dir(config)?;
+    let algorithm = HashAlgorithm::from_config(config)?;
+    download_packages_from_lock(agent, &cache_dir, &pending, algorithm, false).await?;
+    status!(
+        "{:>12} mops.lock from mops.toml, starting from {kept} pin(s) of both sides",
+        style("Resolved").green().bold()
+    );
+    Ok(())
+}
+
+/// Both sides of every conflict in `str`, as the whole file from our and their point of view.
+/// The common ancestor section of diff3-style conflicts is dropped.
+fn split_conflict(str: &str) -> (String, String) {
+    enum Side {
+        Both,
+        Ours,
+        Base,
+        Theirs,
+    }
+    let mut side = Side::Both;
+    let (mut ours, mut theirs) = (String::new(), String::new());
+    for line in str.lines() {
+        match side {
+            _ if line.starts_with("<<<<<<< ") => side = Side::Ours,
+            Side::Ours if line.starts_with("||||||| ") => side = Side::Base,
+            Side::Ours | Side::Base if line == "=======" => side = Side::Theirs,
+            Side::Theirs if line.starts_with(">>>>>>> ") => side = Side::Both,
+            Side::Both => {
+                ours.push_str(line);
+                ours.push('\n');
+                theirs.push_str(line);
+                theirs.push('\n');
+            }
+            Side::Ours => {
+                ours.push_str(line);
+                ours.push('\n');
+            }
+            Side::Base => (),
+            Side::Theirs => {
+                theirs.push_str(line);
+                theirs.push('\n');
+            }
+        }
+    }
+    (ours, theirs)
+}
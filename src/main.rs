@@ -1,20 +1,169 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use mops_cli::config::{self, Config};
+use mops_cli::*;
 use std::path::PathBuf;
 
-mod build;
-mod github;
-mod mops;
-mod storage;
-mod toml;
-mod utils;
-
 #[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: ClapCommand,
+    #[arg(long, global = true, value_enum, default_value_t)]
+    /// Output format. With json, the result is printed as JSON on stdout and progress on stderr
+    format: OutputFormat,
+}
+#[derive(Subcommand)]
 enum ClapCommand {
+    /// Add a registry package to mops.toml and install it
+    Add(AddArg),
+    /// Experimental: compile the project and show how much code each dependency contributes
+    Analyze(AnalyzeArg),
+    /// Store tokens for private repository dependencies in the OS keychain
+    #[command(subcommand)]
+    Auth(AuthCommand),
+    /// Run the bench/*.mo benchmarks and report instruction counts and heap usage
+    Bench(BenchArg),
     /// Build Motoko project
     Build(BuildArg),
+    /// Archive the project with its locked packages and toolchain pins for an offline rebuild
+    Bundle(BundleArg),
+    /// Inspect the package cache
+    Cache(CacheArg),
+    /// Remove build outputs, generated files and mops.lock
+    Clean(CleanArg),
+    /// Inspect the effective configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Keep parsed manifests in a background process for fast repeated queries
+    #[command(subcommand)]
+    Daemon(DaemonCommand),
+    /// Generate documentation with mo-doc
+    Docs(DocsArg),
+    /// Run a command with the package flags of the project
+    Exec(ExecArg),
+    /// Print a JSON import map of the locked packages for web tooling
+    ImportMap(ImportMapArg),
+    /// Update mops.lock and download the dependencies
+    Install(InstallArg),
+    /// Check mops.toml for common mistakes
+    Lint(LintArg),
+    /// Inspect mops.lock
+    #[command(subcommand)]
+    Lock(LockCommand),
+    /// Convert a project of another package manager to mops.toml
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Republish the registry packages of mops.lock to another registry, e.g. an internal mirror
+    Mirror(MirrorArg),
     /// Calls the Motoko compiler
     Moc(MocArg),
+    /// Pack the current package into a tarball, honoring .mopsignore
+    Pack(PackArg),
+    /// Publish the current package to the registry, resuming an interrupted upload
+    Publish(PublishArg),
+    /// Restore an earlier generation of mops.lock from .mops/history and install it
+    Rollback(RollbackArg),
+    /// Run a single-file Motoko script with the dependencies declared in its header
+    Script(ScriptArg),
+    /// Search the mops registry
+    Search(SearchArg),
+    /// Manage the mops-cli installation
+    #[command(name = "self", subcommand)]
+    SelfCmd(SelfCommand),
+    /// Print the moc package flags, for use as the dfx packtool
+    Sources(SourcesArg),
+    /// Show whether each dependency is installed, intact and up to date
+    Status(StatusArg),
+    /// Run the *.test.mo files of the project
+    Test(TestArg),
+    /// Print the dependency tree from mops.lock
+    Tree(TreeArg),
+    /// Refresh the locked commits of branch-tracking git dependencies
+    Update(UpdateArg),
+    /// Upgrade dependencies in mops.toml to the newest compatible versions
+    Upgrade(UpgradeArg),
+    /// Copy the locked packages into the project, for builds without network or cache
+    Vendor(VendorArg),
+    /// Print the principal used to talk to the registry, and whether it owns the current package
+    Whoami(WhoamiArg),
+}
+#[derive(Subcommand)]
+enum AuthCommand {
+    /// Store a GitHub token, read from the terminal or stdin
+    Github {
+        #[arg(long)]
+        /// A GitHub Enterprise Server instead of github.com
+        host: Option<String>,
+        #[arg(long)]
+        /// Remove the stored token instead
+        logout: bool,
+    },
+}
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// List every configuration value and where it came from
+    List {
+        #[arg(long)]
+        /// Print as JSON
+        json: bool,
+    },
+}
+#[derive(Subcommand)]
+enum DaemonCommand {
+    /// Start the daemon for the current project in the background
+    Start {
+        #[arg(short, long)]
+        /// Directory to store external dependencies
+        cache_dir: Option<PathBuf>,
+    },
+    /// Run the daemon in the foreground
+    #[command(hide = true)]
+    Run {
+        #[arg(short, long)]
+        cache_dir: Option<PathBuf>,
+    },
+    /// Stop the daemon of the current project
+    Stop,
+    /// Show whether the daemon is running and how much it has served
+    Status,
+    /// Resolve a mo: import to a file path
+    ResolveImport {
+        /// The import, e.g. mo:base/Array
+        import: String,
+        #[arg(short, long)]
+        /// Directory to store external dependencies
+        cache_dir: Option<PathBuf>,
+    },
+}
+#[derive(Subcommand)]
+enum LockCommand {
+    /// Show the packages added, removed or changed since a git revision or another lock file
+    Diff {
+        /// A git revision or a path to a lock file [default: HEAD]
+        against: Option<String>,
+    },
+    /// Regenerate a mops.lock with merge conflicts from mops.toml, keeping the pins of both sides
+    Resolve {
+        #[arg(short, long)]
+        /// Directory to store external dependencies
+        cache_dir: Option<PathBuf>,
+        #[command(flatten)]
+        network: NetworkArg,
+    },
+}
+#[derive(Subcommand)]
+enum MigrateCommand {
+    /// Write the dependencies of vessel.dhall and package-set.dhall to mops.toml
+    Vessel(MigrateVesselArg),
+}
+#[derive(Subcommand)]
+enum SelfCommand {
+    /// Replace this binary with the latest release, after verifying its checksum
+    Update {
+        #[arg(long)]
+        /// Only report whether a newer release is available
+        check: bool,
+    },
 }
 #[derive(Parser)]
 struct MocArg {
@@ -24,39 +173,222 @@ struct MocArg {
     /// Arguments passed to moc
     extra_args: Vec<String>,
 }
-#[derive(Parser)]
-pub struct BuildArg {
-    /// The path to the main Motoko file
-    pub main: Option<PathBuf>,
-    #[arg(short, long)]
-    /// Directory to store external dependencies
-    pub cache_dir: Option<PathBuf>,
-    #[arg(short, long)]
-    /// Output Wasm file path
-    pub output: Option<String>,
-    #[arg(long)]
-    /// Lock the dependencies
-    pub lock: bool,
-    #[clap(last = true)]
-    /// Extra arguments passed to moc. Default args are "--release --idl --stable-types --public-metadata candid:service". When extra arguments are provided, the default args are not included.
-    extra_args: Vec<String>,
-}
 
 fn main() -> Result<()> {
-    let cmd = ClapCommand::parse();
-    let agent = ic_agent::Agent::builder()
-        .with_url("https://icp0.io")
-        .build()?;
-    match cmd {
+    let cli = Cli::parse();
+    utils::set_json(matches!(cli.format, OutputFormat::Json));
+    match cli.command {
+        ClapCommand::Cache(args) => {
+            let config = Config::load(&[cache_dir_flag(&args.cache_dir)])?;
+            match args.command {
+                CacheCommand::Dir => cache::dir(&config)?,
+                CacheCommand::Path { package } => cache::path(&config, &package)?,
+                CacheCommand::Ls { json } => cache::ls(&config, json || utils::json())?,
+                CacheCommand::Size => cache::size(&config)?,
+                CacheCommand::Gc { dry_run } => cache::gc(&config, dry_run)?,
+                CacheCommand::Verify => cache::verify(&config)?,
+            }
+        }
+        ClapCommand::Clean(args) => {
+            let config = Config::load(&[cache_dir_flag(&args.cache_dir)])?;
+            clean::clean(&config, args)?;
+        }
+        ClapCommand::Config(ConfigCommand::List { json }) => {
+            let config = Config::load(&[])?;
+            config::list(&config, json || utils::json())?;
+        }
+        ClapCommand::Daemon(cmd) => match cmd {
+            DaemonCommand::Start { cache_dir } => {
+                let config = Config::load(&[cache_dir_flag(&cache_dir)])?;
+                daemon::start(&utils::get_cache_dir(&config)?)?;
+            }
+            DaemonCommand::Run { cache_dir } => {
+                let config = Config::load(&[cache_dir_flag(&cache_dir)])?;
+                daemon::run(utils::get_cache_dir(&config)?)?;
+            }
+            DaemonCommand::Stop => daemon::stop()?,
+            DaemonCommand::Status => daemon::query(daemon::Request::Status)?,
+            DaemonCommand::ResolveImport { import, cache_dir } => {
+                let config = Config::load(&[cache_dir_flag(&cache_dir)])?;
+                daemon::print_import(&utils::get_cache_dir(&config)?, import)?;
+            }
+        },
         ClapCommand::Moc(args) => {
-            use crate::utils::{exec, get_cache_dir, get_moc};
-            let cache_dir = get_cache_dir(&args.cache_dir)?;
+            use mops_cli::utils::{exec, get_cache_dir, get_moc};
+            let config = Config::load(&[cache_dir_flag(&args.cache_dir)])?;
+            let cache_dir = get_cache_dir(&config)?;
             let mut moc = get_moc(&cache_dir)?;
             moc.args(&args.extra_args);
             exec(moc, None)?;
         }
+        ClapCommand::Add(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            add::add(&agent, &config, args)?;
+        }
+        ClapCommand::Analyze(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            analyze::analyze(&agent, &config, args)?;
+        }
+        ClapCommand::Auth(AuthCommand::Github { host, logout }) => {
+            credentials::github(host.as_deref(), logout)?;
+        }
+        ClapCommand::Bench(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            bench::bench(&agent, &config, args)?;
+        }
         ClapCommand::Build(args) => {
-            build::build(&agent, args)?;
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            build::build(&agent, &config, args)?;
+        }
+        ClapCommand::Bundle(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            bundle::bundle(&agent, &config, args)?;
+        }
+        ClapCommand::Install(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            install::install(&agent, &config, args)?;
+        }
+        ClapCommand::Docs(args) => {
+            let config = Config::load(&[cache_dir_flag(&args.cache_dir)])?;
+            docs::docs(&config, args)?;
+        }
+        ClapCommand::Exec(args) => {
+            let config = Config::load(&[cache_dir_flag(&args.cache_dir)])?;
+            exec::exec(&config, args)?;
+        }
+        ClapCommand::ImportMap(args) => {
+            let config = Config::load(&[cache_dir_flag(&args.cache_dir)])?;
+            import_map::import_map(&config, args)?;
+        }
+        ClapCommand::Lock(LockCommand::Diff { against }) => {
+            lock::diff(against)?;
+        }
+        ClapCommand::Lock(LockCommand::Resolve { cache_dir, network }) => {
+            let mut flags = network.flags();
+            flags.push(cache_dir_flag(&cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            lock::resolve(&agent, &config)?;
+        }
+        ClapCommand::Lint(args) => {
+            lint::lint(args)?;
+        }
+        ClapCommand::Migrate(MigrateCommand::Vessel(args)) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            migrate::vessel(&agent, &config, args)?;
+        }
+        ClapCommand::Pack(args) => {
+            pack::pack(args)?;
+        }
+        ClapCommand::Publish(args) => {
+            let mut flags = args.network.flags();
+            flags.push(("identity", args.identity.clone()));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            publish::publish(&agent, args)?;
+        }
+        ClapCommand::Rollback(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            history::rollback(&agent, &config, args)?;
+        }
+        ClapCommand::Script(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            script::script(&agent, &config, args)?;
+        }
+        ClapCommand::Search(args) => {
+            let config = Config::load(&args.network.flags())?;
+            let agent = utils::create_agent(&config)?;
+            registry::search(&agent, args)?;
+        }
+        ClapCommand::SelfCmd(SelfCommand::Update { check }) => {
+            let config = Config::load(&[])?;
+            self_update::update(&config, check)?;
+        }
+        ClapCommand::Sources(args) => {
+            let config = Config::load(&[cache_dir_flag(&args.cache_dir)])?;
+            sources::sources(&config, args)?;
+        }
+        ClapCommand::Status(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            status::status(&agent, &config, args)?;
+        }
+        ClapCommand::Test(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            if args.workspace {
+                test::test_workspace(&agent, &config, args)?;
+            } else {
+                test::test(&agent, &config, args)?;
+            }
+        }
+        ClapCommand::Tree(args) => {
+            tree::tree(args)?;
+        }
+        ClapCommand::Update(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            update::update(&agent, &config, args)?;
+        }
+        ClapCommand::Upgrade(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            upgrade::upgrade(&agent, &config, args)?;
+        }
+        ClapCommand::Vendor(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            vendor::vendor(&agent, &config, args)?;
+        }
+        ClapCommand::Mirror(args) => {
+            let mut flags = args.network.flags();
+            flags.push(cache_dir_flag(&args.cache_dir));
+            flags.push(("identity", args.identity.clone()));
+            let config = Config::load(&flags)?;
+            let agent = utils::create_agent(&config)?;
+            registry::mirror(&agent, &config, args)?;
+        }
+        ClapCommand::Whoami(args) => {
+            let config = Config::load(&[("identity", args.identity)])?;
+            let agent = utils::create_agent(&config)?;
+            identity::whoami(&agent, &config)?;
         }
     }
     Ok(())
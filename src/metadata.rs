@@ -0,0 +1,141 @@
+use crate::config::Config;
+use crate::mops::{self, PackageDetails};
+use crate::retry::{is_transient_call, retry};
+use anyhow::{Error, Result};
+use candid::{Decode, Encode, Principal};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+/// Registry metadata of published versions, under the cache root. A published version never
+/// changes, so entries are kept until `--no-cache` replaces them. The highest version of a
+/// package does change, so it is only reused for `metadata_ttl` seconds.
+const METADATA_DIR: &str = "metadata";
+
+struct Settings {
+    dir: Option<PathBuf>,
+    /// Skip reading the cache, but still write fresh responses to it.
+    refresh: bool,
+    /// How long the highest version of a package is reused.
+    ttl: Duration,
+}
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+pub(crate) fn configure(config: &Config) -> Result<()> {
+    let refresh = config.get_parsed::<bool>("no_cache")?.unwrap_or(false);
+    let dir = config
+        .get("cache_dir")
+        .map(|dir| PathBuf::from(dir).join(METADATA_DIR));
+    let ttl = Duration::from_secs(config.get_parsed("metadata_ttl")?.unwrap_or(300));
+    let _ = SETTINGS.set(Settings { dir, refresh, ttl });
+    Ok(())
+}
+
+/// The cache of `registry`. Another registry may publish other packages under the same names.
+fn registry_dir(registry: Principal) -> Option<PathBuf> {
+    let dir = SETTINGS.get()?.dir.as_ref()?;
+    if registry == mops::CANISTER_ID {
+        Some(dir.clone())
+    } else {
+        Some(dir.join(registry.to_text()))
+    }
+}
+
+fn entry(registry: Principal, name: &str, version: &str) -> Option<PathBuf> {
+    Some(
+        registry_dir(registry)?
+            .join(name)
+            .join(format!("{version}.bin")),
+    )
+}
+
+/// `getPackageDetails` of `name@version`, from the cache when possible.
+pub(crate) async fn package_details(
+    service: &mops::Service<'_>,
+    name: &str,
+    version: &str,
+) -> Result<PackageDetails> {
+    Ok(sourced_package_details(service, name, version).await?.0)
+}
+
+/// `getPackageDetails` of `name@version` and the mirror it came from, `None` for the primary
+/// registry. Mirrors are asked when the registries before them fail.
+pub(crate) async fn sourced_package_details(
+    service: &mops::Service<'_>,
+    name: &str,
+    version: &str,
+) -> Result<(PackageDetails, Option<Principal>)> {
+    let refresh = SETTINGS.get().is_some_and(|s| s.refresh);
+    let registries = crate::mirrors::registries(service);
+    for (i, registry) in registries.iter().enumerate() {
+        let Some(entry) = entry(registry.0, name, version).filter(|_| !refresh) else {
+            continue;
+        };
+        // A corrupt or outdated entry is just a miss.
+        if let Some(details) = fs::read(entry)
+            .ok()
+            .and_then(|bytes| Decode!(&bytes, PackageDetails).ok())
+        {
+            return Ok((details, (i > 0).then_some(registry.0)));
+        }
+    }
+    let what = format!("details of {name}@{version}");
+    let (details, mirror) = crate::mirrors::failover(&what, service, None, |registry| {
+        let (name, version, what) = (name.to_string(), version.to_string(), what.as_str());
+        async move {
+            let call = || registry.get_package_details(&name, &version);
+            Ok(retry(what, call, is_transient_call).await?)
+        }
+    })
+    .await?;
+    let details = details.into_result().map_err(|e| {
+        let registry = crate::mirrors::registry(service, mirror);
+        crate::identity::explain_unauthorized(&registry, Error::msg(e))
+    })?;
+    if let Some(entry) = entry(mirror.unwrap_or(service.0), name, version) {
+        // Only a cache: failing to write it doesn't fail the lookup.
+        let _ = fs::create_dir_all(entry.parent().unwrap())
+            .map_err(Error::from)
+            .and_then(|_| Ok(fs::write(&entry, Encode!(&details)?)?));
+    }
+    Ok((details, mirror))
+}
+
+/// `getHighestVersion` of `name`, from the cache when it was looked up less than `metadata_ttl`
+/// seconds ago. Returns the registry's error for unknown packages, which is not cached.
+pub(crate) async fn highest_version(
+    service: &mops::Service<'_>,
+    name: &str,
+) -> Result<std::result::Result<String, String>> {
+    let settings = SETTINGS.get();
+    let entry = registry_dir(service.0).map(|dir| dir.join(name).join("highest"));
+    if let (Some(entry), Some(settings)) = (&entry, settings) {
+        let fresh = fs::metadata(entry)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .is_some_and(|age| age < settings.ttl);
+        if fresh && !settings.refresh {
+            if let Ok(version) = fs::read_to_string(entry) {
+                return Ok(Ok(version));
+            }
+        }
+    }
+    let name = name.to_string();
+    let what = format!("highest version of {name}");
+    let (version, _) = crate::mirrors::failover(&what, service, None, |registry| {
+        let (name, what) = (name.clone(), what.as_str());
+        async move {
+            let call = || registry.get_highest_version(&name);
+            Ok(retry(what, call, is_transient_call).await?)
+        }
+    })
+    .await?;
+    let version = version.into_result();
+    if let (Some(entry), Ok(version)) = (entry, &version) {
+        let _ =
+            fs::create_dir_all(entry.parent().unwrap()).and_then(|_| fs::write(&entry, version));
+    }
+    Ok(version)
+}
@@ -0,0 +1,473 @@
+//! Converts projects of other Motoko package managers to mops.toml.
+use crate::config::Config;
+use crate::mops;
+use crate::toml::{resolve_mops_lock, write_mops_lock, ResolveOptions};
+use crate::utils::{ensure_online, Pending};
+use crate::workspace::lock_path;
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use ic_agent::Agent;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use toml_edit::{value, DocumentMut};
+
+/// A package of a vessel package set.
+struct SetPackage {
+    repo: String,
+    version: String,
+    dependencies: Vec<String>,
+}
+
+/// Writes the dependencies of vessel.dhall to mops.toml and resolves mops.lock. Packages of the
+/// package set, with the sets it imports, are taken from the registry when it has the same
+/// version, and from their repository otherwise. mops.toml is only written once it resolves.
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn vessel(agent: &Agent, config: &Config, args: crate::MigrateVesselArg) -> Result<()> {
+    ensure_online("look up packages in the registry")?;
+    let manifest = parse(&fs::read_to_string("vessel.dhall")?)?;
+    let manifest = records(&manifest)
+        .into_iter()
+        .find(|r| r.contains_key("dependencies"))
+        .ok_or_else(|| anyhow!("vessel.dhall has no dependencies"))?;
+    let set = match fs::read_to_string("package-set.dhall") {
+        Ok(str) => load_package_set(Path::new("."), &str).await?,
+        Err(_) => BTreeMap::new(),
+    };
+    let service = mops::Service(crate::utils::registry_id(), agent);
+    let mut deps = BTreeMap::new();
+    let mut unknown = Vec::new();
+    let mut queue: VecDeque<_> = list(manifest.get("dependencies")).into();
+    let mut seen = BTreeSet::new();
+    while let Some(name) = queue.pop_front() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        let requirement = match set.get(&name) {
+            Some(pkg) => {
+                let version = pkg.version.trim_start_matches('v');
+                let published = crate::metadata::package_details(&service, &name, version);
+                if published.await.is_ok() {
+                    version.to_string()
+                } else if crate::github::is_repo_url(&pkg.repo) {
+                    // Without a mops.toml in the repo, its dependencies have to be direct ones.
+                    queue.extend(pkg.dependencies.iter().cloned());
+                    format!("{}#{}", pkg.repo.trim_end_matches(".git"), pkg.version)
+                } else {
+                    unknown.push(format!(
+                        "{name} ({} is not on a supported git host)",
+                        pkg.repo
+                    ));
+                    continue;
+                }
+            }
+            None => {
+                unknown.push(format!("{name} (not in the package set)"));
+                continue;
+            }
+        };
+        deps.insert(name, requirement);
+    }
+    if !unknown.is_empty() {
+        return Err(anyhow!(
+            "Cannot map these vessel packages:\n  {}",
+            unknown.join("\n  ")
+        ));
+    }
+
+    let path = Path::new("mops.toml");
+    let mut doc = match fs::read_to_string(path) {
+        Ok(str) => str.parse::<DocumentMut>()?,
+        Err(_) => DocumentMut::new(),
+    };
+    if doc.get("dependencies").is_none() {
+        doc["dependencies"] = toml_edit::table();
+    }
+    for (name, requirement) in &deps {
+        if doc["dependencies"].get(name).is_some() {
+            continue;
+        }
+        status!(
+            "{:>12} {name} = {requirement:?}",
+            style(if args.dry_run { "Would add" } else { "Adding" })
+                .green()
+                .bold()
+        );
+        doc["dependencies"][name] = value(requirement);
+    }
+    if let Some(Value::Text(moc)) = manifest.get("compiler") {
+        if doc.get("toolchain").is_none() {
+            status!(
+                "{:>12} toolchain.moc = {moc:?}",
+                style(if args.dry_run { "Would pin" } else { "Pinning" })
+                    .green()
+                    .bold()
+            );
+            doc["toolchain"] = toml_edit::table();
+            doc["toolchain"]["moc"] = value(moc);
+        }
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+    let manifest = doc.to_string();
+    let options = ResolveOptions {
+        manifest: Some(manifest.clone()),
+        ..ResolveOptions::from_config(config)?
+    };
+    let pkgs = resolve_mops_lock(agent, &Pending::default(), &options).await?;
+    fs::write(path, manifest)?;
+    write_mops_lock(&lock_path(), &pkgs)?;
+    status!(
+        "{:>12} {} dependencies from vessel.dhall. Run `mops install`, then vessel.dhall and package-set.dhall can be removed",
+        style("Migrated").green().bold(),
+        deps.len()
+    );
+    Ok(())
+}
+
+/// The packages of a package set in `dir`, with those of the sets it imports, like the upstream
+/// set of vessel, over HTTP or from local files. The packages it defines itself win.
+async fn load_package_set(dir: &Path, str: &str) -> Result<BTreeMap<String, SetPackage>> {
+    let tokens = parse(str)?;
+    let mut res = BTreeMap::new();
+    for import in imports(&tokens) {
+        let (str, dir) = if import.starts_with("https://") || import.starts_with("http://") {
+            let response = crate::github::client()
+                .get(&import)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .with_context(|| format!("Cannot fetch the package set {import}"))?;
+            (response.text().await?, dir.to_path_buf())
+        } else {
+            let path = dir.join(&import);
+            let str = fs::read_to_string(&path)
+                .with_context(|| format!("Cannot read the package set {}", path.display()))?;
+            (str, path.parent().unwrap_or(dir).to_path_buf())
+        };
+        res.extend(Box::pin(load_package_set(&dir, &str)).await?);
+    }
+    res.extend(package_set(&tokens));
+    Ok(res)
+}
+
+/// The packages defined in a package-set.dhall. Later definitions win, as with `additions #
+/// overrides`. Imported sets are left to [`load_package_set`].
+fn package_set(tokens: &[Token]) -> BTreeMap<String, SetPackage> {
+    let mut res = BTreeMap::new();
+    for record in records(tokens) {
+        let (Some(Value::Text(name)), Some(Value::Text(repo)), Some(Value::Text(version))) = (
+            record.get("name"),
+            record.get("repo"),
+            record.get("version"),
+        ) else {
+            continue;
+        };
+        let pkg = SetPackage {
+            repo: repo.clone(),
+            version: version.clone(),
+            dependencies: list(record.get("dependencies")),
+        };
+        res.insert(name.clone(), pkg);
+    }
+    res
+}
+
+/// Dhall files imported by URL or relative path. Their `sha256:` hashes are of Dhall's
+/// normal form, which we cannot compute, so they are not checked.
+fn imports(tokens: &[Token]) -> Vec<String> {
+    tokens
+        .iter()
+        .filter_map(|t| match t {
+            Token::Word(word)
+                if ["https://", "http://", "./", "../"]
+                    .iter()
+                    .any(|prefix| word.starts_with(prefix)) =>
+            {
+                Some(word.clone())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Text(String),
+    Word(String),
+    Symbol(char),
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Text(String),
+    List(Vec<String>),
+    Other,
+}
+
+fn list(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(Value::List(items)) => items.clone(),
+        _ => Vec::new(),
+    }
+}
+
+/// Splits Dhall source into tokens, dropping comments. Only the literals we read are exact:
+/// everything else becomes words and symbols to skip over.
+fn parse(str: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = str.chars().collect();
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if chars[i..].starts_with(&['-', '-']) {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if chars[i..].starts_with(&['{', '-']) {
+            let mut depth = 0;
+            loop {
+                if i >= chars.len() {
+                    return Err(anyhow!("Unterminated block comment"));
+                }
+                if chars[i..].starts_with(&['{', '-']) {
+                    depth += 1;
+                    i += 2;
+                } else if chars[i..].starts_with(&['-', '}']) {
+                    depth -= 1;
+                    i += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    i += 1;
+                }
+            }
+        } else if c == '"' {
+            let mut text = String::new();
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                if let Some(c) = chars.get(i) {
+                    text.push(*c);
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(anyhow!("Unterminated string"));
+            }
+            res.push(Token::Text(text));
+            i += 1;
+        } else if "{}[],=:".contains(c) {
+            res.push(Token::Symbol(c));
+            i += 1;
+        } else {
+            // `:` ends a word only before whitespace, so URLs and `sha256:...` stay whole.
+            let ends = |i: usize| {
+                chars[i].is_whitespace()
+                    || "{}[],=\"".contains(chars[i])
+                    || (chars[i] == ':' && chars.get(i + 1).is_some_and(|c| c.is_whitespace()))
+            };
+            let start = i;
+            while i < chars.len() && !ends(i) {
+                i += 1;
+            }
+            res.push(Token::Word(chars[start..i].iter().collect()));
+        }
+    }
+    Ok(res)
+}
+
+/// Every record literal of the outermost level, e.g. `{ name = "base", dependencies = [] }`.
+/// Record types like `{ name : Text }` are skipped.
+fn records(tokens: &[Token]) -> Vec<BTreeMap<String, Value>> {
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        if tokens[i] == Token::Symbol('{') {
+            let (record, end) = record(tokens, i + 1);
+            res.extend(record);
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    res
+}
+
+/// Parses the fields of a record starting at `i`, after its `{`. Returns the record, unless it
+/// is a record type, and the position after its `}`.
+fn record(tokens: &[Token], mut i: usize) -> (Option<BTreeMap<String, Value>>, usize) {
+    let mut fields = Some(BTreeMap::new());
+    while i < tokens.len() {
+        match (&tokens[i], tokens.get(i + 1)) {
+            (Token::Symbol('}'), _) => return (fields, i + 1),
+            (Token::Symbol(','), _) => i += 1,
+            (Token::Word(key), Some(Token::Symbol('='))) => {
+                let (value, end) = field_value(tokens, i + 2);
+                if let Some(fields) = &mut fields {
+                    fields.insert(key.clone(), value);
+                }
+                i = end;
+            }
+            _ => {
+                fields = None;
+                i = skip(tokens, i);
+            }
+        }
+    }
+    (fields, i)
+}
+
+/// Parses a field value starting at `i`. Returns it and the position of the `,` or `}` after it.
+fn field_value(tokens: &[Token], i: usize) -> (Value, usize) {
+    let i = i.min(tokens.len());
+    let end = skip(tokens, i);
+    let value = match &tokens[i..end] {
+        [Token::Text(text)] => Value::Text(text.clone()),
+        [Token::Word(some), Token::Text(text)] if some == "Some" => Value::Text(text.clone()),
+        [Token::Symbol('['), items @ ..] => {
+            let items: Vec<_> = items
+                .iter()
+                .take_while(|t| **t != Token::Symbol(']'))
+                .filter_map(|t| match t {
+                    Token::Text(text) => Some(text.clone()),
+                    _ => None,
+                })
+                .collect();
+            Value::List(items)
+        }
+        _ => Value::Other,
+    };
+    (value, end)
+}
+
+/// The position of the next `,` or `}` at the nesting level of `i`.
+fn skip(tokens: &[Token], mut i: usize) -> usize {
+    let mut depth = 0;
+    while i < tokens.len() {
+        match tokens[i] {
+            Token::Symbol('{' | '[') => depth += 1,
+            Token::Symbol(']') => depth -= 1,
+            Token::Symbol('}') if depth == 0 => return i,
+            Token::Symbol('}') => depth -= 1,
+            Token::Symbol(',') if depth == 0 => return i,
+            _ => (),
+        }
+        i += 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SET: &str = r#"
+let upstream = https://github.com/dfinity/vessel-package-set/releases/download/mo-0.6.21-20220215/package-set.dhall sha256:b46f30e811fe5085741be01e126629c2a55d4c3d6ebf49408fb3b4a98e37589b
+let Package = { name : Text, version : Text, repo : Text, dependencies : List Text }
+-- A line comment with { braces
+let additions = [
+  { name = "matchers"
+  , repo = "https://github.com/kritzcreek/motoko-matchers"
+  , version = "v1.2.0"
+  , dependencies = [ "base" ] : List Text
+  },
+] : List Package
+{- A block comment {- nested -} with "quotes" -}
+let overrides = [
+  { name = "matchers", repo = "https://github.com/kritzcreek/motoko-matchers", version = "v1.3.0", dependencies = [] : List Text },
+] : List Package
+in upstream # additions # overrides
+"#;
+
+    #[test]
+    fn parse_drops_comments_and_keeps_urls_whole() {
+        let tokens = parse(SET).unwrap();
+        assert!(tokens.contains(&Token::Word(
+            "https://github.com/dfinity/vessel-package-set/releases/download/mo-0.6.21-20220215/package-set.dhall".to_string()
+        )));
+        assert!(tokens
+            .iter()
+            .any(|t| matches!(t, Token::Word(w) if w.starts_with("sha256:"))));
+        assert!(!tokens.iter().any(|t| match t {
+            Token::Word(w) | Token::Text(w) => w.contains("comment") || w == "quotes",
+            Token::Symbol(_) => false,
+        }));
+    }
+
+    #[test]
+    fn parse_reads_escapes_in_text() {
+        let tokens = parse(r#"{ name = "a\"b" }"#).unwrap();
+        assert_eq!(tokens[3], Token::Text("a\"b".to_string()));
+    }
+
+    #[test]
+    fn parse_rejects_unterminated_literals() {
+        assert!(parse(r#"{ name = "base }"#).is_err());
+        assert!(parse("{- {- -} ").is_err());
+    }
+
+    #[test]
+    fn records_skip_record_types() {
+        let records = records(&parse(SET).unwrap());
+        assert_eq!(records.len(), 2);
+        assert!(records
+            .iter()
+            .all(|r| r.get("name") == Some(&Value::Text("matchers".to_string()))));
+    }
+
+    #[test]
+    fn records_read_optional_text_and_lists() {
+        let str =
+            r#"{ dependencies = [ "base", "matchers" ], compiler = Some "0.10.2", other = 1 }"#;
+        let record = records(&parse(str).unwrap()).remove(0);
+        assert_eq!(
+            list(record.get("dependencies")),
+            vec!["base".to_string(), "matchers".to_string()]
+        );
+        assert_eq!(
+            record.get("compiler"),
+            Some(&Value::Text("0.10.2".to_string()))
+        );
+        assert_eq!(record.get("other"), Some(&Value::Other));
+    }
+
+    #[test]
+    fn package_set_lets_later_definitions_win() {
+        let set = package_set(&parse(SET).unwrap());
+        let matchers = &set["matchers"];
+        assert_eq!(matchers.version, "v1.3.0");
+        assert!(matchers.dependencies.is_empty());
+    }
+
+    #[test]
+    fn imports_are_urls_and_relative_paths() {
+        let tokens = parse("let a = ./upstream.dhall\nlet b = ../c.dhall\nin a # b").unwrap();
+        assert_eq!(imports(&tokens), vec!["./upstream.dhall", "../c.dhall"]);
+        assert_eq!(imports(&parse(SET).unwrap()).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn load_package_set_pins_upstream_packages() {
+        let dir = std::env::temp_dir().join(format!("mops-migrate-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("upstream.dhall"),
+            r#"[ { name = "base", repo = "https://github.com/dfinity/motoko-base", version = "moc-0.6.21", dependencies = [] : List Text }
+, { name = "matchers", repo = "https://github.com/kritzcreek/motoko-matchers", version = "v1.0.0", dependencies = [ "base" ] } ]"#,
+        )
+        .unwrap();
+        let local = r#"let upstream = ./upstream.dhall
+in upstream # [ { name = "matchers", repo = "https://github.com/kritzcreek/motoko-matchers", version = "v1.3.0", dependencies = [ "base" ] } ]"#;
+        let set = load_package_set(&dir, local).await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(set["base"].version, "moc-0.6.21");
+        assert_eq!(set["matchers"].version, "v1.3.0");
+    }
+}
@@ -0,0 +1,109 @@
+//! Registries mirroring the mops registry, asked in order when the ones before them cannot be
+//! reached or fail to answer.
+use crate::config::Config;
+use crate::mops;
+use anyhow::{anyhow, Result};
+use candid::Principal;
+use console::style;
+use ic_agent::Agent;
+use std::future::Future;
+use std::sync::OnceLock;
+
+struct Mirror {
+    id: Principal,
+    /// Its own agent when it is on another replica than the primary registry.
+    agent: Option<Agent>,
+}
+static MIRRORS: OnceLock<Vec<Mirror>> = OnceLock::new();
+
+/// Reads `registry_mirrors`, a comma-separated list of `<canister>` or `<canister>@<url>`.
+/// Mirrors without a URL are on `url`, the replica of the primary registry.
+pub(crate) fn configure(config: &Config, client: &reqwest::Client, url: &str) -> Result<()> {
+    let Some(list) = config.get("registry_mirrors") else {
+        return Ok(());
+    };
+    let mut mirrors = Vec::new();
+    for spec in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (id, mirror_url) = match spec.split_once('@') {
+            Some((id, url)) => (id, Some(url)),
+            None => (spec, None),
+        };
+        let id = Principal::from_text(id)
+            .map_err(|_| anyhow!("Invalid registry mirror {spec}, expected <canister>[@<url>]"))?;
+        let identity = crate::identity::registry_identity(config, id)?;
+        let agent = match (mirror_url, identity) {
+            (None, None) => None,
+            (mirror_url, identity) => {
+                let url = mirror_url.unwrap_or(url);
+                let agent = crate::utils::registry_agent(config, url, client.clone(), identity)?;
+                // A mirror that cannot be reached now fails when asked, and the next one is tried.
+                let _ = crate::utils::fetch_root_key(&agent, url);
+                Some(agent)
+            }
+        };
+        mirrors.push(Mirror { id, agent });
+    }
+    let _ = MIRRORS.set(mirrors);
+    Ok(())
+}
+
+/// The primary registry followed by its mirrors, in order.
+pub(crate) fn registries<'a>(primary: &mops::Service<'a>) -> Vec<mops::Service<'a>> {
+    let mut res = vec![mops::Service(primary.0, primary.1)];
+    for mirror in MIRRORS.get().into_iter().flatten() {
+        res.push(mops::Service(
+            mirror.id,
+            mirror.agent.as_ref().unwrap_or(primary.1),
+        ));
+    }
+    res
+}
+
+/// The registry `mirror` names, the primary one for `None` or a mirror that is not configured.
+pub(crate) fn registry<'a>(
+    primary: &mops::Service<'a>,
+    mirror: Option<Principal>,
+) -> mops::Service<'a> {
+    registries(primary)
+        .into_iter()
+        .find(|r| Some(r.0) == mirror)
+        .unwrap_or(mops::Service(primary.0, primary.1))
+}
+
+/// Runs `call` against the registry `first` names, the primary one for `None`, then against the
+/// others in order until one succeeds. Returns the result and the mirror it came from, `None` for
+/// the primary registry.
+pub(crate) async fn failover<'a, T, Fut>(
+    what: &str,
+    primary: &mops::Service<'a>,
+    first: Option<Principal>,
+    call: impl Fn(mops::Service<'a>) -> Fut,
+) -> Result<(T, Option<Principal>)>
+where
+    Fut: Future<Output = Result<T>>,
+{
+    let first = registry(primary, first).0;
+    let mut registries = registries(primary);
+    registries.sort_by_key(|r| r.0 != first);
+    let mut registries = registries.into_iter().peekable();
+    while let Some(registry) = registries.next() {
+        let id = registry.0;
+        let res = call(mops::Service(registry.0, registry.1)).await;
+        match res.map_err(|e| crate::identity::explain_unauthorized(&registry, e)) {
+            Ok(res) => return Ok((res, (id != primary.0).then_some(id))),
+            Err(e) => match registries.peek() {
+                Some(next) => crate::utils::println(
+                    None,
+                    "stderr",
+                    &format!(
+                        "{:>12} to mirror {} for {what}, after: {e:#}",
+                        style("Falling back").yellow().bold(),
+                        next.0
+                    ),
+                ),
+                None => return Err(e),
+            },
+        }
+    }
+    unreachable!()
+}
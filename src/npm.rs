@@ -0,0 +1,83 @@
+//! The `.mops/<name>@<version>` layout of the JavaScript mops CLI, for tools that read packages
+//! from there instead of asking `mops sources`.
+use crate::config::Config;
+use crate::store::{link_package, LinkMode};
+use crate::toml::{Package, PackageType, Packages};
+use crate::workspace::lock_dir;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Reads `layout`: `cache` keeps packages only in the cache, `npm` also links them into `.mops`.
+pub(crate) fn configure(config: &Config) -> Result<()> {
+    let enabled = match config.get("layout") {
+        None | Some("cache") => false,
+        Some("npm") => true,
+        Some(other) => return Err(anyhow!("unknown layout {other}, expected cache or npm")),
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Where the JavaScript CLI puts `pkg`, relative to `.mops`.
+fn dir_name(pkg: &Package) -> Option<String> {
+    match pkg.get_type() {
+        PackageType::Mops { ver, .. } => Some(format!("{}@{ver}", pkg.name)),
+        PackageType::Repo(repo) => Some(format!("_github/{}#{}", pkg.name, repo.tag)),
+        PackageType::Local(_) => None,
+    }
+}
+
+/// Links the installed packages of `lock` from `root` into `.mops` next to the lock, and removes
+/// the links of packages that are no longer locked.
+pub(crate) fn link(root: &Path, lock: &Packages) -> Result<()> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let dir = lock_dir().join(".mops");
+    let mut linked = BTreeSet::new();
+    for pkg in &lock.package {
+        let Some(name) = dir_name(pkg) else {
+            continue;
+        };
+        let source = std::path::absolute(root.join(pkg.get_path()))?;
+        if !source.join(pkg.get_done_file()).exists() {
+            continue;
+        }
+        let dest = dir.join(&name);
+        // A link into another cache directory is replaced.
+        if dest.is_symlink() && fs::read_link(&dest)? != source {
+            fs::remove_file(&dest)?;
+        }
+        link_package(&source, &dest, LinkMode::Symlink)
+            .or_else(|_| link_package(&source, &dest, LinkMode::Hardlink))?;
+        linked.insert(name);
+    }
+    for (sub, prefix) in [(dir.clone(), ""), (dir.join("_github"), "_github/")] {
+        let Ok(entries) = fs::read_dir(&sub) else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+            let ours = if prefix.is_empty() {
+                name.contains('@')
+            } else {
+                name.contains('#')
+            };
+            if !ours || linked.contains(&name) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
+    Ok(())
+}
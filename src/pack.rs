@@ -0,0 +1,117 @@
+use crate::utils::create_spinner_bar;
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use flate2::{write::GzEncoder, Compression};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::WalkBuilder;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use toml_edit::ImDocument;
+
+/// Paths that are never useful in a published package. `.mopsignore` is applied on top of these.
+const DEFAULT_IGNORE: &[&str] = &[
+    ".git/",
+    ".mops/",
+    ".vessel/",
+    ".dfx/",
+    "node_modules/",
+    "target/",
+    "test/",
+    "tests/",
+    "*.test.mo",
+    "*.wasm",
+    "*.tgz",
+    "mops.lock",
+];
+const IGNORE_FILE: &str = ".mopsignore";
+
+pub fn pack(args: crate::PackArg) -> Result<()> {
+    let root = Path::new(".");
+    let (name, version) = get_package_meta(&root.join("mops.toml"))?;
+    let files = collect_files(root)?;
+    if files.is_empty() {
+        return Err(anyhow!("No files to pack"));
+    }
+    if args.list {
+        if crate::utils::json() {
+            return crate::utils::print_json(&files);
+        }
+        for file in files {
+            println!("{}", file.display());
+        }
+        return Ok(());
+    }
+    let output = args
+        .output
+        .unwrap_or_else(|| PathBuf::from(format!("{name}-{version}.tgz")));
+    let bar = create_spinner_bar(format!("Packing {name}@{version}"));
+    let gz = GzEncoder::new(File::create(&output)?, Compression::default());
+    let mut tar = tar::Builder::new(gz);
+    for file in &files {
+        tar.append_path_with_name(root.join(file), file)
+            .with_context(|| format!("Failed to pack {}", file.display()))?;
+    }
+    tar.into_inner()?.finish()?;
+    bar.finish_and_clear();
+    status!(
+        "{:>12} {name}@{version} ({} files) to {}",
+        style("Packed").green().bold(),
+        files.len(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Returns the files under `root` that belong to the package, relative to `root` and sorted.
+pub fn collect_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let defaults = default_ignore(root)?;
+    let mut files = Vec::new();
+    let walker = WalkBuilder::new(root)
+        .hidden(false)
+        .git_ignore(false)
+        .git_global(false)
+        .git_exclude(false)
+        .parents(false)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            !defaults
+                .matched_path_or_any_parents(entry.path(), is_dir)
+                .is_ignore()
+        })
+        .build();
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let path = entry.path().strip_prefix(root)?;
+        if path == Path::new(IGNORE_FILE) {
+            continue;
+        }
+        files.push(path.to_path_buf());
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn default_ignore(root: &Path) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in DEFAULT_IGNORE {
+        builder.add_line(None, pattern)?;
+    }
+    Ok(builder.build()?)
+}
+
+fn get_package_meta(toml: &Path) -> Result<(String, String)> {
+    let str = fs::read_to_string(toml).context("Cannot find mops.toml")?;
+    let doc = str.parse::<ImDocument<_>>()?;
+    let get = |key: &str| {
+        doc.get("package")
+            .and_then(|pkg| pkg.get(key))
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("package.{key} is required in mops.toml"))
+    };
+    Ok((get("name")?, get("version")?))
+}
@@ -0,0 +1,416 @@
+use crate::github::parse_github_url;
+use crate::mops::{self, DependencyV2, PackageConfigV3, Requirement};
+use crate::pack::collect_files;
+use crate::toml::{get_publish_dependencies, get_publish_dev_dependencies, parse_mops_lock};
+use crate::utils::create_bar;
+use anyhow::{anyhow, Context, Error, Result};
+use candid::Nat;
+use console::style;
+use ic_agent::Agent;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml_edit::ImDocument;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+const STATE_DIR: &str = ".mops/publish";
+
+/// Progress of a publish, written after every acknowledged chunk so that a rerun only
+/// uploads what the registry has not seen yet.
+#[derive(Serialize, Deserialize)]
+struct PublishState {
+    publishing_id: String,
+    files: BTreeMap<String, FileState>,
+}
+#[derive(Serialize, Deserialize)]
+struct FileState {
+    sha256: String,
+    file_id: Option<String>,
+    uploaded: BTreeSet<u64>,
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn publish(agent: &Agent, args: crate::PublishArg) -> Result<()> {
+    crate::utils::ensure_online("publish")?;
+    let root = Path::new(".");
+    let config = read_package_config(&root.join("mops.toml"))?;
+    let name = config.name.clone();
+    let version = config.version.clone();
+    let mut contents = BTreeMap::new();
+    for file in collect_files(root)? {
+        let key = file
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let bytes = fs::read(root.join(&file))?;
+        contents.insert(key, bytes);
+    }
+    if contents.is_empty() {
+        return Err(anyhow!("No files to publish"));
+    }
+    if args.embed_lock {
+        check_lock(&config.dependencies)?;
+        contents.insert("mops.lock".to_string(), fs::read("mops.lock")?);
+    }
+    let service = mops::Service(crate::utils::registry_id(), agent);
+    check_dependencies(&service, &config.dependencies).await?;
+    let state_path = PathBuf::from(STATE_DIR).join(format!("{name}@{version}.json"));
+    let mut state = match load_state(&state_path)? {
+        Some(state) if !args.restart => {
+            check_unchanged(&state, &contents)?;
+            status!(
+                "{:>12} interrupted publish of {name}@{version}",
+                style("Resuming").green().bold()
+            );
+            state
+        }
+        _ => {
+            let publishing_id = service
+                .start_publish(&config)
+                .await?
+                .into_result()
+                .map_err(Error::msg)?;
+            let files = contents
+                .iter()
+                .map(|(key, bytes)| {
+                    let state = FileState {
+                        sha256: hex::encode(Sha256::digest(bytes)),
+                        file_id: None,
+                        uploaded: BTreeSet::new(),
+                    };
+                    (key.clone(), state)
+                })
+                .collect();
+            let state = PublishState {
+                publishing_id,
+                files,
+            };
+            save_state(&state_path, &state)?;
+            state
+        }
+    };
+
+    let total: usize = contents.values().map(|b| chunks(b).len()).sum();
+    let bar = create_bar(total);
+    bar.set_prefix("Uploading");
+    let done: usize = state.files.values().map(|f| f.uploaded.len()).sum();
+    bar.set_position(done as u64);
+    for (key, bytes) in &contents {
+        let chunks = chunks(bytes);
+        let count = Nat::from(chunks.len());
+        bar.set_message(key.clone());
+        let file_id = match state.files[key].file_id.clone() {
+            Some(id) => id,
+            None => {
+                let id = service
+                    .start_file_upload(&state.publishing_id, key, &count, &ByteBuf::from(chunks[0]))
+                    .await?
+                    .into_result()
+                    .map_err(Error::msg)
+                    .with_context(|| format!("Failed to upload {key}"))?;
+                let file = state.files.get_mut(key).unwrap();
+                file.file_id = Some(id.clone());
+                file.uploaded.insert(0);
+                save_state(&state_path, &state)?;
+                bar.inc(1);
+                id
+            }
+        };
+        for (i, chunk) in chunks.iter().enumerate().skip(1) {
+            if state.files[key].uploaded.contains(&(i as u64)) {
+                continue;
+            }
+            service
+                .upload_file_chunk(
+                    &state.publishing_id,
+                    &file_id,
+                    &Nat::from(i),
+                    &ByteBuf::from(*chunk),
+                )
+                .await?
+                .into_result()
+                .map_err(Error::msg)
+                .with_context(|| format!("Failed to upload chunk {i} of {key}"))?;
+            state.files.get_mut(key).unwrap().uploaded.insert(i as u64);
+            save_state(&state_path, &state)?;
+            bar.inc(1);
+        }
+    }
+    bar.finish_and_clear();
+
+    let expected = check_acknowledged(&state, &contents)?;
+    if let Some(listed) = listed_files(&service, &name, &version).await? {
+        check_listed(&listed, &expected, &name, &version)
+            .context("Not finalizing the publish. Rerun with --restart to start over.")?;
+    }
+    service
+        .finish_publish(&state.publishing_id)
+        .await?
+        .into_result()
+        .map_err(Error::msg)
+        .context("Failed to finalize publish. Rerun with --restart to start over.")?;
+    let published = listed_files(&service, &name, &version)
+        .await?
+        .unwrap_or_default();
+    check_listed(&published, &expected, &name, &version)?;
+    fs::remove_file(&state_path)?;
+    status!(
+        "{:>12} {name}@{version} ({} files)",
+        style("Published").green().bold(),
+        contents.len()
+    );
+    Ok(())
+}
+
+/// Publishes `config` with the files of `contents` in one go, without the resumable state of
+/// `publish`, for packages copied from another registry.
+pub(crate) async fn publish_files(
+    service: &mops::Service<'_>,
+    config: &PackageConfigV3,
+    contents: &BTreeMap<String, Vec<u8>>,
+) -> Result<()> {
+    let publishing_id = service
+        .start_publish(config)
+        .await?
+        .into_result()
+        .map_err(Error::msg)?;
+    for (key, bytes) in contents {
+        let chunks = chunks(bytes);
+        let count = Nat::from(chunks.len());
+        let file_id = service
+            .start_file_upload(&publishing_id, key, &count, &ByteBuf::from(chunks[0]))
+            .await?
+            .into_result()
+            .map_err(Error::msg)
+            .with_context(|| format!("Failed to upload {key}"))?;
+        for (i, chunk) in chunks.iter().enumerate().skip(1) {
+            service
+                .upload_file_chunk(
+                    &publishing_id,
+                    &file_id,
+                    &Nat::from(i),
+                    &ByteBuf::from(*chunk),
+                )
+                .await?
+                .into_result()
+                .map_err(Error::msg)
+                .with_context(|| format!("Failed to upload chunk {i} of {key}"))?;
+        }
+    }
+    service
+        .finish_publish(&publishing_id)
+        .await?
+        .into_result()
+        .map_err(Error::msg)
+        .context("Failed to finalize publish")
+}
+
+/// Makes sure every declared dependency resolves to a pinned, available version before anything is uploaded.
+async fn check_dependencies(service: &mops::Service<'_>, deps: &[DependencyV2]) -> Result<()> {
+    let mut errors = Vec::new();
+    for dep in deps {
+        if dep.version.is_empty() {
+            if let Err(e) = parse_github_url(&dep.repo).await {
+                errors.push(format!("{} = {:?}: {e}", dep.name, dep.repo));
+            }
+            continue;
+        }
+        if Version::parse(&dep.version).is_err() {
+            errors.push(format!(
+                "{} = {:?} is not an exact version",
+                dep.name, dep.version
+            ));
+            continue;
+        }
+        if let Err(e) = service
+            .get_package_details(&dep.name, &dep.version)
+            .await?
+            .into_result()
+        {
+            errors.push(format!("{}@{}: {e}", dep.name, dep.version));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "Dependencies do not resolve on the registry:\n  {}",
+            errors.join("\n  ")
+        ));
+    }
+    Ok(())
+}
+
+/// The embedded lock must cover every registry dependency declared in mops.toml.
+fn check_lock(deps: &[DependencyV2]) -> Result<()> {
+    let lock = parse_mops_lock(Path::new("mops.lock"))
+        .context("--embed-lock needs a mops.lock. Run `mops install` first.")?;
+    let missing: Vec<_> = deps
+        .iter()
+        .filter(|d| !d.version.is_empty())
+        .filter(|d| !lock.package.iter().any(|p| p.name == d.name))
+        .map(|d| d.name.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "mops.lock is out of date, missing {}. Run `mops install` first.",
+            missing.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Splits a file into upload chunks. An empty file is uploaded as a single empty chunk.
+fn chunks(bytes: &[u8]) -> Vec<&[u8]> {
+    if bytes.is_empty() {
+        vec![bytes]
+    } else {
+        bytes.chunks(CHUNK_SIZE).collect()
+    }
+}
+
+/// Checks that the registry acknowledged every chunk of every file, as recorded in `state`.
+/// Returns the uploaded file ids.
+fn check_acknowledged(
+    state: &PublishState,
+    contents: &BTreeMap<String, Vec<u8>>,
+) -> Result<BTreeSet<String>> {
+    let mut missing = Vec::new();
+    let mut ids = BTreeSet::new();
+    for (key, bytes) in contents {
+        let file = &state.files[key];
+        let complete = (0..chunks(bytes).len() as u64).all(|i| file.uploaded.contains(&i));
+        match &file.file_id {
+            Some(id) if complete && ids.insert(id.clone()) => (),
+            _ => missing.push(key.as_str()),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "The registry did not acknowledge every upload of {}. Rerun with --restart to start over.",
+            missing.join(", ")
+        ));
+    }
+    Ok(ids)
+}
+
+/// The file ids the registry lists for `name@version`, or `None` while it does not know the
+/// version. Finalizing is permanent, so `publish` compares the list before and after.
+async fn listed_files(
+    service: &mops::Service<'_>,
+    name: &str,
+    version: &str,
+) -> Result<Option<BTreeSet<String>>> {
+    let ids = service
+        .get_file_ids(&name.to_string(), &version.to_string())
+        .await?
+        .into_result()
+        .ok();
+    Ok(ids.map(|ids| ids.into_iter().collect()))
+}
+
+fn check_listed(
+    listed: &BTreeSet<String>,
+    expected: &BTreeSet<String>,
+    name: &str,
+    version: &str,
+) -> Result<()> {
+    if listed != expected {
+        return Err(anyhow!(
+            "Registry lists {} files for {name}@{version}, but {} were uploaded",
+            listed.len(),
+            expected.len()
+        ));
+    }
+    Ok(())
+}
+
+fn check_unchanged(state: &PublishState, contents: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+    let same_files = state.files.len() == contents.len()
+        && contents.iter().all(|(key, bytes)| {
+            state
+                .files
+                .get(key)
+                .is_some_and(|f| f.sha256 == hex::encode(Sha256::digest(bytes)))
+        });
+    if !same_files {
+        return Err(anyhow!(
+            "Files changed since the interrupted publish. Rerun with --restart to start over."
+        ));
+    }
+    Ok(())
+}
+
+fn load_state(path: &Path) -> Result<Option<PublishState>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let str = fs::read_to_string(path)?;
+    let state = serde_json::from_str(&str)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(Some(state))
+}
+
+fn save_state(path: &Path, state: &PublishState) -> Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string(state)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn read_package_config(toml: &Path) -> Result<PackageConfigV3> {
+    let str = fs::read_to_string(toml).context("Cannot find mops.toml")?;
+    let doc = str.parse::<ImDocument<_>>()?;
+    let pkg = doc
+        .get("package")
+        .ok_or_else(|| anyhow!("[package] is required in mops.toml"))?;
+    let get = |key: &str| pkg.get(key).and_then(|v| v.as_str()).map(|v| v.to_string());
+    let required = |key: &str| get(key).ok_or_else(|| anyhow!("package.{key} is required"));
+    let keywords = pkg
+        .get("keywords")
+        .and_then(|v| v.as_array())
+        .map(|a| {
+            a.iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let requirements = doc
+        .get("requirements")
+        .and_then(|t| t.as_table())
+        .map(|t| {
+            t.iter()
+                .filter_map(|(name, v)| {
+                    let value = v.as_str()?.to_string();
+                    Some(Requirement {
+                        name: name.to_string(),
+                        value,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(PackageConfigV3 {
+        name: required("name")?,
+        version: required("version")?,
+        description: get("description").unwrap_or_default(),
+        repository: get("repository").unwrap_or_default(),
+        homepage: get("homepage").unwrap_or_default(),
+        documentation: get("documentation").unwrap_or_default(),
+        license: get("license").unwrap_or_default(),
+        donation: get("donation").unwrap_or_default(),
+        readme: get("readme").unwrap_or_else(|| "README.md".to_string()),
+        base_dir: get("baseDir").unwrap_or_else(|| "src".to_string()),
+        dfx: get("dfx").unwrap_or_default(),
+        moc: get("moc").unwrap_or_default(),
+        keywords,
+        dependencies: get_publish_dependencies()?,
+        dev_dependencies: get_publish_dev_dependencies()?,
+        scripts: Vec::new(),
+        requirements,
+    })
+}
@@ -0,0 +1,213 @@
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::mops;
+use crate::toml::{download_packages_from_lock, parse_mops_lock, Package, PackageType};
+use crate::utils::{get_cache_dir, Pending};
+use crate::workspace::lock_path;
+use anyhow::{anyhow, Context, Result};
+use candid::Principal;
+use console::style;
+use ic_agent::Agent;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Splits `name@version`.
+pub fn parse_package_spec(spec: &str) -> Result<(String, String)> {
+    match spec.split_once('@') {
+        Some((name, version)) if !name.is_empty() && !version.is_empty() => {
+            Ok((name.to_string(), version.to_string()))
+        }
+        _ => Err(anyhow!("Expected <package>@<version>, got {spec}")),
+    }
+}
+
+/// Republishes the registry packages of mops.lock, with their metadata, to the registry `--to`.
+/// The files are the ones `mops install` verified against mops.lock, so the mirror serves what
+/// the project was locked with.
+pub fn mirror(agent: &Agent, config: &Config, args: crate::MirrorArg) -> Result<()> {
+    let id = Principal::from_text(&args.to).map_err(|_| {
+        anyhow!(
+            "Invalid registry canister {}, expected a principal",
+            args.to
+        )
+    })?;
+    let url = match &args.to_url {
+        Some(url) => url.clone(),
+        None => crate::utils::registry_url(config)?,
+    };
+    let identity = match args.identity {
+        Some(_) => crate::identity::load_identity(config)?,
+        None => match crate::identity::registry_identity(config, id)? {
+            Some(identity) => Some(identity),
+            None => crate::identity::load_identity(config)?,
+        },
+    };
+    let target = crate::utils::registry_agent(
+        config,
+        &url,
+        crate::utils::registry_client(config)?,
+        identity,
+    )?;
+    crate::utils::fetch_root_key(&target, &url)
+        .with_context(|| format!("Cannot reach the registry at {url}"))?;
+    republish(agent, &mops::Service(id, &target), config, args.dry_run)
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+async fn republish(
+    agent: &Agent,
+    target: &mops::Service<'_>,
+    config: &Config,
+    dry_run: bool,
+) -> Result<()> {
+    crate::utils::ensure_online("mirror packages")?;
+    let cache_dir = get_cache_dir(config)?;
+    download_packages_from_lock(
+        agent,
+        &cache_dir,
+        &Pending::default(),
+        HashAlgorithm::from_config(config)?,
+        false,
+    )
+    .await?;
+    let lock = parse_mops_lock(&lock_path())
+        .map_err(|_| anyhow!("mops.lock not found. Run `mops install` first."))?;
+    let root = lock.package_root(&cache_dir);
+    let source = mops::Service(crate::utils::registry_id(), agent);
+    let pkgs: Vec<_> = lock
+        .package
+        .iter()
+        .filter(|pkg| matches!(pkg.get_type(), PackageType::Mops { .. }))
+        .collect();
+    let skipped = lock.package.len() - pkgs.len();
+    let (mut published, mut present) = (0, 0);
+    for pkg in dependencies_first(pkgs) {
+        let name = pkg.registry_name().to_string();
+        let version = pkg.version.clone().unwrap_or_default();
+        let there = target.get_package_details(&name, &version).await?;
+        if there.into_result().is_ok() {
+            status!("{:>12} {name}@{version}", style("Present").green().bold());
+            present += 1;
+            continue;
+        }
+        let details = crate::metadata::package_details(&source, &name, &version).await?;
+        let files = package_files(&root.join(pkg.get_path()))?;
+        if dry_run {
+            status!(
+                "{:>12} {name}@{version} ({} files)",
+                style("Would mirror").green().bold(),
+                files.len()
+            );
+            published += 1;
+            continue;
+        }
+        crate::publish::publish_files(target, &details.config, &files)
+            .await
+            .with_context(|| format!("Cannot mirror {name}@{version}"))?;
+        status!(
+            "{:>12} {name}@{version} ({} files)",
+            style("Mirrored").green().bold(),
+            files.len()
+        );
+        published += 1;
+    }
+    status!(
+        "{:>12} {published} package(s) to {}, {present} already there, {skipped} not from the registry",
+        style(if dry_run { "Would mirror" } else { "Mirrored" }).bold(),
+        target.0
+    );
+    Ok(())
+}
+
+/// Orders packages so that each comes after the registry packages it depends on, which the
+/// target registry may check for when publishing.
+fn dependencies_first(mut pkgs: Vec<&Package>) -> Vec<&Package> {
+    let key = |pkg: &Package| format!("{}-{}", pkg.name, pkg.version.as_deref().unwrap_or(""));
+    let mut res: Vec<&Package> = Vec::new();
+    while !pkgs.is_empty() {
+        let waiting: BTreeSet<_> = pkgs.iter().map(|pkg| key(pkg)).collect();
+        let (ready, rest): (Vec<_>, Vec<_>) = pkgs
+            .into_iter()
+            .partition(|pkg| !pkg.dependencies.iter().any(|dep| waiting.contains(dep)));
+        if ready.is_empty() {
+            // A cycle, which the registry cannot have: keep the lock's order.
+            res.extend(rest);
+            break;
+        }
+        res.extend(ready);
+        pkgs = rest;
+    }
+    res
+}
+
+/// The files of a downloaded package by their path in it, without the markers of the cache.
+fn package_files(dir: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut res = BTreeMap::new();
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(dir)?;
+        let marker = rel.components().count() == 1
+            && entry.file_name().to_string_lossy().starts_with("DONE");
+        if !entry.file_type().is_file() || marker {
+            continue;
+        }
+        let key = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        res.insert(key, fs::read(entry.path())?);
+    }
+    Ok(res)
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    name: String,
+    version: String,
+    description: String,
+    downloads_total: u64,
+    downloads_in_last_30_days: u64,
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn search(agent: &Agent, args: crate::SearchArg) -> Result<()> {
+    crate::utils::ensure_online("search the registry")?;
+    let service = mops::Service(crate::utils::registry_id(), agent);
+    let limit = Some(candid::Nat::from(args.limit));
+    let (pkgs, _) = service.search(&args.query, &limit, &None).await?;
+    let results: Vec<_> = pkgs
+        .into_iter()
+        .map(|p| SearchResult {
+            name: p.config.name,
+            version: p.config.version,
+            description: p.config.description,
+            downloads_total: nat_to_u64(&p.downloads_total),
+            downloads_in_last_30_days: nat_to_u64(&p.downloads_in_last_30_days),
+        })
+        .collect();
+    if crate::utils::json() {
+        crate::utils::print_json(&results)?;
+    } else {
+        if results.is_empty() {
+            eprintln!("No packages found for {:?}", args.query);
+        }
+        for r in results {
+            println!(
+                "{} {} {}\n    {}",
+                style(&r.name).green().bold(),
+                style(&r.version).cyan(),
+                style(format!("({} downloads)", r.downloads_total)).dim(),
+                r.description
+            );
+        }
+    }
+    Ok(())
+}
+
+fn nat_to_u64(n: &candid::Nat) -> u64 {
+    n.0.to_string().parse().unwrap_or(u64::MAX)
+}
@@ -0,0 +1,197 @@
+//! Retries of network calls that failed on the way, shared by registry queries, storage
+//! downloads and git host requests, so one dropped connection doesn't abort a long install.
+use crate::config::Config;
+use anyhow::Result;
+use console::style;
+use ic_agent::AgentError;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+struct Policy {
+    /// Tries of a call in total, the first one included
+    attempts: u32,
+    /// The wait after the first failure, doubled after every further one
+    backoff: Duration,
+    /// Add up to as much as the wait at random, so clients failing together don't retry together.
+    jitter: bool,
+}
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+/// Reads `retry_attempts`, `retry_backoff_ms` and `retry_jitter`. Only the first call takes
+/// effect.
+pub(crate) fn configure(config: &Config) -> Result<()> {
+    let _ = POLICY.set(Policy {
+        attempts: config
+            .get_parsed::<u32>("retry_attempts")?
+            .unwrap_or(4)
+            .max(1),
+        backoff: Duration::from_millis(config.get_parsed("retry_backoff_ms")?.unwrap_or(500)),
+        jitter: config.get_parsed::<bool>("retry_jitter")?.unwrap_or(true),
+    });
+    Ok(())
+}
+
+fn policy() -> &'static Policy {
+    POLICY.get_or_init(|| Policy {
+        attempts: 4,
+        backoff: Duration::from_millis(500),
+        jitter: true,
+    })
+}
+
+/// Runs `call` until it succeeds, fails with an error `retryable` rejects, or runs out of
+/// attempts. Returns the last result.
+pub(crate) async fn retry<T, E, Fut>(
+    what: &str,
+    mut call: impl FnMut() -> Fut,
+    retryable: impl Fn(&E) -> bool,
+) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let policy = policy();
+    let mut wait = policy.backoff;
+    for attempt in 1.. {
+        match call().await {
+            Err(e) if attempt < policy.attempts && retryable(&e) => {
+                let jitter = if policy.jitter {
+                    let random = std::collections::hash_map::RandomState::new()
+                        .build_hasher()
+                        .finish();
+                    wait.mul_f64((random % 1000) as f64 / 1000.0)
+                } else {
+                    Duration::ZERO
+                };
+                crate::utils::println(
+                    None,
+                    "stderr",
+                    &format!(
+                        "{:>12} {what} in {}ms, after: {e}",
+                        style("Retrying").yellow().bold(),
+                        (wait + jitter).as_millis()
+                    ),
+                );
+                tokio::time::sleep(wait + jitter).await;
+                wait *= 2;
+            }
+            res => return res,
+        }
+    }
+    unreachable!()
+}
+
+/// Whether a canister call failed on the way or timed out, so sending it again may work. A
+/// reject, an answer that doesn't decode or a refused identity gets the same answer again.
+pub(crate) fn is_transient_call(e: &AgentError) -> bool {
+    match e {
+        AgentError::TransportError(_) | AgentError::TimeoutWaitingForResponse() => true,
+        AgentError::HttpError(payload) => payload.status == 429 || payload.status >= 500,
+        _ => false,
+    }
+}
+
+/// [`is_transient_call`] for the errors of a [`crate::storage::Storage`]. Errors that are not of
+/// a canister call come from other implementations, like the mock, and are taken as transient.
+pub(crate) fn is_transient_storage(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<AgentError>().is_none_or(is_transient_call)
+}
+
+/// Whether an HTTP request failed on the way rather than being answered, so sending it again
+/// may work.
+pub(crate) fn is_transient(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn http(status: u16) -> AgentError {
+        AgentError::HttpError(ic_agent::agent_error::HttpErrorPayload {
+            status,
+            content_type: None,
+            content: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn failures_on_the_way_are_transient() {
+        assert!(is_transient_call(&AgentError::TransportError(
+            "connection reset".into()
+        )));
+        assert!(is_transient_call(&AgentError::TimeoutWaitingForResponse()));
+        assert!(is_transient_call(&http(429)));
+        assert!(is_transient_call(&http(503)));
+    }
+
+    #[test]
+    fn answers_are_not_transient() {
+        assert!(!is_transient_call(&http(400)));
+        assert!(!is_transient_call(&http(404)));
+        assert!(!is_transient_call(&AgentError::MessageError(
+            "cannot decode".to_string()
+        )));
+        assert!(!is_transient_storage(&anyhow::Error::new(http(403))));
+        // Errors of other storages, like the mock, are retried.
+        assert!(is_transient_storage(&anyhow::anyhow!("Injected failure")));
+    }
+
+    /// Runs [`retry`] on a call that fails its first `failures` times with HTTP `status`,
+    /// returning the result and the number of calls.
+    async fn run(failures: u32, status: u16) -> (Result<u32, AgentError>, u32) {
+        let calls = Cell::new(0);
+        let res = retry(
+            "test",
+            || {
+                calls.set(calls.get() + 1);
+                let res = match calls.get() <= failures {
+                    true => Err(http(status)),
+                    false => Ok(calls.get()),
+                };
+                async move { res }
+            },
+            is_transient_call,
+        )
+        .await;
+        (res, calls.get())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_transient_failures_until_one_succeeds() {
+        let (res, calls) = run(3, 503).await;
+        assert_eq!(res.unwrap(), 4);
+        assert_eq!(calls, 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_the_last_attempt() {
+        let start = tokio::time::Instant::now();
+        let (res, calls) = run(10, 503).await;
+        assert!(matches!(res, Err(AgentError::HttpError(_))));
+        assert_eq!(calls, policy().attempts);
+        // 500ms, 1s and 2s between the four attempts, each with up to as much again of jitter.
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(3500), "{waited:?}");
+        assert!(waited < Duration::from_millis(7000), "{waited:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_retry_an_answer() {
+        let (res, calls) = run(10, 404).await;
+        assert!(matches!(res, Err(AgentError::HttpError(p)) if p.status == 404));
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn refused_connections_are_transient() {
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.local_addr().unwrap());
+        drop(server);
+        let refused = reqwest::get(&url).await.unwrap_err();
+        assert!(is_transient(&refused), "{refused}");
+    }
+}
@@ -0,0 +1,112 @@
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::test::TestMode;
+use crate::toml::{
+    download_packages_from_lock, generate_moc_args, update_mops_lock, ResolveOptions,
+};
+use crate::utils::{download_moc, get_cache_dir, get_moc, Pending};
+use anyhow::{anyhow, Context, Result};
+use ic_agent::Agent;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::process::Command;
+use toml_edit::DocumentMut;
+
+/// Opens the manifest block at the top of a script.
+const HEADER: &str = "/* mops.toml";
+
+/// Runs a single-file Motoko script. Its dependencies are declared in a leading block comment
+/// holding a mops.toml:
+///
+/// ```motoko
+/// /* mops.toml
+/// [dependencies]
+/// base = "0.11.1"
+/// */
+/// import Debug "mo:base/Debug";
+/// ```
+///
+/// They are resolved in a throwaway project under `<cache>/scripts`, keyed by the manifest, so
+/// scripts with the same header share one lock.
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn script(agent: &Agent, config: &Config, args: crate::ScriptArg) -> Result<()> {
+    let file = std::path::absolute(&args.file)?;
+    let source =
+        fs::read_to_string(&file).with_context(|| format!("Cannot read {}", file.display()))?;
+    let manifest = parse_header(&source)?;
+    let cache_dir = get_cache_dir(config)?;
+    download_moc(&cache_dir).await?;
+    let key = hex::encode(Sha256::digest(manifest.as_bytes()));
+    let project = cache_dir.join("scripts").join(&key[..16]);
+    fs::create_dir_all(&project)?;
+    let toml = project.join("mops.toml");
+    if fs::read_to_string(&toml).ok().as_deref() != Some(manifest.as_str()) {
+        fs::write(&toml, &manifest)?;
+    }
+    let cwd = std::env::current_dir()?;
+    std::env::set_current_dir(&project)?;
+    let pkgs = async {
+        if has_dependencies(&manifest)? {
+            let pending = Pending::default();
+            update_mops_lock(agent, &pending, &ResolveOptions::from_config(config)?).await?;
+            let algorithm = HashAlgorithm::from_config(config)?;
+            download_packages_from_lock(agent, &cache_dir, &pending, algorithm, false).await?;
+        }
+        generate_moc_args(&cache_dir, false)
+    }
+    .await;
+    std::env::set_current_dir(&cwd)?;
+    let pkgs = pkgs?;
+    let mut moc = get_moc(&cache_dir)?;
+    moc.args(&pkgs);
+    let status = match args.mode {
+        TestMode::Interpreter => moc.arg("-r").arg(&file).status()?,
+        TestMode::Wasi => {
+            let wasm = project.join("script.wasm");
+            moc.arg("-wasi-system-api").arg("-o").arg(&wasm).arg(&file);
+            if !moc.status()?.success() {
+                return Err(anyhow!("Cannot compile {}", args.file.display()));
+            }
+            Command::new("wasmtime")
+                .arg(&wasm)
+                .status()
+                .context("Error executing wasmtime. Is it installed and in PATH?")?
+        }
+    };
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+    Ok(())
+}
+
+/// The mops.toml embedded in `source`, with a `[package]` added if the header has none. Scripts
+/// without a header get a manifest with no dependencies.
+fn parse_header(source: &str) -> Result<String> {
+    let body = match source.trim_start().strip_prefix(HEADER) {
+        Some(rest) => {
+            let end = rest
+                .find("*/")
+                .ok_or_else(|| anyhow!("Unterminated {HEADER} header"))?;
+            &rest[..end]
+        }
+        None => "",
+    };
+    let mut doc = body
+        .parse::<DocumentMut>()
+        .context("Invalid mops.toml header")?;
+    if !doc.contains_key("package") {
+        let mut package = toml_edit::Table::new();
+        package["name"] = toml_edit::value("script");
+        package["version"] = toml_edit::value("0.0.0");
+        doc.insert("package", toml_edit::Item::Table(package));
+    }
+    Ok(doc.to_string())
+}
+
+fn has_dependencies(manifest: &str) -> Result<bool> {
+    let doc = manifest.parse::<DocumentMut>()?;
+    Ok(doc
+        .get("dependencies")
+        .and_then(|d| d.as_table())
+        .is_some_and(|t| !t.is_empty()))
+}
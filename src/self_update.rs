@@ -0,0 +1,125 @@
+use crate::config::Config;
+use crate::github::{get_latest_release, ReleaseAsset};
+use crate::utils::create_spinner_bar;
+use anyhow::{anyhow, Context, Result};
+use console::style;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use semver::Version;
+use sha2::{Digest, Sha256};
+use std::fs;
+
+/// Where release binaries are published, as `<asset>` with a `<asset>.sha256` checksum and a
+/// `<asset>.sig` signature next to it.
+const RELEASE_REPO: &str = "dfinity-ryancroote/mops-cli";
+/// Hex-encoded Ed25519 public key that release binaries are signed with, set by the release
+/// build. The checksum only catches corrupted downloads; the signature proves who built the
+/// binary, so a build without the key cannot update itself.
+const RELEASE_KEY: Option<&str> = option_env!("MOPS_CLI_RELEASE_KEY");
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn update(config: &Config, check: bool) -> Result<()> {
+    crate::github::configure(config)?;
+    let current = Version::parse(env!("CARGO_PKG_VERSION"))?;
+    let release = get_latest_release(RELEASE_REPO).await?;
+    let latest = Version::parse(release.tag_name.trim_start_matches('v'))
+        .with_context(|| format!("Cannot parse release tag {}", release.tag_name))?;
+    if latest <= current {
+        status!(
+            "{:>12} mops-cli {current} is the latest version",
+            style("Up to date").green().bold()
+        );
+        return Ok(());
+    }
+    if check {
+        status!(
+            "{:>12} mops-cli {latest} (you have {current}). Run `mops-cli self update` to install it.",
+            style("Available").cyan().bold()
+        );
+        return Ok(());
+    }
+    let key = RELEASE_KEY.ok_or_else(|| {
+        anyhow!(
+            "This build of mops-cli cannot verify release signatures. Download {latest} from https://github.com/{RELEASE_REPO}/releases instead."
+        )
+    })?;
+    let key = hex::decode(key.trim()).context("Invalid MOPS_CLI_RELEASE_KEY")?;
+    let name = asset_name()?;
+    let find = |name: &str| {
+        release
+            .assets
+            .iter()
+            .find(|a| a.name == name)
+            .ok_or_else(|| anyhow!("Release {latest} has no {name}"))
+    };
+    let binary = find(name)?;
+    let checksum = find(&format!("{name}.sha256"))?;
+    let signature = find(&format!("{name}.sig"))?;
+    let bar = create_spinner_bar(format!("Downloading mops-cli {latest}"));
+    let expected = String::from_utf8(download(checksum).await?)?;
+    let expected = expected
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("Empty checksum file {}", checksum.name))?
+        .to_lowercase();
+    let bytes = download(binary).await?;
+    let actual = hex::encode(Sha256::digest(&bytes));
+    if actual != expected {
+        return Err(anyhow!(
+            "Checksum mismatch for {}: expected {expected}, got {actual}. The current binary was not changed.",
+            binary.name
+        ));
+    }
+    let signature = String::from_utf8(download(signature).await?)?;
+    let signature = hex::decode(signature.trim())
+        .with_context(|| format!("Invalid signature file {name}.sig"))?;
+    UnparsedPublicKey::new(&ED25519, &key)
+        .verify(&bytes, &signature)
+        .map_err(|_| {
+            anyhow!(
+                "The signature of {name} does not match the release key. The current binary was not changed."
+            )
+        })?;
+    bar.set_message("Installing");
+    replace_current_exe(&bytes)?;
+    bar.finish_and_clear();
+    status!(
+        "{:>12} mops-cli {current} -> {latest}",
+        style("Updated").green().bold()
+    );
+    Ok(())
+}
+
+/// The release asset built for this platform.
+fn asset_name() -> Result<&'static str> {
+    match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => Ok("mops-cli-linux64"),
+        ("macos", "x86_64") => Ok("mops-cli-macos"),
+        ("macos", "aarch64") => Ok("mops-cli-macos-arm64"),
+        (os, arch) => Err(anyhow!("No mops-cli releases are built for {os}-{arch}")),
+    }
+}
+
+async fn download(asset: &ReleaseAsset) -> Result<Vec<u8>> {
+    let response = crate::github::client()
+        .get(&asset.browser_download_url)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Writes the new binary next to the running one and renames it over it, so an interrupted update
+/// never leaves a half-written executable behind.
+fn replace_current_exe(bytes: &[u8]) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let exe = fs::canonicalize(&exe).unwrap_or(exe);
+    let tmp = exe.with_extension("new");
+    fs::write(&tmp, bytes).with_context(|| format!("Cannot write {}", tmp.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o755))?;
+    }
+    fs::rename(&tmp, &exe).with_context(|| format!("Cannot replace {}", exe.display()))?;
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use crate::config::Config;
+use crate::daemon::{ask, Request};
+use crate::toml::{generate_moc_args, get_effective_sources};
+use crate::utils::{get_cache_dir, relative_to};
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize)]
+struct Source {
+    name: String,
+    path: String,
+}
+
+/// Prints the moc package flags, one flag per line, as dfx expects from a packtool. A running
+/// daemon answers from memory.
+pub fn sources(config: &Config, args: crate::SourcesArg) -> Result<()> {
+    let cache_dir = get_cache_dir(config)?;
+    let cwd = std::env::current_dir()?;
+    let show = |path: &Path| {
+        if args.relative {
+            relative_to(&cwd.join(path), &cwd)
+        } else {
+            path.to_path_buf()
+        }
+    };
+    if args.json || crate::utils::json() {
+        let request = Request::Sources {
+            cache_dir: cache_dir.clone(),
+        };
+        let sources: Vec<(String, PathBuf)> = match ask(request) {
+            Some(sources) => sources,
+            None => get_effective_sources(&cache_dir, false)?,
+        };
+        let sources: Vec<_> = sources
+            .into_iter()
+            .map(|(name, path)| Source {
+                name,
+                path: show(&path).to_string_lossy().to_string(),
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&sources)?);
+        return Ok(());
+    }
+    let request = Request::MocArgs {
+        cache_dir: cache_dir.clone(),
+    };
+    let args: Vec<String> = match ask(request) {
+        Some(args) => args,
+        None => generate_moc_args(&cache_dir, false)?,
+    };
+    let mut lines: Vec<Vec<String>> = Vec::new();
+    for arg in args {
+        if arg.starts_with("--") {
+            lines.push(vec![arg]);
+        } else if let Some(line) = lines.last_mut() {
+            // Every value after the flag name is a path, except the package or alias name.
+            let arg = if line.len() == 1 && line[0] != "--actor-idl" {
+                arg
+            } else {
+                show(Path::new(&arg)).to_string_lossy().to_string()
+            };
+            line.push(arg);
+        }
+    }
+    for line in lines {
+        println!("{}", line.join(" "));
+    }
+    Ok(())
+}
@@ -0,0 +1,244 @@
+use crate::config::Config;
+use crate::integrity::verify_dir;
+use crate::mops;
+use crate::toml::{load_mops_lock_or_default, Package, PackageType, LOCAL_OVERRIDES};
+use crate::utils::{get_cache_dir, is_offline};
+use anyhow::Result;
+use console::style;
+use futures::future::join_all;
+use ic_agent::Agent;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use toml_edit::ImDocument;
+use walkdir::WalkDir;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum State {
+    Installed,
+    /// Not in mops.lock, or not downloaded yet
+    Missing,
+    /// The downloaded files don't match the hash in mops.lock
+    HashMismatch,
+    /// A newer version is published. Only checked online.
+    UpdateAvailable,
+    /// Replaced by `.mops/local/<name>` or `[patch]`
+    Overridden,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Dependency {
+    name: String,
+    /// `dependencies` or `dev-dependencies`
+    table: String,
+    /// 1-based line of the entry in mops.toml
+    line: Option<usize>,
+    requirement: String,
+    locked: Option<String>,
+    path: Option<PathBuf>,
+    states: Vec<State>,
+    /// The newest published version, when it is newer than the locked one
+    latest: Option<String>,
+}
+
+/// Hash checks of package directories, remembered with the size and modification time of every
+/// entry, so that only packages whose files changed are hashed again. The daemon keeps one for
+/// its lifetime.
+#[derive(Default)]
+pub(crate) struct Verified(HashMap<PathBuf, (String, Vec<Stamp>, bool)>);
+type Stamp = (PathBuf, u64, Option<SystemTime>);
+
+impl Verified {
+    fn verify(&mut self, dir: &Path, sub: &str, expected: &str) -> Result<bool> {
+        let root = dir.join(sub);
+        let stamps = stamps(&root)?;
+        if let Some((hash, known, ok)) = self.0.get(&root) {
+            if hash == expected && *known == stamps {
+                return Ok(*ok);
+            }
+        }
+        let ok = verify_dir(dir, sub, expected)?;
+        self.0.insert(root, (expected.to_string(), stamps, ok));
+        Ok(ok)
+    }
+}
+
+fn stamps(root: &Path) -> Result<Vec<Stamp>> {
+    if !root.exists() {
+        return Ok(Vec::new());
+    }
+    let mut stamps = Vec::new();
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        stamps.push((entry.into_path(), meta.len(), meta.modified().ok()));
+    }
+    Ok(stamps)
+}
+
+/// Reports the state of every dependency in mops.toml, for people and for editor extensions.
+/// The local part comes from the daemon when one is running.
+pub fn status(agent: &Agent, config: &Config, args: crate::StatusArg) -> Result<()> {
+    let cache_dir = get_cache_dir(config)?;
+    let request = crate::daemon::Request::Dependencies {
+        cache_dir: cache_dir.clone(),
+    };
+    let mut deps = match crate::daemon::ask(request) {
+        Some(deps) => deps,
+        None => dependencies(&cache_dir, &mut Verified::default())?,
+    };
+    if !is_offline() {
+        add_latest(agent, &mut deps)?;
+    }
+    if args.json || crate::utils::json() {
+        println!("{}", serde_json::to_string_pretty(&deps)?);
+        return Ok(());
+    }
+    for dep in &deps {
+        print_dependency(dep);
+    }
+    Ok(())
+}
+
+/// The state of every dependency in mops.toml, except whether a newer version is published.
+pub(crate) fn dependencies(cache_dir: &Path, verified: &mut Verified) -> Result<Vec<Dependency>> {
+    let lock = load_mops_lock_or_default()?;
+    let cache_dir = lock.package_root(cache_dir);
+    let str = fs::read_to_string("mops.toml")?;
+    let doc = str.parse::<ImDocument<_>>()?;
+    let mut deps = Vec::new();
+    for table in ["dependencies", "dev-dependencies"] {
+        let Some(entries) = doc.get(table).and_then(|t| t.as_table()) else {
+            continue;
+        };
+        for (key, item) in entries.iter() {
+            let line = entries
+                .key(key)
+                .and_then(|k| k.span())
+                .map(|span| str[..span.start].matches('\n').count() + 1);
+            let requirement = match item.as_str() {
+                Some(s) => s.to_string(),
+                None => item.to_string().trim().to_string(),
+            };
+            let pkg = lock.package.iter().find(|p| p.name == key);
+            deps.push(inspect(
+                key,
+                table,
+                line,
+                requirement,
+                pkg,
+                &cache_dir,
+                verified,
+            )?);
+        }
+    }
+    Ok(deps)
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+async fn add_latest(agent: &Agent, deps: &mut [Dependency]) -> Result<()> {
+    let lock = load_mops_lock_or_default()?;
+    let service = mops::Service(crate::utils::registry_id(), agent);
+    let latest = join_all(deps.iter().map(|dep| {
+        let pkg = lock.package.iter().find(|p| p.name == dep.name);
+        latest_version(&service, pkg)
+    }))
+    .await;
+    for (dep, latest) in deps.iter_mut().zip(latest) {
+        if let Some(latest) = latest {
+            dep.states.push(State::UpdateAvailable);
+            dep.latest = Some(latest);
+        }
+    }
+    Ok(())
+}
+
+fn inspect(
+    name: &str,
+    table: &str,
+    line: Option<usize>,
+    requirement: String,
+    pkg: Option<&Package>,
+    cache_dir: &Path,
+    verified: &mut Verified,
+) -> Result<Dependency> {
+    let mut dep = Dependency {
+        name: name.to_string(),
+        table: table.to_string(),
+        line,
+        requirement,
+        locked: pkg.and_then(|p| p.version.clone()),
+        path: None,
+        states: Vec::new(),
+        latest: None,
+    };
+    let Some(pkg) = pkg else {
+        dep.states.push(State::Missing);
+        return Ok(dep);
+    };
+    let local = Path::new(LOCAL_OVERRIDES).join(name);
+    if local.is_dir() {
+        dep.path = Some(local);
+        dep.states.push(State::Overridden);
+        return Ok(dep);
+    }
+    if pkg.patched {
+        dep.states.push(State::Overridden);
+    }
+    let path = cache_dir.join(pkg.get_path());
+    let state = if !path.join(pkg.get_done_file()).exists() {
+        State::Missing
+    } else {
+        match &pkg.integrity {
+            Some(expected) if !verified.verify(&path, &pkg.hashed_dir(), expected)? => {
+                State::HashMismatch
+            }
+            _ => State::Installed,
+        }
+    };
+    dep.path = Some(path);
+    dep.states.push(state);
+    Ok(dep)
+}
+
+/// The highest published version of a locked registry package, if newer than the locked one.
+async fn latest_version(service: &mops::Service<'_>, pkg: Option<&Package>) -> Option<String> {
+    let pkg = pkg?;
+    let PackageType::Mops { ver, .. } = pkg.get_type() else {
+        return None;
+    };
+    let latest = crate::metadata::highest_version(service, pkg.registry_name())
+        .await
+        .ok()?
+        .ok()?;
+    let newer = latest.parse::<Version>().ok()? > ver.parse::<Version>().ok()?;
+    newer.then_some(latest)
+}
+
+fn print_dependency(dep: &Dependency) {
+    let verb = if dep.states.contains(&State::HashMismatch) {
+        style("Corrupted").red().bold()
+    } else if dep.states.contains(&State::Missing) {
+        style("Missing").red().bold()
+    } else if dep.states.contains(&State::Overridden) {
+        style("Overridden").yellow().bold()
+    } else {
+        style("Installed").green().bold()
+    };
+    let mut line = format!("{verb:>12} {}", dep.name);
+    match &dep.locked {
+        Some(locked) => line.push_str(&format!(" {locked}")),
+        None => line.push_str(&format!(" {}", dep.requirement)),
+    }
+    if dep.table == "dev-dependencies" {
+        line.push_str(" (dev)");
+    }
+    if let Some(latest) = &dep.latest {
+        line.push_str(&format!(", {} available", style(latest).cyan()));
+    }
+    println!("{line}");
+}
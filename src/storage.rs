@@ -1,45 +1,188 @@
-// This is an experimental feature to generate Rust binding from Candid.
-// You may want to manually adjust some of the types.
-#![allow(dead_code, unused_imports)]
-use candid::{self, CandidType, Decode, Deserialize, Encode, Principal};
-type Result<T> = std::result::Result<T, ic_agent::AgentError>;
-
-pub type FileId1 = String;
-pub type Chunk = serde_bytes::ByteBuf;
-pub type Err1 = String;
-pub type Result8 = candid::MotokoResult<Chunk, Err1>;
-pub type FileId2 = String;
-#[derive(CandidType, Deserialize)]
-pub struct FileMeta {
-    pub id: FileId2,
-    pub owners: Vec<Principal>,
-    pub path: String,
-    #[serde(rename = "chunkCount")]
-    pub chunk_count: u128,
-}
-pub type Result7 = candid::MotokoResult<FileMeta, Err1>;
-
-pub struct Service<'a>(pub Principal, pub &'a ic_agent::Agent);
-impl<'a> Service<'a> {
-    pub async fn download_chunk(&self, arg0: &FileId1, arg1: &candid::Nat) -> Result<Result8> {
-        let args = Encode!(&arg0, &arg1)?;
-        let bytes = self
-            .1
-            .query(&self.0, "downloadChunk")
-            .with_arg(args)
-            .call()
-            .await?;
-        Ok(Decode!(&bytes, Result8)?)
-    }
-    pub async fn get_file_meta(&self, arg0: &FileId1) -> Result<Result7> {
-        let args = Encode!(&arg0)?;
-        let bytes = self
-            .1
-            .query(&self.0, "getFileMeta")
-            .with_arg(args)
-            .call()
-            .await?;
-        Ok(Decode!(&bytes, Result7)?)
-    }
-}
-pub const CANISTER_ID: Principal = Principal::from_slice(&[0, 0, 0, 0, 1, 144, 3, 243, 1, 1]); // gl576-4yaaa-aaaam-qapzq-cai
+//! Downloads from the storage canister, on top of the generated [`crate::bindings::storage`].
+pub use crate::bindings::storage::*;
+use crate::retry::{is_transient_storage, retry};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// The calls made to the storage canister, so downloads can run against something other than
+/// the IC. See `mock::MockStorage`, with the `mock-storage` feature.
+#[allow(async_fn_in_trait)]
+pub trait Storage {
+    async fn get_file_meta(&self, id: &FileId1) -> anyhow::Result<Result7>;
+    async fn download_chunk(&self, id: &FileId1, index: &candid::Nat) -> anyhow::Result<Result8>;
+}
+impl Storage for Service<'_> {
+    async fn get_file_meta(&self, id: &FileId1) -> anyhow::Result<Result7> {
+        Ok(Service::get_file_meta(self, id).await?)
+    }
+    async fn download_chunk(&self, id: &FileId1, index: &candid::Nat) -> anyhow::Result<Result8> {
+        Ok(Service::download_chunk(self, id, index).await?)
+    }
+}
+
+/// Chunks of one file downloaded at the same time.
+const CHUNKS_IN_FLIGHT: usize = 8;
+
+async fn file_meta<S: Storage>(storage: &S, id: &str) -> anyhow::Result<FileMeta> {
+    let id = id.to_string();
+    let what = format!("file {id}");
+    retry(&what, || storage.get_file_meta(&id), is_transient_storage)
+        .await?
+        .into_result()
+        .map_err(anyhow::Error::msg)
+}
+
+/// The chunks of a file in order, downloading up to [`CHUNKS_IN_FLIGHT`] ahead.
+fn chunks<'a, S: Storage>(
+    storage: &'a S,
+    id: &'a str,
+    meta: &FileMeta,
+) -> impl Stream<Item = anyhow::Result<Chunk>> + 'a {
+    let id = id.to_string();
+    stream::iter(0..meta.chunk_count)
+        .map(move |i| {
+            let id = id.clone();
+            async move {
+                let index = i.into();
+                let what = format!("chunk {i} of file {id}");
+                retry(
+                    &what,
+                    || storage.download_chunk(&id, &index),
+                    is_transient_storage,
+                )
+                .await?
+                .into_result()
+                .map_err(anyhow::Error::msg)
+            }
+        })
+        .buffered(CHUNKS_IN_FLIGHT)
+}
+
+/// Downloads every chunk of a file, returning its path and content.
+pub async fn fetch_file<S: Storage>(storage: &S, id: &str) -> anyhow::Result<(String, Vec<u8>)> {
+    let meta = file_meta(storage, id).await?;
+    let blob = chunks(storage, id, &meta)
+        .try_fold(Vec::new(), |mut blob, chunk| async move {
+            blob.extend(chunk.into_vec());
+            Ok(blob)
+        })
+        .await?;
+    Ok((meta.path, blob))
+}
+/// Downloads a file to its path under `base_path`, writing chunks as they arrive so only a few
+/// are in memory. The file is written next to its destination and renamed when complete, so an
+/// interrupted download never leaves a truncated file behind.
+pub async fn download_file<S: Storage>(
+    storage: &S,
+    id: &str,
+    base_path: &Path,
+) -> anyhow::Result<PathBuf> {
+    let meta = file_meta(storage, id).await?;
+    let path = base_path.join(&meta.path);
+    write_file(&path, chunks(storage, id, &meta)).await?;
+    Ok(path)
+}
+
+async fn write_file(
+    path: &Path,
+    chunks: impl Stream<Item = anyhow::Result<impl AsRef<[u8]>>>,
+) -> anyhow::Result<()> {
+    let dir = path.parent().unwrap();
+    tokio::fs::create_dir_all(dir).await?;
+    let name = path.file_name().unwrap().to_string_lossy();
+    let tmp = dir.join(format!(".{name}.part"));
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    let mut chunks = std::pin::pin!(chunks);
+    while let Some(chunk) = chunks.try_next().await? {
+        file.write_all(chunk.as_ref()).await?;
+    }
+    file.flush().await?;
+    drop(file);
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[cfg(any(test, feature = "mock-storage"))]
+pub mod mock;
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockStorage;
+    use super::*;
+    use std::time::Duration;
+
+    const ID: &str = "base@0.1.0/src/Array.mo";
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn fetch_file_joins_chunks_in_order() {
+        let storage =
+            MockStorage::new()
+                .with_chunk_size(4)
+                .with_file(ID, "src/Array.mo", content(10));
+        let (path, blob) = fetch_file(&storage, ID).await.unwrap();
+        assert_eq!(path, "src/Array.mo");
+        assert_eq!(blob, content(10));
+        // The meta, then one request per chunk.
+        assert_eq!(storage.requests(), 4);
+    }
+
+    #[tokio::test]
+    async fn fetch_file_of_an_empty_file() {
+        let storage = MockStorage::new().with_file(ID, "src/Array.mo", Vec::new());
+        let (_, blob) = fetch_file(&storage, ID).await.unwrap();
+        assert!(blob.is_empty());
+        assert_eq!(storage.requests(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetch_file_retries_failed_calls() {
+        let storage = MockStorage::new()
+            .with_chunk_size(4)
+            .with_latency(Duration::from_millis(20))
+            .with_file(ID, "src/Array.mo", content(10));
+        storage.fail(ID, None, 1);
+        storage.fail(ID, Some(1), 2);
+        let (_, blob) = fetch_file(&storage, ID).await.unwrap();
+        assert_eq!(blob, content(10));
+        assert_eq!(storage.requests(), 4 + 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetch_file_gives_up_after_the_last_attempt() {
+        let storage =
+            MockStorage::new()
+                .with_chunk_size(4)
+                .with_file(ID, "src/Array.mo", content(10));
+        storage.fail(ID, Some(2), 100);
+        let err = fetch_file(&storage, ID).await.unwrap_err();
+        assert!(format!("{err:#}").contains("Injected failure"), "{err:#}");
+        assert_eq!(storage.requests(), 1 + 2 + 4);
+    }
+
+    #[tokio::test]
+    async fn fetch_file_does_not_retry_answers_of_the_canister() {
+        let storage = MockStorage::new();
+        let err = fetch_file(&storage, ID).await.unwrap_err();
+        assert_eq!(err.to_string(), "File not found");
+        assert_eq!(storage.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn download_file_writes_the_file_in_place() {
+        let dir = std::env::temp_dir().join(format!("mops-storage-test-{}", std::process::id()));
+        let storage =
+            MockStorage::new()
+                .with_chunk_size(3)
+                .with_file(ID, "src/Array.mo", content(8));
+        let path = download_file(&storage, ID, &dir).await.unwrap();
+        assert_eq!(path, dir.join("src/Array.mo"));
+        assert_eq!(std::fs::read(&path).unwrap(), content(8));
+        assert!(!dir.join("src/.Array.mo.part").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
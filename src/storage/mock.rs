@@ -0,0 +1,123 @@
+//! An in-process storage canister, for demos and tests that shouldn't depend on the IC.
+use super::{Chunk, FileId1, FileMeta, Result7, Result8, Storage};
+use anyhow::anyhow;
+use candid::{MotokoResult, Nat, Principal};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+struct File {
+    path: String,
+    content: Vec<u8>,
+}
+
+/// Serves files from memory in chunks, like the storage canister. Every request waits for the
+/// configured latency first, and failures can be injected per file and chunk.
+pub struct MockStorage {
+    files: HashMap<FileId1, File>,
+    chunk_size: usize,
+    latency: Duration,
+    /// Remaining failures per `(file, chunk)`. A chunk of `None` fails the file meta request.
+    failures: Mutex<HashMap<(FileId1, Option<u128>), usize>>,
+    requests: AtomicUsize,
+}
+
+impl Default for MockStorage {
+    fn default() -> Self {
+        MockStorage {
+            files: HashMap::new(),
+            chunk_size: 1024 * 1024,
+            latency: Duration::ZERO,
+            failures: Mutex::new(HashMap::new()),
+            requests: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl MockStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_file(mut self, id: &str, path: &str, content: impl Into<Vec<u8>>) -> Self {
+        let file = File {
+            path: path.to_string(),
+            content: content.into(),
+        };
+        self.files.insert(id.to_string(), file);
+        self
+    }
+    /// Size of the chunks files are split into. Defaults to 1 MiB.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk size must be positive");
+        self.chunk_size = chunk_size;
+        self
+    }
+    /// Delay before every response.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+    /// Makes the next `times` requests for chunk `index` of `id` fail as if the call didn't reach
+    /// the canister. An `index` of `None` fails the file meta request instead.
+    pub fn fail(&self, id: &str, index: Option<u128>, times: usize) {
+        let mut failures = self.failures.lock().unwrap();
+        *failures.entry((id.to_string(), index)).or_default() += times;
+    }
+    /// Number of requests served so far, failed ones included.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    async fn respond(&self, id: &FileId1, index: Option<u128>) -> anyhow::Result<()> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        if let Some(left) = failures.get_mut(&(id.clone(), index)) {
+            if *left > 0 {
+                *left -= 1;
+                return Err(anyhow!(
+                    "Injected failure for {id}{}",
+                    index.map(|i| format!(" chunk {i}")).unwrap_or_default()
+                ));
+            }
+        }
+        Ok(())
+    }
+    fn chunk_count(&self, file: &File) -> u128 {
+        file.content.len().div_ceil(self.chunk_size) as u128
+    }
+}
+
+impl Storage for MockStorage {
+    async fn get_file_meta(&self, id: &FileId1) -> anyhow::Result<Result7> {
+        self.respond(id, None).await?;
+        let Some(file) = self.files.get(id) else {
+            return Ok(MotokoResult::err("File not found".to_string()));
+        };
+        Ok(MotokoResult::ok(FileMeta {
+            id: id.clone(),
+            owners: vec![Principal::anonymous()],
+            path: file.path.clone(),
+            chunk_count: self.chunk_count(file),
+        }))
+    }
+    async fn download_chunk(&self, id: &FileId1, index: &Nat) -> anyhow::Result<Result8> {
+        let index = u128::try_from(index.0.clone())
+            .map_err(|_| anyhow!("Chunk index {index} out of range"))?;
+        self.respond(id, Some(index)).await?;
+        let Some(file) = self.files.get(id) else {
+            return Ok(MotokoResult::err("File not found".to_string()));
+        };
+        if index >= self.chunk_count(file) {
+            return Ok(MotokoResult::err("Chunk not found".to_string()));
+        }
+        let start = index as usize * self.chunk_size;
+        let end = (start + self.chunk_size).min(file.content.len());
+        Ok(MotokoResult::ok(Chunk::from(
+            file.content[start..end].to_vec(),
+        )))
+    }
+}
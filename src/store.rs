@@ -0,0 +1,116 @@
+use crate::config::Config;
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use walkdir::WalkDir;
+
+/// How packages from the global store are made available in a cache directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LinkMode {
+    /// Hard link every file. Falls back to copying across file systems.
+    Hardlink,
+    /// Link the whole package directory
+    Symlink,
+    /// Don't use the store, download straight into the cache directory
+    Off,
+}
+impl FromStr for LinkMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hardlink" => Ok(LinkMode::Hardlink),
+            "symlink" => Ok(LinkMode::Symlink),
+            "off" => Ok(LinkMode::Off),
+            _ => Err(anyhow!(
+                "unknown link mode {s}, expected hardlink, symlink or off"
+            )),
+        }
+    }
+}
+
+/// The global store: immutable `mops/{name}-{ver}` and `git/{repo}/{commit}` directories,
+/// downloaded once per machine and linked into every cache directory that needs them.
+pub(crate) struct Store {
+    pub dir: PathBuf,
+    mode: LinkMode,
+}
+static STORE: OnceLock<Option<Store>> = OnceLock::new();
+
+/// Reads `store_dir` and `link_mode`. Only the first call takes effect.
+pub(crate) fn configure(config: &Config) -> Result<()> {
+    let _ = STORE.set(Store::from_config(config)?);
+    Ok(())
+}
+
+pub(crate) fn get() -> Option<&'static Store> {
+    STORE.get().and_then(|s| s.as_ref())
+}
+
+impl Store {
+    /// The store `config` names, or `None` when it is off or is the cache directory itself.
+    pub(crate) fn from_config(config: &Config) -> Result<Option<Store>> {
+        let mode = config
+            .get_parsed::<LinkMode>("link_mode")?
+            .unwrap_or(LinkMode::Hardlink);
+        Ok(match (config.get("store_dir"), config.get("cache_dir")) {
+            (Some(dir), cache) if mode != LinkMode::Off => {
+                let dir = PathBuf::from(dir);
+                let same = cache.is_some_and(|cache| {
+                    fs::canonicalize(cache).ok() == fs::canonicalize(&dir).ok() && dir.exists()
+                });
+                (!same).then_some(Store { dir, mode })
+            }
+            _ => None,
+        })
+    }
+    /// Makes the complete package at `stored` appear at `dest`, including its `DONE*` markers.
+    pub(crate) fn link(&self, stored: &Path, dest: &Path) -> Result<()> {
+        link_package(stored, dest, self.mode)
+    }
+}
+
+/// Makes the package at `stored` appear at `dest` the way `mode` says.
+pub(crate) fn link_package(stored: &Path, dest: &Path, mode: LinkMode) -> Result<()> {
+    match mode {
+        LinkMode::Symlink => {
+            if dest.is_symlink() {
+                return Ok(());
+            }
+            if dest.exists() {
+                fs::remove_dir_all(dest)?;
+            }
+            fs::create_dir_all(dest.parent().unwrap())?;
+            symlink_dir(stored, dest).with_context(|| format!("Cannot link {}", dest.display()))?;
+        }
+        LinkMode::Hardlink | LinkMode::Off => {
+            for entry in WalkDir::new(stored) {
+                let entry = entry?;
+                let to = dest.join(entry.path().strip_prefix(stored)?);
+                if entry.file_type().is_dir() {
+                    fs::create_dir_all(&to)?;
+                    continue;
+                }
+                if to.exists() {
+                    continue;
+                }
+                // Markers are copied, so that each cache directory tracks its own last use.
+                let marker = entry.file_name().to_string_lossy().starts_with("DONE");
+                if marker || fs::hard_link(entry.path(), &to).is_err() {
+                    fs::copy(entry.path(), &to)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+}
+#[cfg(windows)]
+fn symlink_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_dir(src, dst)
+}
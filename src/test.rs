@@ -0,0 +1,266 @@
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::toml::{download_packages_from_lock, generate_moc_args};
+use crate::utils::{create_spinner_bar, download_moc, get_cache_dir, get_moc, Pending};
+use crate::workspace::lock_path;
+use crate::workspace::members;
+use anyhow::{anyhow, Context, Result};
+use clap::{CommandFactory, ValueEnum};
+use console::style;
+use ic_agent::Agent;
+use ignore::WalkBuilder;
+use indicatif::HumanDuration;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum TestMode {
+    /// Run with the moc interpreter
+    #[default]
+    Interpreter,
+    /// Compile to a WASI module and run it with wasmtime
+    Wasi,
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn test(agent: &Agent, config: &Config, args: crate::TestArg) -> Result<()> {
+    let cache_dir = get_cache_dir(config)?;
+    download_moc(&cache_dir).await?;
+    if lock_path().exists() && !args.no_install {
+        let algorithm = HashAlgorithm::from_config(config)?;
+        download_packages_from_lock(agent, &cache_dir, &Pending::default(), algorithm, false)
+            .await?;
+    }
+    let pkgs = generate_moc_args(&cache_dir, true)?;
+    let files = find_test_files(Path::new("."), args.filter.as_deref())?;
+    if files.is_empty() {
+        status!("No *.test.mo files found");
+        return Ok(());
+    }
+    let start = Instant::now();
+    let mut failed = Vec::new();
+    for file in &files {
+        let bar = create_spinner_bar(format!(
+            "{:>12} {}",
+            style("Running").cyan(),
+            file.display()
+        ));
+        let output = run_test(&cache_dir, file, &pkgs, args.mode)?;
+        bar.finish_and_clear();
+        if output.status.success() {
+            status!("{:>12} {}", style("PASS").green().bold(), file.display());
+        } else {
+            status!("{:>12} {}", style("FAIL").red().bold(), file.display());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            for line in stdout.lines().chain(stderr.lines()) {
+                status!("             {line}");
+            }
+            failed.push(file);
+        }
+    }
+    let passed = files.len() - failed.len();
+    status!(
+        "\n{:>12} {passed} passed, {} failed in {}",
+        style("Tests").bold(),
+        failed.len(),
+        HumanDuration(start.elapsed())
+    );
+    if !failed.is_empty() {
+        return Err(anyhow!("{} test(s) failed", failed.len()));
+    }
+    Ok(())
+}
+
+/// Runs the tests of every workspace member in parallel, each in its own `mops test` process
+/// started in the member's directory, so build outputs never collide. Output lines are prefixed
+/// with `[member]`. The packages are installed once before, so the members don't race on the
+/// cache.
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn test_workspace(agent: &Agent, config: &Config, args: crate::TestArg) -> Result<()> {
+    let cache_dir = get_cache_dir(config)?;
+    download_moc(&cache_dir).await?;
+    if lock_path().exists() {
+        let algorithm = HashAlgorithm::from_config(config)?;
+        download_packages_from_lock(agent, &cache_dir, &Pending::default(), algorithm, false)
+            .await?;
+    }
+    let members = members(Path::new("."))?;
+    if members.is_empty() {
+        status!("The workspace has no members");
+        return Ok(());
+    }
+    let exe = std::env::current_exe()?;
+    let mut forwarded = vec![
+        "test".to_string(),
+        "--no-install".to_string(),
+        "--mode".to_string(),
+    ];
+    forwarded.push(
+        args.mode
+            .to_possible_value()
+            .unwrap()
+            .get_name()
+            .to_string(),
+    );
+    forwarded.push("--cache-dir".to_string());
+    forwarded.push(std::path::absolute(&cache_dir)?.display().to_string());
+    // Switches like `--offline` are passed on without their value.
+    let command = crate::NetworkArg::command();
+    for (flag, value) in args.network.flags() {
+        let Some(value) = value else { continue };
+        forwarded.push(format!("--{}", flag.replace('_', "-")));
+        let takes_value = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == flag)
+            .is_some_and(|arg| arg.get_action().takes_values());
+        if takes_value {
+            forwarded.push(value);
+        }
+    }
+    if let Some(filter) = &args.filter {
+        forwarded.push(filter.clone());
+    }
+    let jobs = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let slots = Arc::new(Semaphore::new(jobs));
+    let start = Instant::now();
+    let mut set = JoinSet::new();
+    for member in members.iter().cloned() {
+        let mut cmd = tokio::process::Command::new(&exe);
+        cmd.args(&forwarded)
+            .current_dir(&member.dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let slots = slots.clone();
+        set.spawn(async move {
+            let _slot = slots.acquire_owned().await?;
+            let started = Instant::now();
+            let status = run_prefixed(cmd, &member.name).await?;
+            Ok::<_, anyhow::Error>((member.name, status.success(), started.elapsed()))
+        });
+    }
+    let mut results = Vec::new();
+    while let Some(res) = set.join_next().await {
+        let (name, passed, time) = res??;
+        results.push((name, passed, time));
+        if !passed && args.fail_fast {
+            set.abort_all();
+            break;
+        }
+    }
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    status!();
+    for (name, passed, time) in &results {
+        let verdict = if *passed {
+            style("PASS").green().bold()
+        } else {
+            style("FAIL").red().bold()
+        };
+        status!("{verdict:>12} {name} in {}", HumanDuration(*time));
+    }
+    let failed = results.iter().filter(|r| !r.1).count();
+    let skipped = members.len() - results.len();
+    let mut msg = format!(
+        "\n{:>12} {} member(s) passed, {failed} failed",
+        style("Workspace").bold(),
+        results.len() - failed
+    );
+    if skipped > 0 {
+        msg.push_str(&format!(", {skipped} cancelled"));
+    }
+    status!("{msg} in {}", HumanDuration(start.elapsed()));
+    if failed > 0 {
+        return Err(anyhow!("{failed} workspace member(s) failed"));
+    }
+    Ok(())
+}
+
+/// Runs `cmd`, echoing its stdout and stderr line by line with a `[name]` prefix.
+async fn run_prefixed(
+    mut cmd: tokio::process::Command,
+    name: &str,
+) -> Result<std::process::ExitStatus> {
+    let mut child = cmd.spawn().context("Cannot start mops test")?;
+    let prefix = style(format!("[{name}]")).cyan().to_string();
+    let stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+    let (out, err) = tokio::join!(
+        echo_lines(stdout, &prefix, false),
+        echo_lines(stderr, &prefix, true)
+    );
+    out?;
+    err?;
+    Ok(child.wait().await?)
+}
+
+async fn echo_lines<R: AsyncBufRead + Unpin>(
+    mut lines: Lines<R>,
+    prefix: &str,
+    stderr: bool,
+) -> std::io::Result<()> {
+    while let Some(line) = lines.next_line().await? {
+        if stderr {
+            eprintln!("{prefix} {line}");
+        } else {
+            status!("{prefix} {line}");
+        }
+    }
+    Ok(())
+}
+
+/// Finds `*.test.mo` files under `root`, respecting .gitignore and skipping `.mops`.
+pub fn find_test_files(root: &Path, filter: Option<&str>) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let walker = WalkBuilder::new(root)
+        .filter_entry(|e| e.file_name() != ".mops")
+        .build();
+    for entry in walker {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy();
+        if !name.ends_with(".test.mo") {
+            continue;
+        }
+        if filter.is_some_and(|f| !path.to_string_lossy().contains(f)) {
+            continue;
+        }
+        files.push(path.strip_prefix(root).unwrap_or(path).to_path_buf());
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn run_test(cache_dir: &Path, file: &Path, pkgs: &[String], mode: TestMode) -> Result<Output> {
+    let mut moc = get_moc(cache_dir)?;
+    moc.args(pkgs);
+    match mode {
+        TestMode::Interpreter => {
+            moc.arg("-r").arg(file);
+            moc.output()
+                .with_context(|| format!("Error executing {:#?}", moc))
+        }
+        TestMode::Wasi => {
+            let wasm = Path::new(".mops/test").join(file.with_extension("wasm"));
+            std::fs::create_dir_all(wasm.parent().unwrap())?;
+            moc.arg("-wasi-system-api").arg("-o").arg(&wasm).arg(file);
+            let output = moc
+                .output()
+                .with_context(|| format!("Error executing {:#?}", moc))?;
+            if !output.status.success() {
+                return Ok(output);
+            }
+            let mut wasmtime = Command::new("wasmtime");
+            wasmtime.arg(&wasm);
+            wasmtime
+                .output()
+                .context("Error executing wasmtime. Is it installed and in PATH?")
+        }
+    }
+}
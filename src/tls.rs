@@ -0,0 +1,132 @@
+//! Extra root certificates and certificate pinning, for networks that intercept or restrict TLS.
+use crate::config::Config;
+use anyhow::{anyhow, Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Applies `tls_ca_file`, a PEM bundle of roots to trust besides the built-in ones, and
+/// `tls_pins`, comma-separated `host=fingerprint` pairs. A fingerprint is the SHA-256 of a
+/// certificate as printed by `openssl x509 -noout -fingerprint -sha256`; connections to a pinned
+/// host only succeed if its chain contains a pinned certificate.
+pub(crate) fn configure(
+    builder: reqwest::ClientBuilder,
+    config: &Config,
+) -> Result<reqwest::ClientBuilder> {
+    let roots = match config.get("tls_ca_file") {
+        Some(file) => CertificateDer::pem_file_iter(file)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| anyhow!("{e}"))
+            .with_context(|| format!("Cannot read the certificates in tls_ca_file {file}"))?,
+        None => Vec::new(),
+    };
+    let pins = match config.get("tls_pins") {
+        Some(pins) => parse_pins(pins)?,
+        None => BTreeMap::new(),
+    };
+    if pins.is_empty() {
+        let mut builder = builder;
+        for root in &roots {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_der(root)?);
+        }
+        return Ok(builder);
+    }
+    // Pinning needs a verifier of our own, which only rustls lets us plug in.
+    let mut store = RootCertStore::empty();
+    store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    for root in roots {
+        store.add(root)?;
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let inner =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(store), provider.clone()).build()?;
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinningVerifier { inner, pins }))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(builder.use_preconfigured_tls(tls))
+}
+
+fn parse_pins(str: &str) -> Result<BTreeMap<String, Vec<Vec<u8>>>> {
+    let mut res: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for pin in str.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (host, fingerprint) = pin
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid tls_pins entry {pin:?}, expected host=fingerprint"))?;
+        let fingerprint = hex::decode(fingerprint.trim().replace(':', ""))
+            .ok()
+            .filter(|bytes| bytes.len() == 32)
+            .ok_or_else(|| anyhow!("Invalid SHA-256 fingerprint in tls_pins entry {pin:?}"))?;
+        res.entry(host.trim().to_ascii_lowercase())
+            .or_default()
+            .push(fingerprint);
+    }
+    Ok(res)
+}
+
+/// Verifies as usual, then also requires a pinned certificate in the chain of pinned hosts.
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: BTreeMap<String, Vec<Vec<u8>>>,
+}
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let ServerName::DnsName(host) = server_name else {
+            return Ok(verified);
+        };
+        let Some(pins) = self.pins.get(&host.as_ref().to_ascii_lowercase()) else {
+            return Ok(verified);
+        };
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|cert| pins.iter().any(|pin| pin[..] == Sha256::digest(cert)[..]));
+        if !pinned {
+            return Err(rustls::Error::General(format!(
+                "no certificate of {} matches tls_pins",
+                host.as_ref()
+            )));
+        }
+        Ok(verified)
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
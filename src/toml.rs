@@ -1,46 +1,232 @@
 use crate::build::MotokoImport;
-use crate::github::{download_github_package, fetch_file, parse_github_url, RepoInfo};
+use crate::coalesce::Coalesce;
+use crate::config::Config;
+use crate::github::{download_github_package, fetch_file, parse_github_url, RefKind, RepoInfo};
+use crate::integrity::{hash_dir, verify_dir, HashAlgorithm};
+use crate::workspace::{lock_dir, lock_path};
 use crate::{
     mops, storage,
-    utils::{create_bar, println},
+    utils::{create_bar, ensure_online, is_offline, println, Pending},
 };
 use anyhow::{anyhow, Error, Result};
 use candid::Principal;
 use console::style;
-use futures::future::try_join_all;
+use futures::future::{join_all, try_join_all};
+use futures::stream::{FuturesUnordered, StreamExt};
 use ic_agent::Agent;
 use indicatif::ProgressBar;
-use semver::Version;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fs;
+use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use tokio::sync::Semaphore;
 use toml_edit::{value, DocumentMut, ImDocument};
+use walkdir::WalkDir;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Package {
-    name: String,
-    version: Option<String>,
-    source: String,
-    base_dir: String,
-    repo: Option<RepoInfo>,
-    dependencies: Vec<String>,
+/// Packages in here shadow the locked package of the same name when compiling.
+pub(crate) const LOCAL_OVERRIDES: &str = ".mops/local";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Package {
+    /// The name the package is imported under.
+    pub name: String,
+    /// The registry name, when the dependency is renamed with `{ package = "...", version = "..." }`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    pub version: Option<String>,
+    pub source: String,
+    /// The registry mirror the package was resolved from, when the registries before it failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    pub base_dir: String,
+    pub repo: Option<RepoInfo>,
+    pub dependencies: Vec<String>,
+    /// `<algorithm>:<hex>` hash of the downloaded files, recorded after the first download and
+    /// trusted from then on.
+    pub integrity: Option<String>,
+    /// Directory under the cache root, recorded at resolution. Older locks don't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Only needed by `[dev-dependencies]`, so left out of build flags.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dev: bool,
+    /// Replaced by an entry of `[patch]` in mops.toml, instead of what its dependents asked for.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub patched: bool,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Canister {
     canister_id: String,
     name: Option<String>,
     timestamp: Option<String>,
     candid: String,
 }
-#[derive(Default, Serialize, Deserialize)]
-struct Packages {
-    package: Vec<Package>,
+/// Knobs for resolving mops.toml into mops.lock.
+#[derive(Default)]
+pub(crate) struct ResolveOptions {
+    /// Pin the dependencies of registry packages to the versions in the lock their author published.
+    pub use_author_lock: bool,
+    /// Fail instead of warning when a package requires a newer moc than the project's toolchain.
+    pub strict: bool,
+    pub normalization: Normalization,
+    /// Fail instead of changing mops.toml or mops.lock.
+    pub locked: bool,
+    /// Look up the commits of repository dependencies that follow a branch again, instead of
+    /// keeping the locked ones.
+    pub refresh_branches: bool,
+    /// Pins to start from instead of mops.lock, e.g. both sides of a merge conflict in it.
+    pub pins: Option<Packages>,
+    /// Content of mops.toml to resolve instead of the file, so it is only written once it
+    /// resolves.
+    pub manifest: Option<String>,
+}
+impl ResolveOptions {
+    pub(crate) fn from_config(config: &Config) -> Result<Self> {
+        Ok(ResolveOptions {
+            normalization: config.get_parsed("name_normalization")?.unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+}
+/// How package names and versions become directory names in the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Normalization {
+    /// Lowercase, with anything but `a-z0-9._-` replaced. A name changed this way gets a short
+    /// hash of the original appended, so it can't collide with another name.
+    #[default]
+    Safe,
+    /// Use names as they are
+    None,
+}
+impl std::str::FromStr for Normalization {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "safe" => Ok(Normalization::Safe),
+            "none" => Ok(Normalization::None),
+            _ => Err(anyhow!(
+                "unknown name normalization {s}, expected safe or none"
+            )),
+        }
+    }
+}
+impl Normalization {
+    fn component(self, s: &str) -> String {
+        if self == Normalization::None {
+            return s.to_string();
+        }
+        let mut res: String = s
+            .chars()
+            .map(|c| match c.to_ascii_lowercase() {
+                c @ ('a'..='z' | '0'..='9' | '.' | '_' | '-') => c,
+                _ => '_',
+            })
+            .collect();
+        if res.starts_with('.') || res.is_empty() {
+            res.insert(0, '_');
+        }
+        if res != s {
+            res.push_str(&format!("~{}", short_hash(s)));
+        }
+        res
+    }
+    /// Where a registry package lives under the cache root.
+    pub(crate) fn mops_path(self, name: &str, version: &str) -> String {
+        format!("mops/{}-{}", self.component(name), self.component(version))
+    }
+}
+fn short_hash(s: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(&Sha256::digest(s.as_bytes())[..4])
+}
+/// Records an install path for every package that doesn't have one yet. Two packages that would
+/// share a directory are told apart by a hash of their key.
+fn assign_paths(pkgs: &mut [Package], normalization: Normalization) {
+    let mut used: BTreeMap<String, String> = pkgs
+        .iter()
+        .filter(|p| p.path.is_some())
+        .map(|p| (p.get_path(), p.get_key()))
+        .collect();
+    for pkg in pkgs.iter_mut() {
+        if pkg.path.is_some() || matches!(pkg.get_type(), PackageType::Local(_)) {
+            continue;
+        }
+        let key = pkg.get_key();
+        let mut path = match pkg.get_type() {
+            PackageType::Mops { ver, .. } => normalization.mops_path(pkg.registry_name(), ver),
+            PackageType::Repo(repo) => format!(
+                "git/{}/{}",
+                normalization.component(&repo.location().replace('/', "-")),
+                repo.short_commit()
+            ),
+            PackageType::Local(_) => unreachable!(),
+        };
+        if used.get(&path).is_some_and(|other| *other != key) {
+            path = format!("{path}~{}", short_hash(&key));
+        }
+        used.insert(path.clone(), key);
+        pkg.path = Some(path);
+    }
+}
+/// Marks the packages that can't be reached from `roots`, the names of the regular dependencies,
+/// as only needed by dev-dependencies.
+fn mark_dev(pkgs: &mut [Package], roots: &[String]) {
+    let names: Vec<_> = pkgs.iter().map(|p| p.name.clone()).collect();
+    let mut reached = BTreeSet::new();
+    let mut stack: Vec<String> = roots.to_vec();
+    while let Some(name) = stack.pop() {
+        if !reached.insert(name.clone()) {
+            continue;
+        }
+        let Some(pkg) = pkgs.iter().find(|p| p.name == name) else {
+            continue;
+        };
+        // Dependencies are recorded as `{name}-{requirement}`, and names can contain `-`.
+        for dep in &pkg.dependencies {
+            if let Some(dep) = names
+                .iter()
+                .filter(|n| dep.starts_with(&format!("{n}-")))
+                .max_by_key(|n| n.len())
+            {
+                stack.push(dep.clone());
+            }
+        }
+    }
+    for pkg in pkgs {
+        pkg.dev = !reached.contains(&pkg.name);
+    }
+}
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Packages {
+    #[serde(default)]
+    pub package: Vec<Package>,
     canister: Option<Vec<Canister>>,
+    /// Directory the packages were copied to by `mops vendor`, relative to the lock.
+    pub vendor: Option<String>,
+}
+impl Packages {
+    fn is_empty(&self) -> bool {
+        self.package.is_empty() && self.canister.as_deref().unwrap_or_default().is_empty()
+    }
+    pub(crate) fn vendor_dir(&self) -> Option<PathBuf> {
+        self.vendor.as_ref().map(|dir| lock_dir().join(dir))
+    }
+    /// Where the packages of this lock are installed: the vendor directory, or else `cache_dir`.
+    pub(crate) fn package_root(&self, cache_dir: &Path) -> PathBuf {
+        self.vendor_dir().unwrap_or_else(|| cache_dir.to_path_buf())
+    }
 }
 
-pub async fn update_mops_toml(agent: &Agent, libs: BTreeSet<MotokoImport>) -> Result<()> {
+pub async fn update_mops_toml(
+    agent: &Agent,
+    libs: BTreeSet<MotokoImport>,
+    pending: &Pending,
+    options: &ResolveOptions,
+) -> Result<()> {
     let mops = Path::new("mops.toml");
     let mut doc = if mops.exists() {
         let str = fs::read_to_string(mops)?;
@@ -48,7 +234,7 @@ pub async fn update_mops_toml(agent: &Agent, libs: BTreeSet<MotokoImport>) -> Re
     } else {
         DocumentMut::new()
     };
-    let service = mops::Service(mops::CANISTER_ID, agent);
+    let service = mops::Service(crate::utils::registry_id(), agent);
     if doc.get("dependencies").is_none() {
         doc["dependencies"] = toml_edit::table();
     }
@@ -56,13 +242,18 @@ pub async fn update_mops_toml(agent: &Agent, libs: BTreeSet<MotokoImport>) -> Re
         doc["canister"] = toml_edit::array();
     }
     let mut unknown_libs = Vec::new();
+    let mut missing = Vec::new();
     for lib in libs {
         match lib {
             MotokoImport::Lib(lib) => {
                 if doc["dependencies"].get(&lib).is_some() {
                     continue;
                 }
-                let version = service.get_highest_version(&lib).await?.into_result();
+                if options.locked {
+                    missing.push(lib);
+                    continue;
+                }
+                let version = crate::metadata::highest_version(&service, &lib).await?;
                 match version {
                     Ok(version) => {
                         println(
@@ -97,6 +288,10 @@ pub async fn update_mops_toml(agent: &Agent, libs: BTreeSet<MotokoImport>) -> Re
                 }) {
                     continue;
                 }
+                if options.locked {
+                    missing.push(format!("canister {id}"));
+                    continue;
+                }
                 let mut table = toml_edit::Table::new();
                 println(
                     None,
@@ -112,26 +307,75 @@ pub async fn update_mops_toml(agent: &Agent, libs: BTreeSet<MotokoImport>) -> Re
             MotokoImport::Local(_) => (),
         }
     }
-    fs::write(mops, doc.to_string())?;
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "mops.toml is missing {} but --locked was passed. Add them or run without --locked.",
+            missing.join(", ")
+        ));
+    }
+    if !options.locked {
+        fs::write(mops, doc.to_string())?;
+    }
     if !unknown_libs.is_empty() {
         return Err(anyhow!("The following imports cannot be found on mops. Please manually add it to mops.toml:\n{unknown_libs:?}"));
     }
-    update_mops_lock(agent).await?;
+    update_mops_lock(agent, pending, options).await?;
     Ok(())
 }
-async fn update_mops_lock(agent: &Agent) -> Result<()> {
-    let lock = Path::new("mops.lock");
-    let pkgs = parse_mops_lock(lock).unwrap_or_default();
-    let mut map: BTreeMap<_, _> = pkgs.package.into_iter().map(|p| (p.get_key(), p)).collect();
+pub async fn update_mops_lock(
+    agent: &Agent,
+    pending: &Pending,
+    options: &ResolveOptions,
+) -> Result<()> {
+    let pkgs = resolve_mops_lock(agent, pending, options).await?;
+    write_mops_lock(&lock_path(), &pkgs)
+}
+/// Resolves mops.toml against the existing mops.lock, without writing anything.
+pub(crate) async fn resolve_mops_lock(
+    agent: &Agent,
+    pending: &Pending,
+    options: &ResolveOptions,
+) -> Result<Packages> {
+    let lock = &lock_path();
+    let pkgs = match &options.pins {
+        Some(pins) => pins.clone(),
+        None => {
+            if fs::read_to_string(lock).is_ok_and(|str| has_conflict_markers(&str)) {
+                return Err(anyhow!(
+                    "mops.lock has merge conflicts. Run `mops lock resolve` to regenerate it."
+                ));
+            }
+            parse_mops_lock(lock).unwrap_or_default()
+        }
+    };
+    let vendor = pkgs.vendor;
+    let mut map: BTreeMap<_, _> = pkgs
+        .package
+        .into_iter()
+        .filter(|p| !(options.refresh_branches && p.follows_branch()))
+        .map(|p| (p.get_key(), p))
+        .collect();
     let mut canisters: BTreeMap<_, _> = pkgs
         .canister
         .unwrap_or_default()
         .into_iter()
         .map(|c| (c.get_key(), c))
         .collect();
-    let str = fs::read_to_string(Path::new("mops.toml"))?;
-    let toml = parse_mops_toml(&str)?;
-    let service = mops::Service(mops::CANISTER_ID, agent);
+    let toml = match (&options.manifest, crate::workspace::root()) {
+        (Some(manifest), _) => parse_mops_toml(manifest)?,
+        (None, Some(root)) => parse_workspace(root)?,
+        (None, None) => parse_mops_toml(
+            &fs::read_to_string(Path::new("mops.toml"))
+                .map_err(|e| anyhow!("Cannot read mops.toml in the current directory: {e}"))?,
+        )?,
+    };
+    let service = mops::Service(crate::utils::registry_id(), agent);
+    let lookups = Lookups::default();
+    if let Some(req) = &toml.cli_requirement {
+        check_cli("This project", req)?;
+    }
+    check_registry_cli(agent).await?;
+    let toolchain = get_toolchain_moc()?.and_then(|v| parse_version(&v));
     let bar = create_bar(toml.dependencies.len() + toml.canisters.len());
     bar.set_prefix("Updating mops.lock");
     for canister in toml.canisters {
@@ -145,6 +389,10 @@ async fn update_mops_lock(agent: &Agent) -> Result<()> {
             (None, candid)
         } else {
             use std::time::SystemTime;
+            ensure_online(format_args!(
+                "fetch the interface of canister {}",
+                canister.canister_id
+            ))?;
             // TODO handle aaaaa-aa
             let id = Principal::from_text(&canister.canister_id)?;
             let candid = String::from_utf8(
@@ -173,154 +421,646 @@ async fn update_mops_lock(agent: &Agent) -> Result<()> {
         bar.inc(1);
     }
 
-    let mut queue = toml.dependencies.into_iter().collect::<VecDeque<_>>();
-    // TODO: maintain a map between mops to resolved package.get_key, so we can rewrite dependencies entry at the end
-    while let Some(m) = queue.pop_front() {
-        let pkg = match m {
-            Mops::Mops { name, version } => {
-                bar.set_message(name.clone());
-                if map.contains_key(&format!("{name}-{version}")) {
-                    bar.inc(1);
-                    continue;
-                }
-                let pkg = service
-                    .get_package_details(&name, &version)
-                    .await?
-                    .into_result()
-                    .map_err(Error::msg)?;
-                let source = pkg.publication.storage.to_string();
-                let base_dir = pkg.config.base_dir;
-                let dependencies = pkg
-                    .config
-                    .dependencies
-                    .into_iter()
-                    .map(|d| {
-                        let name = d.name;
-                        let mops = if d.version.is_empty() {
-                            Mops::Repo { name, repo: d.repo }
-                        } else {
-                            Mops::Mops {
-                                name,
-                                version: d.version,
-                            }
-                        };
-                        bar.inc_length(1);
-                        let key = mops.get_display_key();
-                        queue.push_back(mops);
-                        key
-                    })
-                    .collect();
-                Package {
-                    name,
-                    version: Some(version),
-                    source,
-                    base_dir,
-                    repo: None,
-                    dependencies,
-                }
-            }
-            Mops::Repo { name, repo } => {
-                bar.set_message(name.clone());
-                let repo_info = parse_github_url(&repo).await?;
-                if map.contains_key(&format!("{}-{}-{}", name, repo_info.repo, repo_info.commit)) {
-                    bar.inc(1);
-                    continue;
-                }
-                let mut version = None;
-                let dependencies = if let Ok(str) = fetch_file(&repo_info, "mops.toml").await {
-                    let mops = parse_mops_toml(&str)?;
-                    version = mops.version;
-                    // TODO remove Mops::Local
-                    mops.dependencies
-                        .into_iter()
-                        .map(|m| {
-                            let key = m.get_display_key();
-                            bar.inc_length(1);
-                            queue.push_back(m);
-                            key
-                        })
-                        .collect()
-                } else {
-                    Vec::new()
-                };
-                if version.is_none() {
-                    version = repo_info.guess_version();
-                }
-                Package {
-                    name,
-                    version,
-                    source: "github".to_string(),
-                    base_dir: repo_info.base_dir.clone(),
-                    repo: Some(repo_info),
-                    dependencies,
-                }
+    let patches: BTreeMap<_, _> = toml
+        .patches
+        .into_iter()
+        .map(|m| (m.name().to_string(), m))
+        .collect();
+    // Every requirement of a patched package, direct or transitive, is replaced by the patch.
+    let patch = |m: Mops| patches.get(m.name()).cloned().unwrap_or(m);
+    // Locked versions from before a patch was added or removed would conflict with the new ones.
+    map.retain(|_, p| patches.contains_key(&p.name) == p.patched);
+    let roots: Vec<_> = toml
+        .dependencies
+        .iter()
+        .map(|m| m.name().to_string())
+        .collect();
+    let queue = toml
+        .dependencies
+        .into_iter()
+        .chain(toml.dev_dependencies)
+        .map(|m| (patch(m), Vec::new()))
+        .collect();
+    let map = RefCell::new(map);
+    let (service, lookups, map_ref, bar_ref) = (&service, &lookups, &map, &bar);
+    let toolchain = toolchain.as_ref();
+    let lookup =
+        move |m| resolve_package(service, lookups, m, map_ref, bar_ref, options, toolchain);
+    resolve_graph(&map, queue, patch, lookup, pending, &bar).await?;
+    let map = map.into_inner();
+    bar.finish_and_clear();
+    let mut pkgs = resolve_versions(map)?;
+    assign_paths(&mut pkgs, options.normalization);
+    mark_dev(&mut pkgs, &roots);
+    for (name, m) in &patches {
+        match pkgs.iter_mut().find(|p| p.name == *name) {
+            Some(pkg) => {
+                pkg.patched = true;
+                println(
+                    None,
+                    "stdout",
+                    &format!(
+                        "{:>12} {name} with {}",
+                        style("Patched").yellow().bold(),
+                        m.requirement()
+                    ),
+                );
             }
-            Mops::Local { name, path } => {
-                bar.set_message(name.clone());
-                let toml = Path::new(&path).join("mops.toml");
-                let canonicalized = fs::canonicalize(path)?;
-                if map.contains_key(&format!("{name}-{}", canonicalized.display())) {
-                    bar.inc(1);
-                    continue;
-                }
-                let source = format!("file://{}", canonicalized.display());
-                let mut version = None;
-                let mops = if toml.exists() {
-                    let str = fs::read_to_string(toml)?;
-                    let mops = parse_mops_toml(&str)?;
-                    version = mops.version;
-                    mops.dependencies
-                } else {
-                    Vec::new()
-                };
-                Package {
-                    name,
-                    version,
-                    source,
-                    base_dir: "src".to_string(),
-                    repo: None,
-                    dependencies: mops
-                        .into_iter()
-                        .map(|m| {
-                            let key = m.get_display_key();
-                            bar.inc_length(1);
-                            queue.push_back(m);
-                            key
-                        })
-                        .collect(),
-                }
+            None => println(
+                None,
+                "stderr",
+                &format!(
+                    "{:>12} [patch] {name}, nothing depends on it",
+                    style("Unused").yellow().bold()
+                ),
+            ),
+        }
+    }
+    let pkgs = Packages {
+        package: pkgs,
+        canister: Some(canisters.into_values().collect()),
+        vendor,
+    };
+    if options.locked {
+        ensure_lock_unchanged(lock, &pkgs)?;
+    }
+    Ok(pkgs)
+}
+/// Looks up `queue` and everything it depends on into `map`, where `lookup` resolves a single
+/// requirement like [`resolve_package`]. Up to `--jobs` packages are looked up at once.
+/// Requirements queued twice share their lookups, and the second result is dropped once the
+/// first is in `map`.
+async fn resolve_graph<Fut>(
+    map: &RefCell<BTreeMap<String, Package>>,
+    mut queue: VecDeque<(Mops, Vec<String>)>,
+    patch: impl Fn(Mops) -> Mops,
+    lookup: impl Fn(Mops) -> Fut,
+    pending: &Pending,
+    bar: &ProgressBar,
+) -> Result<()>
+where
+    Fut: Future<Output = Result<Option<(Package, Vec<Mops>)>>>,
+{
+    // What mops.lock already pins, so a failure report only lists what this run resolved.
+    let locked: BTreeSet<String> = map.borrow().keys().cloned().collect();
+    let total_roots = queue.len();
+    for (m, _) in queue.iter() {
+        pending.start(&m.get_display_key());
+    }
+    // TODO: maintain a map between mops to resolved package.get_key, so we can rewrite dependencies entry at the end
+    let mut in_flight = FuturesUnordered::new();
+    loop {
+        while in_flight.len() < crate::utils::jobs() {
+            let Some((m, path)) = queue.pop_front() else {
+                break;
+            };
+            bar.set_message(m.name().to_string());
+            let key = m.get_display_key();
+            let resolved = lookup(m);
+            in_flight.push(async move { (key, path, resolved.await) });
+        }
+        let Some((key, path, resolved)) = in_flight.next().await else {
+            break;
+        };
+        pending.finish(&key);
+        let resolved = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                bar.finish_and_clear();
+                let map = map.borrow();
+                let queued = queue.len() + in_flight.len();
+                return Err(e.context(resolution_report(
+                    &key,
+                    &path,
+                    &map,
+                    &locked,
+                    queued,
+                    total_roots,
+                )));
             }
         };
-        assert!(map.insert(pkg.get_key(), pkg).is_none());
+        let Some((mut pkg, deps)) = resolved else {
+            bar.inc(1);
+            continue;
+        };
+        if map.borrow().contains_key(&pkg.get_key()) {
+            bar.inc(1);
+            continue;
+        }
+        for dep in deps {
+            let dep = patch(dep);
+            let dep_key = dep.get_display_key();
+            pkg.dependencies.push(dep_key.clone());
+            pending.start(&dep_key);
+            bar.inc_length(1);
+            let mut dep_path = path.clone();
+            dep_path.push(key.clone());
+            queue.push_back((dep, dep_path));
+        }
+        map.borrow_mut().insert(pkg.get_key(), pkg);
         bar.inc(1);
     }
-    bar.finish_and_clear();
-    let pkgs = resolve_versions(map)?;
+    Ok(())
+}
+pub(crate) fn render_mops_lock(pkgs: &Packages) -> Result<String> {
     let mut res = DocumentMut::new();
+    if let Some(vendor) = &pkgs.vendor {
+        res.insert("vendor", value(vendor));
+    }
     let mut pkg_array = toml_edit::ArrayOfTables::new();
-    for p in pkgs {
-        let d = toml_edit::ser::to_document(&p)?;
+    for p in &pkgs.package {
+        let d = toml_edit::ser::to_document(p)?;
         pkg_array.push(d.as_table().clone());
     }
     res.insert("package", toml_edit::Item::ArrayOfTables(pkg_array));
     let mut can_array = toml_edit::ArrayOfTables::new();
-    for c in canisters.into_values() {
-        let d = toml_edit::ser::to_document(&c)?;
+    for c in pkgs.canister.iter().flatten() {
+        let d = toml_edit::ser::to_document(c)?;
         can_array.push(d.as_table().clone());
     }
     res.insert("canister", toml_edit::Item::ArrayOfTables(can_array));
-    use std::io::Write;
-    let mut buf = fs::File::create(lock)?;
-    buf.write_all(
-        b"# This file is auto-generated by mops.\n# It is not intended for manual editing.\n\n",
-    )?;
-    buf.write_all(res.to_string().as_bytes())?;
+    Ok(format!(
+        "# This file is auto-generated by mops.\n# It is not intended for manual editing.\n\n{res}"
+    ))
+}
+pub(crate) fn write_mops_lock(lock: &Path, pkgs: &Packages) -> Result<()> {
+    // A project without dependencies needs no lock until it gets some.
+    if pkgs.is_empty() && !lock.exists() {
+        return Ok(());
+    }
+    let content = render_mops_lock(pkgs)?;
+    crate::history::record(lock, &content)?;
+    fs::write(lock, content)?;
     Ok(())
 }
+/// Fails when writing `pkgs` would change `lock`, naming the packages that differ, or when
+/// installing it would, to record missing hashes.
+fn ensure_lock_unchanged(lock: &Path, pkgs: &Packages) -> Result<()> {
+    let Ok(old) = fs::read_to_string(lock) else {
+        if pkgs.is_empty() {
+            return Ok(());
+        }
+        return Err(anyhow!(
+            "{} is missing and --locked was passed. Run install without --locked to create it.",
+            lock.display()
+        ));
+    };
+    // Recording a missing hash after the download would change the lock as well.
+    let unhashed: Vec<_> = pkgs
+        .package
+        .iter()
+        .filter(|p| p.integrity.is_none() && !matches!(p.get_type(), PackageType::Local(_)))
+        .map(Package::get_key)
+        .collect();
+    if !unhashed.is_empty() {
+        return Err(anyhow!(
+            "{} has no integrity for these packages but --locked was passed:\n  {}\nRun install without --locked to record it.",
+            lock.display(),
+            unhashed.join("\n  ")
+        ));
+    }
+    if old == render_mops_lock(pkgs)? {
+        return Ok(());
+    }
+    let old: BTreeSet<_> = parse_mops_lock(lock)
+        .map(|p| p.package.iter().map(Package::get_key).collect())
+        .unwrap_or_default();
+    let new: BTreeSet<_> = pkgs.package.iter().map(Package::get_key).collect();
+    let mut changes: Vec<_> = new.difference(&old).map(|k| format!("+ {k}")).collect();
+    changes.extend(old.difference(&new).map(|k| format!("- {k}")));
+    let details = if changes.is_empty() {
+        String::new()
+    } else {
+        format!(":\n  {}", changes.join("\n  "))
+    };
+    Err(anyhow!(
+        "{} needs to be updated but --locked was passed{details}\nRun install without --locked to update it.",
+        lock.display()
+    ))
+}
+/// Fails when a package in mops.lock isn't in the cache yet, for `--frozen`.
+pub(crate) fn ensure_cached(root: &Path) -> Result<()> {
+    let lock = load_mops_lock_or_default()?;
+    let root = &lock.package_root(root);
+    let missing: Vec<_> = lock
+        .package
+        .iter()
+        .filter(|pkg| !matches!(pkg.get_type(), PackageType::Local(_)))
+        .filter(|pkg| !root.join(pkg.get_path()).join(pkg.get_done_file()).exists())
+        .map(Package::get_key)
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "--frozen was passed, but {} package(s) need to be downloaded:\n  {}",
+        missing.len(),
+        missing.join("\n  ")
+    ))
+}
+/// Registry and GitHub lookups made during one resolution. Packages required by several others
+/// are often queued again before their first lookup finishes, and then share it.
+#[derive(Default)]
+struct Lookups<'a> {
+    /// Along with the mirror that answered, `None` for the primary registry.
+    details: Coalesce<'a, (mops::PackageDetails, Option<Principal>)>,
+    repos: Coalesce<'a, RepoInfo>,
+    /// mops.toml of a repo at a commit, if it has one.
+    manifests: Coalesce<'a, Option<String>>,
+}
+/// Fetches the metadata of a single requirement, returning the package and its direct dependencies.
+/// Returns `None` if the package is already in `map`.
+async fn resolve_package<'a>(
+    service: &'a mops::Service<'a>,
+    lookups: &Lookups<'a>,
+    m: Mops,
+    map: &RefCell<BTreeMap<String, Package>>,
+    bar: &ProgressBar,
+    options: &ResolveOptions,
+    toolchain: Option<&Version>,
+) -> Result<Option<(Package, Vec<Mops>)>> {
+    let res = match m {
+        Mops::Mops {
+            name,
+            version,
+            package,
+        } => {
+            if map.borrow().contains_key(&format!("{name}-{version}")) {
+                return Ok(None);
+            }
+            let alias = package.as_ref().map(|_| name.clone());
+            let name = package.unwrap_or(name);
+            ensure_online(format_args!(
+                "resolve {name}@{version}, which is not in mops.lock"
+            ))?;
+            let key = format!("{name}@{version}");
+            let details = lookups
+                .details
+                .get(key, {
+                    let (name, version) = (name.clone(), version.clone());
+                    async move {
+                        crate::metadata::sourced_package_details(service, &name, &version).await
+                    }
+                })
+                .await?;
+            let (pkg, mirror) = (&details.0, details.1);
+            let requirement = |key: &str| {
+                pkg.config
+                    .requirements
+                    .iter()
+                    .find(|r| r.name == key)
+                    .map(|r| r.value.as_str())
+            };
+            if let Some(req) = requirement("mops-cli") {
+                check_cli(&format!("{name}@{version}"), req)?;
+            }
+            let moc = requirement("moc");
+            check_compiler(&name, &version, moc, toolchain, options.strict, bar)?;
+            let source = pkg.publication.storage.to_string();
+            let base_dir = pkg.config.base_dir.clone();
+            let author_lock = if options.use_author_lock {
+                let registry = crate::mirrors::registry(service, mirror);
+                fetch_author_lock(&registry, &name, &version, pkg.publication.storage).await?
+            } else {
+                None
+            };
+            if author_lock.is_some() {
+                println(
+                    Some(bar),
+                    "stdout",
+                    &format!(
+                        "{:>12} author lock of {name}@{version}",
+                        style("Using").green().bold()
+                    ),
+                );
+            }
+            let deps = pkg
+                .config
+                .dependencies
+                .iter()
+                .map(|d| {
+                    let name = d.name.clone();
+                    if d.version.is_empty() {
+                        let repo = d.repo.clone();
+                        return Mops::Repo {
+                            name,
+                            repo,
+                            subdir: None,
+                            reference: None,
+                        };
+                    }
+                    let locked = author_lock.as_ref().and_then(|lock| {
+                        lock.package
+                            .iter()
+                            .find(|p| p.name == name && p.repo.is_none() && p.package.is_none())
+                            .and_then(|p| p.version.clone())
+                    });
+                    Mops::Mops {
+                        name,
+                        version: locked.unwrap_or_else(|| d.version.clone()),
+                        package: None,
+                    }
+                })
+                .collect();
+            let (name, package) = match alias {
+                Some(alias) => (alias, Some(name)),
+                None => (name, None),
+            };
+            let pkg = Package {
+                name,
+                package,
+                version: Some(version),
+                source,
+                registry: mirror.map(|id| id.to_text()),
+                base_dir,
+                repo: None,
+                dependencies: Vec::new(),
+                integrity: None,
+                path: None,
+                dev: false,
+                patched: false,
+            };
+            (pkg, deps)
+        }
+        Mops::Repo {
+            name,
+            repo,
+            subdir,
+            reference,
+        } => {
+            let url = match &reference {
+                // A full commit id needs no lookup.
+                Some((RefKind::Rev, rev)) if rev.len() == 40 => format!("{repo}#{rev}@{rev}"),
+                Some((_, reference)) => format!("{repo}#{reference}"),
+                None => repo,
+            };
+            // A locked commit is kept without looking the ref up, which also works offline.
+            let locked = map.borrow().values().any(|p| {
+                p.name == name
+                    && p.repo
+                        .as_ref()
+                        .is_some_and(|r| crate::github::is_locked_as(&url, subdir.as_deref(), r))
+            });
+            if locked {
+                return Ok(None);
+            }
+            let repo_info = lookups
+                .repos
+                .get(url.clone(), async move { parse_github_url(&url).await })
+                .await?;
+            let mut repo_info = RepoInfo::clone(&repo_info);
+            if subdir.is_some() {
+                repo_info.subdir = subdir;
+            }
+            repo_info.kind = reference.map(|(kind, _)| kind);
+            if map.borrow().contains_key(&format!(
+                "{}-{}-{}",
+                name,
+                repo_info.location(),
+                repo_info.commit
+            )) {
+                return Ok(None);
+            }
+            let mut version = None;
+            let key = format!(
+                "{}@{}/{}",
+                repo_info.location(),
+                repo_info.commit,
+                repo_info.source_dir()
+            );
+            let manifest = lookups.manifests.get(key, {
+                let repo_info = repo_info.clone();
+                async move { Ok(fetch_file(&repo_info, "mops.toml").await.ok()) }
+            });
+            let deps = if let Some(str) = manifest.await?.as_ref() {
+                let mops = parse_mops_toml(str)?;
+                let display = repo_info
+                    .guess_version()
+                    .unwrap_or(repo_info.commit.clone());
+                if let Some(req) = &mops.cli_requirement {
+                    check_cli(&format!("{name}@{display}"), req)?;
+                }
+                check_compiler(
+                    &name,
+                    &display,
+                    mops.moc_requirement.as_deref(),
+                    toolchain,
+                    options.strict,
+                    bar,
+                )?;
+                version = mops.version;
+                // TODO remove Mops::Local
+                mops.dependencies
+            } else {
+                Vec::new()
+            };
+            if version.is_none() {
+                version = repo_info.guess_version();
+            }
+            let pkg = Package {
+                name,
+                package: None,
+                version,
+                source: "github".to_string(),
+                registry: None,
+                base_dir: repo_info.source_dir(),
+                repo: Some(repo_info),
+                dependencies: Vec::new(),
+                integrity: None,
+                path: None,
+                dev: false,
+                patched: false,
+            };
+            (pkg, deps)
+        }
+        Mops::Local { name, path } => {
+            let toml = Path::new(&path).join("mops.toml");
+            let canonicalized = fs::canonicalize(path)?;
+            if map
+                .borrow()
+                .contains_key(&format!("{name}-{}", canonicalized.display()))
+            {
+                return Ok(None);
+            }
+            // Relative to the lock, so the lock works wherever the project is checked out.
+            let relative = crate::utils::relative_to(&canonicalized, &lock_dir());
+            let source = format!(
+                "file://{}",
+                relative.display().to_string().replace('\\', "/")
+            );
+            let mut version = None;
+            let deps = if toml.exists() {
+                let str = fs::read_to_string(toml)?;
+                let mops = parse_mops_toml(&str)?;
+                version = mops.version;
+                mops.dependencies
+            } else {
+                Vec::new()
+            };
+            let pkg = Package {
+                name,
+                package: None,
+                version,
+                source,
+                registry: None,
+                base_dir: "src".to_string(),
+                repo: None,
+                dependencies: Vec::new(),
+                integrity: None,
+                path: None,
+                dev: false,
+                patched: false,
+            };
+            (pkg, deps)
+        }
+    };
+    Ok(Some(res))
+}
+/// Parses a minimum version, or a semver requirement such as `^1.2`.
+fn parse_requirement(requirement: &str) -> Result<VersionReq, semver::Error> {
+    if requirement.starts_with(|c: char| c.is_ascii_digit()) {
+        VersionReq::parse(&format!(">={requirement}"))
+    } else {
+        VersionReq::parse(requirement)
+    }
+}
+/// Fails if `who` needs a newer mops-cli than this one. Metadata written for a newer CLI may mean
+/// something this version cannot understand, so resolving anyway could silently pick wrong versions.
+fn check_cli(who: &str, requirement: &str) -> Result<()> {
+    let current = Version::parse(env!("CARGO_PKG_VERSION"))?;
+    let req = parse_requirement(requirement)
+        .map_err(|_| anyhow!("{who} has a mops-cli requirement {requirement:?} that this mops-cli ({current}) cannot read. Please upgrade mops-cli."))?;
+    if !req.matches(&current) {
+        return Err(anyhow!(
+            "{who} requires mops-cli {requirement}, but this is mops-cli {current}. Please upgrade mops-cli."
+        ));
+    }
+    Ok(())
+}
+/// The registry can announce a minimum CLI version in its `mops:min-cli-version` metadata.
+/// Registries without it, or that cannot be reached for it, impose nothing.
+async fn check_registry_cli(agent: &Agent) -> Result<()> {
+    if is_offline() {
+        return Ok(());
+    }
+    let Ok(blob) = agent
+        .read_state_canister_metadata(crate::utils::registry_id(), "mops:min-cli-version")
+        .await
+    else {
+        return Ok(());
+    };
+    let requirement = String::from_utf8_lossy(&blob);
+    if requirement.trim().is_empty() {
+        return Ok(());
+    }
+    check_cli("The mops registry", requirement.trim())
+}
+/// Compares a package's moc requirement (a minimum version, or a semver requirement) with the
+/// project's pinned toolchain. Nothing is checked when the project does not pin moc.
+fn check_compiler(
+    name: &str,
+    version: &str,
+    requirement: Option<&str>,
+    toolchain: Option<&Version>,
+    strict: bool,
+    bar: &ProgressBar,
+) -> Result<()> {
+    let (Some(requirement), Some(toolchain)) = (requirement, toolchain) else {
+        return Ok(());
+    };
+    let Ok(req) = parse_requirement(requirement) else {
+        return Ok(());
+    };
+    if req.matches(toolchain) {
+        return Ok(());
+    }
+    let msg =
+        format!("{name}@{version} requires moc {requirement}, but the toolchain pins {toolchain}");
+    if strict {
+        return Err(anyhow!(msg));
+    }
+    println(
+        Some(bar),
+        "stderr",
+        &format!("{:>12} {msg}", style("Warning").yellow().bold()),
+    );
+    Ok(())
+}
+/// Fetches the mops.lock embedded in a published package, if its author chose to publish one.
+async fn fetch_author_lock(
+    service: &mops::Service<'_>,
+    name: &str,
+    version: &str,
+    storage_id: Principal,
+) -> Result<Option<Packages>> {
+    let ids = service
+        .get_file_ids(&name.to_string(), &version.to_string())
+        .await?
+        .into_result()
+        .map_err(Error::msg)?;
+    let Some(id) = ids.into_iter().find(|id| id.ends_with("/mops.lock")) else {
+        return Ok(None);
+    };
+    let storage = storage::Service(storage_id, service.1);
+    let (_, blob) = storage::fetch_file(&storage, &id).await?;
+    let doc = String::from_utf8(blob)?.parse::<ImDocument<_>>()?;
+    let lock = toml_edit::de::from_document::<Packages>(doc)
+        .map_err(|e| anyhow!("Invalid author lock in {name}@{version}: {e}"))?;
+    Ok(Some(lock))
+}
+/// Describes how far resolution got before failing on `key`, and which packages required it.
+/// Packages in `locked` came from mops.lock and are only counted, not listed as resolved.
+fn resolution_report(
+    key: &str,
+    path: &[String],
+    map: &BTreeMap<String, Package>,
+    locked: &BTreeSet<String>,
+    queued: usize,
+    total_roots: usize,
+) -> String {
+    let resolved: Vec<_> = map
+        .iter()
+        .filter(|(key, _)| !locked.contains(*key))
+        .map(|(_, pkg)| pkg)
+        .collect();
+    let mut msg = format!(
+        "Failed to resolve {} (after resolving {} package(s), with {queued} more queued)",
+        style(key).red(),
+        resolved.len()
+    );
+    if path.is_empty() {
+        msg.push_str("\n  required by: mops.toml");
+    } else {
+        msg.push_str(&format!(
+            "\n  required by: mops.toml -> {}",
+            path.join(" -> ")
+        ));
+    }
+    if resolved.is_empty() {
+        msg.push_str(&format!(
+            "\nNothing resolved yet ({total_roots} direct dependencies)"
+        ));
+    } else {
+        msg.push_str(&format!("\nResolved so far ({}):", resolved.len()));
+        for pkg in resolved {
+            let version = pkg.version.as_deref().unwrap_or("?");
+            msg.push_str(&format!("\n  {}@{version} ({})", pkg.name, pkg.source));
+        }
+    }
+    let reused = map.keys().filter(|key| locked.contains(*key)).count();
+    if reused > 0 {
+        msg.push_str(&format!("\n{reused} more were already pinned in mops.lock"));
+    }
+    msg
+}
 fn resolve_versions(map: BTreeMap<String, Package>) -> Result<Vec<Package>> {
     let mut res: BTreeMap<String, Package> = BTreeMap::new();
     for pkg in map.into_values() {
         if let Some(e) = res.get(&pkg.name) {
+            // The same import name can't stand for two different registry packages.
+            if e.package != pkg.package {
+                return Err(anyhow!(resolve_error(e, &pkg)));
+            }
             match (&e.version, &pkg.version) {
                 (None, _) | (_, None) => return Err(anyhow!(resolve_error(e, &pkg))),
                 (Some(ve), Some(vp)) => match (parse_version(ve), parse_version(vp)) {
@@ -350,18 +1090,89 @@ fn resolve_error(p1: &Package, p2: &Package) -> String {
         style(&p2).green()
     )
 }
-pub fn generate_moc_args(base_path: &Path) -> Result<Vec<String>> {
-    let lock = parse_mops_lock(Path::new("mops.lock")).unwrap_or_default();
-    let mut args: Vec<_> = lock
+/// Returns the name and source directory of every locked package, as passed to `moc --package`.
+/// Packages only needed by dev-dependencies are included with `dev`.
+pub fn get_package_sources(base_path: &Path, dev: bool) -> Result<Vec<(String, PathBuf)>> {
+    let lock = load_mops_lock_or_default()?;
+    let base_path = &lock.package_root(base_path);
+    Ok(lock
         .package
         .into_iter()
-        .flat_map(|pkg| {
-            let path = base_path
-                .join(pkg.get_path())
-                .join(pkg.base_dir)
-                .to_string_lossy()
-                .to_string();
-            vec!["--package".to_string(), pkg.name, path]
+        .filter(|pkg| dev || !pkg.dev)
+        .map(|pkg| {
+            let path = base_path.join(pkg.get_path()).join(&pkg.base_dir);
+            (pkg.name, path)
+        })
+        .collect())
+}
+/// [`get_package_sources`] with the `.mops/local` overrides swapped in, as moc gets them.
+pub fn get_effective_sources(base_path: &Path, dev: bool) -> Result<Vec<(String, PathBuf)>> {
+    let lock = load_mops_lock_or_default()?;
+    apply_local_overrides(get_package_sources(base_path, dev)?, &lock)
+}
+/// Swaps in `.mops/local/<name>` for locked packages of that name, so dependency sources can be
+/// edited in place without touching mops.toml. Deleting the directory restores the locked version.
+fn apply_local_overrides(
+    sources: Vec<(String, PathBuf)>,
+    lock: &Packages,
+) -> Result<Vec<(String, PathBuf)>> {
+    let dir = Path::new(LOCAL_OVERRIDES);
+    if !dir.is_dir() {
+        return Ok(sources);
+    }
+    let mut unused = BTreeSet::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            unused.insert(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    let sources = sources
+        .into_iter()
+        .map(|(name, path)| {
+            if !unused.remove(&name) {
+                return (name, path);
+            }
+            let root = dir.join(&name);
+            let base_dir = lock
+                .package
+                .iter()
+                .find(|p| p.name == name)
+                .map(|p| root.join(&p.base_dir))
+                .filter(|p| p.is_dir())
+                .unwrap_or(root);
+            eprintln!(
+                "{:>12} {name} with {} instead of the locked version",
+                style("Overriding").yellow().bold(),
+                base_dir.display()
+            );
+            (name, base_dir)
+        })
+        .collect();
+    for name in unused {
+        eprintln!(
+            "{:>12} {LOCAL_OVERRIDES}/{name}, {name} is not a dependency",
+            style("Ignoring").yellow().bold()
+        );
+    }
+    Ok(sources)
+}
+pub fn generate_moc_args(base_path: &Path, dev: bool) -> Result<Vec<String>> {
+    let lock = load_mops_lock_or_default()?;
+    let root = lock.package_root(base_path);
+    for pkg in &lock.package {
+        if !matches!(pkg.get_type(), PackageType::Local(_)) {
+            crate::cache::touch(&root.join(pkg.get_path()), &pkg.get_done_file());
+        }
+    }
+    let mut args: Vec<_> = get_effective_sources(base_path, dev)?
+        .into_iter()
+        .flat_map(|(name, path)| {
+            vec![
+                "--package".to_string(),
+                name,
+                path.to_string_lossy().to_string(),
+            ]
         })
         .collect();
     if let Some(canisters) = lock.canister {
@@ -384,49 +1195,325 @@ pub fn generate_moc_args(base_path: &Path) -> Result<Vec<String>> {
     }
     Ok(args)
 }
-pub async fn download_packages_from_lock(agent: &Agent, root: &Path) -> Result<()> {
-    let lock = Path::new("mops.lock");
-    let pkgs = parse_mops_lock(lock)?.package;
-    let service = Rc::new(mops::Service(mops::CANISTER_ID, agent));
+pub async fn download_packages_from_lock(
+    agent: &Agent,
+    root: &Path,
+    pending: &Pending,
+    algorithm: HashAlgorithm,
+    keep_going: bool,
+) -> Result<()> {
+    let lock = &lock_path();
+    // Nothing to download for a project without dependencies, see `write_mops_lock`.
+    if !lock.exists() {
+        return Ok(());
+    }
+    let mut lock_file = parse_mops_lock(lock)?;
+    // A vendored project gets its packages into the vendor directory, bypassing cache and store.
+    let vendor = lock_file.vendor_dir();
+    let store = match vendor {
+        Some(_) => None,
+        None => {
+            crate::cache::ensure_layout(root)?;
+            crate::cache::register_project(root, lock)?;
+            crate::store::get()
+        }
+    };
+    if let Some(store) = store {
+        crate::cache::ensure_layout(&store.dir)?;
+        crate::cache::register_project(&store.dir, lock)?;
+    }
+    let root = vendor.as_deref().unwrap_or(root);
+    let pkgs: Vec<_> = lock_file.package.clone();
+    let service = Rc::new(mops::Service(crate::utils::registry_id(), agent));
     let bar = Rc::new(create_bar(pkgs.len()));
     bar.set_prefix("Downloading packages");
     let mut mop_futures = Vec::new();
     let mut git_futures = Vec::new();
+    // Bounds the downloads in flight, registry and git packages together.
+    let jobs = Rc::new(Semaphore::new(crate::utils::jobs()));
     for pkg in pkgs {
         bar.set_message(pkg.name.clone());
         let subpath = pkg.get_path();
-        let path = root.join(subpath);
-        if path.join(pkg.get_done_file()).exists() {
+        let path = root.join(&subpath);
+        if is_intact(&path, &pkg, &bar)? {
             bar.inc(1);
             continue;
         }
+        // With a store, packages are downloaded there and linked into `root`.
+        let target = match store {
+            Some(store) => {
+                let stored = store.dir.join(&subpath);
+                if is_intact(&stored, &pkg, &bar)? {
+                    store.link(&stored, &path)?;
+                    bar.inc(1);
+                    continue;
+                }
+                stored
+            }
+            None => path.clone(),
+        };
+        if !matches!(pkg.get_type(), PackageType::Local(_)) {
+            ensure_online(format_args!(
+                "download {}, which is not in the cache",
+                pkg.name
+            ))?;
+        }
+        let link = move |target: &Path| match store {
+            Some(store) => store.link(target, &path),
+            None => Ok(()),
+        };
+        let name = pkg.name.clone();
+        let pending = pending.clone();
+        let staging = staging_dir(&target, &pkg);
+        let install = {
+            let (staging, pkg) = (staging.clone(), pkg.clone());
+            move || {
+                let res = install(&staging, &target, &pkg).and_then(|_| link(&target));
+                if res.is_err() {
+                    let _ = fs::remove_dir_all(&staging);
+                }
+                res
+            }
+        };
         match pkg.get_type() {
             PackageType::Mops { id, .. } => {
                 let id = Principal::from_text(id)?;
-                mop_futures.push(download_mops_package(
-                    path,
-                    pkg.name,
+                pending.start(&name);
+                let mirror = match &pkg.registry {
+                    Some(mirror) => Some(Principal::from_text(mirror)?),
+                    None => None,
+                };
+                let fut = download_mops_package(
+                    staging,
+                    pkg.registry_name().to_string(),
                     pkg.version.unwrap(),
                     service.clone(),
+                    mirror,
                     id,
                     bar.clone(),
-                ));
+                );
+                let jobs = jobs.clone();
+                mop_futures.push(async move {
+                    let _permit = jobs.acquire().await.unwrap();
+                    let res = fut.await.and_then(|_| install());
+                    pending.finish(&name);
+                    res.map_err(|e| (name, e))
+                });
             }
             PackageType::Repo(_) => {
-                git_futures.push(download_github_package(
-                    path,
-                    pkg.repo.unwrap(),
-                    bar.clone(),
-                ));
+                pending.start(&name);
+                let fut = download_github_package(staging, pkg.repo.unwrap(), bar.clone());
+                let jobs = jobs.clone();
+                git_futures.push(async move {
+                    let _permit = jobs.acquire().await.unwrap();
+                    let res = fut.await.and_then(|_| install());
+                    pending.finish(&name);
+                    res.map_err(|e| (name, e))
+                });
             }
             PackageType::Local(_) => {
                 bar.inc(1);
             }
         }
     }
-    try_join_all(mop_futures).await?;
-    try_join_all(git_futures).await?;
+    let mut failures = Vec::new();
+    if keep_going {
+        let mut results = join_all(mop_futures).await;
+        results.extend(join_all(git_futures).await);
+        failures.extend(results.into_iter().filter_map(Result::err));
+    } else {
+        try_join_all(mop_futures).await.map_err(|(_, e)| e)?;
+        try_join_all(git_futures).await.map_err(|(_, e)| e)?;
+    }
     bar.finish_and_clear();
+    record_integrity(root, &mut lock_file, algorithm)?;
+    if !failures.is_empty() {
+        let list: Vec<_> = failures
+            .iter()
+            .map(|(name, e)| format!("{name}: {e:#}"))
+            .collect();
+        return Err(anyhow!(
+            "{} package(s) failed to download:\n  {}",
+            failures.len(),
+            list.join("\n  ")
+        ));
+    }
+    crate::npm::link(root, &lock_file)
+}
+/// The sibling directory `pkg` is downloaded into before it replaces `target`, so an
+/// interrupted download never leaves files where a package is expected. Packages of the same
+/// commit share `target`, so each gets its own.
+fn staging_dir(target: &Path, pkg: &Package) -> PathBuf {
+    let name = target.file_name().unwrap().to_string_lossy();
+    let entry = short_hash(&format!("{}/{}", pkg.get_key(), pkg.hashed_dir()));
+    target.with_file_name(format!(".{name}.partial-{}-{entry}", std::process::id()))
+}
+
+/// Whether a package of `dir` is complete. Packages of the same commit share it, each with a
+/// marker of its own.
+fn has_markers(dir: &Path) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        if entry?.file_name().to_string_lossy().starts_with("DONE") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Moves `pkg` from `staging` to `target` once it matches the hash in mops.lock. Packages of the
+/// same commit share a directory: their files are moved into it one by one, the marker last.
+fn install(staging: &Path, target: &Path, pkg: &Package) -> Result<()> {
+    if let Some(expected) = &pkg.integrity {
+        if !verify_dir(staging, &pkg.hashed_dir(), expected)? {
+            return Err(anyhow!(
+                "The downloaded files of {} don't match the hash in mops.lock ({expected}). The package may have been tampered with.",
+                pkg.get_key()
+            ));
+        }
+    }
+    if !target.is_dir() || !has_markers(target)? {
+        // A directory without markers is what an interrupted download of older versions left.
+        if target.exists() {
+            fs::remove_dir_all(target)?;
+        }
+        fs::rename(staging, target)?;
+        return Ok(());
+    }
+    let done = pkg.get_done_file();
+    let mut files = Vec::new();
+    for entry in WalkDir::new(staging) {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(staging)?;
+        if entry.file_type().is_file() && rel != Path::new(&done) {
+            files.push(rel.to_path_buf());
+        }
+    }
+    for rel in files {
+        let dest = target.join(&rel);
+        fs::create_dir_all(dest.parent().unwrap())?;
+        fs::rename(staging.join(&rel), dest)?;
+    }
+    fs::rename(staging.join(&done), target.join(&done))?;
+    fs::remove_dir_all(staging)?;
+    Ok(())
+}
+
+/// Whether `dir` holds a complete copy of `pkg` that matches the hash in mops.lock. A copy that
+/// doesn't match is removed with its marker, so it gets downloaded again. Other packages of the
+/// same commit keep their base directories.
+fn is_intact(dir: &Path, pkg: &Package, bar: &ProgressBar) -> Result<bool> {
+    if !dir.join(pkg.get_done_file()).exists() {
+        return Ok(false);
+    }
+    let Some(expected) = &pkg.integrity else {
+        return Ok(true);
+    };
+    if verify_dir(dir, &pkg.hashed_dir(), expected)? {
+        return Ok(true);
+    }
+    println(
+        Some(bar),
+        "stderr",
+        &format!(
+            "{:>12} {} in {} doesn't match the hash in mops.lock, downloading it again",
+            style("Corrupted").red().bold(),
+            pkg.get_key(),
+            dir.display()
+        ),
+    );
+    let PackageType::Repo(repo) = pkg.get_type() else {
+        fs::remove_dir_all(dir)?;
+        return Ok(false);
+    };
+    fs::remove_file(dir.join(pkg.get_done_file()))?;
+    let sub = dir.join(repo.source_dir());
+    if !has_markers(dir)? {
+        fs::remove_dir_all(dir)?;
+    } else if sub != dir && sub.exists() {
+        fs::remove_dir_all(sub)?;
+    }
+    Ok(false)
+}
+/// What installing a lock would do for one package.
+pub(crate) struct PlannedPackage {
+    pub name: String,
+    pub version: Option<String>,
+    pub path: PathBuf,
+    pub action: PlanAction,
+}
+pub(crate) enum PlanAction {
+    Cached,
+    Local,
+    /// Download from `from`, with the estimated size in bytes when the source reports one.
+    Download {
+        from: String,
+        size: Option<u64>,
+    },
+}
+/// Works out which packages of `lock` are already in the cache at `root` and which need downloading.
+pub(crate) async fn plan_downloads(
+    agent: &Agent,
+    root: &Path,
+    lock: &Packages,
+) -> Result<Vec<PlannedPackage>> {
+    let service = mops::Service(crate::utils::registry_id(), agent);
+    let root = &lock.package_root(root);
+    let mut res = Vec::new();
+    for pkg in &lock.package {
+        let path = root.join(pkg.get_path());
+        let action = match pkg.get_type() {
+            PackageType::Local(_) => PlanAction::Local,
+            _ if path.join(pkg.get_done_file()).exists() => PlanAction::Cached,
+            PackageType::Mops { ver, id } => {
+                let size = if is_offline() {
+                    None
+                } else {
+                    crate::metadata::package_details(&service, pkg.registry_name(), ver)
+                        .await
+                        .ok()
+                        .and_then(|details| {
+                            details.file_stats.source_size.0.to_string().parse().ok()
+                        })
+                };
+                PlanAction::Download {
+                    from: format!("registry storage {id}"),
+                    size,
+                }
+            }
+            PackageType::Repo(repo) => PlanAction::Download {
+                from: format!("{}@{}", repo.web_path(), repo.short_commit()),
+                size: None,
+            },
+        };
+        res.push(PlannedPackage {
+            name: pkg.name.clone(),
+            version: pkg.version.clone(),
+            path,
+            action,
+        });
+    }
+    Ok(res)
+}
+/// Records the hash of every downloaded package that doesn't have one yet, rewriting mops.lock if needed.
+fn record_integrity(root: &Path, lock: &mut Packages, algorithm: HashAlgorithm) -> Result<()> {
+    let mut changed = false;
+    for pkg in lock.package.iter_mut() {
+        if pkg.integrity.is_some() || matches!(pkg.get_type(), PackageType::Local(_)) {
+            continue;
+        }
+        let path = root.join(pkg.get_path());
+        if !path.join(pkg.get_done_file()).exists() {
+            continue;
+        }
+        pkg.integrity = Some(hash_dir(&path, &pkg.hashed_dir(), algorithm)?);
+        changed = true;
+    }
+    if changed {
+        let path = &lock_path();
+        let content = render_mops_lock(lock)?;
+        crate::history::amend(path, &content)?;
+        fs::write(path, &content)?;
+    }
     Ok(())
 }
 async fn download_mops_package(
@@ -434,21 +1521,43 @@ async fn download_mops_package(
     lib: String,
     version: String,
     service: Rc<mops::Service<'_>>,
+    mirror: Option<Principal>,
     storage_id: Principal,
     bar: Rc<ProgressBar>,
 ) -> Result<()> {
-    let ids = service
-        .get_file_ids(&lib, &version)
-        .await?
-        .into_result()
-        .map_err(Error::msg)?;
-    let mut futures = Vec::new();
-    let storage = Rc::new(storage::Service(storage_id, service.1));
-    for id in ids {
-        futures.push(download_file(base_path.clone(), id, storage.clone()));
-    }
-    try_join_all(futures).await?;
-    fs::write(base_path.join("DONE"), "")?;
+    // The registry mops.lock records is asked first, and its storage is known. Any other one is
+    // asked where it stores the package.
+    let what = format!("the files of {lib}@{version}");
+    let first = crate::mirrors::registry(&service, mirror).0;
+    crate::mirrors::failover(&what, &service, mirror, |registry| {
+        let (base_path, lib, version) = (base_path.clone(), lib.clone(), version.clone());
+        async move {
+            let storage_id = if registry.0 == first {
+                storage_id
+            } else {
+                let details = registry.get_package_details(&lib, &version).await?;
+                details
+                    .into_result()
+                    .map_err(Error::msg)?
+                    .publication
+                    .storage
+            };
+            let ids = registry
+                .get_file_ids(&lib, &version)
+                .await?
+                .into_result()
+                .map_err(Error::msg)?;
+            let mut futures = Vec::new();
+            let storage = Rc::new(storage::Service(storage_id, registry.1));
+            for id in ids {
+                futures.push(download_file(base_path.clone(), id, storage.clone()));
+            }
+            try_join_all(futures).await?;
+            Ok(())
+        }
+    })
+    .await?;
+    crate::cache::write_marker(&base_path, "DONE", format!("{lib}@{version}"))?;
     println(
         Some(&bar),
         "stdout",
@@ -457,36 +1566,35 @@ async fn download_mops_package(
     bar.inc(1);
     Ok(())
 }
-async fn download_file(
+async fn download_file<S: storage::Storage>(
     base_path: PathBuf,
     id: String,
-    storage: Rc<storage::Service<'_>>,
+    storage: Rc<S>,
 ) -> Result<()> {
-    let meta = storage
-        .get_file_meta(&id)
-        .await?
-        .into_result()
-        .map_err(Error::msg)?;
-    let mut blob = Vec::new();
-    for i in 0..meta.chunk_count {
-        let chunk = storage
-            .download_chunk(&id, &i.into())
-            .await?
-            .into_result()
-            .map_err(Error::msg)?;
-        blob.extend(chunk);
-    }
-    let path = base_path.join(meta.path);
-    fs::create_dir_all(path.parent().unwrap())?;
-    fs::write(path, blob)?;
+    storage::download_file(storage.as_ref(), &id, &base_path).await?;
     Ok(())
 }
 #[allow(clippy::enum_variant_names)]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum Mops {
-    Mops { name: String, version: String },
-    Repo { name: String, repo: String },
-    Local { name: String, path: String },
+    Mops {
+        name: String,
+        version: String,
+        /// Registry name of a renamed dependency. `name` is then the import name.
+        package: Option<String>,
+    },
+    Repo {
+        name: String,
+        repo: String,
+        /// `subdir` of `{ git = "...", subdir = "..." }`
+        subdir: Option<String>,
+        /// `branch`, `tag` or `rev` of `{ git = "...", branch = "..." }`
+        reference: Option<(RefKind, String)>,
+    },
+    Local {
+        name: String,
+        path: String,
+    },
 }
 #[derive(Debug, Serialize, Deserialize)]
 struct CanisterInfo {
@@ -496,47 +1604,219 @@ struct CanisterInfo {
 }
 #[derive(Debug)]
 struct MopsConfig {
+    name: Option<String>,
+    base_dir: Option<String>,
     version: Option<String>,
+    /// `[requirements] moc`
+    moc_requirement: Option<String>,
+    /// `[requirements] mops-cli`
+    cli_requirement: Option<String>,
     dependencies: Vec<Mops>,
+    /// `[dev-dependencies]`, only for tests and benchmarks
+    dev_dependencies: Vec<Mops>,
+    /// `[patch]`, replacing packages of the same name anywhere in the dependency graph
+    patches: Vec<Mops>,
     canisters: Vec<CanisterInfo>,
 }
+/// Returns the direct dependencies declared in the project's mops.toml, as `(name, requirement)`.
+pub(crate) fn get_direct_dependencies() -> Result<Vec<(String, String)>> {
+    Ok(parsed_project()?.dependencies)
+}
+/// Parses mops.lock in the current directory, through the same cache as [`get_direct_dependencies`].
+pub(crate) fn load_mops_lock() -> Result<Packages> {
+    parsed_project()?
+        .lock
+        .ok_or_else(|| anyhow!("mops.lock not found. Run `mops install` first."))
+}
+/// Like [`load_mops_lock`], but no packages when there is no mops.lock yet. A lock that cannot
+/// be parsed is still an error, not an empty project.
+pub(crate) fn load_mops_lock_or_default() -> Result<Packages> {
+    Ok(parsed_project()?.lock.unwrap_or_default())
+}
+/// Where the parsed mops.toml dependencies and mops.lock are cached.
+const PARSED_CACHE: &str = ".mops/graph.json";
+#[derive(Serialize, Deserialize)]
+struct ParsedProject {
+    /// Hash of the CLI version, mops.toml, mops.lock and the manifests of the workspace the rest
+    /// was parsed from.
+    key: String,
+    dependencies: Vec<(String, String)>,
+    lock: Option<Packages>,
+}
+/// The direct dependencies and lock of the project. Parsing TOML dominates read-only commands
+/// like `tree` and `sources`, so the result is kept in `.mops/graph.json` until one of the files,
+/// including any manifest of the workspace, or the CLI version changes.
+fn parsed_project() -> Result<ParsedProject> {
+    let manifest = fs::read("mops.toml").ok();
+    let lock = fs::read(lock_path()).ok();
+    let mut hasher = blake3::Hasher::new();
+    // Another version may parse the same files differently, or serialize them another way.
+    let version = env!("CARGO_PKG_VERSION");
+    hasher
+        .update(&(version.len() as u64).to_le_bytes())
+        .update(version.as_bytes());
+    // Members are resolved into the same lock, so their manifests are part of the key as well.
+    let mut members = Vec::new();
+    if let Some(root) = crate::workspace::root() {
+        members.push(root.join("mops.toml"));
+        for member in crate::workspace::members(root)? {
+            members.push(member.dir.join("mops.toml"));
+        }
+    }
+    let mut files = vec![manifest.clone(), lock.clone()];
+    for path in &members {
+        files.push(Some(path.to_string_lossy().into_owned().into_bytes()));
+        files.push(fs::read(path).ok());
+    }
+    for file in &files {
+        match file {
+            Some(bytes) => hasher
+                .update(&(bytes.len() as u64).to_le_bytes())
+                .update(bytes),
+            None => hasher.update(b"none"),
+        };
+    }
+    let key = hasher.finalize().to_hex().to_string();
+    if let Some(cached) = fs::read(PARSED_CACHE)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<ParsedProject>(&bytes).ok())
+    {
+        if cached.key == key {
+            return Ok(cached);
+        }
+    }
+    let dependencies = match &manifest {
+        Some(bytes) => parse_mops_toml(std::str::from_utf8(bytes)?)?
+            .dependencies
+            .iter()
+            .map(|m| (m.name().to_string(), m.requirement().to_string()))
+            .collect(),
+        None => Vec::new(),
+    };
+    let lock = match &lock {
+        Some(_) => Some(parse_mops_lock(&lock_path())?),
+        None => None,
+    };
+    let parsed = ParsedProject {
+        key,
+        dependencies,
+        lock,
+    };
+    // Only a cache: outside a project, or in a read-only checkout, we just parse every time.
+    if manifest.is_some() && fs::create_dir_all(".mops").is_ok() {
+        let _ = fs::write(PARSED_CACHE, serde_json::to_vec(&parsed)?);
+    }
+    Ok(parsed)
+}
+/// Dependencies of the project in the form the registry expects on publish. Local paths are rejected.
+pub(crate) fn get_publish_dependencies() -> Result<Vec<mops::DependencyV2>> {
+    let toml = parse_mops_toml(&fs::read_to_string("mops.toml")?)?;
+    toml.dependencies
+        .into_iter()
+        .map(|m| to_publish_dependency(m).map_err(|e| anyhow!("Cannot publish a package with {e}")))
+        .collect()
+}
+/// `[dev-dependencies]` for the registry. Local and renamed ones only matter to the author and are
+/// left out.
+pub(crate) fn get_publish_dev_dependencies() -> Result<Vec<mops::DependencyV2>> {
+    let toml = parse_mops_toml(&fs::read_to_string("mops.toml")?)?;
+    Ok(toml
+        .dev_dependencies
+        .into_iter()
+        .filter_map(|m| to_publish_dependency(m).ok())
+        .collect())
+}
+/// Fails for dependencies the registry can't represent, describing them.
+fn to_publish_dependency(m: Mops) -> std::result::Result<mops::DependencyV2, String> {
+    match m {
+        Mops::Mops {
+            name,
+            package: Some(package),
+            ..
+        } => Err(format!(
+            "a renamed dependency: {name} = {{ package = {package:?} }}"
+        )),
+        Mops::Mops { name, version, .. } => Ok(mops::DependencyV2 {
+            name,
+            repo: String::new(),
+            version,
+        }),
+        Mops::Repo {
+            name,
+            subdir: Some(subdir),
+            ..
+        } => Err(format!(
+            "a monorepo dependency: {name} = {{ subdir = {subdir:?} }}"
+        )),
+        Mops::Repo {
+            name,
+            repo,
+            reference,
+            ..
+        } => Ok(mops::DependencyV2 {
+            name,
+            // The registry only knows the URL form.
+            repo: match reference {
+                Some((_, reference)) => format!("{repo}#{reference}"),
+                None => repo,
+            },
+            version: String::new(),
+        }),
+        Mops::Local { name, path } => Err(format!("a local dependency: {name} = {path:?}")),
+    }
+}
+/// Returns the moc version pinned in the `[toolchain]` table of the project's mops.toml, if any.
+pub(crate) fn get_toolchain_moc() -> Result<Option<String>> {
+    let mops = Path::new("mops.toml");
+    if !mops.exists() {
+        return Ok(None);
+    }
+    let doc = fs::read_to_string(mops)?.parse::<ImDocument<_>>()?;
+    let Some(moc) = doc.get("toolchain").and_then(|t| t.get("moc")) else {
+        return Ok(None);
+    };
+    let moc = moc
+        .as_str()
+        .ok_or_else(|| anyhow!("toolchain.moc must be a version string"))?;
+    Ok(Some(moc.to_string()))
+}
+/// Returns `package.name` from the project's mops.toml, if any.
+pub fn get_package_name() -> Result<Option<String>> {
+    let mops = Path::new("mops.toml");
+    if !mops.exists() {
+        return Ok(None);
+    }
+    Ok(parse_mops_toml(&fs::read_to_string(mops)?)?.name)
+}
+/// Returns `package.baseDir` from the project's mops.toml, defaulting to `src`.
+pub fn get_package_base_dir() -> Result<String> {
+    let mops = Path::new("mops.toml");
+    let base_dir = if mops.exists() {
+        parse_mops_toml(&fs::read_to_string(mops)?)?.base_dir
+    } else {
+        None
+    };
+    Ok(base_dir.unwrap_or_else(|| "src".to_string()))
+}
 fn parse_mops_toml(str: &str) -> Result<MopsConfig> {
     let doc = str.parse::<ImDocument<_>>()?;
-    let mut mops = Vec::new();
+    let mut name = None;
+    let mut base_dir = None;
     let mut version = None;
     if let Some(pkg) = doc.get("package") {
+        if let Some(dir) = pkg.get("baseDir") {
+            base_dir = Some(dir.as_value().unwrap().as_str().unwrap().to_string());
+        }
+        if let Some(n) = pkg.get("name") {
+            name = Some(n.as_value().unwrap().as_str().unwrap().to_string());
+        }
         if let Some(ver) = pkg.get("version") {
             version = Some(ver.as_value().unwrap().as_str().unwrap().to_string());
         }
     }
-    if let Some(deps) = doc.get("dependencies") {
-        let deps = deps
-            .as_table()
-            .ok_or_else(|| anyhow!("invalid dependencies"))?;
-        for (lib, version) in deps.iter() {
-            let version = version
-                .as_value()
-                .ok_or_else(|| anyhow!("invalid version"))?
-                .as_str()
-                .ok_or_else(|| anyhow!("invalid version"))?;
-            if version.starts_with("https://github.com") {
-                mops.push(Mops::Repo {
-                    name: lib.to_string(),
-                    repo: version.to_string(),
-                });
-            } else if Path::new(version).exists() {
-                mops.push(Mops::Local {
-                    name: lib.to_string(),
-                    path: version.to_string(),
-                });
-            } else {
-                mops.push(Mops::Mops {
-                    name: lib.to_string(),
-                    version: version.to_string(),
-                });
-            }
-        }
-    }
+    let dependencies = parse_dependencies(&doc, "dependencies")?;
+    let dev_dependencies = parse_dependencies(&doc, "dev-dependencies")?;
+    let patches = parse_dependencies(&doc, "patch")?;
     let mut canisters = Vec::new();
     if let Some(item) = doc.get("canister") {
         for canister in item.as_array_of_tables().unwrap().iter() {
@@ -561,25 +1841,227 @@ fn parse_mops_toml(str: &str) -> Result<MopsConfig> {
             });
         }
     }
+    let requirement = |key: &str| {
+        doc.get("requirements")
+            .and_then(|r| r.get(key))
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+    };
+    let moc_requirement = requirement("moc");
+    let cli_requirement = requirement("mops-cli");
     Ok(MopsConfig {
+        name,
+        base_dir,
         version,
-        dependencies: mops,
+        moc_requirement,
+        cli_requirement,
+        dependencies,
+        dev_dependencies,
+        patches,
         canisters,
     })
 }
-fn parse_mops_lock(lock: &Path) -> Result<Packages> {
-    let str = fs::read_to_string(lock)?;
+/// The mops.toml of a workspace root and all of its members as one, so they share a lock.
+/// Dependencies on other members become path dependencies, and the `[patch]` of the root applies
+/// to everything.
+fn parse_workspace(root: &Path) -> Result<MopsConfig> {
+    let members = crate::workspace::members(root)?;
+    let mut res = parse_mops_toml(&fs::read_to_string(root.join("mops.toml"))?)?;
+    rebase_dependencies(&mut res, root, &members);
+    for member in &members {
+        let mut toml = parse_mops_toml(&fs::read_to_string(member.dir.join("mops.toml"))?)?;
+        rebase_dependencies(&mut toml, &member.dir, &members);
+        res.dependencies.extend(toml.dependencies);
+        res.dev_dependencies.extend(toml.dev_dependencies);
+        res.canisters.extend(toml.canisters);
+    }
+    Ok(res)
+}
+/// Points dependencies on workspace members at their directories, and makes local paths in the
+/// mops.toml of `dir` relative to the current directory.
+fn rebase_dependencies(toml: &mut MopsConfig, dir: &Path, members: &[crate::workspace::Member]) {
+    for m in toml
+        .dependencies
+        .iter_mut()
+        .chain(toml.dev_dependencies.iter_mut())
+    {
+        match m {
+            Mops::Local { path, .. } => *path = dir.join(&*path).display().to_string(),
+            Mops::Mops { name, package, .. } => {
+                let registry_name = package.as_ref().unwrap_or(name);
+                if let Some(member) = members.iter().find(|m| m.name == *registry_name) {
+                    *m = Mops::Local {
+                        name: name.clone(),
+                        path: member.dir.display().to_string(),
+                    };
+                }
+            }
+            Mops::Repo { .. } => (),
+        }
+    }
+}
+fn parse_dependencies(doc: &ImDocument<String>, table: &str) -> Result<Vec<Mops>> {
+    let mut mops = Vec::new();
+    let Some(deps) = doc.get(table) else {
+        return Ok(mops);
+    };
+    let deps = deps.as_table().ok_or_else(|| anyhow!("invalid {table}"))?;
+    for (lib, version) in deps.iter() {
+        let version = version
+            .as_value()
+            .ok_or_else(|| anyhow!("invalid version"))?;
+        if let Some(spec) = version.as_inline_table() {
+            let field = |key: &str| spec.get(key).and_then(|v| v.as_str()).map(String::from);
+            if let Some(path) = field("path") {
+                mops.push(Mops::Local {
+                    name: lib.to_string(),
+                    path,
+                });
+                continue;
+            }
+            if let Some(repo) = field("git") {
+                if !crate::github::is_repo_url(&repo) {
+                    return Err(anyhow!("{table}.{lib}: unsupported repository URL {repo}"));
+                }
+                let refs: Vec<_> = [
+                    (RefKind::Branch, "branch"),
+                    (RefKind::Tag, "tag"),
+                    (RefKind::Rev, "rev"),
+                ]
+                .into_iter()
+                .filter_map(|(kind, key)| Some((kind, field(key)?)))
+                .collect();
+                if refs.len() > 1 {
+                    return Err(anyhow!(
+                        "{table}.{lib} can only have one of branch, tag and rev"
+                    ));
+                }
+                if !refs.is_empty() && repo.contains('#') {
+                    return Err(anyhow!(
+                        "{table}.{lib} gives a ref both in the URL and as branch, tag or rev"
+                    ));
+                }
+                mops.push(Mops::Repo {
+                    name: lib.to_string(),
+                    repo,
+                    subdir: field("subdir").map(|s| s.trim_matches('/').to_string()),
+                    reference: refs.into_iter().next(),
+                });
+                continue;
+            }
+            mops.push(Mops::Mops {
+                name: lib.to_string(),
+                version: field("version")
+                    .ok_or_else(|| anyhow!("{table}.{lib} needs a version or a path"))?,
+                package: field("package"),
+            });
+            continue;
+        }
+        let version = version.as_str().ok_or_else(|| anyhow!("invalid version"))?;
+        if crate::github::is_repo_url(version) {
+            mops.push(Mops::Repo {
+                name: lib.to_string(),
+                repo: version.to_string(),
+                subdir: None,
+                reference: None,
+            });
+        } else if let Some(path) = version.strip_prefix("path:") {
+            mops.push(Mops::Local {
+                name: lib.to_string(),
+                path: path.to_string(),
+            });
+        } else if Path::new(version).exists() && parse_version(version).is_none() {
+            // Older manifests give local paths without a prefix.
+            eprintln!(
+                "{:>12} {table}.{lib} = {version:?} is used as a local path because that directory exists. Write \"path:{version}\" to say so",
+                style("Warning").yellow().bold()
+            );
+            mops.push(Mops::Local {
+                name: lib.to_string(),
+                path: version.to_string(),
+            });
+        } else {
+            mops.push(Mops::Mops {
+                name: lib.to_string(),
+                version: version.to_string(),
+                package: None,
+            });
+        }
+    }
+    Ok(mops)
+}
+/// The packages pinned the same way on both sides or on one side only, for regenerating a lock
+/// after a merge conflict. For canisters, `ours` wins.
+pub(crate) fn merge_locks(ours: Packages, theirs: Packages) -> Packages {
+    let mut package: BTreeMap<_, _> = theirs
+        .package
+        .into_iter()
+        .map(|p| (p.get_key(), p))
+        .collect();
+    package.extend(ours.package.into_iter().map(|p| (p.get_key(), p)));
+    // Two sides pinning a package differently are resolved again from mops.toml, instead of the
+    // higher or older pin winning over what mops.toml asks for now.
+    let mut pins = BTreeMap::<_, usize>::new();
+    for pkg in package.values() {
+        *pins.entry(pkg.name.clone()).or_default() += 1;
+    }
+    package.retain(|_, p| pins[&p.name] == 1);
+    let mut canister: BTreeMap<_, _> = theirs
+        .canister
+        .into_iter()
+        .flatten()
+        .map(|c| (c.get_key(), c))
+        .collect();
+    canister.extend(
+        ours.canister
+            .into_iter()
+            .flatten()
+            .map(|c| (c.get_key(), c)),
+    );
+    Packages {
+        package: package.into_values().collect(),
+        canister: Some(canister.into_values().collect()),
+        vendor: ours.vendor.or(theirs.vendor),
+    }
+}
+/// Whether a git merge left conflict markers in the file.
+pub(crate) fn has_conflict_markers(str: &str) -> bool {
+    str.lines().any(|l| l.starts_with("<<<<<<< "))
+}
+pub(crate) fn parse_mops_lock(lock: &Path) -> Result<Packages> {
+    parse_mops_lock_str(&fs::read_to_string(lock)?)
+}
+pub(crate) fn parse_mops_lock_str(str: &str) -> Result<Packages> {
+    if has_conflict_markers(str) {
+        return Err(anyhow!(
+            "mops.lock has merge conflicts. Run `mops lock resolve` to regenerate it."
+        ));
+    }
     let doc = str.parse::<ImDocument<_>>()?;
     let lock = toml_edit::de::from_document::<Packages>(doc)?;
+    // Paths are joined to the cache root, and `mops clean --global` removes them.
+    for pkg in &lock.package {
+        let Some(path) = &pkg.path else { continue };
+        if path.is_empty()
+            || !Path::new(path)
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            return Err(anyhow!(
+                "Invalid path {path} of {} in mops.lock, expected a directory in the cache",
+                pkg.name
+            ));
+        }
+    }
     Ok(lock)
 }
-enum PackageType<'a> {
+pub(crate) enum PackageType<'a> {
     Mops { ver: &'a str, id: &'a str },
     Local(&'a str),
     Repo(&'a RepoInfo),
 }
 impl Package {
-    fn get_type(&self) -> PackageType {
+    pub(crate) fn get_type(&self) -> PackageType<'_> {
         if self.source.starts_with("file://") {
             let local = self.source.strip_prefix("file://").unwrap();
             PackageType::Local(local)
@@ -592,25 +2074,51 @@ impl Package {
             }
         }
     }
+    /// A repository dependency declared with `branch`, whose commit moves on with the branch.
+    pub(crate) fn follows_branch(&self) -> bool {
+        self.repo
+            .as_ref()
+            .is_some_and(|repo| repo.kind == Some(RefKind::Branch))
+    }
     fn get_key(&self) -> String {
         // Make sure this is the same logic as used in update_mops_lock
         match self.get_type() {
             PackageType::Mops { ver, .. } => format!("{}-{}", self.name, ver),
-            PackageType::Repo(repo) => format!("{}-{}-{}", self.name, repo.repo, repo.commit),
-            PackageType::Local(local) => format!("{}-{}", self.name, local),
+            PackageType::Repo(repo) => format!("{}-{}-{}", self.name, repo.location(), repo.commit),
+            PackageType::Local(local) => {
+                let dir = lock_dir().join(local);
+                let dir = fs::canonicalize(&dir).unwrap_or(dir);
+                format!("{}-{}", self.name, dir.display())
+            }
         }
     }
-    fn get_path(&self) -> String {
+    pub(crate) fn get_path(&self) -> String {
+        if let Some(path) = &self.path {
+            return path.clone();
+        }
         match self.get_type() {
-            PackageType::Mops { ver, .. } => format!("mops/{}-{}", self.name, ver),
+            PackageType::Mops { ver, .. } => format!("mops/{}-{}", self.registry_name(), ver),
             PackageType::Repo(repo) => {
-                let repo_name = repo.repo.replace('/', "-");
-                format!("git/{}/{}", repo_name, &repo.commit[..8])
+                let repo_name = repo.location().replace('/', "-");
+                format!("git/{}/{}", repo_name, repo.short_commit())
             }
-            PackageType::Local(local) => local.to_string(),
+            // Older locks have absolute paths, which `join` keeps as they are.
+            PackageType::Local(local) => lock_dir().join(local).display().to_string(),
+        }
+    }
+    /// The directory under `get_path` that the integrity covers. Packages of the same commit share
+    /// a directory, each with its own base directory in it.
+    pub(crate) fn hashed_dir(&self) -> String {
+        match self.get_type() {
+            PackageType::Repo(repo) => repo.source_dir(),
+            _ => String::new(),
         }
     }
-    fn get_done_file(&self) -> String {
+    /// The name to look the package up by in the registry.
+    pub(crate) fn registry_name(&self) -> &str {
+        self.package.as_deref().unwrap_or(&self.name)
+    }
+    pub(crate) fn get_done_file(&self) -> String {
         // Make sure this returns the same name as each download function
         match self.get_type() {
             PackageType::Mops { .. } => "DONE".to_string(),
@@ -641,12 +2149,206 @@ impl CanisterInfo {
     }
 }
 impl Mops {
+    fn name(&self) -> &str {
+        match self {
+            Mops::Mops { name, .. } | Mops::Repo { name, .. } | Mops::Local { name, .. } => name,
+        }
+    }
+    fn requirement(&self) -> &str {
+        match self {
+            Mops::Mops { version, .. } => version,
+            Mops::Repo { repo, .. } => repo,
+            Mops::Local { path, .. } => path,
+        }
+    }
     fn get_display_key(&self) -> String {
         // only for displaying in dependencies, not used for dedup
         match self {
-            Mops::Mops { name, version } => format!("{name}-{version}"),
-            Mops::Repo { name, repo } => format!("{name}-{repo}"),
+            Mops::Mops { name, version, .. } => format!("{name}-{version}"),
+            Mops::Repo { name, repo, .. } => format!("{name}-{repo}"),
             Mops::Local { name, path } => format!("{name}-{path}"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn mops(name: &str, version: &str) -> Mops {
+        Mops::Mops {
+            name: name.to_string(),
+            version: version.to_string(),
+            package: None,
+        }
+    }
+
+    /// A registry of `(name, version, dependencies)`, where looking up a package takes as many
+    /// milliseconds as given in `delays`, so lookups finish in a chosen order.
+    struct Registry {
+        packages: Vec<(&'static str, &'static str, Vec<Mops>)>,
+        delays: BTreeMap<&'static str, u64>,
+        /// Lookups by key that were not answered from the map, so went to the registry.
+        fetches: RefCell<BTreeMap<String, usize>>,
+    }
+    impl Registry {
+        fn new(packages: Vec<(&'static str, &'static str, Vec<Mops>)>) -> Self {
+            Registry {
+                packages,
+                delays: BTreeMap::new(),
+                fetches: RefCell::new(BTreeMap::new()),
+            }
+        }
+        fn with_delay(mut self, name: &'static str, ms: u64) -> Self {
+            self.delays.insert(name, ms);
+            self
+        }
+        fn fetches(&self, key: &str) -> usize {
+            self.fetches.borrow().get(key).copied().unwrap_or(0)
+        }
+        /// Like [`resolve_package`] for registry packages.
+        async fn lookup(
+            &self,
+            m: Mops,
+            map: &RefCell<BTreeMap<String, Package>>,
+        ) -> Result<Option<(Package, Vec<Mops>)>> {
+            let Mops::Mops { name, version, .. } = m else {
+                unreachable!()
+            };
+            let key = format!("{name}-{version}");
+            if map.borrow().contains_key(&key) {
+                return Ok(None);
+            }
+            *self.fetches.borrow_mut().entry(key).or_default() += 1;
+            let delay = self.delays.get(name.as_str()).copied().unwrap_or(10);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            let (_, _, deps) = self
+                .packages
+                .iter()
+                .find(|(n, v, _)| *n == name && *v == version)
+                .ok_or_else(|| anyhow!("{name}@{version} not found"))?;
+            let pkg = Package {
+                name,
+                package: None,
+                version: Some(version),
+                source: "storage".to_string(),
+                registry: None,
+                base_dir: "src".to_string(),
+                repo: None,
+                dependencies: Vec::new(),
+                integrity: None,
+                path: None,
+                dev: false,
+                patched: false,
+            };
+            Ok(Some((pkg, deps.clone())))
+        }
+        async fn resolve(&self, roots: Vec<Mops>) -> Result<BTreeMap<String, Package>> {
+            let map = RefCell::new(BTreeMap::new());
+            let queue = roots.into_iter().map(|m| (m, Vec::new())).collect();
+            let lookup = |m| self.lookup(m, &map);
+            let (pending, bar) = (Pending::default(), ProgressBar::hidden());
+            resolve_graph(&map, queue, |m| m, lookup, &pending, &bar).await?;
+            assert!(pending.list().is_empty(), "{:?}", pending.list());
+            Ok(map.into_inner())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_dependency_resolved_twice_at_once_is_kept_once() {
+        // a and b finish together, so both lookups of c are in flight at the same time.
+        let registry = Registry::new(vec![
+            ("a", "1.0.0", vec![mops("c", "1.0.0")]),
+            ("b", "1.0.0", vec![mops("c", "1.0.0")]),
+            ("c", "1.0.0", vec![mops("d", "1.0.0")]),
+            ("d", "1.0.0", vec![]),
+        ]);
+        let map = registry
+            .resolve(vec![mops("a", "1.0.0"), mops("b", "1.0.0")])
+            .await
+            .unwrap();
+        assert_eq!(
+            map.keys().collect::<Vec<_>>(),
+            ["a-1.0.0", "b-1.0.0", "c-1.0.0", "d-1.0.0"]
+        );
+        assert_eq!(registry.fetches("c-1.0.0"), 2);
+        // The second result for c is dropped with its dependencies.
+        assert_eq!(registry.fetches("d-1.0.0"), 1);
+        assert_eq!(map["a-1.0.0"].dependencies, ["c-1.0.0"]);
+        assert_eq!(map["b-1.0.0"].dependencies, ["c-1.0.0"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_dependency_queued_after_it_resolved_is_not_looked_up_again() {
+        let registry = Registry::new(vec![
+            ("a", "1.0.0", vec![mops("c", "1.0.0")]),
+            ("b", "1.0.0", vec![mops("c", "1.0.0")]),
+            ("c", "1.0.0", vec![]),
+        ])
+        .with_delay("b", 100);
+        let map = registry
+            .resolve(vec![mops("a", "1.0.0"), mops("b", "1.0.0")])
+            .await
+            .unwrap();
+        assert_eq!(map.len(), 3);
+        // The second lookup finds c in the map.
+        assert_eq!(registry.fetches("c-1.0.0"), 1);
+        assert_eq!(map["b-1.0.0"].dependencies, ["c-1.0.0"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn conflicting_versions_resolve_to_the_highest_whichever_finishes_first() {
+        for (slow, fast) in [("a", "b"), ("b", "a")] {
+            let registry = Registry::new(vec![
+                ("a", "1.0.0", vec![mops("c", "1.0.0")]),
+                ("b", "1.0.0", vec![mops("c", "2.0.0")]),
+                ("c", "1.0.0", vec![]),
+                ("c", "2.0.0", vec![]),
+            ])
+            .with_delay(slow, 100)
+            .with_delay(fast, 1);
+            let map = registry
+                .resolve(vec![mops("a", "1.0.0"), mops("b", "1.0.0")])
+                .await
+                .unwrap();
+            assert!(map.contains_key("c-1.0.0") && map.contains_key("c-2.0.0"));
+            let pkgs = resolve_versions(map).unwrap();
+            let c = pkgs.iter().find(|p| p.name == "c").unwrap();
+            assert_eq!(c.version.as_deref(), Some("2.0.0"), "{slow} finishing last");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_same_name_for_two_registry_packages_is_a_conflict() {
+        let registry = Registry::new(vec![
+            ("a", "1.0.0", vec![mops("c", "1.0.0")]),
+            ("c", "1.0.0", vec![]),
+        ]);
+        let mut map = registry.resolve(vec![mops("a", "1.0.0")]).await.unwrap();
+        let mut renamed = map["c-1.0.0"].clone();
+        renamed.package = Some("other".to_string());
+        renamed.version = Some("2.0.0".to_string());
+        map.insert(renamed.get_key(), renamed);
+        let err = resolve_versions(map).unwrap_err();
+        assert!(err.to_string().starts_with("Version conflict"), "{err}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_lookup_reports_how_it_was_required() {
+        let registry = Registry::new(vec![
+            ("a", "1.0.0", vec![mops("b", "1.0.0")]),
+            ("b", "1.0.0", vec![mops("missing", "1.0.0")]),
+        ]);
+        let err = registry
+            .resolve(vec![mops("a", "1.0.0")])
+            .await
+            .unwrap_err();
+        let msg = format!("{err:#}");
+        assert!(
+            msg.contains("required by: mops.toml -> a-1.0.0 -> b-1.0.0"),
+            "{msg}"
+        );
+        assert!(msg.contains("missing@1.0.0 not found"), "{msg}");
+    }
+}
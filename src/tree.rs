@@ -0,0 +1,112 @@
+use crate::graph::{DependencyGraph, Edge};
+use anyhow::Result;
+use clap::ValueEnum;
+use console::style;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum EdgeDisplay {
+    /// Only show the resolved version
+    #[default]
+    Normal,
+    /// Show the version requested by the parent next to the resolved version
+    Requirements,
+}
+
+pub fn tree(args: crate::TreeArg) -> Result<()> {
+    let graph = DependencyGraph::load()?;
+    let mut printed = BTreeSet::new();
+    if crate::utils::json() {
+        let roots: Vec<_> = graph
+            .roots
+            .iter()
+            .map(|(idx, edge)| json_node(&graph, *idx, edge, &mut printed))
+            .collect();
+        return crate::utils::print_json(&roots);
+    }
+    for (idx, edge) in &graph.roots {
+        print_node(&graph, *idx, edge, "", "", args.edges, &mut printed);
+    }
+    Ok(())
+}
+
+fn print_node(
+    graph: &DependencyGraph,
+    idx: NodeIndex,
+    edge: &Edge,
+    prefix: &str,
+    child_prefix: &str,
+    edges: EdgeDisplay,
+    printed: &mut BTreeSet<NodeIndex>,
+) {
+    let node = &graph.graph[idx];
+    let resolved = node.version.as_deref().unwrap_or("?");
+    let mut line = format!("{prefix}{} {resolved}", node.name);
+    if let EdgeDisplay::Requirements = edges {
+        if edge.requirement == resolved {
+            line = format!("{prefix}{} {}", node.name, style(resolved).green());
+        } else {
+            line = format!(
+                "{prefix}{} {} -> {}",
+                node.name,
+                edge.requirement,
+                style(resolved).yellow()
+            );
+        }
+    }
+    // petgraph yields the most recently added edge first
+    let mut children: Vec<_> = graph.graph.edges(idx).collect();
+    children.reverse();
+    if !printed.insert(idx) && !children.is_empty() {
+        println!("{line} {}", style("(*)").dim());
+        return;
+    }
+    println!("{line}");
+    for (i, e) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        let (branch, indent) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        print_node(
+            graph,
+            e.target(),
+            e.weight(),
+            &format!("{child_prefix}{branch}"),
+            &format!("{child_prefix}{indent}"),
+            edges,
+            printed,
+        );
+    }
+}
+
+/// The node at `idx` with its dependencies. Like `(*)` in the printed tree, a node seen before
+/// is marked `repeated` and its dependencies are left out.
+fn json_node(
+    graph: &DependencyGraph,
+    idx: NodeIndex,
+    edge: &Edge,
+    printed: &mut BTreeSet<NodeIndex>,
+) -> Value {
+    let node = &graph.graph[idx];
+    let mut children: Vec<_> = graph.graph.edges(idx).collect();
+    children.reverse();
+    let mut res = json!({
+        "name": node.name,
+        "version": node.version,
+        "requirement": edge.requirement,
+    });
+    if !printed.insert(idx) && !children.is_empty() {
+        res["repeated"] = json!(true);
+        return res;
+    }
+    res["dependencies"] = children
+        .iter()
+        .map(|e| json_node(graph, e.target(), e.weight(), printed))
+        .collect();
+    res
+}
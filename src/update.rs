@@ -0,0 +1,110 @@
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::toml::{
+    download_packages_from_lock, parse_mops_lock, resolve_mops_lock, write_mops_lock,
+    ResolveOptions,
+};
+use crate::utils::{ensure_online, get_cache_dir, Pending};
+use crate::workspace::lock_path;
+use anyhow::{anyhow, Result};
+use console::style;
+use ic_agent::Agent;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+/// Moves the repository dependencies that follow a branch to its newest commit. Registry
+/// packages keep their locked versions.
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn update(agent: &Agent, config: &Config, args: crate::UpdateArg) -> Result<()> {
+    let lock = lock_path();
+    let old = parse_mops_lock(&lock)
+        .map_err(|_| anyhow!("mops.lock not found. Run `mops install` first."))?;
+    let old: BTreeMap<_, _> = old
+        .package
+        .into_iter()
+        .filter(|pkg| pkg.follows_branch())
+        .map(|pkg| (pkg.name.clone(), pkg))
+        .collect();
+    if old.is_empty() {
+        if crate::utils::json() {
+            crate::utils::print_json(&json!({ "dry_run": args.dry_run, "updated": [] }))?;
+        }
+        status!(
+            "{:>12} no dependency follows a branch",
+            style("Fresh").green().bold()
+        );
+        return Ok(());
+    }
+    ensure_online("look up the newest commits of branches")?;
+    let options = ResolveOptions {
+        refresh_branches: true,
+        ..ResolveOptions::from_config(config)?
+    };
+    let pending = Pending::default();
+    let pkgs = resolve_mops_lock(agent, &pending, &options).await?;
+    let mut updated = Vec::new();
+    for pkg in pkgs.package.iter().filter(|pkg| pkg.follows_branch()) {
+        let (Some(from), Some(to)) = (old.get(&pkg.name).and_then(|p| p.repo.as_ref()), &pkg.repo)
+        else {
+            continue;
+        };
+        if from.commit == to.commit {
+            continue;
+        }
+        updated.push(json!({
+            "name": pkg.name,
+            "repo": to.web_path(),
+            "branch": to.tag,
+            "from": from.commit,
+            "to": to.commit,
+        }));
+        let verb = if args.dry_run {
+            "Would update"
+        } else {
+            "Updating"
+        };
+        status!(
+            "{:>12} {} {}#{} {}..{}",
+            style(verb).green().bold(),
+            pkg.name,
+            to.web_path(),
+            to.tag,
+            from.short_commit(),
+            to.short_commit()
+        );
+    }
+    let report = || -> Result<()> {
+        if crate::utils::json() {
+            crate::utils::print_json(&json!({ "dry_run": args.dry_run, "updated": updated }))?;
+        }
+        Ok(())
+    };
+    if updated.is_empty() {
+        report()?;
+        status!(
+            "{:>12} {} branch(es) have no new commits",
+            style("Fresh").green().bold(),
+            old.len()
+        );
+        return Ok(());
+    }
+    if args.dry_run {
+        return report();
+    }
+    write_mops_lock(&lock, &pkgs)?;
+    download_packages_from_lock(
+        agent,
+        &get_cache_dir(config)?,
+        &pending,
+        HashAlgorithm::from_config(config)?,
+        false,
+    )
+    .await?;
+    status!(
+        "{:>12} {} dependenc{}",
+        style("Updated").green().bold(),
+        updated.len(),
+        if updated.len() == 1 { "y" } else { "ies" }
+    );
+    report()
+}
@@ -0,0 +1,190 @@
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::mops;
+use crate::toml::{
+    download_packages_from_lock, get_direct_dependencies, update_mops_lock, ResolveOptions,
+};
+use crate::utils::{ensure_online, get_cache_dir, Pending};
+use anyhow::{anyhow, Error, Result};
+use console::style;
+use ic_agent::Agent;
+use semver::{Version, VersionReq};
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+use toml_edit::DocumentMut;
+
+struct Upgrade {
+    name: String,
+    from: Version,
+    /// Newest version within the compatible range, if newer than `from`.
+    compatible: Option<Version>,
+    /// Newest version overall, if it is outside the compatible range.
+    incompatible: Option<Version>,
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn upgrade(agent: &Agent, config: &Config, args: crate::UpgradeArg) -> Result<()> {
+    let service = mops::Service(crate::utils::registry_id(), agent);
+    let deps = get_direct_dependencies()?;
+    if !deps.is_empty() {
+        ensure_online("check for newer versions")?;
+    }
+    for name in &args.packages {
+        if !deps.iter().any(|(dep, _)| dep == name) {
+            return Err(anyhow!("{name} is not a dependency in mops.toml"));
+        }
+    }
+    let mut doc = fs::read_to_string("mops.toml")?.parse::<DocumentMut>()?;
+    let mut upgrades = Vec::new();
+    for (name, requirement) in deps {
+        if !args.packages.is_empty() && !args.packages.contains(&name) {
+            continue;
+        }
+        // GitHub and local dependencies have no registry versions to move between.
+        let Ok(from) = requirement.parse::<Version>() else {
+            continue;
+        };
+        let package = doc
+            .get("dependencies")
+            .and_then(|deps| deps.get(&name))
+            .and_then(|dep| dep.get("package"))
+            .and_then(|package| package.as_str())
+            .map(String::from);
+        upgrades.push(find_upgrade(&service, name, package, from).await?);
+    }
+    let mut upgraded = Vec::new();
+    let mut withheld = Vec::new();
+    for up in &upgrades {
+        let target = if args.incompatible {
+            up.incompatible.as_ref().or(up.compatible.as_ref())
+        } else {
+            up.compatible.as_ref()
+        };
+        if let Some(to) = target {
+            let verb = if args.dry_run {
+                "Would upgrade"
+            } else {
+                "Upgrading"
+            };
+            status!(
+                "{:>12} {} {} -> {to}",
+                style(verb).green().bold(),
+                up.name,
+                up.from
+            );
+            set_requirement(&mut doc, &up.name, &to.to_string());
+            upgraded.push(
+                json!({ "name": up.name, "from": up.from.to_string(), "to": to.to_string() }),
+            );
+        }
+        if let (false, Some(latest)) = (args.incompatible, &up.incompatible) {
+            status!(
+                "{:>12} {} {latest} is not compatible with {}. Pass --incompatible to upgrade",
+                style("Withheld").yellow().bold(),
+                up.name,
+                up.from
+            );
+            withheld.push(
+                json!({ "name": up.name, "from": up.from.to_string(), "to": latest.to_string() }),
+            );
+        }
+    }
+    let report = || -> Result<()> {
+        if crate::utils::json() {
+            crate::utils::print_json(&json!({
+                "dry_run": args.dry_run,
+                "upgraded": upgraded,
+                "withheld": withheld,
+            }))?;
+        }
+        Ok(())
+    };
+    if upgraded.is_empty() {
+        report()?;
+        status!(
+            "{:>12} all dependencies",
+            style("Up to date").green().bold()
+        );
+        return Ok(());
+    }
+    if args.dry_run {
+        return report();
+    }
+    fs::write(Path::new("mops.toml"), doc.to_string())?;
+    let cache_dir = get_cache_dir(config)?;
+    let pending = Pending::default();
+    update_mops_lock(agent, &pending, &ResolveOptions::from_config(config)?).await?;
+    download_packages_from_lock(
+        agent,
+        &cache_dir,
+        &pending,
+        HashAlgorithm::from_config(config)?,
+        false,
+    )
+    .await?;
+    status!(
+        "{:>12} mops.toml and mops.lock",
+        style("Updated").green().bold()
+    );
+    report()
+}
+
+/// Looks up the published versions of `name` and splits the newer ones by `^from` compatibility.
+async fn find_upgrade(
+    service: &mops::Service<'_>,
+    name: String,
+    package: Option<String>,
+    from: Version,
+) -> Result<Upgrade> {
+    let registry_name = package.as_ref().unwrap_or(&name);
+    let highest = crate::metadata::highest_version(service, registry_name)
+        .await?
+        .map_err(Error::msg)?;
+    let details = crate::metadata::package_details(service, registry_name, &highest).await?;
+    let mut versions: Vec<Version> = details
+        .version_history
+        .iter()
+        .filter_map(|v| v.config.version.parse().ok())
+        .filter(|v: &Version| *v > from && (v.pre.is_empty() || !from.pre.is_empty()))
+        .collect();
+    versions.sort();
+    versions.reverse();
+    let req = VersionReq::parse(&format!("^{from}"))?;
+    let mut compatible = None;
+    let mut incompatible = None;
+    for version in versions {
+        let is_compatible = req.matches(&version);
+        if (is_compatible && compatible.is_some()) || (!is_compatible && incompatible.is_some()) {
+            continue;
+        }
+        if is_compatible {
+            compatible = Some(version);
+            break;
+        }
+        incompatible = Some(version);
+    }
+    Ok(Upgrade {
+        name,
+        from,
+        compatible,
+        incompatible,
+    })
+}
+
+/// Replaces the version of a dependency, keeping its comments and spacing.
+fn set_requirement(doc: &mut DocumentMut, name: &str, version: &str) {
+    if let Some(spec) = doc["dependencies"][name].as_inline_table_mut() {
+        if let Some(value) = spec.get_mut("version") {
+            let decor = value.decor().clone();
+            *value = version.into();
+            *value.decor_mut() = decor;
+        }
+        return;
+    }
+    if let Some(value) = doc["dependencies"][name].as_value_mut() {
+        let decor = value.decor().clone();
+        *value = version.into();
+        *value.decor_mut() = decor;
+    }
+}
@@ -1,48 +1,266 @@
+use crate::config::Config;
 use crate::github::get_latest_release_tag;
 use anyhow::{anyhow, Context, Result};
+use candid::Principal;
 use console::style;
 use flate2::read::GzDecoder;
+use ic_agent::agent::http_transport::ReqwestTransport;
+use ic_agent::{Agent, Identity};
 use indicatif::{ProgressBar, ProgressStyle};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
 use std::fs::{self, File};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
 use tar::Archive;
 
-pub fn get_cache_dir(base_path: &Option<PathBuf>) -> Result<PathBuf> {
-    if let Some(dir) = base_path {
-        Ok(PathBuf::from(dir))
-    } else if let Ok(home) = std::env::var("HOME") {
-        Ok(PathBuf::from(home).join(".mops"))
-    } else {
+pub fn get_cache_dir(config: &Config) -> Result<PathBuf> {
+    config.get("cache_dir").map(PathBuf::from).ok_or_else(|| {
+        anyhow!("Cannot find home directory, use --cache-dir to specify the cache directory.")
+    })
+}
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Fails with a clear message when `--offline` is set, instead of letting `what` wait on the network.
+pub(crate) fn ensure_online(what: impl std::fmt::Display) -> Result<()> {
+    if OFFLINE.load(Ordering::Relaxed) {
         return Err(anyhow!(
-            "Cannot find home directory, use --cache_dir to specify the cache directory."
+            "Cannot {what} in offline mode. Run once without --offline to fill mops.lock and the cache."
         ));
     }
+    Ok(())
+}
+pub(crate) fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Makes commands print their result as JSON on stdout, and everything else on stderr.
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+/// Whether `--format json` was passed.
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Prints the result of a command as JSON on stdout.
+pub(crate) fn print_json(value: &impl serde::Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+static JOBS: AtomicUsize = AtomicUsize::new(8);
+
+/// How many packages are downloaded at the same time, from `jobs`.
+pub(crate) fn jobs() -> usize {
+    JOBS.load(Ordering::Relaxed)
+}
+
+static REGISTRY: OnceLock<Principal> = OnceLock::new();
+
+/// The canister of the mops registry, from `registry_canister`.
+pub(crate) fn registry_id() -> Principal {
+    *REGISTRY.get().unwrap_or(&crate::mops::CANISTER_ID)
+}
+
+/// Creates the agent talking to the mops registry, applying the per-request timeouts from `config`.
+pub fn create_agent(config: &Config) -> Result<Agent> {
+    OFFLINE.store(
+        config.get_parsed::<bool>("offline")?.unwrap_or(false),
+        Ordering::Relaxed,
+    );
+    JOBS.store(
+        config.get_parsed::<usize>("jobs")?.unwrap_or(8).max(1),
+        Ordering::Relaxed,
+    );
+    if let Some(id) = config.get("registry_canister") {
+        let id = Principal::from_text(id)
+            .map_err(|_| anyhow!("Invalid registry canister {id}, expected a principal"))?;
+        let _ = REGISTRY.set(id);
+    }
+    crate::github::configure(config)?;
+    crate::git::configure(config)?;
+    crate::store::configure(config)?;
+    crate::history::configure(config)?;
+    crate::metadata::configure(config)?;
+    crate::npm::configure(config)?;
+    crate::retry::configure(config)?;
+    let client = registry_client(config)?;
+    let url = registry_url(config)?;
+    crate::mirrors::configure(config, &client, &url)?;
+    let agent = registry_agent(
+        config,
+        &url,
+        client,
+        crate::identity::load_identity(config)?,
+    )?;
+    let name = match config.get("registry_url") {
+        Some(_) => "registry".to_string(),
+        None => format!("{} network", config.get("network").unwrap_or("ic")),
+    };
+    fetch_root_key(&agent, &url).with_context(|| format!("Cannot reach the {name} at {url}"))?;
+    Ok(agent)
+}
+
+/// The HTTP client of registry and storage calls, with the `registry_timeout` of each request.
+pub(crate) fn registry_client(config: &Config) -> Result<reqwest::Client> {
+    let timeout = config.get_parsed::<u64>("registry_timeout")?.unwrap_or(60);
+    Ok(http_client_builder(config)?
+        .timeout(Duration::from_secs(timeout))
+        .build()?)
+}
+
+/// The replica the registry is on: `registry_url`, or else the one of `network`.
+pub(crate) fn registry_url(config: &Config) -> Result<String> {
+    match config.get("registry_url") {
+        Some(url) => Ok(url.to_string()),
+        None => crate::dfx::network_url(config.get("network").unwrap_or("ic")),
+    }
+}
+
+/// An agent for a registry on the replica at `url`. `ingress_expiry` (seconds) bounds how long
+/// an update call may wait to be executed, `max_response_size` (bytes) fails larger answers
+/// instead of reading them, and `verify_query_signatures = false` accepts uncertified queries.
+pub(crate) fn registry_agent(
+    config: &Config,
+    url: &str,
+    client: reqwest::Client,
+    identity: Option<Box<dyn Identity>>,
+) -> Result<Agent> {
+    // Connection errors are retried by `retry`, with the other transient errors.
+    let mut transport =
+        ReqwestTransport::create_with_client(url, client)?.with_max_tcp_errors_retries(0);
+    if let Some(size) = config.get_parsed::<usize>("max_response_size")? {
+        transport = transport.with_max_response_body_size(size);
+    }
+    let expiry = config.get_parsed::<u64>("ingress_expiry")?;
+    let verify = config
+        .get_parsed::<bool>("verify_query_signatures")?
+        .unwrap_or(true);
+    let mut builder = Agent::builder()
+        .with_transport(transport)
+        .with_ingress_expiry(expiry.map(Duration::from_secs))
+        .with_verify_query_signatures(verify);
+    if let Some(identity) = identity {
+        builder = builder.with_boxed_identity(identity);
+    }
+    Ok(builder.build()?)
+}
+
+/// Fetches the root key of a replica other than mainnet, whose key is the only one built into
+/// the agent. Nothing is fetched offline.
+pub(crate) fn fetch_root_key(agent: &Agent, url: &str) -> Result<()> {
+    if crate::dfx::is_mainnet_url(url) || is_offline() {
+        return Ok(());
+    }
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(agent.fetch_root_key())?;
+    Ok(())
+}
+
+/// Sent with every HTTP request unless `user_agent` is configured.
+pub(crate) const USER_AGENT: &str = concat!("mops-cli/", env!("CARGO_PKG_VERSION"));
+
+/// The single place where HTTP transport tuning is applied: response compression, connection
+/// pooling, keep-alive, proxy and TLS. Set `http_keep_alive = 0` to disable TCP and HTTP/2
+/// keep-alive pings. Without `proxy`, `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` apply.
+pub fn http_client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
+    let compression = config
+        .get_parsed::<bool>("http_compression")?
+        .unwrap_or(true);
+    let idle_timeout = config.get_parsed::<u64>("http_pool_idle_timeout")?;
+    let max_idle = config.get_parsed::<usize>("http_pool_max_idle")?;
+    let keep_alive = config
+        .get_parsed::<u64>("http_keep_alive")?
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    let connect_timeout = config
+        .get_parsed::<u64>("http_connect_timeout")?
+        .unwrap_or(10);
+    let mut builder = reqwest::Client::builder()
+        .user_agent(config.get("user_agent").unwrap_or(USER_AGENT))
+        .connect_timeout(Duration::from_secs(connect_timeout))
+        .gzip(compression)
+        .brotli(compression)
+        .pool_idle_timeout(idle_timeout.map(Duration::from_secs))
+        .tcp_keepalive(keep_alive);
+    if let Some(max_idle) = max_idle {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(interval) = keep_alive {
+        builder = builder
+            .http2_keep_alive_interval(interval)
+            .http2_keep_alive_while_idle(true)
+            .http2_adaptive_window(true);
+    }
+    if let Some(proxy) = config.get("proxy") {
+        // The URL may carry credentials, so it stays out of the message.
+        let proxy = reqwest::Proxy::all(proxy).map_err(|_| anyhow!("Invalid proxy URL"))?;
+        let no_proxy = config
+            .get("no_proxy")
+            .and_then(reqwest::NoProxy::from_string);
+        builder = builder.proxy(proxy.no_proxy(no_proxy));
+    }
+    crate::tls::configure(builder, config)
 }
 
 pub fn get_moc(base_path: &Path) -> Result<Command> {
-    let cmd = Command::new(format!("{}/bin/moc", base_path.display()));
-    Ok(cmd)
+    let (dir, _) = toolchain_dir(base_path)?;
+    Ok(Command::new(dir.join("moc")))
+}
+
+pub fn get_mo_doc(base_path: &Path) -> Result<Command> {
+    let (dir, _) = toolchain_dir(base_path)?;
+    Ok(Command::new(dir.join("mo-doc")))
+}
+
+/// Where moc and mo-doc live: `moc/<version>` for the version pinned in the `[toolchain]` table
+/// of mops.toml, or `bin` for the latest release.
+fn toolchain_dir(base_path: &Path) -> Result<(PathBuf, Option<String>)> {
+    Ok(match crate::toml::get_toolchain_moc()? {
+        Some(version) => (base_path.join("moc").join(&version), Some(version)),
+        None => (base_path.join("bin"), None),
+    })
 }
 
 pub async fn download_moc(base_path: &Path) -> Result<()> {
     use std::io::Write;
-    if base_path.join("bin/moc").exists() {
+    let (dir, pinned) = toolchain_dir(base_path)?;
+    if dir.join("moc").exists() {
         return Ok(());
     }
+    ensure_online("download moc")?;
     let bar = create_spinner_bar("Downloading moc");
-    let tag = get_latest_release_tag("dfinity/motoko").await?;
-    let platform = if cfg!(target_os = "macos") {
-        "Darwin"
-    } else if cfg!(target_os = "linux") {
-        "Linux"
-    } else {
-        anyhow::bail!("Unsupported platform");
+    let tag = match pinned {
+        Some(version) => version,
+        None => get_latest_release_tag("dfinity/motoko").await?,
     };
-    let url = format!("https://github.com/dfinity/motoko/releases/download/{tag}/motoko-{platform}-x86_64-{tag}.tar.gz");
+    let platform = match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("macos", "aarch64") => "Darwin-arm64",
+        ("macos", _) => "Darwin-x86_64",
+        ("linux", "aarch64") => "Linux-aarch64",
+        ("linux", _) => "Linux-x86_64",
+        _ => anyhow::bail!("Unsupported platform"),
+    };
+    let url = format!(
+        "https://github.com/dfinity/motoko/releases/download/{tag}/motoko-{platform}-{tag}.tar.gz"
+    );
     bar.set_message(format!("Downloading moc {tag}"));
-    let response = reqwest::get(url).await?;
-    let gz_file = base_path.join(format!("bin/moc-{tag}.tar.gz"));
+    let response = crate::github::client()
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?;
+    let gz_file = dir.join(format!("moc-{tag}.tar.gz"));
     fs::create_dir_all(gz_file.parent().unwrap())?;
     let mut file = File::create(&gz_file)?;
     let content = response.bytes().await?;
@@ -51,7 +269,7 @@ pub async fn download_moc(base_path: &Path) -> Result<()> {
     let gz = File::open(&gz_file)?;
     let tar = GzDecoder::new(gz);
     let mut archive = Archive::new(tar);
-    archive.unpack(base_path.join("bin"))?;
+    archive.unpack(&dir)?;
     fs::remove_file(&gz_file)?;
     bar.set_message(format!(
         "{:>12} moc {tag}",
@@ -78,7 +296,7 @@ pub fn exec(mut cmd: Command, bar: Option<&ProgressBar>) -> Result<()> {
 }
 pub fn println(bar: Option<&ProgressBar>, target: &str, msg: &str) {
     if bar.is_none() || bar.is_some_and(|bar| bar.is_hidden()) {
-        if target == "stderr" {
+        if target == "stderr" || json() {
             eprintln!("{msg}");
         } else {
             println!("{msg}");
@@ -103,3 +321,50 @@ pub fn create_spinner_bar(msg: impl Into<std::borrow::Cow<'static, str>>) -> Pro
     pb.set_message(msg);
     pb
 }
+
+/// Packages that are still being resolved or downloaded, so we can report them when a deadline is hit.
+#[derive(Default, Clone)]
+pub struct Pending(Rc<RefCell<BTreeSet<String>>>);
+impl Pending {
+    pub fn start(&self, name: &str) {
+        self.0.borrow_mut().insert(name.to_string());
+    }
+    pub fn finish(&self, name: &str) {
+        self.0.borrow_mut().remove(name);
+    }
+    pub fn list(&self) -> Vec<String> {
+        self.0.borrow().iter().cloned().collect()
+    }
+}
+
+/// `path` relative to `base`, both absolute. Falls back to `path` if they share no root.
+pub(crate) fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let path: Vec<_> = path.components().collect();
+    let base: Vec<_> = base.components().collect();
+    if path.first() != base.first() {
+        return path.iter().collect();
+    }
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let mut res: PathBuf = base[common..]
+        .iter()
+        .map(|_| Component::ParentDir)
+        .collect();
+    res.extend(&path[common..]);
+    if res.as_os_str().is_empty() {
+        res.push(".");
+    }
+    res
+}
+
+/// Asks the user to confirm on the terminal. The prompt goes to stderr, as stdout may carry the
+/// result as JSON. Outside a terminal there is no one to ask, so this fails with a hint to pass
+/// `--yes` to `what`.
+pub(crate) fn confirm(what: &str) -> Result<bool> {
+    let term = console::Term::stderr();
+    if !term.is_term() {
+        return Err(anyhow!("Not a terminal. Pass --yes to {what}."));
+    }
+    term.write_str("Proceed? [y/N] ")?;
+    let answer = term.read_line()?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
@@ -0,0 +1,103 @@
+//! Copies the locked packages into the project, so it builds without network or package cache.
+use crate::config::Config;
+use crate::integrity::HashAlgorithm;
+use crate::toml::{download_packages_from_lock, parse_mops_lock, write_mops_lock, PackageType};
+use crate::utils::{get_cache_dir, relative_to, Pending};
+use crate::workspace::{lock_dir, lock_path};
+use anyhow::{anyhow, Result};
+use console::style;
+use ic_agent::Agent;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Marks a directory `mops vendor` wrote, so it may empty it on later runs.
+const MARKER: &str = ".mops-vendor";
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+pub async fn vendor(agent: &Agent, config: &Config, args: crate::VendorArg) -> Result<()> {
+    let lock = lock_path();
+    if !lock.exists() {
+        return Err(anyhow!("mops.lock not found. Run `mops install` first."));
+    }
+    let cache_dir = get_cache_dir(config)?;
+    let algorithm = HashAlgorithm::from_config(config)?;
+    download_packages_from_lock(agent, &cache_dir, &Pending::default(), algorithm, false).await?;
+    let mut pkgs = parse_mops_lock(&lock)?;
+    let from = pkgs.package_root(&cache_dir);
+    let to = std::path::absolute(&args.dir)?;
+    let vendored = to.join(MARKER).exists();
+    if !vendored && fs::read_dir(&to).is_ok_and(|mut dir| dir.next().is_some()) {
+        return Err(anyhow!(
+            "{} is not empty and was not vendored into before. Pick a new directory.",
+            args.dir.display()
+        ));
+    }
+    let paths: BTreeSet<_> = pkgs
+        .package
+        .iter()
+        .filter(|pkg| !matches!(pkg.get_type(), PackageType::Local(_)))
+        .map(|pkg| PathBuf::from(pkg.get_path()))
+        .collect();
+    if fs::canonicalize(&from).ok() != fs::canonicalize(&to).ok() {
+        for path in &paths {
+            let dest = to.join(path);
+            if dest.exists() {
+                fs::remove_dir_all(&dest)?;
+            }
+            copy_dir(&from.join(path), &dest)?;
+        }
+    }
+    fs::create_dir_all(&to)?;
+    fs::write(to.join(MARKER), "")?;
+    for dir in ["mops", "git"] {
+        if to.join(dir).is_dir() {
+            prune(&to, Path::new(dir), &paths)?;
+        }
+    }
+    let dir = relative_to(&fs::canonicalize(&to)?, &lock_dir());
+    pkgs.vendor = Some(dir.display().to_string().replace('\\', "/"));
+    write_mops_lock(&lock, &pkgs)?;
+    status!(
+        "{:>12} {} package(s) into {}",
+        style("Vendored").green().bold(),
+        paths.len(),
+        args.dir.display()
+    );
+    Ok(())
+}
+
+/// Copies `from` to `to`, following the links a package store leaves in the cache.
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in WalkDir::new(from).follow_links(true) {
+        let entry = entry?;
+        let dest = to.join(entry.path().strip_prefix(from)?);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest)?;
+        } else {
+            fs::copy(entry.path(), &dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes what is under `root/dir` but is neither one of `paths` nor on the way to one, so the
+/// `mops` and `git` directories hold exactly the locked packages.
+fn prune(root: &Path, dir: &Path, paths: &BTreeSet<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        if paths.contains(&path) {
+            continue;
+        }
+        if paths.iter().any(|p| p.starts_with(&path)) {
+            prune(root, &path, paths)?;
+        } else if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
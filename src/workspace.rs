@@ -0,0 +1,115 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use toml_edit::ImDocument;
+
+/// A package of a workspace, declared in the root mops.toml as
+/// `[workspace] members = ["packages/*", "tools/gen"]`.
+#[derive(Debug, Clone)]
+pub(crate) struct Member {
+    /// The package name from the member's mops.toml, or its directory name
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+/// The members of the workspace rooted at `root`. A pattern ending in `/*` matches every
+/// subdirectory with a mops.toml; anything else is a directory path.
+pub(crate) fn members(root: &Path) -> Result<Vec<Member>> {
+    let toml = root.join("mops.toml");
+    let str =
+        fs::read_to_string(&toml).with_context(|| format!("Cannot read {}", toml.display()))?;
+    let doc = str.parse::<ImDocument<_>>()?;
+    let patterns: Vec<String> = doc
+        .get("workspace")
+        .and_then(|w| w.get("members"))
+        .and_then(|m| m.as_array())
+        .ok_or_else(|| anyhow!("{} has no [workspace] members", toml.display()))?
+        .iter()
+        .filter_map(|v| v.as_str().map(|s| s.to_string()))
+        .collect();
+    let mut dirs = Vec::new();
+    for pattern in &patterns {
+        if let Some(parent) = pattern.strip_suffix("/*") {
+            let parent = root.join(parent);
+            let mut found: Vec<_> = fs::read_dir(&parent)
+                .with_context(|| format!("Cannot read {}", parent.display()))?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|dir| dir.join("mops.toml").is_file())
+                .collect();
+            found.sort();
+            dirs.extend(found);
+        } else {
+            let dir = root.join(pattern);
+            if !dir.join("mops.toml").is_file() {
+                return Err(anyhow!(
+                    "Workspace member {pattern} has no mops.toml at {}",
+                    dir.display()
+                ));
+            }
+            dirs.push(dir);
+        }
+    }
+    // A member matched by several patterns is still one member.
+    let mut seen = BTreeSet::new();
+    dirs.retain(|dir| seen.insert(dir.clone()));
+    dirs.into_iter()
+        .map(|dir| {
+            let str = fs::read_to_string(dir.join("mops.toml"))?;
+            let doc = str.parse::<ImDocument<_>>()?;
+            let name = doc
+                .get("package")
+                .and_then(|p| p.get("name"))
+                .and_then(|n| n.as_str())
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| dir.file_name().unwrap().to_string_lossy().to_string());
+            Ok(Member { name, dir })
+        })
+        .collect()
+}
+
+/// The root of the workspace the current directory belongs to, as the root itself or one of its
+/// members. Looked up once per process.
+pub(crate) fn root() -> Option<&'static Path> {
+    static ROOT: OnceLock<Option<PathBuf>> = OnceLock::new();
+    ROOT.get_or_init(|| {
+        let cwd = std::env::current_dir().ok()?;
+        cwd.ancestors()
+            .find(|dir| {
+                has_workspace(dir)
+                    && (*dir == cwd
+                        || members(dir).is_ok_and(|members| {
+                            members
+                                .iter()
+                                .any(|m| fs::canonicalize(&m.dir).is_ok_and(|d| d == cwd))
+                        }))
+            })
+            .map(Path::to_path_buf)
+    })
+    .as_deref()
+}
+
+fn has_workspace(dir: &Path) -> bool {
+    fs::read_to_string(dir.join("mops.toml"))
+        .ok()
+        .and_then(|str| str.parse::<ImDocument<_>>().ok())
+        .is_some_and(|doc| doc.get("workspace").is_some())
+}
+
+/// The mops.lock of the project: the one at the workspace root, shared by all members, or else
+/// the one in the current directory.
+pub(crate) fn lock_path() -> PathBuf {
+    match root() {
+        Some(root) => root.join("mops.lock"),
+        None => PathBuf::from("mops.lock"),
+    }
+}
+
+/// Canonical directory of the project's mops.lock. Local paths in the lock are relative to it.
+pub(crate) fn lock_dir() -> PathBuf {
+    let lock = lock_path();
+    let dir = lock.parent().filter(|p| !p.as_os_str().is_empty());
+    let dir = dir.unwrap_or(Path::new("."));
+    fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf())
+}
@@ -0,0 +1,93 @@
+//! A running daemon must answer exactly what the commands print without one.
+
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A project with one local dependency, which installs without the network.
+struct Project(PathBuf);
+
+impl Project {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("mops-daemon-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("lib/src")).unwrap();
+        fs::create_dir_all(dir.join("app")).unwrap();
+        fs::write(
+            dir.join("lib/mops.toml"),
+            "[package]\nname = \"lib\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("lib/src/lib.mo"), "module {}\n").unwrap();
+        fs::write(
+            dir.join("app/mops.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\nlib = \"path:../lib\"\n",
+        )
+        .unwrap();
+        let project = Project(dir);
+        project.mops(&["install"]);
+        project
+    }
+
+    fn mops(&self, args: &[&str]) -> String {
+        let output = Command::new(env!("CARGO_BIN_EXE_mops-cli"))
+            .args(args)
+            .current_dir(self.0.join("app"))
+            .env("HOME", self.0.join("home"))
+            .env("XDG_CACHE_HOME", self.0.join("home/.cache"))
+            .env("MOPS_OFFLINE", "true")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "mops {args:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn outputs(&self) -> Vec<String> {
+        vec![
+            self.mops(&["sources"]),
+            self.mops(&["sources", "--json"]),
+            self.mops(&["status", "--json"]),
+            self.mops(&["daemon", "resolve-import", "mo:lib/Foo"]),
+        ]
+    }
+
+    fn daemon_requests(&self) -> u64 {
+        let status: Value = serde_json::from_str(&self.mops(&["daemon", "status"])).unwrap();
+        status["requests"].as_u64().unwrap()
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = Command::new(env!("CARGO_BIN_EXE_mops-cli"))
+            .args(["daemon", "stop"])
+            .current_dir(self.0.join("app"))
+            .output();
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn daemon_answers_like_the_commands() {
+    let project = Project::new();
+    let direct = project.outputs();
+    assert!(direct[0].contains("--package lib"), "{}", direct[0]);
+
+    project.mops(&["daemon", "start"]);
+    let before = project.daemon_requests();
+    assert_eq!(project.outputs(), direct);
+    // The four commands and this status query reached the daemon.
+    assert_eq!(project.daemon_requests(), before + 5);
+
+    // A `.mops/local` override is picked up by both.
+    fs::create_dir_all(project.0.join("app/.mops/local/lib")).unwrap();
+    let served = project.outputs();
+    project.mops(&["daemon", "stop"]);
+    assert_eq!(project.outputs(), served);
+    assert!(served[0].contains(".mops/local/lib"), "{}", served[0]);
+}